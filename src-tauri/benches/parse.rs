@@ -0,0 +1,124 @@
+use std::{collections::HashSet, path::Path};
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use dtools::node::Node;
+use dtools::sniffer::{
+    capture::open_file,
+    network::ListenerAction,
+    parser::{
+        metadata::{PacketHeader, PacketMetadata},
+        packet::PacketParser,
+    },
+    protocol::protocol::ProtocolManager,
+};
+
+const FIXTURES: &str = "tests/fixtures/";
+const SEGMENT_SIZE: usize = 64;
+
+/// Payloads of the fixture capture holding exactly one message that parses
+fn captured_payloads(protocol: &ProtocolManager) -> Vec<Vec<u8>> {
+    let mut cap = open_file(Path::new(FIXTURES).join("cap.pcap")).unwrap();
+    let mut payloads = Vec::new();
+    while let Ok(packet) = cap.next_packet() {
+        let Ok(header) = PacketHeader::from_vec(&packet.data.to_vec()) else {
+            continue;
+        };
+        let Ok(metadata) = PacketMetadata::from_buffer(header.body.clone()) else {
+            continue;
+        };
+        let framed_size = header.body.len() - metadata.data.len();
+        if framed_size > 5
+            || PacketParser::from_metadata(&metadata)
+                .parse(protocol)
+                .is_err()
+        {
+            continue;
+        }
+        payloads.push(header.body);
+    }
+    payloads
+}
+
+fn bench_parse(c: &mut Criterion) {
+    let mut protocol = ProtocolManager::new();
+    protocol.init(FIXTURES).unwrap();
+    let payloads = captured_payloads(&protocol);
+
+    let mut group = c.benchmark_group("parse");
+    group.throughput(Throughput::Elements(payloads.len() as u64));
+    group.bench_function("from_buffer + parse", |b| {
+        b.iter(|| {
+            for payload in &payloads {
+                let metadata = PacketMetadata::from_buffer(payload.clone()).unwrap();
+                PacketParser::from_metadata(&metadata)
+                    .parse(&protocol)
+                    .unwrap();
+            }
+        })
+    });
+    group.finish();
+}
+
+fn bench_reassembly(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let node = runtime
+        .block_on(Node::new(Path::new(FIXTURES), None, false))
+        .unwrap();
+    let payloads = captured_payloads(&node.protocol.read().unwrap());
+    let ids: HashSet<u16> = payloads
+        .iter()
+        .map(|payload| u16::from_be_bytes([payload[0], payload[1]]) >> 2)
+        .collect();
+    for id in ids {
+        node.packet_listener
+            .subscribe(id, "bench", |_, _| ListenerAction::Continue);
+    }
+
+    // each payload is split in segments, every pair of segments is swapped.
+    // The segments keep their TCP sequence number, the reassembly puts them back in order.
+    let mut segments: Vec<(u32, Vec<u8>)> = Vec::new();
+    let mut offset = 0u32;
+    for (index, payload) in payloads.iter().enumerate() {
+        let mut payload_segments: Vec<(u32, Vec<u8>)> = payload
+            .chunks(SEGMENT_SIZE)
+            .map(|segment| {
+                let seq = offset;
+                offset += segment.len() as u32;
+                (seq, segment.to_vec())
+            })
+            .collect();
+        // the first payload starts the stream, there is nothing to reorder it after
+        if index > 0 {
+            for pair in payload_segments.chunks_mut(2) {
+                pair.reverse();
+            }
+        }
+        segments.extend(payload_segments);
+    }
+    let stream_len = offset;
+
+    let mut group = c.benchmark_group("reassembly");
+    group.throughput(Throughput::Elements(payloads.len() as u64));
+    group.bench_function("shuffled segments", |b| {
+        // each iteration continues the stream of the previous one
+        let mut start = 0u32;
+        b.iter_batched(
+            || segments.clone(),
+            |segments| {
+                for (offset, segment) in segments {
+                    node.packet_listener.inject_bytes(
+                        vec![127, 0, 0, 1],
+                        start.wrapping_add(offset),
+                        &segment,
+                    );
+                }
+                start = start.wrapping_add(stream_len);
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_parse, bench_reassembly);
+criterion_main!(benches);
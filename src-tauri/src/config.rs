@@ -6,17 +6,19 @@ use std::{
 use thiserror::Error;
 use tracing::info;
 
-use crate::sniffer::config::NetworkConfig;
+use crate::sniffer::config::{EventFilterConfig, NetworkConfig};
 
 #[derive(Debug, Clone, Serialize, Deserialize, specta::Type)]
-#[serde(rename_all = "camelCase")]
+#[serde(rename_all = "camelCase", default)]
 pub struct NodeConfig {
     pub network: NetworkConfig,
     pub game_version: Version,
+    /// Events the sniffer processes, applied when the capture starts and when it changes
+    pub events: EventFilterConfig,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, specta::Type)]
-#[serde(rename_all = "camelCase")]
+#[derive(Debug, Clone, Default, Serialize, Deserialize, specta::Type)]
+#[serde(rename_all = "camelCase", default)]
 pub struct Version {
     pub version: String,
     pub check_for_updates: bool,
@@ -52,10 +54,8 @@ impl Default for NodeConfig {
     fn default() -> Self {
         NodeConfig {
             network: NetworkConfig::default(),
-            game_version: Version {
-                version: "".to_string(),
-                check_for_updates: false,
-            },
+            game_version: Version::default(),
+            events: EventFilterConfig::default(),
         }
     }
 }
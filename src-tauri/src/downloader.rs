@@ -1,21 +1,28 @@
-use crate::constants::{DATA_URL, EXTRACTOR_DIR, VERSION_REGEX};
+use crate::constants::{DATA_URL, EVENTS_FILE, EXTRACTOR_DIR, VERSION_FILE, VERSION_REGEX};
 use crate::node::Node;
 use fs_extra::dir::move_dir;
 use fs_extra::dir::remove;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use tempdir::TempDir;
 use thiserror::Error;
-use tracing::{debug, info};
+use tracing::{debug, info, warn};
 
 #[derive(Debug)]
 pub struct Downloader {
     pub latest_version: Option<String>,
+    /// Datafus releases page, overridden in tests
+    base_url: String,
 }
 
 impl Downloader {
     pub fn new() -> Downloader {
+        return Downloader::with_base_url(DATA_URL);
+    }
+
+    pub fn with_base_url(base_url: impl Into<String>) -> Downloader {
         return Downloader {
             latest_version: None,
+            base_url: base_url.into(),
         };
     }
 
@@ -38,8 +45,9 @@ impl Downloader {
                 if need_update {
                     let version = version.to_string();
                     let res = self
-                        .download(&node.http, &version, node.data_dir.clone())
-                        .await;
+                        .ensure_protocol(&node.http, &version, &node.data_dir)
+                        .await
+                        .map(|_| ());
 
                     debug!("Updating config with new version");
 
@@ -60,20 +68,44 @@ impl Downloader {
         return res;
     }
 
+    /// The latest release redirects to its tag, the version is the tag read from the final url.
+    /// It is kept whole, the download url of the release is built from it.
     pub async fn get_latest_version(&mut self, client: &reqwest::Client) -> Option<String> {
-        let latest_data_url = format!("{}/latest", DATA_URL);
-        let response = client.head(&latest_data_url).send().await.unwrap();
+        let latest_data_url = format!("{}/latest", self.base_url);
+        let response = client.head(&latest_data_url).send().await.ok()?;
         let url = response.url().as_str();
 
-        let final_segment = url.split('/').last().unwrap();
-        assert!(
-            VERSION_REGEX.is_match(final_segment),
-            "Invalid version format"
-        );
-
+        let final_segment = url.split('/').last()?;
+        if !VERSION_REGEX.is_match(final_segment) {
+            warn!("Invalid version format: {}", final_segment);
+            return None;
+        }
         return Some(final_segment.to_string());
     }
 
+    /// Downloads the protocol of `version` unless it is already cached in `data_dir`.
+    /// Returns `data_dir`, the directory `ProtocolManager::init` loads the protocol from.
+    pub async fn ensure_protocol(
+        &mut self,
+        client: &reqwest::Client,
+        version: &String,
+        data_dir: &Path,
+    ) -> Result<PathBuf, DownloaderError> {
+        let dist_folder = data_dir.join(EXTRACTOR_DIR);
+        let events_file = dist_folder.join(EVENTS_FILE);
+        let cached_version = std::fs::read_to_string(dist_folder.join(VERSION_FILE)).ok();
+
+        if events_file.exists() && cached_version.as_deref() == Some(version.as_str()) {
+            info!("Protocol {} is already cached", version);
+            return Ok(data_dir.to_path_buf());
+        }
+
+        self.download(client, version, data_dir.to_path_buf())
+            .await?;
+        std::fs::write(dist_folder.join(VERSION_FILE), version)?;
+        Ok(data_dir.to_path_buf())
+    }
+
     pub async fn download(
         &mut self,
         client: &reqwest::Client,
@@ -81,7 +113,7 @@ impl Downloader {
         data_dir: PathBuf,
     ) -> Result<(), DownloaderError> {
         info!("Downloading version: {}", version);
-        let download_url = format!("{}/download/{}/data.zip", DATA_URL, version);
+        let download_url = format!("{}/download/{}/data.zip", self.base_url, version);
         let temp_dir = TempDir::new("downloader").expect("Failed to create temp dir");
 
         let response = client.get(&download_url).send().await?;
@@ -140,3 +172,110 @@ pub enum DownloaderError {
     #[error(transparent)]
     ConfigError(#[from] crate::config::ConfigError),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sniffer::protocol::protocol::ProtocolManager;
+    use std::{
+        io::{BufRead, BufReader, Cursor, Write},
+        net::TcpListener,
+        sync::{Arc, Mutex},
+    };
+
+    fn data_zip() -> Vec<u8> {
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        let options = zip::write::FileOptions::default();
+        zip.add_directory("data/A/DofusInvoker", options).unwrap();
+        zip.add_directory("data/B", options).unwrap();
+        zip.add_directory("data/C", options).unwrap();
+        zip.start_file("data/A/events.json", options).unwrap();
+        zip.write_all(b"[]").unwrap();
+        zip.finish().unwrap().into_inner()
+    }
+
+    /// Minimal GitHub releases page of Datafus with a single release `tag`, records the requested paths
+    fn serve_releases(tag: &str) -> (String, Arc<Mutex<Vec<String>>>) {
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}/releases", server.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let tag = tag.to_string();
+        let zip = data_zip();
+
+        let recorded = requests.clone();
+        std::thread::spawn(move || {
+            for stream in server.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                // skip the headers
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 2 {
+                    line.clear();
+                }
+
+                let path = request_line.split(' ').nth(1).unwrap().to_string();
+                recorded.lock().unwrap().push(path.clone());
+                let (status, headers, body) = match path.as_str() {
+                    "/releases/latest" => (
+                        "302 Found",
+                        format!("Location: /releases/tag/{}\r\n", tag),
+                        vec![],
+                    ),
+                    path if path == format!("/releases/tag/{}", tag) => {
+                        ("200 OK", String::new(), vec![])
+                    }
+                    path if path == format!("/releases/download/{}/data.zip", tag) => {
+                        ("200 OK", String::new(), zip.clone())
+                    }
+                    _ => ("404 Not Found", String::new(), vec![]),
+                };
+                write!(
+                    stream,
+                    "HTTP/1.1 {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n",
+                    status,
+                    headers,
+                    body.len()
+                )
+                .unwrap();
+                stream.write_all(&body).unwrap();
+            }
+        });
+
+        (base_url, requests)
+    }
+
+    #[tokio::test]
+    async fn test_ensure_protocol_is_cached() {
+        let (base_url, requests) = serve_releases("v2.71.3");
+        let data_dir = TempDir::new("downloader_test").unwrap();
+        let client = reqwest::Client::new();
+        let mut downloader = Downloader::with_base_url(base_url);
+
+        // the tag is kept as is, it is part of the download url
+        let version = downloader.get_latest_version(&client).await.unwrap();
+        assert_eq!(version, "v2.71.3");
+
+        let protocol_dir = downloader
+            .ensure_protocol(&client, &version, data_dir.path())
+            .await
+            .unwrap();
+        let events_file = protocol_dir.join(EXTRACTOR_DIR).join(EVENTS_FILE);
+        assert_eq!(std::fs::read_to_string(&events_file).unwrap(), "[]");
+        ProtocolManager::new().init(&protocol_dir).unwrap();
+
+        // second call is served from the cache
+        downloader
+            .ensure_protocol(&client, &version, data_dir.path())
+            .await
+            .unwrap();
+        let downloads = requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|path| *path == "/releases/download/v2.71.3/data.zip")
+            .count();
+        assert_eq!(downloads, 1);
+    }
+}
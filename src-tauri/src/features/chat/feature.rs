@@ -9,7 +9,7 @@ use std::{
 use crate::{
     features::chat::config::ChatEvent,
     node::Node,
-    sniffer::{parser::packet::Packet, protocol::protocol::KnownEvent},
+    sniffer::{network::ListenerAction, parser::packet::Packet, protocol::protocol::KnownEvent},
 };
 use tauri_plugin_notification::NotificationExt;
 use tauri_specta::Event;
@@ -69,7 +69,7 @@ impl ChatFeature {
 
     fn init_subscription(&self) {
         let node = self.node.as_ref().unwrap();
-        let mut packet_listner = node.packet_listener.lock().unwrap();
+        let packet_listner = &node.packet_listener;
         let events = [
             KnownEvent::ChatServerMessage.to_string(),
             KnownEvent::ChatServerWithObjectMessage.to_string(),
@@ -90,16 +90,19 @@ impl ChatFeature {
 
             packet_listner.subscribe(*id, LISTENER_ID, move |packet, node| {
                 ChatFeature::listener(packet, node);
+                ListenerAction::Continue
             });
         });
     }
 
     fn listener(packet: &Packet, node: &Node) {
+        // headless nodes (tests, benches) have no window to forward the message to
+        let Some(handle) = node.handle.as_ref() else {
+            return;
+        };
         let chat_feature = node.features.chat.read().unwrap();
         let config = chat_feature.config.as_ref().unwrap().config.read().unwrap();
 
-        let handle = node.handle.as_ref().unwrap();
-
         let chat_event = ChatEvent::from_packet(&packet);
         let views = config
             .views
@@ -0,0 +1,11 @@
+#[cfg(feature = "capture")]
+pub mod config;
+pub mod constants;
+#[cfg(feature = "capture")]
+pub mod downloader;
+#[cfg(feature = "capture")]
+pub mod features;
+#[cfg(feature = "capture")]
+pub mod node;
+pub mod sniffer;
+pub mod store;
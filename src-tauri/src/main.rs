@@ -3,19 +3,12 @@
 
 use std::sync::Arc;
 
-use node::Node;
+use dtools::{config, features, node, node::Node, sniffer};
 use tauri::{Manager, WindowEvent};
 use tauri_specta::ts;
 use tracing::{debug, error, info};
 
-use crate::features::chat::config::ChatEvent;
-
-pub mod config;
-pub mod constants;
-pub mod downloader;
-pub mod features;
-pub mod node;
-pub mod sniffer;
+use dtools::features::chat::config::ChatEvent;
 
 fn fix_specta(path: &str) {
     // replace all occurence of "plugin:tauri-specta" in the file
@@ -111,11 +104,24 @@ fn get_global_config(state: tauri::State<'_, Arc<Node>>) -> config::NodeConfig {
 #[tauri::command]
 #[specta::specta]
 fn get_last_packet_timestamp(state: tauri::State<'_, Arc<Node>>) -> u128 {
-    let packet_listener = state.packet_listener.lock().unwrap();
-    let last_packet_time = packet_listener.last_packet_time.read().unwrap();
+    let last_packet_time = state.packet_listener.last_packet_time.read().unwrap();
     last_packet_time.clone()
 }
 
+#[tauri::command]
+#[specta::specta]
+fn get_capture_stats(state: tauri::State<'_, Arc<Node>>) -> sniffer::metrics::CaptureStats {
+    state.packet_listener.capture_stats()
+}
+
+#[tauri::command]
+#[specta::specta]
+fn get_protocol_catalog(
+    state: tauri::State<'_, Arc<Node>>,
+) -> sniffer::protocol::protocol::ProtocolCatalog {
+    state.protocol.read().unwrap().to_catalog()
+}
+
 fn main() {
     let app = tauri::Builder::default();
 
@@ -133,6 +139,8 @@ fn main() {
                 list_chat_tabs,
                 get_global_config,
                 get_last_packet_timestamp,
+                get_capture_stats,
+                get_protocol_catalog,
                 set_active_chat_tab,
                 get_last_open_chat_tab,
             ])
@@ -196,6 +204,8 @@ fn main() {
             list_chat_tabs,
             get_global_config,
             get_last_packet_timestamp,
+            get_capture_stats,
+            get_protocol_catalog,
             set_active_chat_tab,
             get_last_open_chat_tab,
         ]);
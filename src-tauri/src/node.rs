@@ -1,5 +1,4 @@
 use std::{
-    collections::HashMap,
     fs,
     path::{Path, PathBuf},
     sync::{Arc, Mutex, RwLock},
@@ -7,11 +6,13 @@ use std::{
 
 use crate::{
     config::{self, NodeConfig},
+    constants::STORE_FILE_NAME,
     downloader,
 };
 use crate::{
     features,
-    sniffer::{network, protocol},
+    sniffer::{config::NetworkConfigError, network, protocol},
+    store::{Store, StoreError},
 };
 use thiserror::Error;
 use tracing::{error, info};
@@ -27,7 +28,7 @@ pub struct Node {
     pub config: Arc<config::Manager<NodeConfig>>,
     pub http: reqwest::Client,
     pub downloader: Arc<Mutex<downloader::Downloader>>,
-    pub packet_listener: Arc<Mutex<network::PacketListener>>,
+    pub packet_listener: Arc<network::PacketListener>,
     pub protocol: Arc<RwLock<protocol::protocol::ProtocolManager>>,
 
     pub handle: Option<tauri::AppHandle>,
@@ -35,7 +36,7 @@ pub struct Node {
     pub features: Features,
 
     /// Temporary store for data, often use in the packet listener
-    pub store: Arc<Mutex<HashMap<String, String>>>,
+    pub store: Arc<Mutex<Store>>,
 }
 
 #[derive(Debug)]
@@ -67,6 +68,13 @@ impl Node {
         let packet_listener = network::PacketListener::new();
         let downloader = downloader::Downloader::new();
 
+        let store_file_path = data_dir_path.join(STORE_FILE_NAME);
+        let store = if store_file_path.exists() {
+            Store::load(&store_file_path)?
+        } else {
+            Store::new()
+        };
+
         let features = Features {
             chat: Arc::new(RwLock::new(features::chat::feature::ChatFeature::new())),
         };
@@ -77,17 +85,21 @@ impl Node {
             downloader: Arc::new(Mutex::new(downloader)),
             http: http_client,
             protocol: Arc::new(RwLock::new(protocol)),
-            packet_listener: Arc::new(Mutex::new(packet_listener)),
+            packet_listener: Arc::new(packet_listener),
             handle,
             features,
-            store: Arc::new(Mutex::new(HashMap::new())),
+            store: Arc::new(Mutex::new(store)),
         });
 
-        node.packet_listener.lock().unwrap().set_node(node.clone());
+        node.packet_listener.set_node(node.clone());
 
         if init {
+            // reported before anything is downloaded, naming the field to fix
+            let network = node.config.config.read().unwrap().network.clone();
+            network.validate()?;
             node.downloader.lock().unwrap().init(&node).await?;
-            node.packet_listener.lock().unwrap().run()?;
+            node.packet_listener.run()?;
+            node.packet_listener.watch_config()?;
         }
 
         node.protocol.write().unwrap().init(data_dir_path)?;
@@ -104,6 +116,12 @@ impl Node {
         return Ok(node);
     }
 
+    /// Saves the store next to the config, it is loaded back by `Node::new`
+    pub fn save_store(&self) -> Result<(), StoreError> {
+        let store = self.store.lock().unwrap();
+        store.save(self.data_dir.join(STORE_FILE_NAME))
+    }
+
     pub fn init_logger(data_dir: &Path) -> Result<WorkerGuard, FromEnvError> {
         let log_dir = data_dir.join("logs");
         let (log_file, guard) = NonBlocking::new(
@@ -136,7 +154,9 @@ impl Node {
                     .with_writer(std::io::stdout)
                     .with_filter(EnvFilter::from_default_env()),
             )
-            .init();
+            // The global subscriber can only be set once, ignore the error if another node already did it
+            .try_init()
+            .ok();
 
         std::panic::set_hook(Box::new(move |panic| {
             if let Some(location) = panic.location() {
@@ -168,4 +188,37 @@ pub enum NodeError {
     FailedToInitializeProtocol(#[from] protocol::protocol::ProtocolError),
     #[error("Failed to run packet listener")]
     FailedToRunPacketListener(#[from] network::PacketListenerError),
+    #[error("Failed to load store")]
+    FailedToLoadStore(#[from] StoreError),
+    #[error("Invalid config: {0}")]
+    InvalidConfig(#[from] NetworkConfigError),
+}
+
+#[cfg(test)]
+mod tests {
+    use tempdir::TempDir;
+
+    use super::*;
+    use crate::constants::{EVENTS_FILE, EXTRACTOR_DIR};
+
+    #[tokio::test]
+    async fn test_store_is_persisted() {
+        let data_dir = TempDir::new("node").unwrap();
+        let protocol_dir = data_dir.path().join(EXTRACTOR_DIR);
+        fs::create_dir_all(&protocol_dir).unwrap();
+        fs::copy(
+            Path::new("tests/fixtures")
+                .join(EXTRACTOR_DIR)
+                .join(EVENTS_FILE),
+            protocol_dir.join(EVENTS_FILE),
+        )
+        .unwrap();
+
+        let node = Node::new(data_dir.path(), None, false).await.unwrap();
+        node.store.lock().unwrap().set_int("count", 42);
+        node.save_store().unwrap();
+
+        let node = Node::new(data_dir.path(), None, false).await.unwrap();
+        assert_eq!(node.store.lock().unwrap().get_int("count"), Some(42));
+    }
 }
@@ -0,0 +1,859 @@
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{sync_channel, SyncSender, TrySendError},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant, SystemTime},
+};
+
+use flate2::read::GzDecoder;
+use pcap::{Activated, Capture, Offline};
+use tempdir::TempDir;
+use thiserror::Error;
+use tracing::warn;
+
+/// A source of captured packets that can be driven by the packet listener
+pub trait CaptureSource: Send {
+    /// Returns the raw bytes of the next packet
+    fn next_packet(&mut self) -> Result<&[u8], pcap::Error>;
+
+    /// Same as `next_packet` with the time the packet was captured.
+    /// Sources without one stamp it with the time it is read.
+    fn next_packet_at(&mut self) -> Result<(SystemTime, &[u8]), pcap::Error> {
+        self.next_packet().map(|data| (SystemTime::now(), data))
+    }
+
+    /// Statistics of the capture, not every source supports them
+    fn stats(&mut self) -> Result<pcap::Stat, pcap::Error>;
+
+    /// Link-layer type of the captured packets
+    fn datalink(&self) -> pcap::Linktype;
+}
+
+impl<T: Activated + ?Sized> CaptureSource for Capture<T> {
+    fn next_packet(&mut self) -> Result<&[u8], pcap::Error> {
+        Capture::next_packet(self).map(|packet| packet.data)
+    }
+
+    fn next_packet_at(&mut self) -> Result<(SystemTime, &[u8]), pcap::Error> {
+        let packet = Capture::next_packet(self)?;
+        let timestamp = Duration::from_secs(packet.header.ts.tv_sec.max(0) as u64)
+            + Duration::from_micros(packet.header.ts.tv_usec.max(0) as u64);
+        Ok((SystemTime::UNIX_EPOCH + timestamp, packet.data))
+    }
+
+    fn stats(&mut self) -> Result<pcap::Stat, pcap::Error> {
+        Capture::stats(self)
+    }
+
+    fn datalink(&self) -> pcap::Linktype {
+        self.get_datalink()
+    }
+}
+
+/// Capture file formats, both are read by libpcap
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureFileFormat {
+    Pcap,
+    PcapNg,
+}
+
+impl CaptureFileFormat {
+    pub fn from_magic(magic: [u8; 4]) -> Option<Self> {
+        match u32::from_le_bytes(magic) {
+            // micro and nanosecond pcap, in both byte orders
+            0xa1b2c3d4 | 0xd4c3b2a1 | 0xa1b23c4d | 0x4d3cb2a1 => Some(CaptureFileFormat::Pcap),
+            // section header block type, same in both byte orders
+            0x0a0d0d0a => Some(CaptureFileFormat::PcapNg),
+            _ => None,
+        }
+    }
+
+    pub fn detect(path: impl AsRef<Path>) -> Result<Self, CaptureFileError> {
+        let mut magic = [0; 4];
+        File::open(path)?.read_exact(&mut magic)?;
+        CaptureFileFormat::from_magic(magic).ok_or(CaptureFileError::UnknownFormat(magic))
+    }
+}
+
+/// Opens a pcap or pcapng file for replay with `run_with_capture`, `.gz` files are decompressed first.
+/// Timestamps are always reported in microseconds, libpcap rescales the pcapng interface resolution.
+pub fn open_file(path: impl AsRef<Path>) -> Result<Capture<Offline>, CaptureFileError> {
+    let path = path.as_ref();
+    if path
+        .extension()
+        .map_or(false, |extension| extension == "gz")
+    {
+        return open_gzip_file(path);
+    }
+    CaptureFileFormat::detect(path)?;
+    Ok(Capture::from_file(path)?)
+}
+
+/// libpcap only reads plain files, so the capture is decompressed to a temporary file.
+/// The opened capture keeps its own handle on it once the directory is removed.
+fn open_gzip_file(path: &Path) -> Result<Capture<Offline>, CaptureFileError> {
+    let temp_dir = TempDir::new("capture")?;
+    let decompressed_path = temp_dir.path().join(path.file_stem().unwrap_or_default());
+    std::io::copy(
+        &mut GzDecoder::new(File::open(path)?),
+        &mut File::create(&decompressed_path)?,
+    )?;
+    open_file(&decompressed_path)
+}
+
+/// Capture files of `dir` sorted by name, so the timestamped files of a rotation come in order.
+/// Files that aren't pcap, pcapng or gzip compressed captures are skipped.
+pub fn capture_files(dir: impl AsRef<Path>) -> Result<Vec<PathBuf>, CaptureFileError> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if !path.is_file() {
+            continue;
+        }
+        let compressed = path
+            .extension()
+            .map_or(false, |extension| extension == "gz");
+        if compressed || CaptureFileFormat::detect(&path).is_ok() {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Records larger than this are a corrupt stream rather than a packet
+const MAX_STREAM_RECORD_LEN: usize = 16 * 1024 * 1024;
+
+/// Pcap savefile read from any stream, like the standard input of `tcpdump -w - | dtools`.
+/// Only the pcap format is read, pcapng streams are rejected.
+pub struct PcapStream<R> {
+    reader: R,
+    big_endian: bool,
+    /// The record timestamps have nanoseconds rather than microseconds
+    nanoseconds: bool,
+    datalink: pcap::Linktype,
+    current: Vec<u8>,
+}
+
+impl<R: Read> PcapStream<R> {
+    /// Reads the global header of the stream
+    pub fn new(mut reader: R) -> Result<Self, CaptureFileError> {
+        let mut header = [0; PCAP_GLOBAL_HEADER_LEN as usize];
+        reader.read_exact(&mut header)?;
+        let magic = [header[0], header[1], header[2], header[3]];
+        if CaptureFileFormat::from_magic(magic) != Some(CaptureFileFormat::Pcap) {
+            return Err(CaptureFileError::UnknownFormat(magic));
+        }
+        let big_endian = matches!(u32::from_le_bytes(magic), 0xd4c3b2a1 | 0x4d3cb2a1);
+        let nanoseconds = matches!(u32::from_le_bytes(magic), 0xa1b23c4d | 0x4d3cb2a1);
+        let mut stream = PcapStream {
+            reader,
+            big_endian,
+            nanoseconds,
+            datalink: pcap::Linktype::ETHERNET,
+            current: Vec::new(),
+        };
+        stream.datalink = pcap::Linktype(stream.read_u32(&header[20..24]) as i32);
+        Ok(stream)
+    }
+
+    fn read_u32(&self, bytes: &[u8]) -> u32 {
+        let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+        match self.big_endian {
+            true => u32::from_be_bytes(bytes),
+            false => u32::from_le_bytes(bytes),
+        }
+    }
+}
+
+impl<R: Read + Send> CaptureSource for PcapStream<R> {
+    fn next_packet(&mut self) -> Result<&[u8], pcap::Error> {
+        self.next_packet_at().map(|(_, data)| data)
+    }
+
+    fn next_packet_at(&mut self) -> Result<(SystemTime, &[u8]), pcap::Error> {
+        let mut header = [0; 16];
+        let read_error = |err: std::io::Error| match err.kind() {
+            // the writer closed the stream, a record cut by it is dropped
+            std::io::ErrorKind::UnexpectedEof => pcap::Error::NoMorePackets,
+            kind => pcap::Error::IoError(kind),
+        };
+        self.reader.read_exact(&mut header).map_err(read_error)?;
+        let len = self.read_u32(&header[8..12]) as usize;
+        if len > MAX_STREAM_RECORD_LEN {
+            return Err(pcap::Error::PcapError(format!(
+                "Record of {} bytes in the pcap stream",
+                len
+            )));
+        }
+        self.current.resize(len, 0);
+        self.reader
+            .read_exact(&mut self.current)
+            .map_err(read_error)?;
+        let fraction = self.read_u32(&header[4..8]) as u64;
+        let timestamp = Duration::from_secs(self.read_u32(&header[0..4]) as u64)
+            + match self.nanoseconds {
+                true => Duration::from_nanos(fraction),
+                false => Duration::from_micros(fraction),
+            };
+        Ok((SystemTime::UNIX_EPOCH + timestamp, &self.current))
+    }
+
+    fn stats(&mut self) -> Result<pcap::Stat, pcap::Error> {
+        Err(pcap::Error::NoMorePackets)
+    }
+
+    fn datalink(&self) -> pcap::Linktype {
+        self.datalink
+    }
+}
+
+/// When a recording moves on to its next file, unset limits never rotate
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RotationPolicy {
+    /// Bytes written to a file, headers included
+    pub max_size: Option<u64>,
+    pub max_duration: Option<Duration>,
+}
+
+/// Pcap savefile being written by a `CaptureRecorder`
+#[derive(Debug)]
+struct RecordFile {
+    writer: BufWriter<File>,
+    size: u64,
+    opened_at: Instant,
+    datalink: pcap::Linktype,
+}
+
+/// Writes captured packets to `capture-0001.pcap`, `capture-0002.pcap`... in a directory.
+/// The numbering goes on after the recordings already there, they are never overwritten.
+/// Every file has its own pcap header, so each one can be replayed with `open_file`.
+#[derive(Debug)]
+pub struct CaptureRecorder {
+    dir: PathBuf,
+    policy: RotationPolicy,
+    current: Option<RecordFile>,
+    files: Vec<PathBuf>,
+    next_index: u32,
+}
+
+/// Number of a `capture-0001.pcap` file written by a `CaptureRecorder`
+fn recording_index(path: &Path) -> Option<u32> {
+    path.file_name()?
+        .to_str()?
+        .strip_prefix("capture-")?
+        .strip_suffix(".pcap")?
+        .parse()
+        .ok()
+}
+
+const PCAP_GLOBAL_HEADER_LEN: u64 = 24;
+const PCAP_SNAPLEN: u32 = 65535;
+
+/// Microsecond pcap 2.4, no timezone offset
+fn write_pcap_header(writer: &mut impl Write, datalink: pcap::Linktype) -> std::io::Result<()> {
+    writer.write_all(&0xa1b2c3d4u32.to_le_bytes())?;
+    writer.write_all(&2u16.to_le_bytes())?;
+    writer.write_all(&4u16.to_le_bytes())?;
+    writer.write_all(&0i32.to_le_bytes())?;
+    writer.write_all(&0u32.to_le_bytes())?;
+    writer.write_all(&PCAP_SNAPLEN.to_le_bytes())?;
+    writer.write_all(&(datalink.0 as u32).to_le_bytes())
+}
+
+/// Writes a packet record and returns its size, frames past the snaplen are truncated
+fn write_pcap_record(
+    writer: &mut impl Write,
+    timestamp: SystemTime,
+    data: &[u8],
+) -> std::io::Result<u64> {
+    let timestamp = timestamp
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    let len = data.len() as u32;
+    let captured = &data[..len.min(PCAP_SNAPLEN) as usize];
+    writer.write_all(&(timestamp.as_secs() as u32).to_le_bytes())?;
+    writer.write_all(&timestamp.subsec_micros().to_le_bytes())?;
+    writer.write_all(&(captured.len() as u32).to_le_bytes())?;
+    writer.write_all(&len.to_le_bytes())?;
+    writer.write_all(captured)?;
+    Ok(16 + captured.len() as u64)
+}
+
+impl CaptureRecorder {
+    pub fn new(dir: impl AsRef<Path>, policy: RotationPolicy) -> Result<Self, CaptureFileError> {
+        std::fs::create_dir_all(dir.as_ref())?;
+        let mut last_index = 0;
+        for entry in std::fs::read_dir(dir.as_ref())? {
+            last_index = last_index.max(recording_index(&entry?.path()).unwrap_or(0));
+        }
+        Ok(CaptureRecorder {
+            dir: dir.as_ref().to_path_buf(),
+            policy,
+            current: None,
+            files: Vec::new(),
+            next_index: last_index + 1,
+        })
+    }
+
+    /// Files written so far, in order
+    pub fn files(&self) -> &[PathBuf] {
+        &self.files
+    }
+
+    /// Appends a packet, the file is rotated once it exceeds the policy or the datalink changes
+    pub fn write(&mut self, datalink: pcap::Linktype, data: &[u8]) -> Result<(), CaptureFileError> {
+        self.write_at(datalink, SystemTime::now(), data)
+    }
+
+    /// Same as `write` for a packet captured at `timestamp`
+    pub fn write_at(
+        &mut self,
+        datalink: pcap::Linktype,
+        timestamp: SystemTime,
+        data: &[u8],
+    ) -> Result<(), CaptureFileError> {
+        if self
+            .current
+            .as_ref()
+            .map_or(false, |file| file.datalink != datalink)
+        {
+            self.close()?;
+        }
+        if self.current.is_none() {
+            self.open(datalink)?;
+        }
+        let file = self.current.as_mut().unwrap();
+        file.size += write_pcap_record(&mut file.writer, timestamp, data)?;
+
+        let full = self.policy.max_size.map_or(false, |max| file.size >= max);
+        let expired = self
+            .policy
+            .max_duration
+            .map_or(false, |max| file.opened_at.elapsed() >= max);
+        if full || expired {
+            self.close()?;
+        }
+        Ok(())
+    }
+
+    /// Flushes the current file, the next packet opens a new one
+    pub fn close(&mut self) -> Result<(), CaptureFileError> {
+        if let Some(mut file) = self.current.take() {
+            file.writer.flush()?;
+        }
+        Ok(())
+    }
+
+    fn open(&mut self, datalink: pcap::Linktype) -> Result<(), CaptureFileError> {
+        let path = self
+            .dir
+            .join(format!("capture-{:04}.pcap", self.next_index));
+        // a file created since `new` is not overwritten either
+        let file = File::options().write(true).create_new(true).open(&path)?;
+        self.next_index += 1;
+        let mut writer = BufWriter::new(file);
+        write_pcap_header(&mut writer, datalink)?;
+        self.files.push(path);
+        self.current = Some(RecordFile {
+            writer,
+            size: PCAP_GLOBAL_HEADER_LEN,
+            opened_at: Instant::now(),
+            datalink,
+        });
+        Ok(())
+    }
+}
+
+impl Drop for CaptureRecorder {
+    fn drop(&mut self) {
+        let _ = self.close();
+    }
+}
+
+/// Where a `BackgroundRecorder` writes the packets
+pub trait PacketSink: Send + 'static {
+    fn write_packet(
+        &mut self,
+        datalink: pcap::Linktype,
+        timestamp: SystemTime,
+        data: &[u8],
+    ) -> Result<(), CaptureFileError>;
+
+    /// Files written so far, when the sink writes to files
+    fn files(&self) -> &[PathBuf] {
+        &[]
+    }
+}
+
+impl PacketSink for CaptureRecorder {
+    fn write_packet(
+        &mut self,
+        datalink: pcap::Linktype,
+        timestamp: SystemTime,
+        data: &[u8],
+    ) -> Result<(), CaptureFileError> {
+        self.write_at(datalink, timestamp, data)
+    }
+
+    fn files(&self) -> &[PathBuf] {
+        CaptureRecorder::files(self)
+    }
+}
+
+impl PacketSink for Box<dyn PacketSink> {
+    fn write_packet(
+        &mut self,
+        datalink: pcap::Linktype,
+        timestamp: SystemTime,
+        data: &[u8],
+    ) -> Result<(), CaptureFileError> {
+        (**self).write_packet(datalink, timestamp, data)
+    }
+
+    fn files(&self) -> &[PathBuf] {
+        (**self).files()
+    }
+}
+
+type QueuedPacket = (pcap::Linktype, SystemTime, Vec<u8>);
+
+/// Writes the packets on its own thread, so a slow disk never stalls the capture loop.
+/// Packets arriving while `queue_size` of them wait to be written are dropped from the recording.
+pub struct BackgroundRecorder<S: PacketSink = CaptureRecorder> {
+    sender: Option<SyncSender<QueuedPacket>>,
+    writer: Option<JoinHandle<S>>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl<S: PacketSink> BackgroundRecorder<S> {
+    pub fn spawn(mut sink: S, queue_size: usize) -> Self {
+        let (sender, receiver) = sync_channel::<QueuedPacket>(queue_size);
+        let writer = std::thread::spawn(move || {
+            for (datalink, timestamp, data) in receiver {
+                if let Err(err) = sink.write_packet(datalink, timestamp, &data) {
+                    warn!("Failed to record packet: {:?}", err);
+                }
+            }
+            sink
+        });
+        BackgroundRecorder {
+            sender: Some(sender),
+            writer: Some(writer),
+            dropped: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Queues the packet captured at `timestamp` without blocking, returns false when it was dropped
+    pub fn write(&self, datalink: pcap::Linktype, timestamp: SystemTime, data: &[u8]) -> bool {
+        let Some(sender) = &self.sender else {
+            return false;
+        };
+        match sender.try_send((datalink, timestamp, data.to_vec())) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_) | TrySendError::Disconnected(_)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                false
+            }
+        }
+    }
+
+    /// Packets dropped because the writer was behind
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Waits for the queued packets to be written and returns the sink, `None` when its thread panicked
+    pub fn finish(mut self) -> Option<S> {
+        self.join()
+    }
+
+    fn join(&mut self) -> Option<S> {
+        self.sender = None;
+        let sink = self.writer.take()?.join();
+        match sink {
+            Ok(sink) => Some(sink),
+            Err(_) => {
+                warn!("Recorder thread panicked");
+                None
+            }
+        }
+    }
+}
+
+impl<S: PacketSink> std::fmt::Debug for BackgroundRecorder<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BackgroundRecorder")
+            .field("running", &self.writer.is_some())
+            .field("dropped", &self.dropped())
+            .finish()
+    }
+}
+
+impl<S: PacketSink> Drop for BackgroundRecorder<S> {
+    fn drop(&mut self) {
+        self.join();
+    }
+}
+
+/// The most recent captured frames, kept in memory to be saved after the fact with `dump_to`.
+/// The oldest frames are dropped once the ring holds more than `max_packets` or `max_bytes`.
+#[derive(Debug)]
+pub struct PacketRing {
+    packets: VecDeque<(SystemTime, Vec<u8>)>,
+    bytes: usize,
+    max_packets: Option<usize>,
+    max_bytes: Option<usize>,
+    datalink: pcap::Linktype,
+}
+
+impl PacketRing {
+    pub fn new(max_packets: Option<usize>, max_bytes: Option<usize>) -> Self {
+        PacketRing {
+            packets: VecDeque::new(),
+            bytes: 0,
+            max_packets,
+            max_bytes,
+            datalink: pcap::Linktype::ETHERNET,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.packets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+
+    /// A pcap file has a single datalink, the frames of the previous one are dropped when it changes
+    pub fn push(&mut self, datalink: pcap::Linktype, timestamp: SystemTime, data: &[u8]) {
+        if datalink != self.datalink {
+            self.packets.clear();
+            self.bytes = 0;
+            self.datalink = datalink;
+        }
+        self.packets.push_back((timestamp, data.to_vec()));
+        self.bytes += data.len();
+        while self
+            .max_packets
+            .map_or(false, |max| self.packets.len() > max)
+            || self.max_bytes.map_or(false, |max| self.bytes > max)
+        {
+            let Some((_, dropped)) = self.packets.pop_front() else {
+                break;
+            };
+            self.bytes -= dropped.len();
+        }
+    }
+
+    /// Writes the frames of the ring to a pcap file, returns how many were written
+    pub fn dump_to(&self, path: impl AsRef<Path>) -> Result<usize, CaptureFileError> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        write_pcap_header(&mut writer, self.datalink)?;
+        for (timestamp, data) in &self.packets {
+            write_pcap_record(&mut writer, *timestamp, data)?;
+        }
+        writer.flush()?;
+        Ok(self.packets.len())
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum CaptureFileError {
+    #[error("IO error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("Unknown capture file format (magic {0:02x?})")]
+    UnknownFormat([u8; 4]),
+    #[error("Pcap error: {0}")]
+    Pcap(#[from] pcap::Error),
+}
+
+/// In memory capture source, yields the given results in order then `NoMorePackets`
+#[cfg(test)]
+pub struct MockCapture {
+    packets: std::collections::VecDeque<Result<Vec<u8>, pcap::Error>>,
+    /// Time since the start of the capture at which each packet is yielded
+    timestamps: std::collections::VecDeque<Duration>,
+    started_at: Option<Instant>,
+    current: Vec<u8>,
+}
+
+#[cfg(test)]
+impl MockCapture {
+    pub fn new(packets: Vec<Result<Vec<u8>, pcap::Error>>) -> Self {
+        MockCapture {
+            packets: packets.into(),
+            timestamps: std::collections::VecDeque::new(),
+            started_at: None,
+            current: Vec::new(),
+        }
+    }
+
+    /// Each frame is yielded once its timestamp, relative to the first read, is reached
+    pub fn timed(packets: Vec<(Duration, Vec<u8>)>) -> Self {
+        let (timestamps, packets): (Vec<_>, Vec<_>) = packets
+            .into_iter()
+            .map(|(timestamp, packet)| (timestamp, Ok(packet)))
+            .unzip();
+        MockCapture {
+            timestamps: timestamps.into(),
+            ..MockCapture::new(packets)
+        }
+    }
+}
+
+/// Ethernet frame of an IPv4 TCP segment from port 5555 to 50000 on 127.0.0.1.
+/// `seq` is the TCP sequence number of its first payload byte, the IP id is taken from it.
+#[cfg(test)]
+pub fn tcp_frame(seq: u32, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0; 14];
+    frame[12..14].copy_from_slice(&[0x08, 0x00]);
+
+    let mut ip = vec![0; 20];
+    ip[0] = 0x45;
+    ip[2..4].copy_from_slice(&((40 + payload.len()) as u16).to_be_bytes());
+    ip[4..6].copy_from_slice(&(seq as u16).to_be_bytes());
+    ip[9] = 6;
+    ip[12..16].copy_from_slice(&[127, 0, 0, 1]);
+    ip[16..20].copy_from_slice(&[127, 0, 0, 1]);
+    frame.extend(ip);
+
+    let mut tcp = vec![0; 20];
+    tcp[0..2].copy_from_slice(&5555u16.to_be_bytes());
+    tcp[2..4].copy_from_slice(&50000u16.to_be_bytes());
+    tcp[4..8].copy_from_slice(&seq.to_be_bytes());
+    tcp[12] = 0x50;
+    tcp[13] = 0x18;
+    frame.extend(tcp);
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Body of a `BasicPingMessage` (8285), the message most listener tests inject
+#[cfg(test)]
+pub const PING: [u8; 4] = [0x81, 0x75, 0x01, 0x01];
+
+/// Node of the test fixtures, its protocol is the one of `tests/fixtures/`
+#[cfg(test)]
+pub async fn fixture_node() -> Arc<crate::node::Node> {
+    crate::node::Node::new(Path::new("tests/fixtures/"), None, false)
+        .await
+        .unwrap()
+}
+
+/// Counts the packets of `event` in the `count` key of the node store
+#[cfg(test)]
+pub fn count_packets(
+    listener: &crate::sniffer::network::PacketListener,
+    event: crate::sniffer::protocol::protocol::EventId,
+) {
+    listener.subscribe(event, "test", |_, node| {
+        node.store.lock().unwrap().increment("count", 1);
+        crate::sniffer::network::ListenerAction::Continue
+    });
+}
+
+/// Injects `PING` from 127.0.0.1 as the `index`th message of a stream of pings
+#[cfg(test)]
+pub fn inject_ping(listener: &crate::sniffer::network::PacketListener, index: u32) {
+    listener.inject_bytes(vec![127, 0, 0, 1], index * PING.len() as u32, &PING);
+}
+
+#[cfg(test)]
+impl CaptureSource for MockCapture {
+    fn next_packet(&mut self) -> Result<&[u8], pcap::Error> {
+        let started_at = *self.started_at.get_or_insert_with(Instant::now);
+        if let Some(timestamp) = self.timestamps.pop_front() {
+            std::thread::sleep((started_at + timestamp).saturating_duration_since(Instant::now()));
+        }
+        match self.packets.pop_front() {
+            Some(Ok(packet)) => {
+                self.current = packet;
+                Ok(&self.current)
+            }
+            Some(Err(err)) => Err(err),
+            None => Err(pcap::Error::NoMorePackets),
+        }
+    }
+
+    fn stats(&mut self) -> Result<pcap::Stat, pcap::Error> {
+        Err(pcap::Error::NoMorePackets)
+    }
+
+    fn datalink(&self) -> pcap::Linktype {
+        pcap::Linktype::ETHERNET
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_background_recorder() {
+        /// Waits for the test to open the gate before writing each packet
+        struct SlowSink {
+            gate: std::sync::mpsc::Receiver<()>,
+            written: Vec<Vec<u8>>,
+        }
+
+        impl PacketSink for SlowSink {
+            fn write_packet(
+                &mut self,
+                _: pcap::Linktype,
+                _: SystemTime,
+                data: &[u8],
+            ) -> Result<(), CaptureFileError> {
+                let _ = self.gate.recv();
+                self.written.push(data.to_vec());
+                Ok(())
+            }
+        }
+
+        let (gate, receiver) = std::sync::mpsc::channel();
+        let sink = SlowSink {
+            gate: receiver,
+            written: Vec::new(),
+        };
+        let recorder = BackgroundRecorder::spawn(sink, 2);
+        // the writer is stuck on the first packet, the queue takes two more
+        let started = Instant::now();
+        let queued = (0..10u8)
+            .filter(|i| recorder.write(pcap::Linktype::ETHERNET, SystemTime::now(), &[*i]))
+            .count();
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert!(queued <= 3);
+        assert_eq!(recorder.dropped(), 10 - queued as u64);
+
+        drop(gate);
+        let sink = recorder.finish().unwrap();
+        assert_eq!(sink.written.len(), queued);
+        assert_eq!(sink.written[0], vec![0]);
+
+        struct FailingSink;
+
+        impl PacketSink for FailingSink {
+            fn write_packet(
+                &mut self,
+                _: pcap::Linktype,
+                _: SystemTime,
+                _: &[u8],
+            ) -> Result<(), CaptureFileError> {
+                panic!("disk gone");
+            }
+        }
+
+        let recorder = BackgroundRecorder::spawn(FailingSink, 2);
+        recorder.write(pcap::Linktype::ETHERNET, SystemTime::now(), &[0]);
+        assert!(recorder.finish().is_none());
+    }
+
+    #[test]
+    fn test_pcap_stream() {
+        let bytes = std::fs::read("tests/fixtures/cap.pcap").unwrap();
+        let mut stream = PcapStream::new(std::io::Cursor::new(bytes)).unwrap();
+        let mut file = open_file("tests/fixtures/cap.pcap").unwrap();
+        assert_eq!(CaptureSource::datalink(&stream), file.get_datalink());
+
+        let mut count = 0;
+        while let Ok(expected) = CaptureSource::next_packet_at(&mut file) {
+            let expected = (expected.0, expected.1.to_vec());
+            let (timestamp, data) = stream.next_packet_at().unwrap();
+            assert_eq!((timestamp, data.to_vec()), expected);
+            count += 1;
+        }
+        assert!(count > 0);
+        assert!(matches!(
+            CaptureSource::next_packet(&mut stream),
+            Err(pcap::Error::NoMorePackets)
+        ));
+
+        let pcapng = std::fs::read("tests/fixtures/cap.pcapng").unwrap();
+        assert!(matches!(
+            PcapStream::new(std::io::Cursor::new(pcapng)),
+            Err(CaptureFileError::UnknownFormat(_))
+        ));
+    }
+
+    #[test]
+    fn test_pcapng_replays_like_pcap() {
+        assert_eq!(
+            CaptureFileFormat::detect("tests/fixtures/cap.pcapng").unwrap(),
+            CaptureFileFormat::PcapNg
+        );
+        assert_eq!(
+            CaptureFileFormat::detect("tests/fixtures/cap.pcap").unwrap(),
+            CaptureFileFormat::Pcap
+        );
+
+        // cap.pcapng holds the first 100 packets of cap.pcap, with nanosecond timestamps
+        let mut pcapng = open_file("tests/fixtures/cap.pcapng").unwrap();
+        let mut pcap = open_file("tests/fixtures/cap.pcap").unwrap();
+        let mut count = 0;
+        while let Ok(packet) = pcapng.next_packet() {
+            let expected = pcap.next_packet().unwrap();
+            assert_eq!(packet.header.ts.tv_sec, expected.header.ts.tv_sec);
+            assert_eq!(packet.header.ts.tv_usec, expected.header.ts.tv_usec);
+            assert_eq!(packet.data, expected.data);
+            count += 1;
+        }
+        assert_eq!(count, 100);
+    }
+
+    #[test]
+    fn test_recorder_rotation() {
+        let dir = TempDir::new("recorder").unwrap();
+        let policy = RotationPolicy {
+            max_size: Some(100),
+            max_duration: None,
+        };
+        let mut recorder = CaptureRecorder::new(dir.path(), policy).unwrap();
+
+        let mut source = open_file("tests/fixtures/cap.pcap").unwrap();
+        let mut expected = Vec::new();
+        for _ in 0..10 {
+            let (timestamp, data) = CaptureSource::next_packet_at(&mut source).unwrap();
+            recorder
+                .write_at(pcap::Linktype::ETHERNET, timestamp, data)
+                .unwrap();
+            expected.push((timestamp, data.to_vec()));
+        }
+        recorder.close().unwrap();
+
+        let files = recorder.files().to_vec();
+        assert!(files.len() > 1);
+        assert!(files[0].ends_with("capture-0001.pcap"));
+        let replay = |files: &[PathBuf]| {
+            let mut replayed = Vec::new();
+            for file in files {
+                let mut cap = open_file(file).unwrap();
+                while let Ok((timestamp, data)) = CaptureSource::next_packet_at(&mut cap) {
+                    replayed.push((timestamp, data.to_vec()));
+                }
+            }
+            replayed
+        };
+        assert_eq!(replay(&files), expected);
+
+        // a second recording in the same directory keeps the first one
+        let mut second = CaptureRecorder::new(dir.path(), policy).unwrap();
+        second
+            .write(pcap::Linktype::ETHERNET, &expected[0].1)
+            .unwrap();
+        second.close().unwrap();
+        assert_eq!(
+            second.files()[0],
+            dir.path()
+                .join(format!("capture-{:04}.pcap", files.len() + 1))
+        );
+        assert_eq!(replay(&files), expected);
+    }
+}
@@ -1,16 +1,450 @@
-use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    net::{IpAddr, ToSocketAddrs},
+};
 
+use pcap::Device;
+use serde::{Deserialize, Deserializer, Serialize};
+use thiserror::Error;
+use tracing::warn;
+
+use super::protocol::protocol::{EventId, EventName, ProtocolManager};
+
+/// Largest frame captured by default, enough for any TCP segment without jumbo frames
+pub const DEFAULT_SNAPLEN: i32 = 65535;
+/// Kernel buffer of the capture, larger than the libpcap default of 2 MiB to absorb bursts on busy maps
+pub const DEFAULT_BUFFER_SIZE: i32 = 16 * 1024 * 1024;
+/// Reads give up after this long without traffic, so the capture loop can check if it was stopped
+pub const DEFAULT_READ_TIMEOUT_MS: i32 = 100;
+
+/// What `NetworkConfig::configure` sets on a capture, implemented by the inactive pcap capture
+pub trait CaptureSettings: Sized {
+    fn immediate_mode(self, immediate: bool) -> Self;
+    fn promisc(self, promiscuous: bool) -> Self;
+    fn timeout(self, ms: i32) -> Self;
+    fn snaplen(self, snaplen: i32) -> Self;
+    fn buffer_size(self, size: i32) -> Self;
+}
+
+impl CaptureSettings for pcap::Capture<pcap::Inactive> {
+    fn immediate_mode(self, immediate: bool) -> Self {
+        pcap::Capture::immediate_mode(self, immediate)
+    }
+
+    fn promisc(self, promiscuous: bool) -> Self {
+        pcap::Capture::promisc(self, promiscuous)
+    }
+
+    fn timeout(self, ms: i32) -> Self {
+        pcap::Capture::timeout(self, ms)
+    }
+
+    fn snaplen(self, snaplen: i32) -> Self {
+        pcap::Capture::snaplen(self, snaplen)
+    }
+
+    fn buffer_size(self, size: i32) -> Self {
+        pcap::Capture::buffer_size(self, size)
+    }
+}
+
+/// Missing fields take the value of `NetworkConfig::default`, so partial configs load
 #[derive(Debug, Clone, Serialize, Deserialize, specta::Type)]
+#[serde(rename_all = "camelCase", default)]
 pub struct NetworkConfig {
-    pub port: u16,
+    /// Older configs stored a single `port`
+    #[serde(alias = "port", deserialize_with = "deserialize_ports")]
+    pub ports: Vec<u16>,
     pub interface: String,
+    /// Only the traffic to and from this game server is captured, an IP or a hostname
+    pub host: Option<String>,
+    /// Also captured along `interface`, when the machine has several networks (VPN and LAN)
+    pub additional_interfaces: Vec<String>,
+    /// Bytes kept of each frame, a value smaller than the largest segment truncates payloads and breaks reassembly
+    pub snaplen: i32,
+    /// Size in bytes of the buffer holding packets until they are read
+    pub buffer_size: i32,
+    pub read_timeout_ms: i32,
+    /// Packets are delivered as soon as they arrive, lowest latency.
+    /// Disabling it lets the kernel batch them, raising throughput on busy links.
+    pub immediate_mode: bool,
+    /// Also captures the frames addressed to other machines, needed on bridged VMs and some NICs
+    pub promiscuous: bool,
+}
+
+impl NetworkConfig {
+    /// BPF expression matching every configured port
+    pub fn port_filter(&self) -> String {
+        if self.ports.is_empty() {
+            return "tcp".to_string();
+        }
+        self.ports
+            .iter()
+            .map(|port| format!("tcp port {}", port))
+            .collect::<Vec<_>>()
+            .join(" or ")
+    }
+
+    /// BPF expression of the capture, the ports restricted to `host` when it is set
+    pub fn filter(&self) -> String {
+        match &self.host {
+            Some(host) => format!("({}) and host {}", self.port_filter(), host),
+            None => self.port_filter(),
+        }
+    }
+
+    /// Resolves `host` to an IP, hostnames are only looked up once when the capture starts
+    pub fn resolve_host(&self) -> Result<Option<IpAddr>, NetworkConfigError> {
+        let Some(host) = self.host.as_deref() else {
+            return Ok(None);
+        };
+        if let Ok(ip) = host.parse() {
+            return Ok(Some(ip));
+        }
+        (host, 0)
+            .to_socket_addrs()
+            .ok()
+            .and_then(|mut addrs| addrs.next())
+            .map(|addr| Some(addr.ip()))
+            .ok_or_else(|| NetworkConfigError::InvalidHost(host.to_string()))
+    }
+
+    /// Applies the capture settings of the config to a device before it is opened
+    pub fn configure<C: CaptureSettings>(&self, cap: C) -> C {
+        cap.immediate_mode(self.immediate_mode)
+            .promisc(self.promiscuous)
+            .timeout(self.read_timeout_ms)
+            .snaplen(self.snaplen)
+            .buffer_size(self.buffer_size)
+    }
+
+    /// Every interface to capture on, `interface` first
+    pub fn interfaces(&self) -> Vec<&str> {
+        std::iter::once(self.interface.as_str())
+            .chain(self.additional_interfaces.iter().map(String::as_str))
+            .collect()
+    }
+
+    /// Checks the config before opening a capture, the interface must be one of the pcap devices
+    pub fn validate(&self) -> Result<(), NetworkConfigError> {
+        // listing errors are reported by the capture itself
+        let devices = Device::list().ok().map(|devices| {
+            devices
+                .into_iter()
+                .map(|device| device.name)
+                .collect::<Vec<_>>()
+        });
+        self.validate_with_devices(devices.as_deref())
+    }
+
+    fn validate_with_devices(&self, devices: Option<&[String]>) -> Result<(), NetworkConfigError> {
+        if self.interface.trim().is_empty() {
+            return Err(NetworkConfigError::EmptyInterface);
+        }
+        if let Some(devices) = devices {
+            if let Some(interface) = self
+                .interfaces()
+                .into_iter()
+                .find(|interface| !devices.iter().any(|device| device == interface))
+            {
+                return Err(NetworkConfigError::UnknownInterface(interface.to_string()));
+            }
+        }
+        if let Some(port) = self.ports.iter().find(|port| **port == 0) {
+            return Err(NetworkConfigError::InvalidPort(*port));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum NetworkConfigError {
+    #[error("network.interface is empty")]
+    EmptyInterface,
+    #[error("network.interface: no capture device named {0:?}")]
+    UnknownInterface(String),
+    #[error("network.ports: {0} is not a valid port")]
+    InvalidPort(u16),
+    #[error("network.host: {0:?} is neither an IP nor a known hostname")]
+    InvalidHost(String),
 }
 
 impl Default for NetworkConfig {
     fn default() -> Self {
         NetworkConfig {
-            port: 5555,
+            ports: vec![5555],
             interface: "en0".to_string(),
+            host: None,
+            additional_interfaces: Vec::new(),
+            snaplen: DEFAULT_SNAPLEN,
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            read_timeout_ms: DEFAULT_READ_TIMEOUT_MS,
+            immediate_mode: true,
+            promiscuous: false,
+        }
+    }
+}
+
+/// An event of the protocol by id, or by class name which survives game updates
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, specta::Type)]
+#[serde(untagged)]
+pub enum EventRef {
+    Id(EventId),
+    Class(EventName),
+}
+
+/// Events processed by the sniffer, the others are dropped before being parsed.
+/// An empty `allow` lets every event through, `deny` wins over `allow`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, specta::Type)]
+#[serde(rename_all = "camelCase", default)]
+pub struct EventFilterConfig {
+    pub allow: Vec<EventRef>,
+    pub deny: Vec<EventRef>,
+}
+
+/// `EventFilterConfig` with the class names resolved for one protocol
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EventFilter {
+    allowed: Option<HashSet<EventId>>,
+    denied: HashSet<EventId>,
+}
+
+impl EventFilterConfig {
+    /// Class names missing from `protocol` are skipped with a warning
+    pub fn resolve(&self, protocol: &ProtocolManager) -> EventFilter {
+        let ids = |events: &[EventRef]| -> HashSet<EventId> {
+            events
+                .iter()
+                .filter_map(|event| match event {
+                    EventRef::Id(id) => Some(*id),
+                    EventRef::Class(class) => {
+                        let id = protocol.get_protocol_id_by_class(class).copied();
+                        if id.is_none() {
+                            warn!("Unknown event {:?} in the event filter", class);
+                        }
+                        id
+                    }
+                })
+                .collect()
+        };
+        EventFilter {
+            allowed: (!self.allow.is_empty()).then(|| ids(&self.allow)),
+            denied: ids(&self.deny),
+        }
+    }
+}
+
+impl EventFilter {
+    pub fn allows(&self, event: &EventId) -> bool {
+        self.allowed
+            .as_ref()
+            .map_or(true, |allowed| allowed.contains(event))
+            && !self.denied.contains(event)
+    }
+}
+
+fn deserialize_ports<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u16>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Ports {
+        One(u16),
+        Many(Vec<u16>),
+    }
+
+    Ok(match Ports::deserialize(deserializer)? {
+        Ports::One(port) => vec![port],
+        Ports::Many(ports) => ports,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Keeps what `configure` set, pcap doesn't read the settings back
+    #[derive(Debug, Default, PartialEq)]
+    struct Settings {
+        immediate_mode: Option<bool>,
+        promiscuous: Option<bool>,
+        timeout: Option<i32>,
+        snaplen: Option<i32>,
+        buffer_size: Option<i32>,
+    }
+
+    impl CaptureSettings for Settings {
+        fn immediate_mode(self, immediate: bool) -> Self {
+            Settings {
+                immediate_mode: Some(immediate),
+                ..self
+            }
+        }
+
+        fn promisc(self, promiscuous: bool) -> Self {
+            Settings {
+                promiscuous: Some(promiscuous),
+                ..self
+            }
+        }
+
+        fn timeout(self, ms: i32) -> Self {
+            Settings {
+                timeout: Some(ms),
+                ..self
+            }
+        }
+
+        fn snaplen(self, snaplen: i32) -> Self {
+            Settings {
+                snaplen: Some(snaplen),
+                ..self
+            }
+        }
+
+        fn buffer_size(self, size: i32) -> Self {
+            Settings {
+                buffer_size: Some(size),
+                ..self
+            }
         }
     }
+
+    #[test]
+    fn test_event_filter() {
+        let protocol = ProtocolManager::from_json(
+            r#"[
+                {"id": "1", "class_name": "PositionMessage", "superclass": null, "attributes": {}},
+                {"id": "2", "class_name": "LevelMessage", "superclass": null, "attributes": {}}
+            ]"#,
+        )
+        .unwrap();
+        let config: EventFilterConfig =
+            serde_json::from_str(r#"{ "allow": ["PositionMessage", 2, "Missing"], "deny": [2] }"#)
+                .unwrap();
+        let filter = config.resolve(&protocol);
+        assert!(filter.allows(&1));
+        assert!(!filter.allows(&2));
+        assert!(!filter.allows(&3));
+
+        let filter = EventFilterConfig::default().resolve(&protocol);
+        assert!(filter.allows(&3));
+    }
+
+    #[test]
+    fn test_ports() {
+        let config: NetworkConfig =
+            serde_json::from_str(r#"{ "port": 5555, "interface": "en0" }"#).unwrap();
+        assert_eq!(config.ports, vec![5555]);
+
+        let config: NetworkConfig =
+            serde_json::from_str(r#"{ "ports": [5555, 443], "interface": "en0" }"#).unwrap();
+        assert_eq!(config.port_filter(), "tcp port 5555 or tcp port 443");
+    }
+
+    #[test]
+    fn test_host_filter() {
+        let config = NetworkConfig {
+            ports: vec![5555, 443],
+            host: Some("172.65.243.165".to_string()),
+            ..NetworkConfig::default()
+        };
+        assert_eq!(
+            config.filter(),
+            "(tcp port 5555 or tcp port 443) and host 172.65.243.165"
+        );
+        assert_eq!(
+            config.resolve_host(),
+            Ok(Some("172.65.243.165".parse().unwrap()))
+        );
+        assert_eq!(NetworkConfig::default().filter(), "tcp port 5555");
+
+        let config = NetworkConfig {
+            host: Some("not a host".to_string()),
+            ..NetworkConfig::default()
+        };
+        assert_eq!(
+            config.resolve_host(),
+            Err(NetworkConfigError::InvalidHost("not a host".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_capture_sizes() {
+        let config: NetworkConfig =
+            serde_json::from_str(r#"{ "ports": [5555], "interface": "en0" }"#).unwrap();
+        assert_eq!(config.snaplen, DEFAULT_SNAPLEN);
+        assert_eq!(config.buffer_size, DEFAULT_BUFFER_SIZE);
+        assert_eq!(config.read_timeout_ms, DEFAULT_READ_TIMEOUT_MS);
+
+        let config: NetworkConfig = serde_json::from_str(
+            r#"{ "ports": [5555], "interface": "en0", "snaplen": 1500, "bufferSize": 1048576 }"#,
+        )
+        .unwrap();
+        assert_eq!(config.snaplen, 1500);
+        assert_eq!(config.buffer_size, 1048576);
+
+        let settings = config.configure(Settings::default());
+        assert_eq!(settings.snaplen, Some(1500));
+        assert_eq!(settings.buffer_size, Some(1048576));
+        assert_eq!(settings.timeout, Some(DEFAULT_READ_TIMEOUT_MS));
+    }
+
+    #[test]
+    fn test_partial_config() {
+        let config: NetworkConfig = serde_json::from_str("{}").unwrap();
+        assert_eq!(config.ports, vec![5555]);
+        assert_eq!(config.interface, "en0");
+        assert_eq!(config.host, None);
+        assert_eq!(config.snaplen, DEFAULT_SNAPLEN);
+        assert!(config.immediate_mode);
+
+        let config: NetworkConfig = serde_json::from_str(r#"{ "interface": "eth0" }"#).unwrap();
+        assert_eq!(config.interface, "eth0");
+        assert_eq!(config.ports, vec![5555]);
+    }
+
+    #[test]
+    fn test_capture_modes() {
+        let config: NetworkConfig =
+            serde_json::from_str(r#"{ "ports": [5555], "interface": "en0" }"#).unwrap();
+        assert!(config.immediate_mode);
+        assert!(!config.promiscuous);
+
+        let config: NetworkConfig = serde_json::from_str(
+            r#"{ "ports": [5555], "interface": "en0", "immediateMode": false, "promiscuous": true }"#,
+        )
+        .unwrap();
+        assert!(!config.immediate_mode);
+        assert!(config.promiscuous);
+
+        let settings = config.configure(Settings::default());
+        assert_eq!(settings.immediate_mode, Some(false));
+        assert_eq!(settings.promiscuous, Some(true));
+    }
+
+    #[test]
+    fn test_validate() {
+        let devices = ["en0".to_string(), "lo0".to_string()];
+        let config = |interface: &str, ports: Vec<u16>| NetworkConfig {
+            ports,
+            interface: interface.to_string(),
+            ..NetworkConfig::default()
+        };
+
+        assert_eq!(
+            config("en0", vec![5555]).validate_with_devices(Some(&devices)),
+            Ok(())
+        );
+        assert_eq!(
+            config("", vec![5555]).validate_with_devices(Some(&devices)),
+            Err(NetworkConfigError::EmptyInterface)
+        );
+        assert_eq!(
+            config("eth42", vec![5555]).validate_with_devices(Some(&devices)),
+            Err(NetworkConfigError::UnknownInterface("eth42".to_string()))
+        );
+        assert_eq!(
+            config("en0", vec![5555, 0]).validate_with_devices(Some(&devices)),
+            Err(NetworkConfigError::InvalidPort(0))
+        );
+    }
 }
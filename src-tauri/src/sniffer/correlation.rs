@@ -0,0 +1,194 @@
+use std::time::{Duration, SystemTime};
+
+use super::{
+    parser::{metadata::ConnectionKey, packet::Packet},
+    protocol::protocol::{EventId, FieldName},
+};
+
+/// Requests waiting for their response, the oldest ones are dropped past it
+const MAX_PENDING_REQUESTS: usize = 1024;
+
+/// A request event answered by a response event on the same connection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CorrelationRule {
+    pub request: EventId,
+    pub response: EventId,
+    /// Field both events carry with the same value, without it the oldest pending request matches
+    pub key: Option<FieldName>,
+    /// A request without response for this long is forgotten
+    pub window: Duration,
+}
+
+impl CorrelationRule {
+    pub fn new(request: EventId, response: EventId, window: Duration) -> Self {
+        CorrelationRule {
+            request,
+            response,
+            key: None,
+            window,
+        }
+    }
+
+    /// Matches the response to the request having the same value for `field`
+    pub fn by_field(mut self, field: impl Into<FieldName>) -> Self {
+        self.key = Some(field.into());
+        self
+    }
+
+    fn matches(&self, request: &Packet, response: &Packet) -> bool {
+        let same_connection = match (request.connection, response.connection) {
+            (Some(request), Some(response)) => {
+                request == response || request.reversed() == response
+            }
+            _ => true,
+        };
+        same_connection
+            && self.key.as_ref().map_or(true, |key| {
+                request.data.get(key).is_some() && request.data.get(key) == response.data.get(key)
+            })
+    }
+}
+
+/// A request paired with its response
+#[derive(Debug, Clone)]
+pub struct CorrelatedExchange {
+    pub request: Packet,
+    pub response: Packet,
+    /// Time between the request and the response
+    pub latency: Duration,
+}
+
+impl CorrelatedExchange {
+    pub fn connection(&self) -> Option<ConnectionKey> {
+        self.request.connection
+    }
+}
+
+#[derive(Debug)]
+struct PendingRequest {
+    rule: usize,
+    packet: Packet,
+    timestamp: SystemTime,
+}
+
+/// Pairs the requests and responses of the configured rules, in the order they are observed
+#[derive(Debug, Default)]
+pub struct Correlator {
+    rules: Vec<CorrelationRule>,
+    pending: Vec<PendingRequest>,
+}
+
+impl Correlator {
+    pub fn new(rules: Vec<CorrelationRule>) -> Self {
+        Correlator {
+            rules,
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn rules(&self) -> &[CorrelationRule] {
+        &self.rules
+    }
+
+    /// Whether `event` is the request or the response of a rule
+    pub fn is_correlated(&self, event: &EventId) -> bool {
+        self.rules
+            .iter()
+            .any(|rule| rule.request == *event || rule.response == *event)
+    }
+
+    /// Requests still waiting for their response
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Returns the exchanges `packet` completes, then keeps it when it is a request
+    pub fn observe(&mut self, packet: &Packet, timestamp: SystemTime) -> Vec<CorrelatedExchange> {
+        let rules = &self.rules;
+        self.pending.retain(|pending| {
+            timestamp
+                .duration_since(pending.timestamp)
+                .map_or(true, |elapsed| elapsed <= rules[pending.rule].window)
+        });
+
+        let mut exchanges = Vec::new();
+        for (index, rule) in self.rules.iter().enumerate() {
+            if rule.response != packet.id {
+                continue;
+            }
+            let Some(position) = self
+                .pending
+                .iter()
+                .position(|pending| pending.rule == index && rule.matches(&pending.packet, packet))
+            else {
+                continue;
+            };
+            let request = self.pending.remove(position);
+            exchanges.push(CorrelatedExchange {
+                request: request.packet,
+                response: packet.clone(),
+                latency: timestamp
+                    .duration_since(request.timestamp)
+                    .unwrap_or_default(),
+            });
+        }
+
+        for (index, rule) in self.rules.iter().enumerate() {
+            if rule.request != packet.id {
+                continue;
+            }
+            if self.pending.len() >= MAX_PENDING_REQUESTS {
+                self.pending.remove(0);
+            }
+            self.pending.push(PendingRequest {
+                rule: index,
+                packet: packet.clone(),
+                timestamp,
+            });
+        }
+        exchanges
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use indexmap::IndexMap;
+    use serde_json::json;
+
+    use super::*;
+
+    fn packet(id: EventId, tag: i64) -> Packet {
+        Packet {
+            id,
+            name: String::new(),
+            data: IndexMap::from([("tag".to_string(), json!(tag))]),
+            connection: None,
+            instance_id: None,
+            tcp: None,
+            raw: None,
+            timestamp: None,
+        }
+    }
+
+    #[test]
+    fn test_correlator_window_and_key() {
+        let mut correlator =
+            Correlator::new(vec![
+                CorrelationRule::new(1, 2, Duration::from_secs(1)).by_field("tag")
+            ]);
+        let start = SystemTime::UNIX_EPOCH;
+
+        assert!(correlator.observe(&packet(1, 7), start).is_empty());
+        assert!(correlator.observe(&packet(1, 8), start).is_empty());
+        // the response carries the key of the second request
+        let exchanges = correlator.observe(&packet(2, 8), start + Duration::from_millis(300));
+        assert_eq!(exchanges.len(), 1);
+        assert_eq!(exchanges[0].request.data["tag"], json!(8));
+        assert_eq!(exchanges[0].latency, Duration::from_millis(300));
+
+        // past the window the first request is forgotten
+        let later = start + Duration::from_secs(2);
+        assert!(correlator.observe(&packet(2, 7), later).is_empty());
+        assert_eq!(correlator.pending(), 0);
+    }
+}
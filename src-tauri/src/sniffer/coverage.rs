@@ -0,0 +1,122 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use tracing::debug;
+
+use super::{
+    capture::CaptureSource,
+    parser::{
+        metadata::{LinkLayer, MessageSource, PacketHeader},
+        packet::{PacketError, PacketParser},
+    },
+    protocol::protocol::{EventId, ProtocolManager},
+    reassembly::ReassemblyState,
+};
+
+/// How each event of the protocol fared when a whole capture was parsed
+#[derive(Debug, Default)]
+pub struct CoverageReport {
+    /// Number of messages that parsed, by id
+    pub parsed: BTreeMap<EventId, u64>,
+    /// Errors of the messages that failed to parse, by id
+    pub failed: BTreeMap<EventId, Vec<PacketError>>,
+    /// Events of the protocol that are not in the capture
+    pub missing: BTreeSet<EventId>,
+}
+
+impl CoverageReport {
+    /// Ids that only ever failed, the likely regressions
+    pub fn broken(&self) -> Vec<EventId> {
+        self.failed
+            .keys()
+            .filter(|id| !self.parsed.contains_key(id))
+            .copied()
+            .collect()
+    }
+}
+
+/// Parses every message of `capture` that `protocol` knows, whether something listens to it or not.
+/// The segments sent to one of the game `ports` are framed as messages of the client.
+/// Meant for tests, to catch a protocol change breaking an event no listener covers.
+pub fn parse_coverage(
+    capture: &mut dyn CaptureSource,
+    protocol: &ProtocolManager,
+    ports: &[u16],
+) -> CoverageReport {
+    let link_layer = LinkLayer::from_linktype(capture.datalink()).unwrap_or(LinkLayer::Ethernet);
+    let mut streams: HashMap<(Vec<u8>, u16, u16), ReassemblyState> = HashMap::new();
+    let mut report = CoverageReport::default();
+
+    while let Ok(data) = capture.next_packet() {
+        let Ok(header) = PacketHeader::from_link_layer(data, link_layer) else {
+            continue;
+        };
+        let frames = streams
+            .entry((
+                header.source_ip.clone(),
+                header.source_port,
+                header.destination_port,
+            ))
+            .or_insert_with(|| {
+                ReassemblyState::new().with_source(MessageSource::of(&header, ports))
+            })
+            .push(header);
+
+        for metadata in frames.into_iter().flatten() {
+            if protocol.get_protocol(&metadata.id).is_none() {
+                continue;
+            }
+            match PacketParser::from_metadata(&metadata).parse(protocol) {
+                Ok(_) => *report.parsed.entry(metadata.id).or_default() += 1,
+                Err(err) => {
+                    debug!("Failed to parse packet: {:?} for {:?}", err, metadata.id);
+                    report.failed.entry(metadata.id).or_default().push(err);
+                }
+            }
+        }
+    }
+
+    report.missing = protocol
+        .ids()
+        .filter(|id| !report.parsed.contains_key(id) && !report.failed.contains_key(id))
+        .copied()
+        .collect();
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sniffer::capture::open_file;
+
+    #[test]
+    fn test_parse_coverage() {
+        let mut protocol = ProtocolManager::new();
+        protocol.init("tests/fixtures/coverage").unwrap();
+        let mut capture = open_file("tests/fixtures/cap.pcap").unwrap();
+
+        let report = parse_coverage(&mut capture, &protocol, &[5555]);
+
+        // BasicAckMessage is well defined, ProtocolRequired reads a type the parser doesn't know
+        assert!(report.parsed[&4642] > 0);
+        assert!(!report.failed.contains_key(&4642));
+        assert!(!report.parsed.contains_key(&9922));
+        assert!(matches!(
+            report.failed[&9922][0],
+            PacketError::UnknownFieldType { .. }
+        ));
+        assert_eq!(report.broken(), vec![9922]);
+        assert_eq!(report.missing, BTreeSet::from([16000]));
+    }
+
+    #[test]
+    fn test_parse_coverage_of_client() {
+        let mut protocol = ProtocolManager::new();
+        protocol.init("tests/fixtures/").unwrap();
+        let mut capture = open_file("tests/fixtures/cap.pcap").unwrap();
+
+        // the pings are only sent by the client, framed with their instance id
+        let report = parse_coverage(&mut capture, &protocol, &[5555]);
+        assert_eq!(report.parsed.get(&8285), Some(&23));
+        assert!(!report.failed.contains_key(&8285));
+    }
+}
@@ -0,0 +1,65 @@
+const BYTES_PER_LINE: usize = 16;
+
+/// Formats bytes like `hexdump -C`: offset, 16 hex bytes and an ASCII gutter
+pub fn hex_dump(bytes: &[u8]) -> String {
+    bytes
+        .chunks(BYTES_PER_LINE)
+        .enumerate()
+        .map(|(line, chunk)| {
+            let mut hex = String::with_capacity(BYTES_PER_LINE * 3 + 1);
+            for (i, byte) in chunk.iter().enumerate() {
+                if i == BYTES_PER_LINE / 2 {
+                    hex.push(' ');
+                }
+                hex.push_str(&format!("{:02x} ", byte));
+            }
+            let ascii: String = chunk
+                .iter()
+                .map(|&byte| match byte {
+                    0x20..=0x7e => byte as char,
+                    _ => '.',
+                })
+                .collect();
+            format!(
+                "{:08x}  {:<width$} |{}|",
+                line * BYTES_PER_LINE,
+                hex,
+                ascii,
+                width = BYTES_PER_LINE * 3 + 1
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Same as `hex_dump` but only dumps the first `max_len` bytes
+pub fn hex_dump_truncated(bytes: &[u8], max_len: usize) -> String {
+    if bytes.len() <= max_len {
+        return hex_dump(bytes);
+    }
+    format!(
+        "{}\n... {} more bytes",
+        hex_dump(&bytes[..max_len]),
+        bytes.len() - max_len
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hex_dump() {
+        let bytes = b"Hello, dofus!\x00\x01\x02\xffend";
+        assert_eq!(
+            hex_dump(bytes),
+            "00000000  48 65 6c 6c 6f 2c 20 64  6f 66 75 73 21 00 01 02  |Hello, dofus!...|\n\
+             00000010  ff 65 6e 64                                       |.end|"
+        );
+        assert_eq!(hex_dump(&[]), "");
+        assert_eq!(
+            hex_dump_truncated(bytes, 4),
+            "00000000  48 65 6c 6c                                       |Hell|\n... 16 more bytes"
+        );
+    }
+}
@@ -0,0 +1,234 @@
+use std::time::Duration;
+
+use serde::Serialize;
+
+use super::protocol::protocol::{EventId, EventName};
+
+/// Statistics reported by libpcap for the running capture
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, specta::Type)]
+#[serde(rename_all = "camelCase")]
+pub struct CaptureStats {
+    /// Packets received by the capture
+    pub received: u32,
+    /// Packets dropped because we didn't read them fast enough
+    pub dropped: u32,
+    /// Packets dropped by the network interface or its driver
+    pub if_dropped: u32,
+}
+
+/// Statistics of several captures, like the loops of `additional_interfaces`
+impl std::ops::Add for CaptureStats {
+    type Output = CaptureStats;
+
+    fn add(self, other: CaptureStats) -> CaptureStats {
+        CaptureStats {
+            received: self.received.saturating_add(other.received),
+            dropped: self.dropped.saturating_add(other.dropped),
+            if_dropped: self.if_dropped.saturating_add(other.if_dropped),
+        }
+    }
+}
+
+impl CaptureStats {
+    pub fn total_dropped(&self) -> u32 {
+        self.dropped.saturating_add(self.if_dropped)
+    }
+
+    /// Number of packets dropped since `previous` was reported
+    pub fn dropped_since(&self, previous: &CaptureStats) -> u32 {
+        self.total_dropped()
+            .saturating_sub(previous.total_dropped())
+    }
+}
+
+/// What a capture limited in time went through, see `PacketListener::run_for`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SessionStats {
+    /// Frames read before the time limit, including the ones that are not game traffic
+    pub frames: u64,
+    pub elapsed: Duration,
+    pub capture: CaptureStats,
+}
+
+/// Everything a capture went through, returned by `PacketListener::stop_and_report`
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CaptureSummary {
+    /// Frames read by the capture loops, including the ones that are not game traffic
+    pub frames: u64,
+    /// Counters of every event, see `PacketListener::metrics_report`
+    pub events: Vec<EventMetric>,
+    pub parse_failures: u64,
+    /// Statistics reported by libpcap, zero for capture files
+    pub capture: CaptureStats,
+    /// Frames the recording couldn't keep up with
+    pub recording_dropped: u64,
+    /// Packets dropped because the listener workers queue was full
+    pub queue_dropped: u64,
+    /// Segments dropped because the snaplen cut them
+    pub truncated: u64,
+    pub unrouted: u64,
+    /// Times the reassembly skipped junk after a message
+    pub resyncs: u64,
+    /// Reassembly buffers dropped with their bytes
+    pub buffer_clears: u64,
+    /// Time since the first capture of the listener started
+    pub uptime: Duration,
+}
+
+impl CaptureSummary {
+    /// Packets lost by libpcap, the recording and the listener workers
+    pub fn total_dropped(&self) -> u64 {
+        self.capture.total_dropped() as u64 + self.recording_dropped + self.queue_dropped
+    }
+
+    pub fn frames_per_second(&self) -> f64 {
+        self.per_second(self.frames)
+    }
+
+    /// Packets of every event that reached their listeners, per second
+    pub fn events_per_second(&self) -> f64 {
+        self.per_second(self.events.iter().map(|event| event.metrics.seen).sum())
+    }
+
+    fn per_second(&self, count: u64) -> f64 {
+        if self.uptime.is_zero() {
+            return 0.0;
+        }
+        count as f64 / self.uptime.as_secs_f64()
+    }
+}
+
+/// Counters for a single event id
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, specta::Type)]
+#[serde(rename_all = "camelCase")]
+pub struct EventMetrics {
+    /// Packets that reached the listeners of the event
+    pub seen: u64,
+    /// Packets delivered to at least one listener
+    pub delivered: u64,
+    /// Listener calls skipped because the subscription filter rejected the packet
+    pub filtered: u64,
+    /// Packets dropped because of the event rate limit
+    pub rate_limited: u64,
+    /// Packets kept by the event sampling
+    pub sampled: u64,
+    /// Packets skipped by the event sampling, they are not parsed
+    pub skipped: u64,
+    /// Packets dropped because they were identical to the previous one of the event
+    pub deduplicated: u64,
+    /// Messages of the event that failed to decode
+    pub parse_failures: u64,
+    /// Packets dropped because the listener workers queue was full
+    pub queue_dropped: u64,
+}
+
+/// Row of `metrics_report`, the counters of an event along with its class name
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, specta::Type)]
+#[serde(rename_all = "camelCase")]
+pub struct EventMetric {
+    pub id: EventId,
+    /// `None` when the protocol doesn't know the id
+    pub name: Option<EventName>,
+    pub metrics: EventMetrics,
+}
+
+/// Percentiles of the time spent decoding the packets of an event
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LatencyStats {
+    pub count: u64,
+    pub p50: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+/// Number of latency buckets, bucket `i` holds the samples below `2^i` nanoseconds
+const LATENCY_BUCKETS: usize = 64;
+
+/// Latencies counted in power of two buckets, percentiles are rounded up to the bucket bound
+#[derive(Debug, Clone)]
+pub struct LatencyHistogram {
+    buckets: [u64; LATENCY_BUCKETS],
+    count: u64,
+    max: Duration,
+}
+
+impl LatencyHistogram {
+    pub fn new() -> Self {
+        LatencyHistogram {
+            buckets: [0; LATENCY_BUCKETS],
+            count: 0,
+            max: Duration::ZERO,
+        }
+    }
+
+    pub fn record(&mut self, latency: Duration) {
+        let nanos = latency.as_nanos().min(u64::MAX as u128) as u64;
+        let bucket = (u64::BITS - nanos.leading_zeros()) as usize;
+        self.buckets[bucket.min(LATENCY_BUCKETS - 1)] += 1;
+        self.count += 1;
+        self.max = self.max.max(latency);
+    }
+
+    fn percentile(&self, percentile: u64) -> Duration {
+        let rank = (self.count * percentile).div_ceil(100).max(1);
+        let mut seen = 0;
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                let bound = Duration::from_nanos(1u64 << bucket.min(63));
+                return bound.min(self.max);
+            }
+        }
+        self.max
+    }
+
+    pub fn stats(&self) -> LatencyStats {
+        LatencyStats {
+            count: self.count,
+            p50: self.percentile(50),
+            p99: self.percentile(99),
+            max: self.max,
+        }
+    }
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        LatencyHistogram::new()
+    }
+}
+
+#[cfg(feature = "capture")]
+impl From<pcap::Stat> for CaptureStats {
+    fn from(stat: pcap::Stat) -> Self {
+        CaptureStats {
+            received: stat.received,
+            dropped: stat.dropped,
+            if_dropped: stat.if_dropped,
+        }
+    }
+}
+
+#[cfg(all(test, feature = "capture"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dropped_since() {
+        let previous = CaptureStats {
+            received: 10,
+            dropped: 1,
+            if_dropped: 0,
+        };
+        let current = CaptureStats::from(pcap::Stat {
+            received: 20,
+            dropped: 3,
+            if_dropped: 1,
+        });
+
+        assert_eq!(current.total_dropped(), 4);
+        assert_eq!(current.dropped_since(&previous), 3);
+        assert_eq!(previous.dropped_since(&current), 0);
+    }
+}
@@ -1,4 +1,14 @@
+#[cfg(feature = "capture")]
+pub mod capture;
+#[cfg(feature = "capture")]
 pub mod config;
+pub mod correlation;
+#[cfg(feature = "capture")]
+pub mod coverage;
+pub mod hex;
+pub mod metrics;
+#[cfg(feature = "capture")]
 pub mod network;
 pub mod parser;
 pub mod protocol;
+pub mod reassembly;
@@ -1,312 +1,4983 @@
-use std::{collections::HashMap, sync::RwLock, time::SystemTime};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    hash::{Hash, Hasher},
+    io::Read,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        mpsc::{sync_channel, Receiver, SyncSender},
+        MutexGuard, OnceLock, RwLock,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant, SystemTime},
+};
 
 use core::fmt::Debug;
-use pcap::{Activated, Capture};
+use pcap::{Activated, Active, Capture, Device};
 use std::sync::{Arc, Mutex};
 use thiserror::Error;
-use tracing::{debug, info, warn};
+use tokio::sync::mpsc;
+use tokio_stream::{wrappers::UnboundedReceiverStream, Stream};
+use tracing::{debug, debug_span, error, info, warn};
 
 use crate::{
+    constants::VERSION_REGEX,
     node::Node,
     sniffer::parser::{
-        metadata::{PacketHeader, PacketMetadata, ParseResult},
-        packet::PacketParser,
-        wrapper::DataWrapper,
+        metadata::{
+            ConnectionKey, LinkLayer, MessageSource, PacketHeader, PacketMetadata, ParseResult,
+            TcpInfo,
+        },
+        packet::{PacketError, PacketParser},
     },
+    store::Store,
 };
 
-use super::{parser::packet::Packet, protocol::protocol::EventId};
+use super::{
+    capture::{
+        capture_files, open_file, BackgroundRecorder, CaptureFileError, CaptureRecorder,
+        CaptureSource, PacketRing, PacketSink, PcapStream, RotationPolicy,
+    },
+    config::{EventFilter, EventFilterConfig, NetworkConfig, NetworkConfigError},
+    correlation::{CorrelatedExchange, CorrelationRule, Correlator},
+    hex::hex_dump_truncated,
+    metrics::{
+        CaptureStats, CaptureSummary, EventMetric, EventMetrics, LatencyHistogram, LatencyStats,
+        SessionStats,
+    },
+    parser::packet::Packet,
+    protocol::protocol::{EventId, EventName, ProtocolManager, ProtocolSchema},
+    reassembly::{InvalidBuffer, ReassemblyState},
+};
 
-pub type Listener = fn(&Packet, &Node);
+pub type Listener = fn(&Packet, &Node) -> ListenerAction;
+/// Same as `Listener` but only gets what most listeners need, see `subscribe_with_context`
+pub type ContextListener = fn(&Packet, &ListenerContext) -> ListenerAction;
+pub type BatchListener = fn(&[Packet], &Node);
+/// Called with the framed body of a message, before it is decoded
+pub type RawListener = fn(&EventId, &[u8], &Node);
 pub type ListenerId = &'static str;
-pub type Subscription = (ListenerId, Listener);
+pub type GroupId = &'static str;
+pub type Predicate = Arc<dyn Fn(&Packet) -> bool + Send + Sync>;
 
-#[derive(Debug)]
-pub struct PacketListener {
-    subscriptions: Arc<Mutex<HashMap<EventId, Vec<Subscription>>>>,
-    node: Option<Arc<Node>>,
-    pub last_packet_time: Arc<RwLock<u128>>,
+/// What a listener wants done with its subscription once it handled a packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListenerAction {
+    Continue,
+    /// Removes the subscription, the listener isn't called for the next packets
+    Unsubscribe,
 }
 
-impl PacketListener {
-    pub fn new() -> PacketListener {
-        return PacketListener {
-            subscriptions: Arc::new(Mutex::new(HashMap::new())),
+/// What a `ContextListener` can reach, instead of the whole node.
+/// The node protocol stays read locked while the listener runs, a reload waits for it to return.
+pub struct ListenerContext<'a> {
+    store: &'a Mutex<Store>,
+    event: Option<&'a ProtocolSchema>,
+    timestamp: SystemTime,
+    node: Option<&'a Node>,
+}
+
+impl<'a> ListenerContext<'a> {
+    /// A context without node, to call listeners in tests
+    pub fn new(store: &'a Mutex<Store>, event: Option<&'a ProtocolSchema>) -> Self {
+        ListenerContext {
+            store,
+            event,
+            timestamp: SystemTime::now(),
             node: None,
-            last_packet_time: Arc::new(RwLock::new(0)),
-        };
+        }
     }
 
-    pub fn set_node(&mut self, node: Arc<Node>) {
-        self.node = Some(node);
+    /// `protocol` is the node protocol, read by the caller for the duration of the call
+    fn from_node(node: &'a Node, protocol: &'a ProtocolManager, packet: &Packet) -> Self {
+        ListenerContext {
+            store: &node.store,
+            event: protocol.get_protocol(&packet.id),
+            timestamp: packet.timestamp.unwrap_or_else(SystemTime::now),
+            node: Some(node),
+        }
     }
 
-    pub fn subscribe(&mut self, event: EventId, listener_id: ListenerId, listener: Listener) {
-        info!("Subscribing to event: {:?} for {:?}", event, listener_id);
-        self.subscriptions
-            .lock()
-            .unwrap()
-            .entry(event)
-            .or_default()
-            .push((listener_id, listener));
+    pub fn store(&self) -> MutexGuard<'a, Store> {
+        self.store.lock().unwrap()
     }
 
-    pub fn unsubscribe(&mut self, event: &EventId, listener_id: ListenerId) {
-        info!(
-            "Unsubscribing from event: {:?} for {:?}",
-            event, listener_id
-        );
-        self.subscriptions
-            .lock()
-            .unwrap()
-            .get_mut(event)
-            .map(|listeners| listeners.retain(|(id, _)| id != &listener_id));
+    /// Protocol definition of the packet, `None` when it was replaced since the packet was parsed
+    pub fn event(&self) -> Option<&'a ProtocolSchema> {
+        self.event
     }
 
-    pub fn notify(&self, event: &Packet) {
-        PacketListener::_notify(
-            &self.subscriptions.lock().unwrap(),
-            event,
-            &self.node.as_ref().unwrap(),
-        );
+    /// When the packet was captured, the delivery time for the packets built without a capture
+    pub fn timestamp(&self) -> SystemTime {
+        self.timestamp
+    }
+
+    /// Escape hatch for the listeners needing the rest of the node, `None` in tests
+    pub fn node(&self) -> Option<&'a Node> {
+        self.node
     }
+}
+
+#[derive(Clone, Copy)]
+enum Callback {
+    Node(Listener),
+    Context(ContextListener),
+}
 
-    fn _notify(subscriptions: &HashMap<EventId, Vec<Subscription>>, packet: &Packet, node: &Node) {
-        let listeners = subscriptions.get(&packet.id);
-        if let Some(listeners) = listeners {
-            for (_, listener) in listeners {
-                listener(packet, node);
+impl Callback {
+    fn call(&self, packet: &Packet, node: &Node) -> ListenerAction {
+        match self {
+            Callback::Node(listener) => listener(packet, node),
+            Callback::Context(listener) => {
+                let protocol = node.protocol.read().unwrap();
+                listener(packet, &ListenerContext::from_node(node, &protocol, packet))
             }
         }
     }
+}
 
-    pub fn has_subscriptions_for(&self, event: &EventId, listener_id: ListenerId) -> bool {
-        let subscriptions = self.subscriptions.lock().unwrap();
-        subscriptions.get(event).map_or(false, |listeners| {
-            listeners.iter().any(|(id, _)| id == &listener_id)
-        })
+#[derive(Clone)]
+pub struct Subscription {
+    pub id: ListenerId,
+    listener: Callback,
+    /// When set, the listener is only called for the packets matching it
+    filter: Option<Predicate>,
+    group: Option<GroupId>,
+    /// Removed after its first delivery
+    once: bool,
+    /// Higher priorities are called first, see `subscribe_with_priority`
+    priority: i32,
+    /// Set by `add_subscription`, tells apart the subscriptions sharing a listener id
+    key: u64,
+}
+
+/// What `export_subscriptions` keeps of a subscription, the listener itself can't be serialized
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubscriptionInfo {
+    pub event: EventId,
+    /// Class of `event` in the protocol of the node, its id can change with the game version
+    pub class: Option<EventName>,
+    pub listener_id: ListenerId,
+    pub group: Option<GroupId>,
+}
+
+impl Subscription {
+    /// Unfiltered, without group nor priority, the `subscribe_*` methods change the rest
+    fn new(id: ListenerId, listener: Callback) -> Self {
+        Subscription {
+            id,
+            listener,
+            filter: None,
+            group: None,
+            once: false,
+            priority: 0,
+            key: 0,
+        }
     }
 
-    pub fn has_subscriptions(&self, event: &EventId) -> bool {
-        return PacketListener::_has_subscriptions(&self.subscriptions.lock().unwrap(), event);
+    fn matches(&self, packet: &Packet) -> bool {
+        self.filter.as_ref().map_or(true, |filter| filter(packet))
     }
+}
 
-    fn _has_subscriptions(
-        subscriptions: &HashMap<EventId, Vec<Subscription>>,
-        event: &EventId,
-    ) -> bool {
-        return subscriptions
-            .get(event)
-            .map_or(false, |listeners| !listeners.is_empty());
+/// Keeps `listeners` sorted by priority, `subscription` goes after the ones of the same priority
+fn insert_by_priority(listeners: &mut Vec<Subscription>, subscription: Subscription) {
+    let index = listeners.partition_point(|other| other.priority >= subscription.priority);
+    listeners.insert(index, subscription);
+}
+
+impl Debug for Subscription {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Subscription")
+            .field("id", &self.id)
+            .field("filtered", &self.filter.is_some())
+            .field("group", &self.group)
+            .field("once", &self.once)
+            .field("priority", &self.priority)
+            .finish()
     }
+}
 
-    pub fn run(&self) -> Result<(), PacketListenerError> {
-        if self.node.is_none() {
-            return Err(PacketListenerError::InvalidCaptureDevice);
-        }
+#[derive(Debug, Clone, Copy)]
+struct RawSubscription {
+    id: ListenerId,
+    listener: RawListener,
+}
 
-        let config = self.node.as_ref().unwrap().config.config.read().unwrap();
-        let interface = config.network.interface.as_str();
-        let port = config.network.port;
+/// Packets waiting to be delivered together to a `BatchListener`
+#[derive(Debug)]
+struct BatchSubscription {
+    id: ListenerId,
+    listener: BatchListener,
+    max_batch: usize,
+    max_delay: Duration,
+    pending: Vec<Packet>,
+    /// Arrival of the oldest pending packet
+    first_pending: Option<Instant>,
+}
 
-        info!(
-            "Starting sniffer on interface: {} and port: {}",
-            interface, port
-        );
+impl BatchSubscription {
+    fn is_ready(&self, now: Instant) -> bool {
+        self.pending.len() >= self.max_batch
+            || self
+                .first_pending
+                .map_or(false, |first| now.duration_since(first) >= self.max_delay)
+    }
+}
 
-        let mut cap = Capture::from_device(interface)
-            .unwrap()
-            .immediate_mode(true)
-            .open()
-            .expect("Failed to open device");
-        cap.direction(pcap::Direction::In).unwrap();
+/// Channel side of a `PacketStream`, `event` is `None` for the streams of all events
+#[derive(Debug)]
+struct StreamSender {
+    id: u64,
+    event: Option<EventId>,
+    sender: mpsc::UnboundedSender<Packet>,
+}
 
-        cap.filter(format!("tcp port {}", port).as_str(), false)
-            .unwrap();
+impl StreamSender {
+    fn matches(&self, event: &EventId) -> bool {
+        self.event
+            .map_or(true, |stream_event| stream_event == *event)
+    }
+}
+
+type StreamSenders = Arc<Mutex<Vec<StreamSender>>>;
+
+/// Parsed packets of a `packet_stream`, its channel is removed from the listener on drop
+#[derive(Debug)]
+struct PacketStream {
+    id: u64,
+    streams: StreamSenders,
+    receiver: UnboundedReceiverStream<Packet>,
+}
 
-        self.run_with_capture(cap.into())
+impl Stream for PacketStream {
+    type Item = Packet;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Packet>> {
+        Pin::new(&mut self.receiver).poll_next(cx)
     }
+}
 
-    pub fn run_with_capture(
-        &self,
-        mut cap: Capture<dyn Activated>,
-    ) -> Result<(), PacketListenerError> {
-        if self.node.is_none() {
-            return Err(PacketListenerError::InvalidCaptureDevice);
-        }
+impl Drop for PacketStream {
+    fn drop(&mut self) {
+        self.streams
+            .lock()
+            .unwrap()
+            .retain(|stream| stream.id != self.id);
+    }
+}
 
-        debug!("Running packet listener");
-        let subscriptions = self.subscriptions.clone();
-        let procol_manager = self.node.as_ref().unwrap().protocol.clone();
-        let node = self.node.clone().unwrap();
-        let last_packet_time = self.last_packet_time.clone();
+/// Why a buffer could not be turned into a packet
+#[derive(Debug)]
+pub enum ParseFailureKind {
+    Metadata(ParseResult),
+    Packet(PacketError),
+}
 
-        tauri::async_runtime::spawn(async move {
-            let buffer = &mut DataWrapper::new(Vec::new());
-            let mut last_packet_header: Option<PacketHeader> = None;
-
-            while let Ok(packet) = cap.next_packet() {
-                let data = packet.data.to_vec();
-                let now = SystemTime::now();
-
-                *last_packet_time.write().unwrap() = now
-                    .duration_since(SystemTime::UNIX_EPOCH)
-                    .unwrap()
-                    .as_millis();
-
-                let packet_header = PacketHeader::from_vec(&data);
-                if packet_header.is_err() {
-                    warn!("Failed to parse packet header: {:?}", packet_header);
-                    continue;
-                }
-                let header = packet_header.unwrap();
-
-                let mut reorder = false;
-                if let Some(ref _last_packet_header) = last_packet_header {
-                    if _last_packet_header.source_ip != header.source_ip {
-                    } else if _last_packet_header.seq_num < header.seq_num {
-                        buffer.reorder(header.body.clone()); // TODO: remove clone
-                        reorder = true;
-                    }
-                }
+/// A parse failure along with the raw bytes that caused it
+#[derive(Debug)]
+pub struct ParseFailure {
+    /// `None` when the buffer is too short to read the message id
+    pub id: Option<EventId>,
+    pub error: ParseFailureKind,
+    pub data: Vec<u8>,
+}
 
-                if !reorder {
-                    buffer.extend_from_slice(&header.body);
-                }
-                let metadata = PacketMetadata::from_buffer(buffer.get_remaining().to_vec());
+pub type ParseErrorCallback = dyn Fn(&ParseFailure) + Send + Sync;
 
-                match metadata {
-                    Err(err) => match err {
-                        ParseResult::Incomplete => {
-                            // warn!("Incomplete packet: {:?}", err);
-                            last_packet_header = Some(header);
-                        }
-                        _ => {
-                            warn!("Failed to parse metadata: {:?}", err);
-                            buffer.clear();
-                        }
-                    },
-                    Ok(metadata) => {
-                        buffer.clear(); // TODO: adapt to other ranges
-                                        // debug!("Parsed metadata: {:?}", metadata.id);
-                        last_packet_header = None;
-                        if PacketListener::_has_subscriptions(
-                            &subscriptions.lock().unwrap(),
-                            &metadata.id,
-                        ) {
-                            let mut parser = PacketParser::from_metadata(&metadata);
-                            match parser.parse(&procol_manager.read().unwrap()) {
-                                Ok(packet) => {
-                                    PacketListener::_notify(
-                                        &subscriptions.lock().unwrap(),
-                                        &packet,
-                                        &node,
-                                    );
-                                }
-                                Err(err) => {
-                                    warn!(
-                                        "Failed to parse packet: {:?} for {:?}",
-                                        err, metadata.id
-                                    );
-                                }
-                            }
-                        }
-                    }
-                };
-            }
-        });
+/// Callbacks registered on one of the listener hooks, like `on_parse_error`
+struct Callbacks<F: ?Sized>(Vec<Arc<F>>);
 
-        Ok(())
+impl<F: ?Sized> Default for Callbacks<F> {
+    fn default() -> Self {
+        Callbacks(Vec::new())
     }
 }
 
-#[derive(Debug, Error)]
-pub enum PacketListenerError {
-    #[error("Failed to open device")]
-    FailedToOpenDevice,
-    #[error("Invalid capture device")]
-    InvalidCaptureDevice,
+impl<F: ?Sized> Callbacks<F> {
+    /// Called once the lock is released, a callback can then register another one
+    fn snapshot(&self) -> Vec<Arc<F>> {
+        self.0.clone()
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    use std::path::Path;
+impl<F: ?Sized> Debug for Callbacks<F> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Callbacks({})", self.0.len())
+    }
+}
 
-    use super::*;
+/// TCP sequence numbers skipped on a connection, the segments were dropped or not routed to the capture
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SequenceGap {
+    pub connection: ConnectionKey,
+    pub from: u32,
+    pub to: u32,
+    pub bytes: u32,
+}
 
-    #[test]
-    fn test_packet_listener() {
-        let mut listener = PacketListener::new();
+pub type SequenceGapCallback = dyn Fn(&SequenceGap) + Send + Sync;
 
-        assert_eq!(listener.subscriptions.lock().unwrap().len(), 0);
+/// Called with the id of every framed message, the time it was framed and its connection
+pub type EventSeenCallback = dyn Fn(EventId, SystemTime, ConnectionKey) + Send + Sync;
 
-        let listener_id = "test";
-        let listener_fn = |_event: &Packet, _: &Node| {};
-        let event = 0;
+/// Called with every request paired with its response, see `correlate`
+pub type ExchangeCallback = dyn Fn(&CorrelatedExchange) + Send + Sync;
 
-        listener.subscribe(event.clone(), listener_id, listener_fn);
-        assert_eq!(listener.subscriptions.lock().unwrap().len(), 1);
-        assert_eq!(
-            listener
-                .subscriptions
-                .lock()
-                .unwrap()
-                .get(&event)
-                .unwrap()
-                .len(),
-            1
-        );
+/// Packet waiting for a listener worker, it carries the listener so the workers don't keep it alive
+struct ListenerJob {
+    listener: PacketListener,
+    listeners: Option<Vec<Subscription>>,
+    packet: Packet,
+    /// Node of the connection the packet came from, see `route_connection`
+    node: Arc<Node>,
+}
 
-        listener.unsubscribe(&event, listener_id);
-        assert_eq!(listener.subscriptions.lock().unwrap().len(), 1);
-        assert_eq!(
-            listener
-                .subscriptions
-                .lock()
-                .unwrap()
-                .get(&event)
-                .unwrap()
-                .len(),
-            0
-        );
+/// Bounded queue of the packets handed to the listener worker threads.
+/// The threads stop once the listener and all the queued packets are dropped.
+struct ListenerWorkers {
+    sender: SyncSender<ListenerJob>,
+}
+
+impl ListenerWorkers {
+    fn spawn(threads: usize, queue_size: usize) -> Self {
+        let (sender, receiver) = sync_channel(queue_size);
+        let receiver: Arc<Mutex<Receiver<ListenerJob>>> = Arc::new(Mutex::new(receiver));
+        for _ in 0..threads {
+            let receiver = receiver.clone();
+            std::thread::spawn(move || loop {
+                // the lock is released before calling the listeners
+                let job = receiver.lock().unwrap().recv();
+                let Ok(job) = job else {
+                    break;
+                };
+                job.listener._notify(job.listeners, &job.packet, &job.node);
+            });
+        }
+        ListenerWorkers { sender }
     }
+}
 
-    #[tokio::test]
-    async fn test_with_capture() {
-        let cap = Capture::from_file("tests/fixtures/cap.pcap").unwrap();
-        let path = "tests/fixtures/".to_string();
-        let path = Path::new(&path);
-        let node = Node::new(path, None, false).await;
-        if let Err(err) = node {
-            panic!("Failed to create node: {:?}", err);
+impl Debug for ListenerWorkers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ListenerWorkers")
+    }
+}
+
+pub type RawSegmentCallback = dyn Fn(&PacketHeader) + Send + Sync;
+
+/// Parse outcomes at the start of a capture suggesting the protocol file is for another game version
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProtocolMismatch {
+    pub failures: u64,
+    pub successes: u64,
+}
+
+pub type ProtocolMismatchCallback = dyn Fn(&ProtocolMismatch) + Send + Sync;
+
+/// How often the pcap statistics are refreshed while capturing
+const CAPTURE_STATS_INTERVAL: Duration = Duration::from_secs(1);
+/// Number of body bytes included in parse failure logs
+const HEX_DUMP_LOG_LIMIT: usize = 64;
+/// How many times the capture is reopened when the device goes away before giving up
+const MAX_REOPEN_ATTEMPTS: u32 = 5;
+const REOPEN_INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+/// Packets waiting to be written to the recording before new ones are dropped
+const RECORDING_QUEUE_SIZE: usize = 4096;
+
+/// What the capture loop does when reading a packet fails
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaptureErrorPolicy {
+    /// Keeps reading after a read timeout, they are expected on idle interfaces
+    pub retry_on_timeout: bool,
+    /// Attempts to reopen a device that went away, the capture stops when they all failed
+    pub max_reopen_attempts: u32,
+    /// Delay before the first reopen attempt, doubled after each failed one
+    pub reopen_backoff: Duration,
+}
+
+impl Default for CaptureErrorPolicy {
+    fn default() -> Self {
+        CaptureErrorPolicy {
+            retry_on_timeout: true,
+            max_reopen_attempts: MAX_REOPEN_ATTEMPTS,
+            reopen_backoff: REOPEN_INITIAL_BACKOFF,
         }
-        let node = node.unwrap();
-        let listener_fn = |event: &Packet, node: &Node| {
-            let key = event.id.to_string();
-            let mut store = node.store.lock().unwrap();
-            match store.get(&key) {
-                Some(count) => {
-                    let count = count.parse::<u32>().unwrap();
-                    let count = count + 1;
-                    let count = count.to_string();
-                    store.insert(key, count);
-                }
-                None => {
-                    store.insert(key, "1".to_string());
-                }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureErrorAction {
+    Retry,
+    Reopen,
+    Stop,
+}
+
+impl CaptureErrorPolicy {
+    /// `can_reopen` is false for the captures not opened from a device, like files
+    pub fn action(&self, error: &pcap::Error, can_reopen: bool) -> CaptureErrorAction {
+        match error {
+            pcap::Error::TimeoutExpired if self.retry_on_timeout => CaptureErrorAction::Retry,
+            pcap::Error::PcapError(_) | pcap::Error::IoError(_)
+                if can_reopen && self.max_reopen_attempts > 0 =>
+            {
+                CaptureErrorAction::Reopen
             }
+            _ => CaptureErrorAction::Stop,
+        }
+    }
+}
+
+/// Number of parsed messages at the start of a capture checked for a protocol mismatch
+const MISMATCH_WINDOW: u64 = 200;
+/// Parsed messages needed before a mismatch can be suspected
+const MISMATCH_MIN_SAMPLES: u64 = 20;
+/// Share of parse failures above which a mismatch is suspected
+const MISMATCH_FAILURE_RATIO: f64 = 0.5;
+/// Connections without segments for this long are no longer listed as active
+const CONNECTION_IDLE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+/// Consecutive panics before a listener is skipped for a while
+const LISTENER_BACKOFF_THRESHOLD: u32 = 3;
+const LISTENER_INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const LISTENER_MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// First message of the server, it carries the game version
+const HANDSHAKE_EVENT: &str = "ProtocolRequired";
+/// How often the watcher reads the configured interfaces
+const CONFIG_POLL_INTERVAL: Duration = Duration::from_millis(250);
+/// A new interface must stay selected this long before the capture restarts on it
+const INTERFACE_CHANGE_DEBOUNCE: Duration = Duration::from_secs(1);
+
+/// Settings applied when opening a capture and reassembling its packets
+#[derive(Debug, Clone)]
+pub struct CaptureOptions {
+    /// BPF expression combined with the port filter
+    pub filter: Option<String>,
+    /// Reassembly buffers growing past this size are dropped
+    pub max_buffer_bytes: Option<usize>,
+    pub direction: pcap::Direction,
+    /// Drops the segments with an invalid TCP checksum instead of reassembling corrupt bytes
+    pub verify_checksums: bool,
+    pub error_policy: CaptureErrorPolicy,
+    /// Reports the messages not taking their whole body as parse failures, see `PacketParser::strict`
+    pub strict_lengths: bool,
+    /// Drops the segments of the connections without a route instead of handing them to the listener node
+    pub drop_unrouted: bool,
+}
+
+impl Default for CaptureOptions {
+    fn default() -> Self {
+        CaptureOptions {
+            filter: None,
+            max_buffer_bytes: None,
+            direction: pcap::Direction::In,
+            verify_checksums: false,
+            error_policy: CaptureErrorPolicy::default(),
+            strict_lengths: false,
+            drop_unrouted: false,
+        }
+    }
+}
+
+/// Cloning a listener shares its subscriptions and state
+#[derive(Debug, Clone)]
+pub struct PacketListener {
+    subscriptions: Arc<Mutex<HashMap<EventId, Vec<Subscription>>>>,
+    disabled_groups: Arc<Mutex<HashSet<GroupId>>>,
+    /// When set, messages of other events are dropped as soon as their id is known
+    interest_set: Arc<RwLock<Option<HashSet<EventId>>>>,
+    /// Events let through by the config of the node, see `apply_event_filter`
+    event_filter: Arc<RwLock<EventFilter>>,
+    batches: Arc<Mutex<HashMap<EventId, Vec<BatchSubscription>>>>,
+    raw_subscriptions: Arc<Mutex<HashMap<EventId, Vec<RawSubscription>>>>,
+    streams: StreamSenders,
+    next_stream_id: Arc<AtomicU64>,
+    next_subscription_key: Arc<AtomicU64>,
+    /// Bumped on stop and restart, capture loops started for an older generation stop
+    capture_generation: Arc<AtomicU64>,
+    /// Capture loops currently running, one per interface
+    running_captures: Arc<AtomicUsize>,
+    /// Bumped on stop and by each `watch_config`, watchers of an older generation exit
+    watch_generation: Arc<AtomicU64>,
+    node: Arc<OnceLock<Arc<Node>>>,
+    pub last_packet_time: Arc<RwLock<u128>>,
+    /// Latest libpcap statistics of each capture loop interface
+    capture_stats: Arc<RwLock<HashMap<Option<String>, CaptureStats>>>,
+    reassembly: Arc<Mutex<HashMap<StreamKey, ReassemblyState>>>,
+    rate_limits: Arc<Mutex<HashMap<EventId, RateLimit>>>,
+    /// Listeners that panicked on their last packets
+    listener_backoffs: Arc<Mutex<HashMap<(EventId, ListenerId), ListenerBackoff>>>,
+    samplings: Arc<Mutex<HashMap<EventId, Sampling>>>,
+    /// Hash of the last delivered packet of the events with dedup enabled
+    dedups: Arc<Mutex<HashMap<EventId, Option<u64>>>>,
+    metrics: Arc<Mutex<HashMap<EventId, EventMetrics>>>,
+    latencies: Arc<Mutex<HashMap<EventId, LatencyHistogram>>>,
+    parse_error_callbacks: Arc<Mutex<Callbacks<ParseErrorCallback>>>,
+    raw_segment_callbacks: Arc<Mutex<Callbacks<RawSegmentCallback>>>,
+    sequence_gap_callbacks: Arc<Mutex<Callbacks<SequenceGapCallback>>>,
+    event_seen_callbacks: Arc<Mutex<Callbacks<EventSeenCallback>>>,
+    correlator: Arc<Mutex<Correlator>>,
+    exchange_callbacks: Arc<Mutex<Callbacks<ExchangeCallback>>>,
+    /// Parse outcomes of the first messages, per capture loop interface
+    protocol_health: Arc<Mutex<HashMap<Option<String>, ProtocolHealth>>>,
+    protocol_mismatch_callbacks: Arc<Mutex<Callbacks<ProtocolMismatchCallback>>>,
+    /// Game version read from the handshake, see `detected_version`
+    detected_version: Arc<RwLock<Option<String>>>,
+    /// Captured frames are also written here while a recording is running
+    recorder: Arc<Mutex<Option<BackgroundRecorder<Box<dyn PacketSink>>>>>,
+    /// Latest captured frames, for retroactive dumps
+    ring: Arc<Mutex<Option<PacketRing>>>,
+    /// When set, parsed packets are delivered by these threads instead of the capture loop
+    workers: Option<Arc<ListenerWorkers>>,
+    /// Segments dropped because the capture didn't keep their whole frame
+    truncated: Arc<AtomicU64>,
+    /// Times the reassembly skipped junk after a message to find the next one
+    resyncs: Arc<AtomicU64>,
+    /// Reassembly buffers dropped with their bytes, see `ReassemblyState::take_clears`
+    buffer_clears: Arc<AtomicU64>,
+    /// Frames read by every capture loop
+    frames: Arc<AtomicU64>,
+    /// When the first capture of the listener started, for the uptime of `stop_and_report`
+    started_at: Arc<RwLock<Option<Instant>>>,
+    /// Link-layer type of the last capture opened on each interface
+    datalinks: Arc<RwLock<HashMap<Option<String>, pcap::Linktype>>>,
+    /// Node receiving the packets of a connection, in place of the listener node
+    routes: Arc<RwLock<HashMap<ConnectionKey, Arc<Node>>>>,
+    /// Segments dropped because their connection has no route, with `drop_unrouted`
+    unrouted: Arc<AtomicU64>,
+    options: CaptureOptions,
+}
+
+impl PacketListener {
+    pub fn new() -> PacketListener {
+        return PacketListener {
+            subscriptions: Arc::new(Mutex::new(HashMap::new())),
+            disabled_groups: Arc::new(Mutex::new(HashSet::new())),
+            interest_set: Arc::new(RwLock::new(None)),
+            event_filter: Arc::new(RwLock::new(EventFilter::default())),
+            batches: Arc::new(Mutex::new(HashMap::new())),
+            raw_subscriptions: Arc::new(Mutex::new(HashMap::new())),
+            streams: Arc::new(Mutex::new(Vec::new())),
+            next_stream_id: Arc::new(AtomicU64::new(0)),
+            next_subscription_key: Arc::new(AtomicU64::new(0)),
+            capture_generation: Arc::new(AtomicU64::new(0)),
+            running_captures: Arc::new(AtomicUsize::new(0)),
+            watch_generation: Arc::new(AtomicU64::new(0)),
+            node: Arc::new(OnceLock::new()),
+            last_packet_time: Arc::new(RwLock::new(0)),
+            capture_stats: Arc::new(RwLock::new(HashMap::new())),
+            reassembly: Arc::new(Mutex::new(HashMap::new())),
+            rate_limits: Arc::new(Mutex::new(HashMap::new())),
+            listener_backoffs: Arc::new(Mutex::new(HashMap::new())),
+            samplings: Arc::new(Mutex::new(HashMap::new())),
+            dedups: Arc::new(Mutex::new(HashMap::new())),
+            metrics: Arc::new(Mutex::new(HashMap::new())),
+            latencies: Arc::new(Mutex::new(HashMap::new())),
+            parse_error_callbacks: Arc::new(Mutex::new(Callbacks::default())),
+            raw_segment_callbacks: Arc::new(Mutex::new(Callbacks::default())),
+            sequence_gap_callbacks: Arc::new(Mutex::new(Callbacks::default())),
+            event_seen_callbacks: Arc::new(Mutex::new(Callbacks::default())),
+            correlator: Arc::new(Mutex::new(Correlator::default())),
+            exchange_callbacks: Arc::new(Mutex::new(Callbacks::default())),
+            protocol_health: Arc::new(Mutex::new(HashMap::new())),
+            protocol_mismatch_callbacks: Arc::new(Mutex::new(Callbacks::default())),
+            detected_version: Arc::new(RwLock::new(None)),
+            recorder: Arc::new(Mutex::new(None)),
+            ring: Arc::new(Mutex::new(None)),
+            workers: None,
+            truncated: Arc::new(AtomicU64::new(0)),
+            resyncs: Arc::new(AtomicU64::new(0)),
+            buffer_clears: Arc::new(AtomicU64::new(0)),
+            frames: Arc::new(AtomicU64::new(0)),
+            started_at: Arc::new(RwLock::new(None)),
+            datalinks: Arc::new(RwLock::new(HashMap::new())),
+            routes: Arc::new(RwLock::new(HashMap::new())),
+            unrouted: Arc::new(AtomicU64::new(0)),
+            options: CaptureOptions::default(),
         };
+    }
 
-        let mut listener = node.packet_listener.lock().unwrap();
-        let id = "test";
-        listener.subscribe(1338, id, listener_fn);
+    pub fn builder() -> PacketListenerBuilder {
+        PacketListenerBuilder::default()
+    }
 
-        let res = listener.run_with_capture(cap.into());
-        if let Err(err) = res {
-            panic!("Failed to run with capture: {:?}", err);
+    pub fn options(&self) -> &CaptureOptions {
+        &self.options
+    }
+
+    /// The node can only be set once, it is shared with every clone of the listener
+    pub fn set_node(&self, node: Arc<Node>) {
+        if self.node.set(node).is_err() {
+            warn!("Packet listener node is already set");
         }
+    }
 
-        tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
-        info!("Store: {:?}", node.store.lock().unwrap());
+    fn node(&self) -> Option<&Arc<Node>> {
+        self.node.get()
+    }
+
+    /// The listeners of an event are called by decreasing priority, then in subscription order.
+    /// Listeners subscribed without a priority have a priority of 0.
+    pub fn subscribe(&self, event: EventId, listener_id: ListenerId, listener: Listener) {
+        self.subscribe_with_priority(event, listener_id, 0, listener);
+    }
+
+    /// Same as `subscribe`, the listener runs before the ones of a lower `priority`.
+    /// e.g. a listener filling the store for the others subscribes with a positive priority.
+    pub fn subscribe_with_priority(
+        &self,
+        event: EventId,
+        listener_id: ListenerId,
+        priority: i32,
+        listener: Listener,
+    ) {
+        info!(
+            "Subscribing to event: {:?} for {:?} with priority {}",
+            event, listener_id, priority
+        );
+        self.add_subscription(
+            event,
+            Subscription {
+                priority,
+                ..Subscription::new(listener_id, Callback::Node(listener))
+            },
+        );
+    }
+
+    /// Same as `subscribe`, but the listener is only called for the packets accepted by `predicate`
+    pub fn subscribe_filtered(
+        &self,
+        event: EventId,
+        listener_id: ListenerId,
+        predicate: impl Fn(&Packet) -> bool + Send + Sync + 'static,
+        listener: Listener,
+    ) {
+        info!(
+            "Subscribing to event: {:?} for {:?} with a filter",
+            event, listener_id
+        );
+        self.add_subscription(
+            event,
+            Subscription {
+                filter: Some(Arc::new(predicate)),
+                ..Subscription::new(listener_id, Callback::Node(listener))
+            },
+        );
+    }
+
+    /// Same as `subscribe`, for listeners that only need the store and the event definition
+    pub fn subscribe_with_context(
+        &self,
+        event: EventId,
+        listener_id: ListenerId,
+        listener: ContextListener,
+    ) {
+        info!(
+            "Subscribing to event: {:?} for {:?} with a context",
+            event, listener_id
+        );
+        self.add_subscription(
+            event,
+            Subscription::new(listener_id, Callback::Context(listener)),
+        );
+    }
+
+    /// Same as `subscribe`, but the listener is removed once it handled a packet.
+    /// Meant for waiting on the response to a request.
+    pub fn subscribe_once(&self, event: EventId, listener_id: ListenerId, listener: Listener) {
+        info!(
+            "Subscribing once to event: {:?} for {:?}",
+            event, listener_id
+        );
+        self.add_subscription(
+            event,
+            Subscription {
+                once: true,
+                ..Subscription::new(listener_id, Callback::Node(listener))
+            },
+        );
+    }
+
+    /// Same as `subscribe`, the listener can then be toggled or removed with the rest of `group`
+    pub fn subscribe_in_group(
+        &self,
+        group: GroupId,
+        event: EventId,
+        listener_id: ListenerId,
+        listener: Listener,
+    ) {
+        info!(
+            "Subscribing to event: {:?} for {:?} in group {:?}",
+            event, listener_id, group
+        );
+        self.add_subscription(
+            event,
+            Subscription {
+                group: Some(group),
+                ..Subscription::new(listener_id, Callback::Node(listener))
+            },
+        );
+    }
+
+    /// Listeners of a disabled group stay registered but are not called
+    pub fn disable_group(&self, group: GroupId) {
+        info!("Disabling group: {:?}", group);
+        self.disabled_groups.lock().unwrap().insert(group);
+    }
+
+    pub fn enable_group(&self, group: GroupId) {
+        info!("Enabling group: {:?}", group);
+        self.disabled_groups.lock().unwrap().remove(group);
+    }
+
+    /// Unsubscribes every listener of `group`
+    pub fn remove_group(&self, group: GroupId) {
+        info!("Removing group: {:?}", group);
+        for listeners in self.subscriptions.lock().unwrap().values_mut() {
+            listeners.retain(|subscription| subscription.group != Some(group));
+        }
+        self.disabled_groups.lock().unwrap().remove(group);
+    }
+
+    /// The listener is called with up to `max_batch` packets at once, in arrival order.
+    /// A partial batch is delivered once its oldest packet waited for `max_delay`.
+    pub fn subscribe_batch(
+        &self,
+        event: EventId,
+        listener_id: ListenerId,
+        max_batch: usize,
+        max_delay: Duration,
+        listener: BatchListener,
+    ) {
+        info!(
+            "Subscribing to batches of event: {:?} for {:?}",
+            event, listener_id
+        );
+        self.batches
+            .lock()
+            .unwrap()
+            .entry(event)
+            .or_default()
+            .push(BatchSubscription {
+                id: listener_id,
+                listener,
+                max_batch: max_batch.max(1),
+                max_delay,
+                pending: Vec::new(),
+                first_pending: None,
+            });
+    }
+
+    pub fn unsubscribe_batch(&self, event: &EventId, listener_id: ListenerId) {
+        self.batches
+            .lock()
+            .unwrap()
+            .get_mut(event)
+            .map(|listeners| listeners.retain(|subscription| subscription.id != listener_id));
+    }
+
+    /// Queues the packet for the batch listeners of its event, returns false when there are none
+    fn queue_for_batches(&self, packet: &Packet) -> bool {
+        let mut batches = self.batches.lock().unwrap();
+        let Some(listeners) = batches.get_mut(&packet.id).filter(|l| !l.is_empty()) else {
+            return false;
+        };
+        for subscription in listeners {
+            subscription.first_pending.get_or_insert_with(Instant::now);
+            subscription.pending.push(packet.clone());
+        }
+        true
+    }
+
+    /// Delivers the full or expired batches, every pending packet when `force` is set
+    fn flush_batches(&self, node: &Node, force: bool) {
+        let now = Instant::now();
+        let mut ready = Vec::new();
+        for subscription in self.batches.lock().unwrap().values_mut().flatten() {
+            if subscription.pending.is_empty() || !(force || subscription.is_ready(now)) {
+                continue;
+            }
+            subscription.first_pending = None;
+            ready.push((
+                subscription.listener,
+                std::mem::take(&mut subscription.pending),
+            ));
+        }
+        // called without the lock, so listeners can subscribe
+        for (listener, packets) in ready {
+            listener(&packets, node);
+        }
+    }
+
+    /// The listener gets the raw body of the messages of `event` as soon as they are framed.
+    /// They are only decoded when another kind of listener wants them.
+    pub fn subscribe_raw(&self, event: EventId, listener_id: ListenerId, listener: RawListener) {
+        info!(
+            "Subscribing to raw bytes of event: {:?} for {:?}",
+            event, listener_id
+        );
+        self.raw_subscriptions
+            .lock()
+            .unwrap()
+            .entry(event)
+            .or_default()
+            .push(RawSubscription {
+                id: listener_id,
+                listener,
+            });
+    }
+
+    pub fn unsubscribe_raw(&self, event: &EventId, listener_id: ListenerId) {
+        self.raw_subscriptions
+            .lock()
+            .unwrap()
+            .get_mut(event)
+            .map(|listeners| listeners.retain(|subscription| subscription.id != listener_id));
+    }
+
+    /// Calls the raw listeners of `event` without holding the lock, so they can subscribe
+    fn notify_raw(&self, event: &EventId, data: &[u8], node: &Node) {
+        let listeners = self.raw_subscriptions.lock().unwrap().get(event).cloned();
+        for subscription in listeners.into_iter().flatten() {
+            (subscription.listener)(event, data, node);
+        }
+    }
+
+    fn add_subscription(&self, event: EventId, mut subscription: Subscription) {
+        subscription.key = self.next_subscription_key.fetch_add(1, Ordering::Relaxed);
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        insert_by_priority(subscriptions.entry(event).or_default(), subscription);
+    }
+
+    /// Removes this very subscription, not the later ones with the same id. Returns whether it was still there.
+    fn remove_subscription(&self, event: &EventId, key: u64) -> bool {
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        let Some(listeners) = subscriptions.get_mut(event) else {
+            return false;
+        };
+        let count = listeners.len();
+        listeners.retain(|subscription| subscription.key != key);
+        listeners.len() != count
+    }
+
+    pub fn unsubscribe(&self, event: &EventId, listener_id: ListenerId) {
+        info!(
+            "Unsubscribing from event: {:?} for {:?}",
+            event, listener_id
+        );
+        self.subscriptions
+            .lock()
+            .unwrap()
+            .get_mut(event)
+            .map(|listeners| listeners.retain(|subscription| subscription.id != listener_id));
+    }
+
+    /// Only the events of `interest_set` are considered for parsing, `None` considers all of them
+    pub fn set_interest_set(&self, interest_set: Option<HashSet<EventId>>) {
+        info!("Interest set: {:?}", interest_set);
+        *self.interest_set.write().unwrap() = interest_set;
+    }
+
+    /// Sets the interest set to the events currently subscribed to, and to the containers that may carry them.
+    /// It isn't updated by later subscriptions.
+    pub fn derive_interest_set(&self) {
+        let mut interest_set: HashSet<EventId> = self
+            .subscriptions
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, listeners)| !listeners.is_empty())
+            .map(|(event, _)| *event)
+            .collect();
+        interest_set.extend(
+            self.batches
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(_, listeners)| !listeners.is_empty())
+                .map(|(event, _)| *event),
+        );
+        interest_set.extend(
+            self.raw_subscriptions
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(_, listeners)| !listeners.is_empty())
+                .map(|(event, _)| *event),
+        );
+        if let Some(node) = self.node() {
+            let protocol = node.protocol.read().unwrap();
+            interest_set.extend(protocol.ids().copied().filter(|id| {
+                protocol
+                    .get_protocol(id)
+                    .map_or(false, |event| event.is_data_container())
+            }));
+        }
+        self.set_interest_set(Some(interest_set));
+    }
+
+    fn is_interesting(&self, event: &EventId) -> bool {
+        self.interest_set
+            .read()
+            .unwrap()
+            .as_ref()
+            .map_or(true, |interest_set| interest_set.contains(event))
+            && self.event_filter.read().unwrap().allows(event)
+    }
+
+    /// Resolves the event filter of the node config with its protocol, on top of the interest set.
+    /// Called when the capture starts and when `watch_config` sees the filter change.
+    pub fn apply_event_filter(&self) -> Result<(), PacketListenerError> {
+        let Some(node) = self.node() else {
+            return Err(PacketListenerError::NodeNotConfigured);
+        };
+        let config = node.config.config.read().unwrap().events.clone();
+        let filter = config.resolve(&node.protocol.read().unwrap());
+        info!("Event filter: {:?}", filter);
+        *self.event_filter.write().unwrap() = filter;
+        Ok(())
+    }
+
+    /// Parsed packets of `event`, delivered after the rate limit like any other subscription
+    pub fn packet_stream(&self, event: EventId) -> impl Stream<Item = Packet> {
+        info!("Opening packet stream for event: {:?}", event);
+        self.add_stream(Some(event))
+    }
+
+    /// Same as `packet_stream`, for the packets of every event
+    pub fn packet_stream_all(&self) -> impl Stream<Item = Packet> {
+        info!("Opening packet stream for all events");
+        self.add_stream(None)
+    }
+
+    fn add_stream(&self, event: Option<EventId>) -> PacketStream {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let id = self.next_stream_id.fetch_add(1, Ordering::Relaxed);
+        self.streams
+            .lock()
+            .unwrap()
+            .push(StreamSender { id, event, sender });
+        PacketStream {
+            id,
+            streams: self.streams.clone(),
+            receiver: UnboundedReceiverStream::new(receiver),
+        }
+    }
+
+    fn has_batches(&self, event: &EventId) -> bool {
+        self.batches
+            .lock()
+            .unwrap()
+            .get(event)
+            .map_or(false, |listeners| !listeners.is_empty())
+    }
+
+    fn has_streams(&self, event: &EventId) -> bool {
+        self.streams
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|stream| stream.matches(event))
+    }
+
+    /// Sends the packet to the matching streams, returns false when there are none
+    fn send_to_streams(&self, packet: &Packet) -> bool {
+        let mut sent = false;
+        for stream in self.streams.lock().unwrap().iter() {
+            if stream.matches(&packet.id) && stream.sender.send(packet.clone()).is_ok() {
+                sent = true;
+            }
+        }
+        sent
+    }
+
+    /// Listeners of `event` are called at most once per `interval`, other packets are dropped
+    pub fn set_rate_limit(&self, event: EventId, interval: Duration) {
+        info!(
+            "Rate limiting event: {:?} to one every {:?}",
+            event, interval
+        );
+        self.rate_limits
+            .lock()
+            .unwrap()
+            .insert(event, RateLimit::new(interval));
+    }
+
+    pub fn remove_rate_limit(&self, event: &EventId) {
+        self.rate_limits.lock().unwrap().remove(event);
+    }
+
+    /// Only one of every `rate` packets of `event` is parsed and delivered, the others are skipped
+    pub fn set_sampling(&self, event: EventId, rate: u32) {
+        info!("Sampling event: {:?} at 1 in {}", event, rate);
+        self.samplings
+            .lock()
+            .unwrap()
+            .insert(event, Sampling::new(rate));
+    }
+
+    pub fn remove_sampling(&self, event: &EventId) {
+        self.samplings.lock().unwrap().remove(event);
+    }
+
+    /// When enabled, packets of `event` with the same decoded content as the previous one are dropped
+    pub fn set_dedup(&self, event: EventId, enabled: bool) {
+        info!("Dedup of event: {:?} set to {}", event, enabled);
+        let mut dedups = self.dedups.lock().unwrap();
+        if enabled {
+            dedups.entry(event).or_insert(None);
+        } else {
+            dedups.remove(&event);
+        }
+    }
+
+    /// Returns true when the packet is identical to the previous one of its event
+    fn is_duplicate(&self, packet: &Packet) -> bool {
+        let mut dedups = self.dedups.lock().unwrap();
+        let Some(last_hash) = dedups.get_mut(&packet.id) else {
+            return false;
+        };
+        // the fields are in wire order, so the same content always serializes the same way
+        let mut hasher = DefaultHasher::new();
+        serde_json::to_string(&packet.data)
+            .unwrap_or_default()
+            .hash(&mut hasher);
+        let hash = hasher.finish();
+        if *last_hash == Some(hash) {
+            return true;
+        }
+        *last_hash = Some(hash);
+        false
+    }
+
+    /// Returns false when the packet is skipped by the sampling of its event
+    fn sample(&self, event: &EventId) -> bool {
+        let Some(sampling) = self
+            .samplings
+            .lock()
+            .unwrap()
+            .get_mut(event)
+            .map(Sampling::next)
+        else {
+            return true;
+        };
+        self.update_metrics(event, |metrics| match sampling {
+            true => metrics.sampled += 1,
+            false => metrics.skipped += 1,
+        });
+        sampling
+    }
+
+    pub fn event_metrics(&self, event: &EventId) -> EventMetrics {
+        self.metrics
+            .lock()
+            .unwrap()
+            .get(event)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub fn metrics(&self) -> HashMap<EventId, EventMetrics> {
+        self.metrics.lock().unwrap().clone()
+    }
+
+    /// Counters of every event with their class name, sorted by id.
+    /// They are copied at once, so the rows are consistent with each other.
+    pub fn metrics_report(&self) -> Vec<EventMetric> {
+        let metrics = self.metrics();
+        let protocol = self.node().map(|node| node.protocol.read().unwrap());
+        let mut report: Vec<EventMetric> = metrics
+            .into_iter()
+            .map(|(id, metrics)| EventMetric {
+                id,
+                name: protocol
+                    .as_ref()
+                    .and_then(|protocol| protocol.get_protocol(&id))
+                    .map(|event| event.name.clone()),
+                metrics,
+            })
+            .collect();
+        report.sort_by_key(|metric| metric.id);
+        report
+    }
+
+    /// Time spent decoding the packets of `event`, from its metadata to the listeners call
+    pub fn parse_latency(&self, event: &EventId) -> LatencyStats {
+        self.latencies
+            .lock()
+            .unwrap()
+            .get(event)
+            .map(LatencyHistogram::stats)
+            .unwrap_or_default()
+    }
+
+    pub fn notify(&self, event: &Packet) {
+        self._notify(self.listeners(&event.id), event, self.node().unwrap());
+    }
+
+    /// Copy of the subscriptions of `event`, so listeners are called without holding the lock
+    /// and can subscribe or unsubscribe themselves.
+    fn listeners(&self, event: &EventId) -> Option<Vec<Subscription>> {
+        self.subscriptions.lock().unwrap().get(event).cloned()
+    }
+
+    /// Hands the packet to the listener workers when there are some, they never block the capture loop:
+    /// packets arriving while their queue is full are dropped.
+    fn dispatch(&self, listeners: Option<Vec<Subscription>>, packet: Packet, node: &Arc<Node>) {
+        let Some(workers) = &self.workers else {
+            return self._notify(listeners, &packet, node);
+        };
+        let id = packet.id;
+        let job = ListenerJob {
+            listener: self.clone(),
+            listeners,
+            packet,
+            node: node.clone(),
+        };
+        if workers.sender.try_send(job).is_err() {
+            self.update_metrics(&id, |metrics| metrics.queue_dropped += 1);
+        }
+    }
+
+    fn _notify(&self, listeners: Option<Vec<Subscription>>, packet: &Packet, node: &Node) {
+        if listeners.is_none() && !self.has_streams(&packet.id) && !self.has_batches(&packet.id) {
+            return;
+        }
+        if self.is_duplicate(packet) {
+            self.update_metrics(&packet.id, |metrics| metrics.deduplicated += 1);
+            return;
+        }
+        if let Some(rate_limit) = self.rate_limits.lock().unwrap().get_mut(&packet.id) {
+            if !rate_limit.allow(Instant::now()) {
+                self.update_metrics(&packet.id, |metrics| metrics.rate_limited += 1);
+                return;
+            }
+        }
+
+        let mut delivered = false;
+        let mut filtered = 0;
+        let mut unsubscribed = Vec::new();
+        for subscription in listeners.into_iter().flatten() {
+            // checked for each listener, a listener can disable the group of the next ones
+            if subscription.group.map_or(false, |group| {
+                self.disabled_groups.lock().unwrap().contains(group)
+            }) {
+                continue;
+            }
+            if !subscription.matches(packet) {
+                filtered += 1;
+                continue;
+            }
+            if self.is_backed_off(&packet.id, subscription.id) {
+                continue;
+            }
+            // taken out before the call, a packet delivered meanwhile can't call it again
+            if subscription.once && !self.remove_subscription(&packet.id, subscription.key) {
+                continue;
+            }
+            // a panicking listener doesn't take the capture down with it
+            let action = match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                subscription.listener.call(packet, node)
+            })) {
+                Ok(action) => {
+                    self.listener_backoffs
+                        .lock()
+                        .unwrap()
+                        .remove(&(packet.id, subscription.id));
+                    action
+                }
+                Err(_) => {
+                    self.record_listener_failure(&packet.id, subscription.id);
+                    ListenerAction::Continue
+                }
+            };
+            if action == ListenerAction::Unsubscribe {
+                unsubscribed.push(subscription.key);
+            }
+            delivered = true;
+        }
+        for key in unsubscribed {
+            self.remove_subscription(&packet.id, key);
+        }
+        if self.send_to_streams(packet) {
+            delivered = true;
+        }
+        if self.queue_for_batches(packet) {
+            delivered = true;
+        }
+        self.update_metrics(&packet.id, |metrics| {
+            metrics.seen += 1;
+            metrics.filtered += filtered;
+            if delivered {
+                metrics.delivered += 1;
+            }
+        });
+    }
+
+    /// Listeners panicking several times in a row are skipped for a backoff doubling on each failure
+    fn record_listener_failure(&self, event: &EventId, listener_id: ListenerId) {
+        let mut backoffs = self.listener_backoffs.lock().unwrap();
+        let backoff = backoffs.entry((*event, listener_id)).or_default();
+        if let Some(duration) = backoff.fail(Instant::now()) {
+            warn!(
+                "Temporarily disabling listener {:?} of event {:?} for {:?} after {} failures",
+                listener_id, event, duration, backoff.failures
+            );
+        }
+    }
+
+    /// True while the listener is skipped after panicking repeatedly, until it handles a packet again
+    pub fn is_backed_off(&self, event: &EventId, listener_id: ListenerId) -> bool {
+        self.listener_backoffs
+            .lock()
+            .unwrap()
+            .get(&(*event, listener_id))
+            .map_or(false, |backoff| backoff.is_skipped(Instant::now()))
+    }
+
+    /// Registers a callback fired for every buffer that fails to parse, incomplete buffers excluded
+    pub fn on_parse_error(&self, callback: impl Fn(&ParseFailure) + Send + Sync + 'static) {
+        self.parse_error_callbacks
+            .lock()
+            .unwrap()
+            .0
+            .push(Arc::new(callback));
+    }
+
+    fn report_parse_error(&self, failure: ParseFailure) {
+        let callbacks = self.parse_error_callbacks.lock().unwrap().snapshot();
+        for callback in callbacks {
+            callback(&failure);
+        }
+    }
+
+    /// Registers a callback fired for every TCP segment as captured, before it is reassembled
+    pub fn on_raw_segment(&self, callback: impl Fn(&PacketHeader) + Send + Sync + 'static) {
+        self.raw_segment_callbacks
+            .lock()
+            .unwrap()
+            .0
+            .push(Arc::new(callback));
+    }
+
+    /// Registers a callback fired when a segment arrives ahead of the expected sequence number.
+    /// A gap is also reported when the missing segments were only reordered and arrive later.
+    pub fn on_sequence_gap(&self, callback: impl Fn(&SequenceGap) + Send + Sync + 'static) {
+        self.sequence_gap_callbacks
+            .lock()
+            .unwrap()
+            .0
+            .push(Arc::new(callback));
+    }
+
+    /// Pairs the requests and responses of `rules`, replacing the previous rules and their pending requests.
+    /// The events of the rules are decoded even without listeners, see `on_exchange`.
+    pub fn correlate(&self, rules: Vec<CorrelationRule>) {
+        *self.correlator.lock().unwrap() = Correlator::new(rules);
+    }
+
+    /// Registers a callback fired with every exchange matched by the rules of `correlate`
+    pub fn on_exchange(&self, callback: impl Fn(&CorrelatedExchange) + Send + Sync + 'static) {
+        self.exchange_callbacks
+            .lock()
+            .unwrap()
+            .0
+            .push(Arc::new(callback));
+    }
+
+    fn is_correlated(&self, event: &EventId) -> bool {
+        self.correlator.lock().unwrap().is_correlated(event)
+    }
+
+    fn record_exchanges(&self, packet: &Packet) {
+        let timestamp = packet.timestamp.unwrap_or_else(SystemTime::now);
+        let exchanges = self.correlator.lock().unwrap().observe(packet, timestamp);
+        let callbacks = self.exchange_callbacks.lock().unwrap().snapshot();
+        for exchange in exchanges {
+            debug!(
+                "Exchange {} -> {} in {:?}",
+                exchange.request.id, exchange.response.id, exchange.latency
+            );
+            for callback in callbacks.iter() {
+                callback(&exchange);
+            }
+        }
+    }
+
+    /// Registers a callback fired for every message framed on a connection, from its header only.
+    /// Events without listeners are still not decoded, so this stays cheap on busy maps.
+    pub fn on_event_seen(
+        &self,
+        callback: impl Fn(EventId, SystemTime, ConnectionKey) + Send + Sync + 'static,
+    ) {
+        self.event_seen_callbacks
+            .lock()
+            .unwrap()
+            .0
+            .push(Arc::new(callback));
+    }
+
+    /// Registers a callback fired once per capture when most of its first messages fail to parse
+    pub fn on_protocol_mismatch(
+        &self,
+        callback: impl Fn(&ProtocolMismatch) + Send + Sync + 'static,
+    ) {
+        self.protocol_mismatch_callbacks
+            .lock()
+            .unwrap()
+            .0
+            .push(Arc::new(callback));
+    }
+
+    fn record_parse_outcome(&self, interface: Option<&str>, parsed: bool) {
+        let mismatch = self
+            .protocol_health
+            .lock()
+            .unwrap()
+            .entry(interface.map(str::to_owned))
+            .or_default()
+            .record(parsed);
+        let Some(mismatch) = mismatch else {
+            return;
+        };
+        warn!(
+            "{} of the first {} messages failed to parse, the protocol file is probably for another game version, try updating it",
+            mismatch.failures,
+            mismatch.failures + mismatch.successes
+        );
+        let callbacks = self.protocol_mismatch_callbacks.lock().unwrap().snapshot();
+        for callback in callbacks {
+            callback(&mismatch);
+        }
+    }
+
+    fn update_metrics(&self, event: &EventId, update_fn: impl FnOnce(&mut EventMetrics)) {
+        update_fn(self.metrics.lock().unwrap().entry(*event).or_default());
+    }
+
+    pub fn has_subscriptions_for(&self, event: &EventId, listener_id: ListenerId) -> bool {
+        let subscriptions = self.subscriptions.lock().unwrap();
+        subscriptions.get(event).map_or(false, |listeners| {
+            listeners
+                .iter()
+                .any(|subscription| subscription.id == listener_id)
+        })
+    }
+
+    /// Ids of the listeners subscribed to `event`, in their order of delivery
+    pub fn listeners_for(&self, event: &EventId) -> Vec<ListenerId> {
+        self.subscriptions
+            .lock()
+            .unwrap()
+            .get(event)
+            .map_or_else(Vec::new, |listeners| {
+                listeners
+                    .iter()
+                    .map(|subscription| subscription.id)
+                    .collect()
+            })
+    }
+
+    pub fn has_subscriptions(&self, event: &EventId) -> bool {
+        return PacketListener::_has_subscriptions(&self.subscriptions.lock().unwrap(), event);
+    }
+
+    /// Number of subscriptions across all events
+    pub fn subscription_count(&self) -> usize {
+        self.subscriptions
+            .lock()
+            .unwrap()
+            .values()
+            .map(|listeners| listeners.len())
+            .sum()
+    }
+
+    pub fn event_subscription_count(&self, event: &EventId) -> usize {
+        self.subscriptions
+            .lock()
+            .unwrap()
+            .get(event)
+            .map_or(0, |listeners| listeners.len())
+    }
+
+    /// Lists the subscriptions, sorted by event then in their order of delivery
+    pub fn export_subscriptions(&self) -> Vec<SubscriptionInfo> {
+        let protocol = self.node().map(|node| node.protocol.read().unwrap());
+        let subscriptions = self.subscriptions.lock().unwrap();
+        let mut events: Vec<&EventId> = subscriptions.keys().collect();
+        events.sort();
+        events
+            .into_iter()
+            .flat_map(|event| {
+                let class = protocol
+                    .as_ref()
+                    .and_then(|protocol| protocol.get_protocol(event))
+                    .map(|schema| schema.name.clone());
+                subscriptions[event]
+                    .iter()
+                    .map(move |subscription| SubscriptionInfo {
+                        event: *event,
+                        class: class.clone(),
+                        listener_id: subscription.id,
+                        group: subscription.group,
+                    })
+            })
+            .collect()
+    }
+
+    /// Moves the `exported` subscriptions to the id their class has in `protocol`, e.g. after a version bump.
+    /// Returns the subscriptions left on their old id, their class is unknown or no longer in the protocol.
+    pub fn resubscribe_by_class(
+        &self,
+        exported: &[SubscriptionInfo],
+        protocol: &ProtocolManager,
+    ) -> Vec<SubscriptionInfo> {
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        let mut unresolved = Vec::new();
+        // taken out first, so events swapping their ids don't pick up each other's listeners
+        let mut moved = Vec::new();
+        for info in exported {
+            let Some(id) = info
+                .class
+                .as_ref()
+                .and_then(|class| protocol.get_protocol_id_by_class(class))
+            else {
+                unresolved.push(info.clone());
+                continue;
+            };
+            let Some(listeners) = subscriptions.get_mut(&info.event) else {
+                continue;
+            };
+            if let Some(index) = listeners
+                .iter()
+                .position(|subscription| subscription.id == info.listener_id)
+            {
+                moved.push((*id, listeners.remove(index)));
+            }
+        }
+        for (id, subscription) in moved {
+            info!("Resubscribing {:?} to event {:?}", subscription.id, id);
+            insert_by_priority(subscriptions.entry(id).or_default(), subscription);
+        }
+        unresolved
+    }
+
+    /// Removes every subscription, of all events
+    pub fn clear_all(&self) {
+        info!("Removing all subscriptions");
+        self.subscriptions.lock().unwrap().clear();
+    }
+
+    fn _has_subscriptions(
+        subscriptions: &HashMap<EventId, Vec<Subscription>>,
+        event: &EventId,
+    ) -> bool {
+        return subscriptions
+            .get(event)
+            .map_or(false, |listeners| !listeners.is_empty());
+    }
+
+    /// Connections that received a segment in the last `CONNECTION_IDLE_TIMEOUT`, the idle ones are evicted
+    pub fn active_connections(&self) -> Vec<ConnectionInfo> {
+        self.evict_idle_streams(SystemTime::now());
+        self.reassembly
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|(key, state)| {
+                Some(ConnectionInfo {
+                    interface: key.interface.clone(),
+                    connection: state.connection()?,
+                    buffered_bytes: state.buffered().len(),
+                    last_seen: state.last_seen()?,
+                    packet_count: state.packet_count(),
+                })
+            })
+            .collect()
+    }
+
+    /// Drops the streams without a segment for `CONNECTION_IDLE_TIMEOUT` at `now`,
+    /// their connection ended without a FIN or RST being captured
+    fn evict_idle_streams(&self, now: SystemTime) {
+        self.reassembly.lock().unwrap().retain(|key, state| {
+            let idle = state
+                .last_seen()
+                .and_then(|last_seen| now.duration_since(last_seen).ok())
+                .map_or(false, |idle| idle > CONNECTION_IDLE_TIMEOUT);
+            if idle {
+                debug!("Evicting idle stream {:?}", key);
+            }
+            !idle
+        });
+    }
+
+    /// Latest statistics reported by libpcap, summed over the captured interfaces
+    pub fn capture_stats(&self) -> CaptureStats {
+        self.capture_stats
+            .read()
+            .unwrap()
+            .values()
+            .fold(CaptureStats::default(), |total, stats| total + *stats)
+    }
+
+    /// Latest statistics reported by libpcap for the capture on `interface`
+    pub fn interface_capture_stats(&self, interface: Option<&str>) -> CaptureStats {
+        self.capture_stats
+            .read()
+            .unwrap()
+            .get(&interface.map(str::to_owned))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    fn update_capture_stats(&self, interface: Option<&str>, cap: &mut dyn CaptureSource) {
+        // Offline captures (files) do not support statistics
+        if let Ok(stats) = cap.stats() {
+            let stats = CaptureStats::from(stats);
+            let mut capture_stats = self.capture_stats.write().unwrap();
+            let capture_stats = capture_stats
+                .entry(interface.map(str::to_owned))
+                .or_default();
+            let dropped = stats.dropped_since(capture_stats);
+            if dropped > 0 {
+                warn!(
+                    "{} packets dropped by libpcap ({} since the start of the capture)",
+                    dropped,
+                    stats.total_dropped()
+                );
+            }
+            *capture_stats = stats;
+        }
+    }
+
+    fn capture_filter(&self, config: &NetworkConfig) -> String {
+        match &self.options.filter {
+            Some(filter) => format!("({}) and ({})", config.filter(), filter),
+            None => config.filter(),
+        }
+    }
+
+    fn open_capture(&self, config: &NetworkConfig) -> Result<Capture<Active>, PacketListenerError> {
+        let interface = config.interface.as_str();
+        info!(
+            "Starting sniffer on interface: {} and ports: {:?}",
+            interface, config.ports
+        );
+
+        let mut cap = Capture::from_device(interface)
+            .and_then(|cap| config.configure(cap).open())
+            .map_err(|err| {
+                warn!("Failed to open device {}: {:?}", interface, err);
+                open_error(&err)
+            })?;
+        cap.direction(self.options.direction).map_err(|err| {
+            warn!(
+                "Failed to set the capture direction on {}: {:?}",
+                interface, err
+            );
+            PacketListenerError::CaptureDirection(err)
+        })?;
+
+        let is_loopback = Device::list()
+            .ok()
+            .and_then(|devices| devices.into_iter().find(|device| device.name == interface))
+            .map_or(false, |device| device.flags.is_loopback());
+        if is_loopback {
+            info!(
+                "{} is a loopback device, datalink: {:?}",
+                interface,
+                cap.get_datalink()
+            );
+        }
+
+        self.apply_filter(&mut cap, config)?;
+
+        Ok(cap)
+    }
+
+    fn apply_filter<T: Activated + ?Sized>(
+        &self,
+        cap: &mut Capture<T>,
+        config: &NetworkConfig,
+    ) -> Result<(), PacketListenerError> {
+        let filter = self.capture_filter(config);
+        cap.filter(&filter, false).map_err(|err| {
+            warn!("Failed to set the capture filter {:?}: {:?}", filter, err);
+            PacketListenerError::InvalidFilter(err)
+        })
+    }
+
+    /// Tries to open the capture again after the device went away (unplugged, sleep/wake, ...).
+    /// Sleeps between the attempts, the capture loops run on blocking threads for it.
+    fn reopen_capture(&self, config: &NetworkConfig) -> Option<Capture<Active>> {
+        let policy = &self.options.error_policy;
+        let mut backoff = policy.reopen_backoff;
+        for attempt in 1..=policy.max_reopen_attempts {
+            warn!(
+                "Reopening capture on {} (attempt {}/{})",
+                config.interface, attempt, policy.max_reopen_attempts
+            );
+            std::thread::sleep(backoff);
+            if let Ok(cap) = self.open_capture(config) {
+                info!("Capture reopened on {}", config.interface);
+                return Some(cap);
+            }
+            backoff *= 2;
+        }
+        error!(
+            "Failed to reopen capture on {} after {} attempts",
+            config.interface, policy.max_reopen_attempts
+        );
+        None
+    }
+
+    pub fn run(&self) -> Result<(), PacketListenerError> {
+        if self.node().is_none() {
+            return Err(PacketListenerError::NodeNotConfigured);
+        }
+
+        let config = self.node().unwrap().config.config.read().unwrap();
+        let network = config.network.clone();
+        drop(config);
+        self.apply_event_filter()?;
+
+        network.validate()?;
+        // hostnames are resolved once, BPF would otherwise look them up when compiling the filter
+        let network = match network.resolve_host()? {
+            Some(ip) => NetworkConfig {
+                host: Some(ip.to_string()),
+                ..network
+            },
+            None => network,
+        };
+        // one capture per interface, each reopens its own device
+        let networks: Vec<NetworkConfig> = network
+            .interfaces()
+            .into_iter()
+            .map(|interface| NetworkConfig {
+                interface: interface.to_string(),
+                additional_interfaces: Vec::new(),
+                ..network.clone()
+            })
+            .collect();
+        let captures = networks
+            .iter()
+            .map(|network| self.open_capture(network))
+            .collect::<Result<Vec<_>, _>>()?;
+        for (cap, network) in captures.into_iter().zip(networks) {
+            self.spawn_capture(Box::new(cap), Some(network))?;
+        }
+        Ok(())
+    }
+
+    /// Stops the running capture and starts a new one from the current config.
+    /// Subscriptions and the rest of the listener state are kept.
+    pub fn restart(&self) -> Result<(), PacketListenerError> {
+        info!("Restarting packet capture");
+        self.stop_capture();
+        self.run()
+    }
+
+    /// `restart` once the config of `node` is checked, an invalid one keeps the running capture
+    fn restart_with(&self, node: &Node) -> Result<(), PacketListenerError> {
+        node.config.config.read().unwrap().network.validate()?;
+        self.restart()
+    }
+
+    /// Stops the running capture, it exits after its current read at most `read_timeout_ms` later.
+    /// The `watch_config` watcher stops with it.
+    pub fn stop(&self) {
+        info!("Stopping packet capture");
+        self.watch_generation.fetch_add(1, Ordering::SeqCst);
+        self.stop_capture();
+    }
+
+    /// `stop` keeping the config watcher, for `restart`
+    fn stop_capture(&self) {
+        self.capture_generation.fetch_add(1, Ordering::SeqCst);
+        self.flush();
+    }
+
+    /// Delivers the messages still waiting in the reassembly buffers, called on stop.
+    /// The segments they wait for are given up, see `ReassemblyState::flush`.
+    pub fn flush(&self) {
+        self.flush_streams(|_| true);
+    }
+
+    /// Same as `flush` for the streams captured on `interface`, once its capture loop exits
+    fn flush_interface(&self, interface: Option<&str>) {
+        self.flush_streams(|key| key.interface.as_deref() == interface);
+    }
+
+    fn flush_streams(&self, flushed: impl Fn(&StreamKey) -> bool) {
+        if self.node().is_none() {
+            return;
+        }
+        let buffered: Vec<_> = self
+            .reassembly
+            .lock()
+            .unwrap()
+            .iter_mut()
+            .filter(|(key, _)| flushed(key))
+            .filter_map(|(key, state)| {
+                let frames = state.flush();
+                self.collect_reassembly_counters(state);
+                Some((key.interface.clone(), state.connection()?, frames))
+            })
+            .collect();
+        for (interface, connection, frames) in buffered {
+            if let Some(node) = self.node_for(&connection) {
+                // the buffered messages have no segment left to take a capture time from
+                self.process_frames(
+                    interface.as_deref(),
+                    connection,
+                    None,
+                    SystemTime::now(),
+                    frames,
+                    &node,
+                );
+            }
+        }
+    }
+
+    /// Hands the packets of `connection`, in both directions, to `node` instead of the listener node.
+    /// Meant for several game clients sniffed at once, each with its own store.
+    pub fn route_connection(&self, connection: ConnectionKey, node: Arc<Node>) {
+        info!("Routing {:?} to its own node", connection);
+        self.routes.write().unwrap().insert(connection, node);
+    }
+
+    pub fn unroute_connection(&self, connection: &ConnectionKey) {
+        let mut routes = self.routes.write().unwrap();
+        routes.remove(connection);
+        routes.remove(&connection.reversed());
+    }
+
+    /// Segments dropped so far because their connection had no route, see `drop_unrouted`
+    pub fn unrouted_dropped(&self) -> u64 {
+        self.unrouted.load(Ordering::Relaxed)
+    }
+
+    /// Node of the route of `connection`, the listener node when it has none
+    fn node_for(&self, connection: &ConnectionKey) -> Option<Arc<Node>> {
+        let routes = self.routes.read().unwrap();
+        if let Some(node) = routes
+            .get(connection)
+            .or_else(|| routes.get(&connection.reversed()))
+        {
+            return Some(node.clone());
+        }
+        if self.options.drop_unrouted {
+            self.unrouted.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        self.node().cloned()
+    }
+
+    /// Version sent by the server in its `ProtocolRequired` handshake, `None` until one was captured
+    pub fn detected_version(&self) -> Option<String> {
+        self.detected_version.read().unwrap().clone()
+    }
+
+    /// Reads the version of the handshake, whether or not it is subscribed to
+    fn detect_version(&self, metadata: &PacketMetadata, node: &Node) {
+        let protocol = node.protocol.read().unwrap();
+        if protocol
+            .get_protocol(&metadata.id)
+            .map_or(true, |event| event.name != HANDSHAKE_EVENT)
+        {
+            return;
+        }
+        let Ok(packet) = PacketParser::from_metadata(metadata).parse(&protocol) else {
+            return;
+        };
+        drop(protocol);
+        let Some(version) = packet
+            .get_string("version")
+            .and_then(|version| VERSION_REGEX.captures(version))
+            .map(|captures| captures[1].to_string())
+        else {
+            warn!(
+                "Handshake without a valid version: {:?}",
+                packet.get("version")
+            );
+            return;
+        };
+
+        let loaded = node
+            .config
+            .config
+            .read()
+            .unwrap()
+            .game_version
+            .version
+            .clone();
+        if !loaded.is_empty() && loaded != version {
+            warn!(
+                "Captured game version {} differs from the loaded protocol {}",
+                version, loaded
+            );
+        }
+        info!("Detected game version {}", version);
+        *self.detected_version.write().unwrap() = Some(version);
+    }
+
+    /// Segments dropped so far because the snaplen cut them, their messages would be corrupted
+    pub fn truncated_segments(&self) -> u64 {
+        self.truncated.load(Ordering::Relaxed)
+    }
+
+    /// Times junk bytes were skipped after a message, see `ReassemblyState::with_resync`
+    pub fn resyncs(&self) -> u64 {
+        self.resyncs.load(Ordering::Relaxed)
+    }
+
+    /// Reassembly buffers dropped so far because they went stale, grew too large or couldn't be framed
+    pub fn buffer_clears(&self) -> u64 {
+        self.buffer_clears.load(Ordering::Relaxed)
+    }
+
+    /// Frames read so far by the capture loops, including the ones that are not game traffic
+    pub fn frames_read(&self) -> u64 {
+        self.frames.load(Ordering::Relaxed)
+    }
+
+    fn collect_reassembly_counters(&self, state: &mut ReassemblyState) {
+        self.resyncs
+            .fetch_add(state.take_resyncs(), Ordering::Relaxed);
+        self.buffer_clears
+            .fetch_add(state.take_clears(), Ordering::Relaxed);
+    }
+
+    /// Stops the capture like `stop`, then gathers the counters of the session in one report
+    pub fn stop_and_report(&self) -> CaptureSummary {
+        self.stop();
+        let events = self.metrics_report();
+        let summary = CaptureSummary {
+            frames: self.frames_read(),
+            parse_failures: events
+                .iter()
+                .map(|event| event.metrics.parse_failures)
+                .sum(),
+            queue_dropped: events.iter().map(|event| event.metrics.queue_dropped).sum(),
+            events,
+            capture: self.capture_stats(),
+            recording_dropped: self.recording_dropped(),
+            truncated: self.truncated_segments(),
+            unrouted: self.unrouted_dropped(),
+            resyncs: self.resyncs(),
+            buffer_clears: self.buffer_clears(),
+            uptime: self
+                .started_at
+                .read()
+                .unwrap()
+                .map(|started_at| started_at.elapsed())
+                .unwrap_or_default(),
+        };
+        info!("Capture summary: {:?}", summary);
+        summary
+    }
+
+    /// Link-layer type of the last capture started on `interface`, `None` before the first one.
+    /// Only some of them are parsed, see `LinkLayer::from_linktype`.
+    pub fn datalink(&self, interface: Option<&str>) -> Option<pcap::Linktype> {
+        self.datalinks
+            .read()
+            .unwrap()
+            .get(&interface.map(str::to_owned))
+            .copied()
+    }
+
+    /// libpcap name of `datalink`, like `EN10MB` for Ethernet or `LINUX_SLL` for the Linux `any` device
+    pub fn datalink_name(&self, interface: Option<&str>) -> Option<String> {
+        let datalink = self.datalink(interface)?;
+        Some(
+            datalink
+                .get_name()
+                .unwrap_or_else(|_| format!("DLT {}", datalink.0)),
+        )
+    }
+
+    /// Whether a capture loop is reading packets, it is cleared once the last one exits
+    pub fn is_running(&self) -> bool {
+        self.running_captures.load(Ordering::SeqCst) > 0
+    }
+
+    /// Writes every captured frame to numbered pcap files in `dir`, rotated following `policy`.
+    /// A running recording is closed and replaced.
+    pub fn record(
+        &self,
+        dir: impl AsRef<std::path::Path>,
+        policy: RotationPolicy,
+    ) -> Result<(), PacketListenerError> {
+        info!("Recording capture to {:?}", dir.as_ref());
+        let recorder: Box<dyn PacketSink> = Box::new(CaptureRecorder::new(dir, policy)?);
+        let recorder = BackgroundRecorder::spawn(recorder, RECORDING_QUEUE_SIZE);
+        let previous = self.recorder.lock().unwrap().replace(recorder);
+        // joined without the lock, the capture loop keeps recording meanwhile
+        drop(previous);
+        Ok(())
+    }
+
+    /// Closes the running recording once its pending packets are written, returns the files it wrote
+    pub fn stop_recording(&self) -> Vec<std::path::PathBuf> {
+        let Some(recorder) = self.recorder.lock().unwrap().take() else {
+            return Vec::new();
+        };
+        if recorder.dropped() > 0 {
+            warn!(
+                "{} packets were dropped from the recording",
+                recorder.dropped()
+            );
+        }
+        // a panicked writer is logged by `finish`
+        recorder
+            .finish()
+            .map(|sink| sink.files().to_vec())
+            .unwrap_or_default()
+    }
+
+    /// Packets left out of the running recording because the disk didn't keep up
+    pub fn recording_dropped(&self) -> u64 {
+        self.recorder
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map_or(0, |recorder| recorder.dropped())
+    }
+
+    /// Keeps the latest captured frames in memory, at most `max_packets` of them or `max_bytes` in total.
+    /// A running ring is replaced by an empty one.
+    pub fn keep_ring(&self, max_packets: Option<usize>, max_bytes: Option<usize>) {
+        *self.ring.lock().unwrap() = Some(PacketRing::new(max_packets, max_bytes));
+    }
+
+    pub fn stop_ring(&self) {
+        *self.ring.lock().unwrap() = None;
+    }
+
+    /// Saves the frames currently in the ring to a pcap file, returns how many were written
+    pub fn dump_ring_to(
+        &self,
+        path: impl AsRef<std::path::Path>,
+    ) -> Result<usize, PacketListenerError> {
+        match self.ring.lock().unwrap().as_ref() {
+            Some(ring) => Ok(ring.dump_to(path)?),
+            None => Err(PacketListenerError::InvalidOption("no packet ring is kept")),
+        }
+    }
+
+    fn record_frame(&self, cap: &dyn CaptureSource, timestamp: SystemTime, data: &[u8]) {
+        if let Some(ring) = self.ring.lock().unwrap().as_mut() {
+            ring.push(cap.datalink(), timestamp, data);
+        }
+        if let Some(recorder) = self.recorder.lock().unwrap().as_ref() {
+            recorder.write(cap.datalink(), timestamp, data);
+        }
+    }
+
+    /// Restarts the capture when the configured interfaces change, once the new value settled
+    pub fn watch_config(&self) -> Result<(), PacketListenerError> {
+        let Some(node) = self.node().cloned() else {
+            return Err(PacketListenerError::NodeNotConfigured);
+        };
+        let interfaces = |node: &Node| {
+            node.config
+                .config
+                .read()
+                .unwrap()
+                .network
+                .interfaces()
+                .join(", ")
+        };
+        let events = |node: &Node| node.config.config.read().unwrap().events.clone();
+        let interface = interfaces(&node);
+        let mut watcher = InterfaceWatcher::new(interface);
+        let mut filter = events(&node);
+        let listener = self.clone();
+        let generation = self.watch_generation.fetch_add(1, Ordering::SeqCst) + 1;
+        tauri::async_runtime::spawn(async move {
+            loop {
+                tokio::time::sleep(CONFIG_POLL_INTERVAL).await;
+                if listener.watch_generation.load(Ordering::SeqCst) != generation {
+                    debug!("Config watcher stopped");
+                    break;
+                }
+                let new_filter: EventFilterConfig = events(&node);
+                if new_filter != filter {
+                    filter = new_filter;
+                    if let Err(err) = listener.apply_event_filter() {
+                        error!("Failed to apply the event filter: {:?}", err);
+                    }
+                }
+                let interface = interfaces(&node);
+                if let Some(interface) = watcher.poll(&interface, Instant::now()) {
+                    info!("Network interface changed to {}", interface);
+                    if let Err(err) = listener.restart_with(&node) {
+                        error!("Failed to restart capture on {}: {:?}", interface, err);
+                    }
+                }
+            }
+        });
+        Ok(())
+    }
+
+    pub fn run_with_capture(&self, cap: Capture<dyn Activated>) -> Result<(), PacketListenerError> {
+        self.run_with_source(cap)
+    }
+
+    /// Same as `run_with_capture` for any source of packets, e.g. crafted frames in tests
+    pub fn run_with_source(
+        &self,
+        source: impl CaptureSource + 'static,
+    ) -> Result<(), PacketListenerError> {
+        self.spawn_capture(Box::new(source), None)
+    }
+
+    /// Replays a pcap stream read from `reader` in the background, for captures piped from another tool.
+    /// The capture ends with the stream, the messages still buffered are delivered.
+    pub fn run_with_reader(
+        &self,
+        reader: impl Read + Send + 'static,
+    ) -> Result<(), PacketListenerError> {
+        self.run_with_source(PcapStream::new(reader)?)
+    }
+
+    /// `run_with_reader` on the standard input, e.g. `tcpdump -w - port 5555 | dtools`
+    pub fn run_with_stdin(&self) -> Result<(), PacketListenerError> {
+        self.run_with_reader(std::io::stdin())
+    }
+
+    /// Same as `run_with_capture` but on the current thread, without the tauri runtime.
+    /// Returns once the capture has no more packets or `stop` is called.
+    pub fn run_blocking(&self, cap: Capture<dyn Activated>) -> Result<(), PacketListenerError> {
+        if self.node().is_none() {
+            return Err(PacketListenerError::NodeNotConfigured);
+        }
+        self.capture_loop(Box::new(cap), None);
+        Ok(())
+    }
+
+    /// Captures on the current thread for `duration` then returns, once the buffered messages are delivered.
+    /// `stop` still ends it early.
+    pub fn run_for(
+        &self,
+        cap: impl CaptureSource + 'static,
+        duration: Duration,
+    ) -> Result<SessionStats, PacketListenerError> {
+        let generation = self.capture_generation.load(Ordering::SeqCst);
+        self.run_for_from(generation, Box::new(cap), duration)
+    }
+
+    /// `run_for` of a capture started at `generation`, see `capture_loop_from`
+    fn run_for_from(
+        &self,
+        generation: u64,
+        cap: Box<dyn CaptureSource>,
+        duration: Duration,
+    ) -> Result<SessionStats, PacketListenerError> {
+        if self.node().is_none() {
+            return Err(PacketListenerError::NodeNotConfigured);
+        }
+        let started_at = Instant::now();
+        let frames =
+            self.capture_loop_until(None, cap, None, Some(started_at + duration), generation);
+        Ok(SessionStats {
+            frames,
+            elapsed: started_at.elapsed(),
+            capture: self.capture_stats(),
+        })
+    }
+
+    /// Same as `run_for` without blocking the async runtime
+    pub async fn run_for_async(
+        &self,
+        cap: impl CaptureSource + 'static,
+        duration: Duration,
+    ) -> Result<SessionStats, PacketListenerError> {
+        let listener = self.clone();
+        let generation = self.capture_generation.load(Ordering::SeqCst);
+        tauri::async_runtime::spawn_blocking(move || {
+            listener.run_for_from(generation, Box::new(cap), duration)
+        })
+        .await
+        .expect("the capture loop panicked")
+    }
+
+    /// Replays the capture file `iterations` times in the background, forever when `None`, until `stop` is called.
+    /// Meant for soak tests of the parser and the listeners.
+    pub fn run_with_capture_looping(
+        &self,
+        path: impl AsRef<std::path::Path>,
+        iterations: Option<u32>,
+    ) -> Result<(), PacketListenerError> {
+        if self.node().is_none() {
+            return Err(PacketListenerError::NodeNotConfigured);
+        }
+        // fails early on a missing or unreadable file
+        open_file(path.as_ref())?;
+
+        let listener = self.clone();
+        let path = path.as_ref().to_path_buf();
+        let generation = self.capture_generation.load(Ordering::SeqCst);
+        // with no iterations limit the replay only ends on stop
+        tauri::async_runtime::spawn_blocking(move || {
+            if let Err(err) = listener.replay_looping(&path, iterations, generation) {
+                error!("Failed to replay {:?}: {:?}", path, err);
+            }
+        });
+        Ok(())
+    }
+
+    fn replay_looping(
+        &self,
+        path: &std::path::Path,
+        iterations: Option<u32>,
+        generation: u64,
+    ) -> Result<(), PacketListenerError> {
+        let mut iteration = 0;
+        while iterations.map_or(true, |iterations| iteration < iterations)
+            && self.capture_generation.load(Ordering::SeqCst) == generation
+        {
+            // messages must not straddle the end of the file and its next replay,
+            // the streams of the captures running on an interface are kept
+            self.reassembly
+                .lock()
+                .unwrap()
+                .retain(|key, _| key.interface.is_some());
+            self.capture_loop_from(generation, Box::new(open_file(path)?), None);
+            iteration += 1;
+        }
+        Ok(())
+    }
+
+    /// Replays the capture files of `dir` in the background as one session, see `capture_files`.
+    /// The reassembly is kept from a file to the next, a rotation can split a message.
+    pub fn run_with_capture_dir(
+        &self,
+        dir: impl AsRef<std::path::Path>,
+    ) -> Result<(), PacketListenerError> {
+        if self.node().is_none() {
+            return Err(PacketListenerError::NodeNotConfigured);
+        }
+        let files = capture_files(dir)?;
+        info!("Replaying {} capture files", files.len());
+
+        let listener = self.clone();
+        let generation = self.capture_generation.load(Ordering::SeqCst);
+        tauri::async_runtime::spawn_blocking(move || {
+            listener.replay_files(&files, generation);
+        });
+        Ok(())
+    }
+
+    /// Unreadable files are logged and skipped, the session goes on with the next one
+    fn replay_files(&self, files: &[std::path::PathBuf], generation: u64) {
+        for path in files {
+            if self.capture_generation.load(Ordering::SeqCst) != generation {
+                break;
+            }
+            match open_file(path) {
+                Ok(cap) => self.capture_loop_from(generation, Box::new(cap), None),
+                Err(err) => error!("Failed to replay {:?}: {:?}", path, err),
+            }
+        }
+    }
+
+    /// Runs the capture loop in the background.
+    /// When `network` is set, the capture is reopened with it if the device goes away.
+    fn spawn_capture(
+        &self,
+        cap: Box<dyn CaptureSource>,
+        network: Option<NetworkConfig>,
+    ) -> Result<(), PacketListenerError> {
+        if self.node().is_none() {
+            return Err(PacketListenerError::NodeNotConfigured);
+        }
+
+        debug!("Running packet listener");
+        let listener = self.clone();
+        let generation = self.capture_generation.load(Ordering::SeqCst);
+        // the loop blocks on its reads and on the backoff of `reopen_capture`
+        tauri::async_runtime::spawn_blocking(move || {
+            listener.capture_loop_from(generation, cap, network);
+        });
+
+        Ok(())
+    }
+
+    /// Loopback devices are not always Ethernet framed (`lo0` on macOS uses the null datalink)
+    fn link_layer(&self, interface: Option<&str>, cap: &dyn CaptureSource) -> LinkLayer {
+        let datalink = cap.datalink();
+        self.datalinks
+            .write()
+            .unwrap()
+            .insert(interface.map(str::to_owned), datalink);
+        LinkLayer::from_linktype(datalink).unwrap_or_else(|| {
+            warn!("Unsupported datalink {:?}, parsing as Ethernet", datalink);
+            LinkLayer::Ethernet
+        })
+    }
+
+    fn capture_loop(&self, cap: Box<dyn CaptureSource>, network: Option<NetworkConfig>) {
+        let generation = self.capture_generation.load(Ordering::SeqCst);
+        self.capture_loop_from(generation, cap, network);
+    }
+
+    /// Runs until the capture generation moves past `generation`.
+    /// Background loops take it before they are spawned, a `stop` issued before they start still ends them.
+    fn capture_loop_from(
+        &self,
+        generation: u64,
+        cap: Box<dyn CaptureSource>,
+        network: Option<NetworkConfig>,
+    ) {
+        let interface = network.as_ref().map(|network| network.interface.clone());
+        self.capture_loop_until(interface, cap, network, None, generation);
+    }
+
+    /// Connections are reassembled per `interface`, so concurrent captures don't mix their streams.
+    /// The loop exits at `deadline` or once the generation moves past `generation`.
+    /// Returns the number of frames read.
+    fn capture_loop_until(
+        &self,
+        interface: Option<String>,
+        mut cap: Box<dyn CaptureSource>,
+        network: Option<NetworkConfig>,
+        deadline: Option<Instant>,
+        generation: u64,
+    ) -> u64 {
+        let past_deadline = || deadline.map_or(false, |deadline| Instant::now() >= deadline);
+        let mut frames = 0;
+        let mut last_stats_update = Instant::now();
+        let mut link_layer = self.link_layer(interface.as_deref(), cap.as_ref());
+        // other interfaces keep their own outcomes
+        self.protocol_health
+            .lock()
+            .unwrap()
+            .insert(interface.clone(), ProtocolHealth::default());
+        self.started_at
+            .write()
+            .unwrap()
+            .get_or_insert_with(Instant::now);
+        let _running = RunningCapture::new(&self.running_captures);
+
+        loop {
+            if self.capture_generation.load(Ordering::SeqCst) != generation {
+                info!("Packet capture stopped by a stop or restart");
+                break;
+            }
+            if past_deadline() {
+                info!("Packet capture reached its time limit");
+                break;
+            }
+            let (timestamp, data) = match cap
+                .next_packet_at()
+                .map(|(timestamp, data)| (timestamp, data.to_vec()))
+            {
+                Ok(packet) => packet,
+                Err(err) => match (
+                    self.options.error_policy.action(&err, network.is_some()),
+                    &network,
+                ) {
+                    (CaptureErrorAction::Retry, _) => {
+                        if let Some(node) = self.node() {
+                            self.flush_batches(node, false);
+                        }
+                        continue;
+                    }
+                    (CaptureErrorAction::Reopen, Some(network)) => {
+                        warn!("Failed to read packet: {:?}", err);
+                        match self.reopen_capture(network) {
+                            Some(new_cap) => {
+                                cap = Box::new(new_cap);
+                                link_layer = self.link_layer(interface.as_deref(), cap.as_ref());
+                                continue;
+                            }
+                            None => break,
+                        }
+                    }
+                    _ => {
+                        if !matches!(err, pcap::Error::NoMorePackets) {
+                            warn!("Failed to read packet: {:?}", err);
+                        }
+                        break;
+                    }
+                },
+            };
+            // a frame read after the deadline is out of the session
+            if past_deadline() {
+                info!("Packet capture reached its time limit");
+                break;
+            }
+            frames += 1;
+            self.frames.fetch_add(1, Ordering::Relaxed);
+            self.record_frame(cap.as_ref(), timestamp, &data);
+            let now = SystemTime::now();
+
+            *self.last_packet_time.write().unwrap() = now
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_millis();
+
+            if last_stats_update.elapsed() >= CAPTURE_STATS_INTERVAL {
+                last_stats_update = Instant::now();
+                self.update_capture_stats(interface.as_deref(), cap.as_mut());
+                self.evict_idle_streams(SystemTime::now());
+            }
+
+            if self.options.verify_checksums && !PacketHeader::has_valid_checksum(&data, link_layer)
+            {
+                warn!("Dropping segment with an invalid TCP checksum");
+                continue;
+            }
+            let packet_header = PacketHeader::from_link_layer(&data, link_layer);
+            if let Err(ParseResult::Truncated { declared, captured }) = packet_header {
+                warn!(
+                    "Dropping segment truncated to {} of {} bytes, the snaplen is too low",
+                    captured, declared
+                );
+                self.truncated.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+            if packet_header.is_err() {
+                warn!("Failed to parse packet header: {:?}", packet_header);
+                continue;
+            }
+            self.process_header_on(interface.as_deref(), packet_header.unwrap(), timestamp);
+        }
+
+        self.update_capture_stats(interface.as_deref(), cap.as_mut());
+        self.flush_interface(interface.as_deref());
+        if let Some(node) = self.node() {
+            self.flush_batches(node, true);
+        }
+        info!("Packet capture stopped");
+        frames
+    }
+
+    /// Feeds a TCP payload through the same reassembly and parsing path as captured packets.
+    /// `seq` is the TCP sequence number of its first byte, the IP id is taken from it.
+    #[cfg(any(test, feature = "testing"))]
+    pub fn inject_bytes(&self, source_ip: Vec<u8>, seq: u32, data: &[u8]) {
+        self.process_header(PacketHeader {
+            source_port: 0,
+            destination_port: 0,
+            source_ip,
+            destination_ip: Vec::new(),
+            seq_num: seq as u16,
+            tcp_payload_start: 0,
+            tcp: TcpInfo {
+                seq,
+                flags: TcpInfo::PSH | TcpInfo::ACK,
+                ..Default::default()
+            },
+            body: data.to_vec(),
+        });
+    }
+
+    fn process_header(&self, header: PacketHeader) {
+        self.process_header_on(None, header, SystemTime::now());
+    }
+
+    /// `timestamp` is the capture time of the segment
+    fn process_header_on(
+        &self,
+        interface: Option<&str>,
+        mut header: PacketHeader,
+        timestamp: SystemTime,
+    ) {
+        // debug level only, so it costs nothing when those logs are disabled
+        let span = debug_span!(
+            "packet",
+            source_ip = ?header.source_ip,
+            source_port = header.source_port,
+            destination_port = header.destination_port,
+            seq_num = header.seq_num,
+            event_id = tracing::field::Empty,
+        );
+        let _enter = span.enter();
+
+        let callbacks = self.raw_segment_callbacks.lock().unwrap().snapshot();
+        for callback in callbacks {
+            callback(&header);
+        }
+        let connection = header.connection_key();
+        let tcp = header.tcp;
+        let Some(node) = self.node_for(&connection) else {
+            debug!("Dropping segment of unrouted connection {:?}", connection);
+            return;
+        };
+        let (frames, gap) = {
+            let mut reassembly = self.reassembly.lock().unwrap();
+            let key = StreamKey::new(interface, &header);
+            let closes = header.tcp.is_fin() || header.tcp.is_rst();
+            let state = reassembly.entry(key.clone()).or_insert_with(|| {
+                let protocol = node.protocol.clone();
+                ReassemblyState::with_max_buffer_bytes(self.options.max_buffer_bytes)
+                    .with_source(PacketListener::message_source(&header, &node))
+                    .with_resync(Arc::new(move |id| {
+                        protocol.read().unwrap().get_protocol(&id).is_some()
+                    }))
+            });
+            let frames = state.push(header);
+            self.collect_reassembly_counters(state);
+            let gap = state.take_gap();
+            // the next connection on the same tuple starts a new stream
+            if closes {
+                reassembly.remove(&key);
+            }
+            (frames, gap)
+        };
+        if let Some(gap) = gap {
+            let gap = SequenceGap {
+                connection,
+                from: gap.start,
+                to: gap.end,
+                bytes: gap.end.wrapping_sub(gap.start),
+            };
+            warn!("Missing {} bytes on {:?}", gap.bytes, gap.connection);
+            let callbacks = self.sequence_gap_callbacks.lock().unwrap().snapshot();
+            for callback in callbacks {
+                callback(&gap);
+            }
+        }
+
+        self.process_frames(interface, connection, Some(tcp), timestamp, frames, &node);
+    }
+
+    fn message_source(header: &PacketHeader, node: &Node) -> MessageSource {
+        MessageSource::of(header, &node.config.config.read().unwrap().network.ports)
+    }
+
+    /// Parses and delivers the messages framed by the reassembly.
+    /// `tcp` is the header of the last segment and `timestamp` its capture time.
+    fn process_frames(
+        &self,
+        interface: Option<&str>,
+        connection: ConnectionKey,
+        tcp: Option<TcpInfo>,
+        timestamp: SystemTime,
+        frames: Vec<Result<PacketMetadata, InvalidBuffer>>,
+        node: &Arc<Node>,
+    ) {
+        for frame in frames {
+            match frame {
+                Err(InvalidBuffer { error, data }) => {
+                    warn!(
+                        "Failed to parse metadata: {:?}\n{}",
+                        error,
+                        hex_dump_truncated(&data, HEX_DUMP_LOG_LIMIT)
+                    );
+                    self.report_parse_error(ParseFailure {
+                        id: (data.len() >= 2).then(|| u16::from_be_bytes([data[0], data[1]]) >> 2),
+                        error: ParseFailureKind::Metadata(error),
+                        data,
+                    });
+                }
+                Ok(metadata) => {
+                    tracing::Span::current().record("event_id", metadata.id);
+                    if self.detected_version.read().unwrap().is_none() {
+                        self.detect_version(&metadata, node);
+                    }
+                    let callbacks = self.event_seen_callbacks.lock().unwrap().snapshot();
+                    for callback in callbacks {
+                        callback(metadata.id, timestamp, connection);
+                    }
+                    if !self.is_interesting(&metadata.id) {
+                        continue;
+                    }
+                    self.notify_raw(&metadata.id, &metadata.data, node);
+                    // the message bytes are already consumed, skipping it keeps the stream aligned
+                    let mut listeners = self.listeners(&metadata.id);
+                    // the message carried by a container is only known once it is inflated
+                    let is_container = node
+                        .protocol
+                        .read()
+                        .unwrap()
+                        .get_protocol(&metadata.id)
+                        .map_or(false, |event| event.is_data_container());
+                    if (listeners.as_ref().map_or(false, |l| !l.is_empty())
+                        || is_container
+                        || self.has_streams(&metadata.id)
+                        || self.has_batches(&metadata.id)
+                        || self.is_correlated(&metadata.id))
+                        && self.sample(&metadata.id)
+                    {
+                        let parse_start = Instant::now();
+                        let mut parser = PacketParser::from_metadata(&metadata)
+                            .strict(self.options.strict_lengths);
+                        let parsed = parser.parse_messages(&node.protocol.read().unwrap());
+                        match parsed {
+                            Ok(packets) => {
+                                self.latencies
+                                    .lock()
+                                    .unwrap()
+                                    .entry(metadata.id)
+                                    .or_default()
+                                    .record(parse_start.elapsed());
+                                self.record_parse_outcome(interface, true);
+                                // a container delivers each message it carries
+                                for mut packet in packets {
+                                    // the messages of a container are only filtered once inflated
+                                    if packet.id != metadata.id && !self.is_interesting(&packet.id)
+                                    {
+                                        continue;
+                                    }
+                                    packet.connection = Some(connection);
+                                    packet.tcp = tcp;
+                                    packet.timestamp = Some(timestamp);
+                                    let listeners = match packet.id == metadata.id {
+                                        true => listeners.take(),
+                                        false => self.listeners(&packet.id),
+                                    };
+                                    if self.is_correlated(&packet.id) {
+                                        self.record_exchanges(&packet);
+                                    }
+                                    self.dispatch(listeners, packet, node);
+                                }
+                                self.flush_batches(node, false);
+                            }
+                            Err(err) => {
+                                warn!(
+                                    "Failed to parse packet: {:?} for {:?}\n{}",
+                                    err,
+                                    metadata.id,
+                                    hex_dump_truncated(&metadata.data, HEX_DUMP_LOG_LIMIT)
+                                );
+                                self.record_parse_outcome(interface, false);
+                                self.update_metrics(&metadata.id, |metrics| {
+                                    metrics.parse_failures += 1
+                                });
+                                self.report_parse_error(ParseFailure {
+                                    id: Some(metadata.id),
+                                    error: ParseFailureKind::Packet(err),
+                                    data: metadata.data.to_vec(),
+                                });
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Parse outcomes of the first messages of a capture loop
+#[derive(Debug, Default)]
+struct ProtocolHealth {
+    successes: u64,
+    failures: u64,
+    reported: bool,
+}
+
+impl ProtocolHealth {
+    /// Returns the mismatch the first time the failure ratio goes past the threshold
+    fn record(&mut self, parsed: bool) -> Option<ProtocolMismatch> {
+        let total = self.successes + self.failures;
+        if self.reported || total >= MISMATCH_WINDOW {
+            return None;
+        }
+        match parsed {
+            true => self.successes += 1,
+            false => self.failures += 1,
+        }
+        let total = total + 1;
+        if total < MISMATCH_MIN_SAMPLES
+            || (self.failures as f64) / (total as f64) <= MISMATCH_FAILURE_RATIO
+        {
+            return None;
+        }
+        self.reported = true;
+        Some(ProtocolMismatch {
+            failures: self.failures,
+            successes: self.successes,
+        })
+    }
+}
+
+/// Counts a capture loop in `running_captures` until it is dropped, also when the loop panics
+struct RunningCapture(Arc<AtomicUsize>);
+
+impl RunningCapture {
+    fn new(running_captures: &Arc<AtomicUsize>) -> Self {
+        running_captures.fetch_add(1, Ordering::SeqCst);
+        RunningCapture(running_captures.clone())
+    }
+}
+
+impl Drop for RunningCapture {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[derive(Debug)]
+struct RateLimit {
+    interval: Duration,
+    last_delivered: Option<Instant>,
+}
+
+impl RateLimit {
+    fn new(interval: Duration) -> Self {
+        RateLimit {
+            interval,
+            last_delivered: None,
+        }
+    }
+
+    /// Returns true, and remembers the delivery, when the interval elapsed since the last one
+    fn allow(&mut self, now: Instant) -> bool {
+        match self.last_delivered {
+            Some(last) if now.duration_since(last) < self.interval => false,
+            _ => {
+                self.last_delivered = Some(now);
+                true
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct ListenerBackoff {
+    failures: u32,
+    until: Option<Instant>,
+}
+
+impl ListenerBackoff {
+    /// Counts a failure, returns how long the listener is skipped once past the threshold
+    fn fail(&mut self, now: Instant) -> Option<Duration> {
+        self.failures += 1;
+        if self.failures < LISTENER_BACKOFF_THRESHOLD {
+            return None;
+        }
+        let exponent = (self.failures - LISTENER_BACKOFF_THRESHOLD).min(16);
+        let duration = (LISTENER_INITIAL_BACKOFF * 2u32.pow(exponent)).min(LISTENER_MAX_BACKOFF);
+        self.until = Some(now + duration);
+        Some(duration)
+    }
+
+    fn is_skipped(&self, now: Instant) -> bool {
+        self.until.map_or(false, |until| now < until)
+    }
+}
+
+#[derive(Debug)]
+struct Sampling {
+    rate: u32,
+    count: u64,
+}
+
+impl Sampling {
+    fn new(rate: u32) -> Self {
+        Sampling {
+            rate: rate.max(1),
+            count: 0,
+        }
+    }
+
+    /// Returns true for the first packet, then for one of every `rate`
+    fn next(&mut self) -> bool {
+        let sampled = self.count % self.rate as u64 == 0;
+        self.count += 1;
+        sampled
+    }
+}
+
+/// A connection being reassembled, see `PacketListener::active_connections`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionInfo {
+    /// `None` for replayed and injected packets
+    pub interface: Option<String>,
+    /// Both ends of the stream, `src` sent its segments
+    pub connection: ConnectionKey,
+    /// Bytes waiting for the rest of their message
+    pub buffered_bytes: usize,
+    pub last_seen: SystemTime,
+    pub packet_count: u64,
+}
+
+/// Identifies a TCP stream so each connection is reassembled separately
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct StreamKey {
+    interface: Option<String>,
+    source_ip: Vec<u8>,
+    source_port: u16,
+    destination_ip: Vec<u8>,
+    destination_port: u16,
+}
+
+impl StreamKey {
+    fn new(interface: Option<&str>, header: &PacketHeader) -> Self {
+        StreamKey {
+            interface: interface.map(str::to_string),
+            source_ip: header.source_ip.clone(),
+            source_port: header.source_port,
+            destination_ip: header.destination_ip.clone(),
+            destination_port: header.destination_port,
+        }
+    }
+}
+
+/// Debounces the changes of the configured interface
+#[derive(Debug)]
+struct InterfaceWatcher {
+    current: String,
+    /// Latest different interface and when it was first seen
+    pending: Option<(String, Instant)>,
+}
+
+impl InterfaceWatcher {
+    fn new(current: String) -> Self {
+        InterfaceWatcher {
+            current,
+            pending: None,
+        }
+    }
+
+    /// Returns the interface to restart on once a change stayed for `INTERFACE_CHANGE_DEBOUNCE`
+    fn poll(&mut self, interface: &str, now: Instant) -> Option<String> {
+        if interface == self.current {
+            self.pending = None;
+            return None;
+        }
+        match &self.pending {
+            Some((pending, since)) if pending == interface => {
+                if now.duration_since(*since) < INTERFACE_CHANGE_DEBOUNCE {
+                    return None;
+                }
+                self.current = interface.to_string();
+                self.pending = None;
+                Some(self.current.clone())
+            }
+            _ => {
+                self.pending = Some((interface.to_string(), now));
+                None
+            }
+        }
+    }
+}
+
+/// Builds a fully configured `PacketListener`
+#[derive(Default)]
+pub struct PacketListenerBuilder {
+    node: Option<Arc<Node>>,
+    options: CaptureOptions,
+    rate_limits: Vec<(EventId, Duration)>,
+    listener_workers: Option<(usize, usize)>,
+}
+
+impl PacketListenerBuilder {
+    pub fn node(mut self, node: Arc<Node>) -> Self {
+        self.node = Some(node);
+        self
+    }
+
+    pub fn filter(mut self, filter: impl Into<String>) -> Self {
+        self.options.filter = Some(filter.into());
+        self
+    }
+
+    pub fn max_buffer_bytes(mut self, max_buffer_bytes: usize) -> Self {
+        self.options.max_buffer_bytes = Some(max_buffer_bytes);
+        self
+    }
+
+    pub fn capture_direction(mut self, direction: pcap::Direction) -> Self {
+        self.options.direction = direction;
+        self
+    }
+
+    pub fn verify_checksums(mut self, verify_checksums: bool) -> Self {
+        self.options.verify_checksums = verify_checksums;
+        self
+    }
+
+    pub fn error_policy(mut self, error_policy: CaptureErrorPolicy) -> Self {
+        self.options.error_policy = error_policy;
+        self
+    }
+
+    pub fn strict_lengths(mut self, strict_lengths: bool) -> Self {
+        self.options.strict_lengths = strict_lengths;
+        self
+    }
+
+    pub fn drop_unrouted(mut self, drop_unrouted: bool) -> Self {
+        self.options.drop_unrouted = drop_unrouted;
+        self
+    }
+
+    pub fn rate_limit(mut self, event: EventId, interval: Duration) -> Self {
+        self.rate_limits.push((event, interval));
+        self
+    }
+
+    /// Delivers the packets from `threads` worker threads, for listeners too slow to run in the capture loop.
+    /// At most `queue_size` packets wait for them, the next ones are dropped and counted in `queue_dropped`.
+    /// Packets handled by different threads may reach the listeners out of order.
+    pub fn listener_workers(mut self, threads: usize, queue_size: usize) -> Self {
+        self.listener_workers = Some((threads, queue_size));
+        self
+    }
+
+    pub fn build(self) -> Result<PacketListener, PacketListenerError> {
+        let node = self.node.ok_or(PacketListenerError::NodeNotConfigured)?;
+        if self.options.max_buffer_bytes == Some(0) {
+            return Err(PacketListenerError::InvalidOption("max_buffer_bytes"));
+        }
+        if let Some((0, _) | (_, 0)) = self.listener_workers {
+            return Err(PacketListenerError::InvalidOption("listener_workers"));
+        }
+        if let Some(filter) = &self.options.filter {
+            Capture::dead(pcap::Linktype::ETHERNET)
+                .and_then(|cap| cap.compile(filter, false))
+                .map_err(|err| {
+                    warn!("Invalid capture filter {:?}: {:?}", filter, err);
+                    PacketListenerError::InvalidOption("filter")
+                })?;
+        }
+
+        let mut listener = PacketListener::new();
+        listener.options = self.options;
+        listener.workers = self
+            .listener_workers
+            .map(|(threads, queue_size)| Arc::new(ListenerWorkers::spawn(threads, queue_size)));
+        listener.set_node(node);
+        for (event, interval) in self.rate_limits {
+            listener.set_rate_limit(event, interval);
+        }
+        Ok(listener)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum PacketListenerError {
+    #[error("Failed to open device")]
+    FailedToOpenDevice,
+    #[error("Invalid capture device")]
+    InvalidCaptureDevice,
+    #[error("No node configured")]
+    NodeNotConfigured,
+    #[error("Insufficient privileges to capture packets, {0}")]
+    InsufficientPrivileges(&'static str),
+    #[error("Invalid listener option: {0}")]
+    InvalidOption(&'static str),
+    #[error("Invalid network config: {0}")]
+    InvalidNetworkConfig(#[from] NetworkConfigError),
+    #[error("Failed to record capture: {0}")]
+    Recording(#[from] CaptureFileError),
+    #[error("Failed to set the capture direction: {0}")]
+    CaptureDirection(pcap::Error),
+    #[error("Invalid capture filter: {0}")]
+    InvalidFilter(pcap::Error),
+}
+
+/// How to get the right to capture on the current platform
+const PRIVILEGES_HINT: &str = if cfg!(target_os = "macos") {
+    "run with sudo or give your user read access to /dev/bpf*"
+} else if cfg!(target_os = "windows") {
+    "run as administrator or install Npcap without the admin only option"
+} else {
+    "run with sudo or grant cap_net_raw and cap_net_admin to the executable"
+};
+
+/// libpcap only reports a permission denial in its error message
+fn open_error(err: &pcap::Error) -> PacketListenerError {
+    let denied = match err {
+        pcap::Error::PcapError(message) => {
+            let message = message.to_lowercase();
+            message.contains("permission") || message.contains("not permitted")
+        }
+        pcap::Error::IoError(kind) => *kind == std::io::ErrorKind::PermissionDenied,
+        _ => false,
+    };
+    match denied {
+        true => PacketListenerError::InsufficientPrivileges(PRIVILEGES_HINT),
+        false => PacketListenerError::FailedToOpenDevice,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::sniffer::capture::{
+        count_packets, fixture_node, inject_ping, open_file, tcp_frame, MockCapture, PING,
+    };
+
+    /// Capture time of each packet of a pcap file
+    fn capture_times(path: &Path) -> Vec<SystemTime> {
+        let mut cap = open_file(path).unwrap();
+        let mut timestamps = Vec::new();
+        while let Ok((timestamp, _)) = CaptureSource::next_packet_at(&mut cap) {
+            timestamps.push(timestamp);
+        }
+        timestamps
+    }
+
+    #[test]
+    fn test_packet_listener() {
+        let listener = PacketListener::new();
+
+        assert_eq!(listener.subscriptions.lock().unwrap().len(), 0);
+
+        let listener_id = "test";
+        let listener_fn = |_event: &Packet, _: &Node| ListenerAction::Continue;
+        let event = 0;
+
+        listener.subscribe(event.clone(), listener_id, listener_fn);
+        assert_eq!(listener.subscriptions.lock().unwrap().len(), 1);
+        assert_eq!(
+            listener
+                .subscriptions
+                .lock()
+                .unwrap()
+                .get(&event)
+                .unwrap()
+                .len(),
+            1
+        );
+
+        listener.unsubscribe(&event, listener_id);
+        assert_eq!(listener.subscriptions.lock().unwrap().len(), 1);
+        assert_eq!(
+            listener
+                .subscriptions
+                .lock()
+                .unwrap()
+                .get(&event)
+                .unwrap()
+                .len(),
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn test_with_capture() {
+        let cap = Capture::from_file("tests/fixtures/cap.pcap").unwrap();
+        let path = "tests/fixtures/".to_string();
+        let path = Path::new(&path);
+        let node = Node::new(path, None, false).await;
+        if let Err(err) = node {
+            panic!("Failed to create node: {:?}", err);
+        }
+        let node = node.unwrap();
+        let listener_fn = |event: &Packet, node: &Node| {
+            let key = event.id.to_string();
+            node.store.lock().unwrap().increment(key, 1);
+            ListenerAction::Continue
+        };
+
+        let listener = &node.packet_listener;
+        let id = "test";
+        listener.subscribe(1338, id, listener_fn);
+
+        let res = listener.run_with_capture(cap.into());
+        if let Err(err) = res {
+            panic!("Failed to run with capture: {:?}", err);
+        }
+
+        tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+        info!("Store: {:?}", node.store.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_capture_continues_after_timeout() {
+        let node = fixture_node().await;
+        let listener = &node.packet_listener;
+
+        let cap = MockCapture::new(vec![
+            Err(pcap::Error::TimeoutExpired),
+            Err(pcap::Error::TimeoutExpired),
+            Ok(vec![0; 60]),
+        ]);
+        listener.capture_loop(Box::new(cap), None);
+
+        // the packet after the timeouts has been read
+        assert_ne!(*listener.last_packet_time.read().unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_capture_error_policy() {
+        let policy = CaptureErrorPolicy::default();
+        let gone = pcap::Error::PcapError("The interface went down".to_string());
+        assert_eq!(
+            policy.action(&pcap::Error::TimeoutExpired, false),
+            CaptureErrorAction::Retry
+        );
+        assert_eq!(policy.action(&gone, true), CaptureErrorAction::Reopen);
+        assert_eq!(policy.action(&gone, false), CaptureErrorAction::Stop);
+        assert_eq!(
+            policy.action(&pcap::Error::NoMorePackets, true),
+            CaptureErrorAction::Stop
+        );
+        let no_reopen = CaptureErrorPolicy {
+            retry_on_timeout: false,
+            max_reopen_attempts: 0,
+            ..Default::default()
+        };
+        assert_eq!(
+            no_reopen.action(&pcap::Error::TimeoutExpired, true),
+            CaptureErrorAction::Stop
+        );
+        assert_eq!(no_reopen.action(&gone, true), CaptureErrorAction::Stop);
+
+        let node = fixture_node().await;
+        let capture =
+            |first_error: pcap::Error| MockCapture::new(vec![Err(first_error), Ok(vec![0; 60])]);
+        let listener = PacketListener::builder()
+            .node(node.clone())
+            .error_policy(no_reopen)
+            .build()
+            .unwrap();
+        // the packet after the timeout is never read
+        listener.capture_loop(Box::new(capture(pcap::Error::TimeoutExpired)), None);
+        assert_eq!(*listener.last_packet_time.read().unwrap(), 0);
+
+        // a file can't be reopened, even with the default policy
+        let listener = PacketListener::builder()
+            .node(node.clone())
+            .build()
+            .unwrap();
+        listener.capture_loop(
+            Box::new(capture(pcap::Error::IoError(std::io::ErrorKind::Other))),
+            None,
+        );
+        assert_eq!(*listener.last_packet_time.read().unwrap(), 0);
+        listener.capture_loop(Box::new(capture(pcap::Error::TimeoutExpired)), None);
+        assert_ne!(*listener.last_packet_time.read().unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_inject_bytes() {
+        let node = fixture_node().await;
+        let listener = &node.packet_listener;
+
+        // BasicPingMessage
+        listener.subscribe(8285, "test", |packet, node| {
+            let quiet = packet.data.get("quiet").unwrap().clone();
+            node.store.lock().unwrap().set_json("quiet", quiet);
+            ListenerAction::Continue
+        });
+        inject_ping(listener, 1);
+
+        let store = node.store.lock().unwrap();
+        assert_eq!(
+            store.get_json("quiet"),
+            Some(&serde_json::Value::Bool(true))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        let node = fixture_node().await;
+        let listener = &node.packet_listener;
+
+        count_packets(listener, 8285);
+        listener.set_rate_limit(8285, Duration::from_millis(100));
+        for index in 0..3 {
+            inject_ping(listener, index);
+        }
+
+        assert_eq!(node.store.lock().unwrap().get_int("count"), Some(1));
+        let metrics = listener.event_metrics(&8285);
+        assert_eq!(metrics.delivered, 1);
+        assert_eq!(metrics.rate_limited, 2);
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_filtered() {
+        let node = fixture_node().await;
+        let listener = &node.packet_listener;
+
+        listener.subscribe_filtered(
+            8285,
+            "test",
+            |packet| packet.data.get("quiet") == Some(&serde_json::Value::Bool(true)),
+            |_, node| {
+                node.store.lock().unwrap().increment("count", 1);
+                ListenerAction::Continue
+            },
+        );
+        // quiet = false, then quiet = true
+        listener.inject_bytes(vec![127, 0, 0, 1], 4, &[0x81, 0x75, 0x01, 0x00]);
+        inject_ping(listener, 2);
+
+        assert_eq!(node.store.lock().unwrap().get_int("count"), Some(1));
+        let metrics = listener.event_metrics(&8285);
+        assert_eq!(metrics.seen, 2);
+        assert_eq!(metrics.delivered, 1);
+        assert_eq!(metrics.filtered, 1);
+    }
+
+    #[tokio::test]
+    async fn test_on_parse_error() {
+        let node = fixture_node().await;
+        let listener = &node.packet_listener;
+        let failures = Arc::new(Mutex::new(Vec::new()));
+
+        let collected = failures.clone();
+        listener.on_parse_error(move |failure| {
+            collected
+                .lock()
+                .unwrap()
+                .push((failure.id, failure.data.clone()));
+        });
+        listener.subscribe(9922, "test", |_, _| ListenerAction::Continue);
+        // ProtocolRequired announcing a 5 bytes version but only carrying 2
+        listener.inject_bytes(
+            vec![127, 0, 0, 1],
+            0,
+            &[0x9b, 0x09, 0x04, 0x00, 0x05, b'1', b'.'],
+        );
+        // a header cut before its length is completed by the next segment, it is not a failure
+        listener.inject_bytes(vec![127, 0, 0, 1], 7, &[0x9b, 0x09]);
+        listener.inject_bytes(
+            vec![127, 0, 0, 1],
+            9,
+            &[0x08, 0x00, 0x06, b'a', b'b', b'c', b'd', b'e', b'f'],
+        );
+
+        assert_eq!(
+            *failures.lock().unwrap(),
+            vec![(Some(9922), vec![0x00, 0x05, b'1', b'.'])]
+        );
+    }
+
+    #[test]
+    fn test_run_blocking() {
+        let node = tauri::async_runtime::block_on(fixture_node());
+        let listener = &node.packet_listener;
+        count_packets(listener, 1338);
+
+        listener
+            .run_blocking(open_file("tests/fixtures/cap.pcap").unwrap().into())
+            .unwrap();
+
+        // every packet was handled before returning
+        let count = node.store.lock().unwrap().get_int("count").unwrap();
+        assert!(count > 0);
+        assert_eq!(listener.event_metrics(&1338).delivered, count as u64);
+    }
+
+    #[test]
+    fn test_open_error() {
+        // messages of libpcap on linux and macos
+        for message in [
+            "socket: Operation not permitted",
+            "You don't have permission to perform this capture on that device",
+            "(cannot open BPF device) /dev/bpf0: Permission denied",
+        ] {
+            assert!(matches!(
+                open_error(&pcap::Error::PcapError(message.to_string())),
+                PacketListenerError::InsufficientPrivileges(_)
+            ));
+        }
+        assert!(matches!(
+            open_error(&pcap::Error::PcapError("No such device exists".to_string())),
+            PacketListenerError::FailedToOpenDevice
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_metrics_report() {
+        let node = fixture_node().await;
+        let listener = &node.packet_listener;
+        listener.subscribe(1338, "test", |_, _| ListenerAction::Continue);
+        listener.capture_loop(
+            Box::new(open_file("tests/fixtures/cap.pcap").unwrap()),
+            None,
+        );
+
+        let report = listener.metrics_report();
+        let metric = report.iter().find(|metric| metric.id == 1338).unwrap();
+        assert_eq!(metric.name.as_deref(), Some("GameRolePlayShowActorMessage"));
+        assert!(metric.metrics.seen > 0);
+        assert!(report.windows(2).all(|rows| rows[0].id < rows[1].id));
+    }
+
+    #[test]
+    fn test_run_without_node() {
+        let listener = PacketListener::new();
+
+        assert!(matches!(
+            listener.run(),
+            Err(PacketListenerError::NodeNotConfigured)
+        ));
+        assert!(matches!(
+            listener.run_with_capture(open_file("tests/fixtures/cap.pcap").unwrap().into()),
+            Err(PacketListenerError::NodeNotConfigured)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_builder() {
+        let node = fixture_node().await;
+
+        assert!(matches!(
+            PacketListener::builder().build(),
+            Err(PacketListenerError::NodeNotConfigured)
+        ));
+        assert!(matches!(
+            PacketListener::builder()
+                .node(node.clone())
+                .filter("not a filter (")
+                .build(),
+            Err(PacketListenerError::InvalidOption("filter"))
+        ));
+
+        let listener = PacketListener::builder()
+            .node(node.clone())
+            .filter("host 127.0.0.1")
+            .max_buffer_bytes(8)
+            .capture_direction(pcap::Direction::InOut)
+            .rate_limit(8285, Duration::from_secs(1))
+            .build()
+            .unwrap();
+
+        assert_eq!(listener.options().max_buffer_bytes, Some(8));
+        assert_eq!(listener.options().direction, pcap::Direction::InOut);
+        assert_eq!(
+            listener.capture_filter(&NetworkConfig::default()),
+            "(tcp port 5555) and (host 127.0.0.1)"
+        );
+        let mut cap = Capture::dead(pcap::Linktype::ETHERNET).unwrap();
+        listener
+            .apply_filter(&mut cap, &NetworkConfig::default())
+            .unwrap();
+        assert!(listener.rate_limits.lock().unwrap().contains_key(&8285));
+
+        // incomplete message larger than the buffer cap
+        listener.inject_bytes(vec![127, 0, 0, 1], 0, &[0x9b, 0x09, 0x20, 0, 0, 0, 0, 0, 0]);
+        assert!(listener
+            .reassembly
+            .lock()
+            .unwrap()
+            .values()
+            .all(|state| state.buffered().is_empty()));
+    }
+
+    #[test]
+    fn test_invalid_capture_filter() {
+        let mut listener = PacketListener::new();
+        // set past the builder, which rejects it
+        listener.options.filter = Some("not a filter (".to_string());
+        let mut cap = Capture::dead(pcap::Linktype::ETHERNET).unwrap();
+        assert!(matches!(
+            listener.apply_filter(&mut cap, &NetworkConfig::default()),
+            Err(PacketListenerError::InvalidFilter(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_interleaved_connections() {
+        let node = fixture_node().await;
+        let listener = &node.packet_listener;
+        count_packets(listener, 8285);
+
+        let segment = |source_port: u16, seq: u32, body: &[u8]| PacketHeader {
+            source_port,
+            destination_port: 50000,
+            source_ip: vec![127, 0, 0, 1],
+            destination_ip: vec![127, 0, 0, 1],
+            seq_num: seq as u16,
+            tcp_payload_start: 0,
+            tcp: TcpInfo {
+                seq,
+                ..TcpInfo::default()
+            },
+            body: body.to_vec(),
+        };
+        // a BasicPingMessage split in two, with a full one from another server in between
+        listener.process_header(segment(5555, 1, &[0x81, 0x75, 0x01]));
+        listener.process_header(segment(443, 1, &[0x81, 0x75, 0x01, 0x00]));
+        listener.process_header(segment(5555, 4, &[0x01]));
+
+        assert_eq!(node.store.lock().unwrap().get_int("count"), Some(2));
+    }
+
+    #[derive(Clone, Default)]
+    struct LogBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for LogBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            std::io::Write::write(&mut *self.0.lock().unwrap(), buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_packet_span() {
+        let node = fixture_node().await;
+        let listener = &node.packet_listener;
+        listener.subscribe(9922, "test", |_, _| ListenerAction::Continue);
+
+        let logs = LogBuffer::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::DEBUG)
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        tracing::subscriber::with_default(subscriber, || {
+            // ProtocolRequired with a truncated version, logs a parse failure
+            listener.process_header(PacketHeader {
+                source_port: 5555,
+                destination_port: 50000,
+                source_ip: vec![127, 0, 0, 1],
+                destination_ip: vec![127, 0, 0, 1],
+                seq_num: 42,
+                tcp_payload_start: 0,
+                tcp: TcpInfo::default(),
+                body: vec![0x9b, 0x09, 0x02, 0x00, 0x05],
+            });
+        });
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        let line = logs
+            .lines()
+            .find(|line| line.contains("Failed to parse packet"))
+            .unwrap();
+        assert!(line.contains(
+            "packet{source_ip=[127, 0, 0, 1] source_port=5555 destination_port=50000 seq_num=42 event_id=9922}"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_large_message_reassembly() {
+        let node = fixture_node().await;
+        let listener = &node.packet_listener;
+        listener.subscribe(6915, "test", |packet, node| {
+            let characters = packet.data.get("characters").unwrap().as_array().unwrap();
+            node.store
+                .lock()
+                .unwrap()
+                .set_int("count", characters.len() as i64);
+            ListenerAction::Continue
+        });
+
+        // BreachCharactersMessage with 8000 VarLong of 9 bytes, 72002 bytes body
+        let count: u16 = 8000;
+        let mut body = count.to_be_bytes().to_vec();
+        for _ in 0..count {
+            body.extend([0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x01]);
+        }
+        let size = (body.len() as u32).to_be_bytes();
+        let mut message = vec![0x6c, 0x0f, size[1], size[2], size[3]];
+        message.extend(body);
+
+        // full sized segments, the stream starts at an arbitrary sequence number
+        for (index, segment) in message.chunks(1448).enumerate() {
+            let seq = 0x4d2a_9c01_u32.wrapping_add(index as u32 * 1448);
+            listener.inject_bytes(vec![127, 0, 0, 1], seq, segment);
+        }
+
+        assert_eq!(node.store.lock().unwrap().get_int("count"), Some(8000));
+    }
+
+    #[tokio::test]
+    async fn test_sampling() {
+        let node = fixture_node().await;
+        let listener = &node.packet_listener;
+        count_packets(listener, 8285);
+        listener.set_sampling(8285, 3);
+
+        inject_ping(listener, 1);
+        inject_ping(listener, 2);
+        inject_ping(listener, 3);
+
+        assert_eq!(node.store.lock().unwrap().get_int("count"), Some(1));
+        let metrics = listener.event_metrics(&8285);
+        assert_eq!(metrics.sampled, 1);
+        assert_eq!(metrics.skipped, 2);
+        assert_eq!(metrics.delivered, 1);
+    }
+
+    #[test]
+    fn test_subscription_counts() {
+        let listener = PacketListener::new();
+        listener.subscribe(1, "first", |_, _| ListenerAction::Continue);
+        listener.subscribe(1, "second", |_, _| ListenerAction::Continue);
+        listener.subscribe(2, "first", |_, _| ListenerAction::Continue);
+
+        assert_eq!(listener.subscription_count(), 3);
+        assert_eq!(listener.event_subscription_count(&1), 2);
+        assert_eq!(listener.event_subscription_count(&3), 0);
+        assert_eq!(listener.listeners_for(&1), vec!["first", "second"]);
+        assert!(listener.listeners_for(&3).is_empty());
+
+        listener.clear_all();
+        assert_eq!(listener.subscription_count(), 0);
+        assert_eq!(listener.event_subscription_count(&1), 0);
+    }
+
+    #[tokio::test]
+    async fn test_gzip_replay() {
+        let node = fixture_node().await;
+        let temp_dir = tempdir::TempDir::new("gzip_replay").unwrap();
+        let gzip_path = temp_dir.path().join("cap.pcap.gz");
+        let mut encoder = flate2::write::GzEncoder::new(
+            std::fs::File::create(&gzip_path).unwrap(),
+            flate2::Compression::default(),
+        );
+        std::io::copy(
+            &mut std::fs::File::open("tests/fixtures/cap.pcap").unwrap(),
+            &mut encoder,
+        )
+        .unwrap();
+        encoder.finish().unwrap();
+
+        let mut counts = Vec::new();
+        for path in [Path::new("tests/fixtures/cap.pcap"), gzip_path.as_path()] {
+            let listener = PacketListener::builder()
+                .node(node.clone())
+                .build()
+                .unwrap();
+            // ChatServerMessage
+            listener.subscribe(1304, "test", |_, _| ListenerAction::Continue);
+            listener.capture_loop(Box::new(open_file(path).unwrap()), None);
+            counts.push(listener.event_metrics(&1304).seen);
+        }
+
+        assert!(counts[0] > 0);
+        assert_eq!(counts[0], counts[1]);
+    }
+
+    #[tokio::test]
+    async fn test_packet_stream() {
+        use tokio_stream::StreamExt;
+
+        let node = fixture_node().await;
+        let listener = PacketListener::builder().node(node).build().unwrap();
+
+        let stream = listener.packet_stream_all();
+        listener.capture_loop(
+            Box::new(open_file("tests/fixtures/cap.pcap").unwrap()),
+            None,
+        );
+        let packets: Vec<Packet> = stream.take(2).collect().await;
+
+        assert_eq!(packets.len(), 2);
+        // the dropped stream no longer receives packets
+        assert!(listener.streams.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_dedup() {
+        let node = fixture_node().await;
+        let listener = &node.packet_listener;
+
+        count_packets(listener, 8285);
+        listener.set_dedup(8285, true);
+        // quiet = true twice, then quiet = false
+        inject_ping(listener, 1);
+        inject_ping(listener, 2);
+        listener.inject_bytes(vec![127, 0, 0, 1], 12, &[0x81, 0x75, 0x01, 0x00]);
+
+        assert_eq!(node.store.lock().unwrap().get_int("count"), Some(2));
+        assert_eq!(listener.event_metrics(&8285).deduplicated, 1);
+    }
+
+    #[tokio::test]
+    async fn test_parse_latency() {
+        let node = fixture_node().await;
+        let listener = &node.packet_listener;
+
+        listener.subscribe(8285, "test", |_, _| ListenerAction::Continue);
+        for index in 0..5 {
+            inject_ping(listener, index);
+        }
+
+        let latency = listener.parse_latency(&8285);
+        assert_eq!(latency.count, 5);
+        assert!(latency.p50 <= latency.p99 && latency.p99 <= latency.max);
+        assert_eq!(listener.parse_latency(&1304).count, 0);
+    }
+
+    #[tokio::test]
+    async fn test_groups() {
+        let node = fixture_node().await;
+        let listener = &node.packet_listener;
+
+        listener.subscribe_in_group("combat", 8285, "combat", |_, node| {
+            node.store.lock().unwrap().increment("combat", 1);
+            ListenerAction::Continue
+        });
+        listener.subscribe_in_group("market", 8285, "market", |_, node| {
+            node.store.lock().unwrap().increment("market", 1);
+            ListenerAction::Continue
+        });
+        listener.disable_group("combat");
+        inject_ping(listener, 1);
+        listener.enable_group("combat");
+        inject_ping(listener, 2);
+
+        let store = node.store.lock().unwrap();
+        assert_eq!(store.get_int("combat"), Some(1));
+        assert_eq!(store.get_int("market"), Some(2));
+        drop(store);
+
+        listener.remove_group("combat");
+        assert_eq!(listener.event_subscription_count(&8285), 1);
+
+        // a group disabled by a listener is skipped for the rest of the packet
+        listener.subscribe_with_priority(8285, "mute", 1, |_, node| {
+            node.packet_listener.disable_group("market");
+            ListenerAction::Continue
+        });
+        inject_ping(listener, 3);
+        assert_eq!(node.store.lock().unwrap().get_int("market"), Some(2));
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_batch() {
+        let node = fixture_node().await;
+        let listener = &node.packet_listener;
+
+        listener.subscribe_batch(8285, "test", 5, Duration::from_secs(60), |packets, node| {
+            let quiet: Vec<_> = packets
+                .iter()
+                .map(|packet| packet.data["quiet"].clone())
+                .collect();
+            let mut store = node.store.lock().unwrap();
+            store.increment("batches", 1);
+            store.set_json("quiet", serde_json::Value::Array(quiet));
+        });
+        for (index, quiet) in [1, 0, 1, 1, 0].into_iter().enumerate() {
+            listener.inject_bytes(
+                vec![127, 0, 0, 1],
+                index as u32 * 4,
+                &[0x81, 0x75, 0x01, quiet],
+            );
+        }
+
+        let store = node.store.lock().unwrap();
+        assert_eq!(store.get_int("batches"), Some(1));
+        assert_eq!(
+            store.get_json("quiet"),
+            Some(&serde_json::json!([true, false, true, true, false]))
+        );
+    }
+
+    #[test]
+    fn test_interface_watcher() {
+        let mut watcher = InterfaceWatcher::new("en0".to_string());
+        let start = Instant::now();
+        let after = |millis| start + Duration::from_millis(millis);
+
+        assert_eq!(watcher.poll("en0", after(0)), None);
+        // flipping the dropdown doesn't restart anything
+        assert_eq!(watcher.poll("en1", after(100)), None);
+        assert_eq!(watcher.poll("lo0", after(200)), None);
+        assert_eq!(watcher.poll("lo0", after(800)), None);
+        assert_eq!(watcher.poll("lo0", after(1200)), Some("lo0".to_string()));
+        assert_eq!(watcher.poll("lo0", after(5000)), None);
+    }
+
+    #[tokio::test]
+    async fn test_interest_set() {
+        let node = fixture_node().await;
+        let listener = &node.packet_listener;
+
+        count_packets(listener, 8285);
+        listener.set_interest_set(Some(HashSet::from([9922])));
+        inject_ping(listener, 1);
+
+        assert_eq!(node.store.lock().unwrap().get_int("count"), None);
+        assert_eq!(listener.parse_latency(&8285).count, 0);
+
+        listener.derive_interest_set();
+        inject_ping(listener, 2);
+        assert_eq!(node.store.lock().unwrap().get_int("count"), Some(1));
+
+        // a container holding a ping and a pong, the pong isn't part of the interest set
+        listener.subscribe(5906, "test", |_, node| {
+            node.store.lock().unwrap().increment("pong", 1);
+            ListenerAction::Continue
+        });
+        let mut encoder =
+            flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        std::io::Write::write_all(
+            &mut encoder,
+            &[0x81, 0x75, 0x01, 0x01, 0x5c, 0x49, 0x01, 0x01],
+        )
+        .unwrap();
+        let compressed = encoder.finish().unwrap();
+        let mut container = vec![
+            0x00,
+            0x09,
+            compressed.len() as u8 + 1,
+            compressed.len() as u8,
+        ];
+        container.extend(compressed);
+        listener.inject_bytes(vec![127, 0, 0, 1], 12, &container);
+
+        assert_eq!(node.store.lock().unwrap().get_int("count"), Some(2));
+        assert_eq!(node.store.lock().unwrap().get_int("pong"), None);
+    }
+
+    #[tokio::test]
+    async fn test_listener_unsubscribe() {
+        let node = fixture_node().await;
+        let listener = &node.packet_listener;
+
+        listener.subscribe(8285, "test", |_, node| {
+            node.store.lock().unwrap().increment("count", 1);
+            ListenerAction::Unsubscribe
+        });
+        inject_ping(listener, 1);
+        inject_ping(listener, 2);
+
+        assert_eq!(node.store.lock().unwrap().get_int("count"), Some(1));
+        assert!(!listener.has_subscriptions(&8285));
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_once() {
+        let node = fixture_node().await;
+        let listener = &node.packet_listener;
+
+        listener.subscribe_once(8285, "once", |_, node| {
+            node.store.lock().unwrap().increment("count", 1);
+            ListenerAction::Continue
+        });
+        inject_ping(listener, 1);
+        inject_ping(listener, 2);
+
+        assert_eq!(node.store.lock().unwrap().get_int("count"), Some(1));
+        assert!(!listener.has_subscriptions(&8285));
+
+        // delivered again while it runs
+        listener.subscribe_once(8285, "once", |packet, node| {
+            node.store.lock().unwrap().increment("nested", 1);
+            node.packet_listener.notify(packet);
+            ListenerAction::Continue
+        });
+        inject_ping(listener, 3);
+        assert_eq!(node.store.lock().unwrap().get_int("nested"), Some(1));
+    }
+
+    #[tokio::test]
+    async fn test_listener_order() {
+        let node = fixture_node().await;
+        let listener = &node.packet_listener;
+
+        fn record(node: &Node, digit: i64) -> ListenerAction {
+            let mut store = node.store.lock().unwrap();
+            let order = store.get_int("order").unwrap_or(0);
+            store.set_int("order", order * 10 + digit);
+            ListenerAction::Continue
+        }
+        listener.subscribe(8285, "first", |_, node| record(node, 3));
+        listener.subscribe_with_priority(8285, "late", -1, |_, node| record(node, 5));
+        listener.subscribe_with_priority(8285, "decoder", 10, |_, node| record(node, 1));
+        listener.subscribe(8285, "second", |_, node| record(node, 4));
+        listener.subscribe_with_priority(8285, "decoder2", 10, |_, node| record(node, 2));
+        inject_ping(listener, 1);
+
+        assert_eq!(node.store.lock().unwrap().get_int("order"), Some(12345));
+    }
+
+    #[tokio::test]
+    async fn test_on_raw_segment() {
+        let node = fixture_node().await;
+        let listener = &node.packet_listener;
+        let segments = Arc::new(Mutex::new(Vec::new()));
+
+        let collected = segments.clone();
+        listener.on_raw_segment(move |header| {
+            collected
+                .lock()
+                .unwrap()
+                .push((header.tcp.seq, header.body.len()));
+        });
+        count_packets(listener, 9922);
+        // ProtocolRequired split in 3 segments, the last one arriving before the middle one
+        listener.inject_bytes(vec![127, 0, 0, 1], 0, &[0x9b, 0x09, 0x08]);
+        listener.inject_bytes(vec![127, 0, 0, 1], 7, &[b'c', b'd', b'e', b'f']);
+        listener.inject_bytes(vec![127, 0, 0, 1], 3, &[0x00, 0x06, b'a', b'b']);
+
+        // the callback sees the segments as captured, the reassembly puts them back in order
+        assert_eq!(*segments.lock().unwrap(), vec![(0, 3), (7, 4), (3, 4)]);
+        assert_eq!(node.store.lock().unwrap().get_int("count"), Some(1));
+    }
+
+    #[test]
+    fn test_register_from_callback() {
+        let listener = PacketListener::new();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let (registering, counted) = (listener.clone(), calls.clone());
+        // registers another callback on each segment, it is only called from the next one
+        listener.on_raw_segment(move |_| {
+            let counted = counted.clone();
+            registering.on_raw_segment(move |_| {
+                counted.fetch_add(1, Ordering::SeqCst);
+            });
+        });
+        inject_ping(&listener, 1);
+        inject_ping(&listener, 2);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_listener_workers() {
+        let node = fixture_node().await;
+        let listener = PacketListener::builder()
+            .node(node.clone())
+            .listener_workers(1, 1)
+            .build()
+            .unwrap();
+        listener.subscribe(8285, "slow", |_, _| {
+            std::thread::sleep(Duration::from_millis(200));
+            ListenerAction::Continue
+        });
+
+        let start = Instant::now();
+        for index in 0..4 {
+            inject_ping(&listener, index);
+        }
+        // one packet runs on the worker and one waits, the others are dropped instead of blocking
+        assert!(start.elapsed() < Duration::from_millis(200));
+        let dropped = listener.event_metrics(&8285).queue_dropped;
+        assert!(dropped >= 2);
+
+        std::thread::sleep(Duration::from_millis(600));
+        assert_eq!(listener.event_metrics(&8285).delivered, 4 - dropped);
+    }
+
+    #[tokio::test]
+    async fn test_flush() {
+        let node = fixture_node().await;
+        let listener = &node.packet_listener;
+        count_packets(listener, 8285);
+
+        let segment = |seq: u32, body: &[u8]| PacketHeader {
+            source_port: 0,
+            destination_port: 0,
+            source_ip: vec![127, 0, 0, 1],
+            destination_ip: Vec::new(),
+            seq_num: 0,
+            tcp_payload_start: 0,
+            tcp: TcpInfo {
+                seq,
+                ..TcpInfo::default()
+            },
+            body: body.to_vec(),
+        };
+        {
+            let mut reassembly = listener.reassembly.lock().unwrap();
+            // the captures end with a ping behind a message cut by a lost segment
+            for interface in ["lo", "eth0"] {
+                let state = reassembly
+                    .entry(StreamKey::new(Some(interface), &segment(0, &[])))
+                    .or_default();
+                state.push(segment(0, &PING));
+                state.insert(segment(4, &PING[..2]));
+                state.insert(segment(8, &PING));
+            }
+            // and with two pings reassembled but not framed yet
+            reassembly
+                .entry(StreamKey::new(None, &segment(0, &[])))
+                .or_default()
+                .insert(segment(
+                    0,
+                    &[0x81, 0x75, 0x01, 0x01, 0x81, 0x75, 0x01, 0x00],
+                ));
+        }
+        // the end of a capture loop only flushes its own interface
+        listener.flush_interface(Some("lo"));
+        assert_eq!(node.store.lock().unwrap().get_int("count"), Some(1));
+        listener.flush();
+        assert_eq!(node.store.lock().unwrap().get_int("count"), Some(4));
+
+        // nothing is left for the flush on stop
+        listener.stop();
+        assert_eq!(node.store.lock().unwrap().get_int("count"), Some(4));
+    }
+
+    #[tokio::test]
+    async fn test_route_connection() {
+        let (node, first, second) = (
+            fixture_node().await,
+            fixture_node().await,
+            fixture_node().await,
+        );
+        let listener = &node.packet_listener;
+        count_packets(listener, 8285);
+        let connection = |source_ip: Vec<u8>| {
+            PacketHeader {
+                source_port: 0,
+                destination_port: 0,
+                source_ip,
+                destination_ip: Vec::new(),
+                seq_num: 0,
+                tcp_payload_start: 0,
+                tcp: TcpInfo::default(),
+                body: Vec::new(),
+            }
+            .connection_key()
+        };
+        listener.route_connection(connection(vec![10, 0, 0, 1]), first.clone());
+        // routes match the other direction of the connection too
+        listener.route_connection(connection(vec![10, 0, 0, 2]).reversed(), second.clone());
+
+        listener.inject_bytes(vec![10, 0, 0, 1], 0, &PING);
+        listener.inject_bytes(vec![10, 0, 0, 2], 0, &PING);
+        listener.inject_bytes(vec![10, 0, 0, 2], 4, &PING);
+        listener.inject_bytes(vec![10, 0, 0, 3], 0, &PING);
+
+        let count = |node: &Node| node.store.lock().unwrap().get_int("count");
+        assert_eq!(count(&first), Some(1));
+        assert_eq!(count(&second), Some(2));
+        assert_eq!(count(&node), Some(1));
+
+        let dropping = PacketListener::builder()
+            .node(node.clone())
+            .drop_unrouted(true)
+            .build()
+            .unwrap();
+        count_packets(&dropping, 8285);
+        dropping.inject_bytes(vec![10, 0, 0, 3], 0, &PING);
+        assert_eq!(count(&node), Some(1));
+        assert_eq!(dropping.unrouted_dropped(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_correlate_exchange() {
+        let node = fixture_node().await;
+        let listener = &node.packet_listener;
+        let exchanges = Arc::new(Mutex::new(Vec::new()));
+        let seen = exchanges.clone();
+        listener.on_exchange(move |exchange| seen.lock().unwrap().push(exchange.clone()));
+        // BasicPingMessage answered by BasicPongMessage
+        listener.correlate(vec![CorrelationRule::new(
+            8285,
+            5906,
+            Duration::from_secs(5),
+        )
+        .by_field("quiet")]);
+
+        inject_ping(listener, 1);
+        std::thread::sleep(Duration::from_millis(20));
+        listener.inject_bytes(vec![127, 0, 0, 1], 8, &[0x5c, 0x49, 0x01, 0x01]);
+
+        let exchanges = exchanges.lock().unwrap();
+        assert_eq!(exchanges.len(), 1);
+        assert_eq!(exchanges[0].request.id, 8285);
+        assert_eq!(exchanges[0].response.id, 5906);
+        assert!(exchanges[0].latency >= Duration::from_millis(20));
+    }
+
+    #[tokio::test]
+    async fn test_replay_capture_time() {
+        let node = fixture_node().await;
+        let listener = &node.packet_listener;
+        let exchanges = Arc::new(Mutex::new(Vec::new()));
+        let collected = exchanges.clone();
+        listener.on_exchange(move |exchange| collected.lock().unwrap().push(exchange.clone()));
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let collected = seen.clone();
+        listener.on_event_seen(move |_, timestamp, _| collected.lock().unwrap().push(timestamp));
+        listener.correlate(vec![CorrelationRule::new(
+            8285,
+            5906,
+            Duration::from_secs(5),
+        )]);
+
+        // a ping answered 50ms later, replayed at once
+        let dir = tempdir::TempDir::new("replay").unwrap();
+        let mut recorder = CaptureRecorder::new(dir.path(), RotationPolicy::default()).unwrap();
+        let pinged_at = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let ponged_at = pinged_at + Duration::from_millis(50);
+        recorder
+            .write_at(pcap::Linktype::ETHERNET, pinged_at, &tcp_frame(4, &PING))
+            .unwrap();
+        recorder
+            .write_at(
+                pcap::Linktype::ETHERNET,
+                ponged_at,
+                &tcp_frame(8, &[0x5c, 0x49, 0x01, 0x01]),
+            )
+            .unwrap();
+        recorder.close().unwrap();
+        listener.capture_loop(Box::new(open_file(&recorder.files()[0]).unwrap()), None);
+
+        assert_eq!(*seen.lock().unwrap(), vec![pinged_at, ponged_at]);
+        let exchanges = exchanges.lock().unwrap();
+        assert_eq!(exchanges.len(), 1);
+        assert_eq!(exchanges[0].latency, Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn test_config_event_filter() {
+        let node = fixture_node().await;
+        let listener = &node.packet_listener;
+        count_packets(listener, 8285);
+        listener.subscribe_raw(9922, "test", |_, _, node| {
+            node.store.lock().unwrap().increment("raw", 1);
+        });
+        let config: crate::config::NodeConfig =
+            serde_json::from_str(r#"{ "events": { "allow": ["BasicPingMessage"] } }"#).unwrap();
+        node.config.config.write().unwrap().events = config.events;
+        listener.apply_event_filter().unwrap();
+
+        inject_ping(listener, 1);
+        // ProtocolRequired, dropped before its raw listener and the parser
+        listener.inject_bytes(
+            vec![127, 0, 0, 1],
+            8,
+            &[
+                0x9b, 0x09, 0x08, 0x00, 0x06, b'a', b'b', b'c', b'd', b'e', b'f',
+            ],
+        );
+
+        assert_eq!(node.store.lock().unwrap().get_int("count"), Some(1));
+        assert_eq!(node.store.lock().unwrap().get_int("raw"), None);
+    }
+
+    #[tokio::test]
+    async fn test_restart_with_invalid_config() {
+        let node = fixture_node().await;
+        let listener = &node.packet_listener;
+        node.config.config.write().unwrap().network.interface = String::new();
+
+        assert!(matches!(
+            listener.restart_with(&node),
+            Err(PacketListenerError::InvalidNetworkConfig(
+                NetworkConfigError::EmptyInterface
+            ))
+        ));
+        // the running capture was left alone
+        assert_eq!(listener.capture_generation.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_stop_config_watcher() {
+        let node = fixture_node().await;
+        let listener = &node.packet_listener;
+        let config: crate::config::NodeConfig =
+            serde_json::from_str(r#"{ "events": { "allow": ["BasicPingMessage"] } }"#).unwrap();
+
+        listener.watch_config().unwrap();
+        listener.stop();
+        node.config.config.write().unwrap().events = config.events;
+        tokio::time::sleep(CONFIG_POLL_INTERVAL * 2).await;
+        assert!(listener.is_interesting(&9922));
+
+        listener.watch_config().unwrap();
+        tokio::time::sleep(CONFIG_POLL_INTERVAL * 2).await;
+        assert!(!listener.is_interesting(&9922));
+        listener.stop();
+    }
+
+    #[tokio::test]
+    async fn test_event_seen() {
+        let node = fixture_node().await;
+        let listener = &node.packet_listener;
+        let seen = Arc::new(Mutex::new(Vec::new()));
+
+        let collected = seen.clone();
+        listener.on_event_seen(move |id, _, _| collected.lock().unwrap().push(id));
+        inject_ping(listener, 1);
+
+        assert_eq!(*seen.lock().unwrap(), vec![8285]);
+        // no listener wanted the ping, it was never decoded
+        assert_eq!(listener.event_metrics(&8285).seen, 0);
+    }
+
+    #[tokio::test]
+    async fn test_sequence_gap() {
+        let node = fixture_node().await;
+        let listener = &node.packet_listener;
+        let gaps = Arc::new(Mutex::new(Vec::new()));
+
+        let collected = gaps.clone();
+        listener.on_sequence_gap(move |gap| collected.lock().unwrap().push(gap.clone()));
+        // three pings, 12 bytes are missing before the last one
+        for (seq_num, seq) in [(1, 1000), (2, 1004), (3, 1020)] {
+            listener.process_header(PacketHeader {
+                source_port: 5555,
+                destination_port: 50000,
+                source_ip: vec![127, 0, 0, 1],
+                destination_ip: vec![127, 0, 0, 1],
+                seq_num,
+                tcp_payload_start: 0,
+                tcp: TcpInfo {
+                    seq,
+                    ..Default::default()
+                },
+                body: PING.to_vec(),
+            });
+        }
+
+        let gaps = gaps.lock().unwrap();
+        assert_eq!(gaps.len(), 1);
+        assert_eq!((gaps[0].from, gaps[0].to, gaps[0].bytes), (1008, 1020, 12));
+        assert_eq!(gaps[0].connection.src.port(), 5555);
+    }
+
+    #[tokio::test]
+    async fn test_packet_tcp_info() {
+        let node = fixture_node().await;
+        let listener = &node.packet_listener;
+        listener.subscribe(8285, "test", |packet, node| {
+            let tcp = packet.tcp.unwrap();
+            let mut store = node.store.lock().unwrap();
+            store.set_int("seq", tcp.seq as i64);
+            store.set_int("ack", tcp.ack as i64);
+            store.set_int("flags", tcp.flags as i64);
+            store.set_int("window", tcp.window as i64);
+            ListenerAction::Continue
+        });
+        listener.process_header(PacketHeader {
+            source_port: 5555,
+            destination_port: 50000,
+            source_ip: vec![127, 0, 0, 1],
+            destination_ip: vec![127, 0, 0, 1],
+            seq_num: 1,
+            tcp_payload_start: 0,
+            tcp: TcpInfo {
+                seq: 1000,
+                ack: 2000,
+                flags: TcpInfo::PSH | TcpInfo::ACK,
+                window: 512,
+            },
+            body: PING.to_vec(),
+        });
+
+        let store = node.store.lock().unwrap();
+        assert_eq!(store.get_int("seq"), Some(1000));
+        assert_eq!(store.get_int("ack"), Some(2000));
+        assert_eq!(store.get_int("flags"), Some(0x18));
+        assert_eq!(store.get_int("window"), Some(512));
+    }
+
+    #[tokio::test]
+    async fn test_resubscribe_by_class() {
+        let node = fixture_node().await;
+        let protocol = |events: &str| ProtocolManager::from_json(events).unwrap();
+        *node.protocol.write().unwrap() = protocol(
+            r#"[{"id": "1", "class_name": "HelloMessage", "superclass": null, "attributes": {}},
+                {"id": "2", "class_name": "PingMessage", "superclass": null, "attributes": {}}]"#,
+        );
+        let listener = &node.packet_listener;
+        listener.subscribe(1, "hello", |_, _| ListenerAction::Continue);
+        listener.subscribe_in_group("pings", 2, "ping", |_, _| ListenerAction::Continue);
+        listener.subscribe(3, "unknown", |_, _| ListenerAction::Continue);
+
+        let exported = listener.export_subscriptions();
+        assert_eq!(
+            exported[1],
+            SubscriptionInfo {
+                event: 2,
+                class: Some("PingMessage".to_string()),
+                listener_id: "ping",
+                group: Some("pings"),
+            }
+        );
+
+        // the new version swapped both ids
+        *node.protocol.write().unwrap() = protocol(
+            r#"[{"id": "2", "class_name": "HelloMessage", "superclass": null, "attributes": {}},
+                {"id": "1", "class_name": "PingMessage", "superclass": null, "attributes": {}}]"#,
+        );
+        let unresolved = listener.resubscribe_by_class(&exported, &node.protocol.read().unwrap());
+
+        assert_eq!(unresolved, vec![exported[2].clone()]);
+        assert!(listener.has_subscriptions_for(&2, "hello"));
+        assert!(listener.has_subscriptions_for(&1, "ping"));
+        assert!(!listener.has_subscriptions_for(&1, "hello"));
+        assert!(listener.has_subscriptions_for(&3, "unknown"));
+    }
+
+    #[tokio::test]
+    async fn test_run_with_source() {
+        let node = fixture_node().await;
+        let listener = &node.packet_listener;
+        count_packets(listener, 8285);
+
+        let frames = (1..=3)
+            .map(|seq_num| {
+                (
+                    Duration::from_millis(seq_num as u64 * 10),
+                    tcp_frame(seq_num * 4, &PING),
+                )
+            })
+            .collect();
+        listener
+            .run_with_source(MockCapture::timed(frames))
+            .unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while node.store.lock().unwrap().get_int("count") != Some(3) && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(node.store.lock().unwrap().get_int("count"), Some(3));
+    }
+
+    #[tokio::test]
+    async fn test_resync_after_junk() {
+        let node = fixture_node().await;
+        let listener = &node.packet_listener;
+        count_packets(listener, 8285);
+        // ping then junk whose header announces a huge message, the next segment holds a ping
+        listener.inject_bytes(
+            vec![127, 0, 0, 1],
+            0,
+            &[0x81, 0x75, 0x01, 0x01, 0xff, 0xff, 0xff],
+        );
+        listener.inject_bytes(vec![127, 0, 0, 1], 7, &PING);
+
+        assert_eq!(node.store.lock().unwrap().get_int("count"), Some(2));
+        assert_eq!(listener.resyncs(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_truncated_segment() {
+        let node = fixture_node().await;
+        let listener = &node.packet_listener;
+        count_packets(listener, 8285);
+
+        // ethernet pads the frames of small segments, the padding isn't part of the payload
+        let mut padded = tcp_frame(0, &PING);
+        padded.extend([0; 6]);
+        let header = PacketHeader::from_link_layer(&padded, LinkLayer::Ethernet).unwrap();
+        assert_eq!(header.body, PING);
+
+        let mut truncated = tcp_frame(4, &PING);
+        truncated.truncate(truncated.len() - 2);
+        assert!(matches!(
+            PacketHeader::from_link_layer(&truncated, LinkLayer::Ethernet),
+            Err(ParseResult::Truncated {
+                declared: 58,
+                captured: 56
+            })
+        ));
+
+        listener.capture_loop(
+            Box::new(MockCapture::new(vec![Ok(padded), Ok(truncated)])),
+            None,
+        );
+        assert_eq!(node.store.lock().unwrap().get_int("count"), Some(1));
+        assert_eq!(listener.truncated_segments(), 1);
+    }
+
+    #[test]
+    fn test_datalink() {
+        let listener = PacketListener::new();
+        assert_eq!(listener.datalink(None), None);
+
+        listener.capture_loop(
+            Box::new(open_file("tests/fixtures/sll.pcap").unwrap()),
+            None,
+        );
+        assert_eq!(listener.datalink(None), Some(pcap::Linktype::LINUX_SLL));
+        assert_eq!(listener.datalink_name(None).as_deref(), Some("LINUX_SLL"));
+    }
+
+    #[test]
+    fn test_datalink_per_interface() {
+        let listener = PacketListener::new();
+        let generation = listener.capture_generation.load(Ordering::SeqCst);
+        listener.capture_loop_until(
+            Some("any".to_string()),
+            Box::new(open_file("tests/fixtures/sll.pcap").unwrap()),
+            None,
+            None,
+            generation,
+        );
+        listener.capture_loop_until(
+            Some("eth0".to_string()),
+            Box::new(MockCapture::new(vec![])),
+            None,
+            None,
+            generation,
+        );
+        assert_eq!(
+            listener.datalink(Some("any")),
+            Some(pcap::Linktype::LINUX_SLL)
+        );
+        assert_eq!(
+            listener.datalink(Some("eth0")),
+            Some(pcap::Linktype::ETHERNET)
+        );
+
+        let stats = CaptureStats {
+            received: 10,
+            dropped: 1,
+            if_dropped: 0,
+        };
+        let mut capture_stats = listener.capture_stats.write().unwrap();
+        capture_stats.insert(Some("any".to_string()), stats);
+        capture_stats.insert(Some("eth0".to_string()), stats);
+        drop(capture_stats);
+        assert_eq!(listener.interface_capture_stats(Some("eth0")), stats);
+        assert_eq!(listener.capture_stats().received, 20);
+        assert_eq!(listener.capture_stats().total_dropped(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_run_for() {
+        let node = fixture_node().await;
+        let listener = &node.packet_listener;
+        count_packets(listener, 8285);
+        assert!(matches!(
+            PacketListener::new().run_for(MockCapture::new(Vec::new()), Duration::ZERO),
+            Err(PacketListenerError::NodeNotConfigured)
+        ));
+
+        let frames = [0, 10, 500]
+            .into_iter()
+            .zip(1..)
+            .map(|(millis, index)| (Duration::from_millis(millis), tcp_frame(index * 4, &PING)))
+            .collect();
+        let stats = listener
+            .run_for(MockCapture::timed(frames), Duration::from_millis(200))
+            .unwrap();
+
+        assert_eq!(stats.frames, 2);
+        assert!(stats.elapsed >= Duration::from_millis(200));
+        assert_eq!(node.store.lock().unwrap().get_int("count"), Some(2));
+        assert!(!listener.is_running());
+    }
+
+    #[tokio::test]
+    async fn test_dump_ring() {
+        let node = fixture_node().await;
+        let listener = &node.packet_listener;
+        let dir = tempdir::TempDir::new("ring").unwrap();
+        let path = dir.path().join("ring.pcap");
+        assert!(listener.dump_ring_to(&path).is_err());
+
+        listener.keep_ring(Some(3), None);
+        let frames: Vec<Vec<u8>> = (1..=5).map(|index| tcp_frame(index * 4, &PING)).collect();
+        listener.capture_loop(
+            Box::new(MockCapture::new(frames.iter().cloned().map(Ok).collect())),
+            None,
+        );
+        assert_eq!(listener.dump_ring_to(&path).unwrap(), 3);
+
+        let mut cap = open_file(&path).unwrap();
+        let mut dumped = Vec::new();
+        while let Ok(packet) = cap.next_packet() {
+            dumped.push(packet.data.to_vec());
+        }
+        assert_eq!(dumped, frames[2..]);
+
+        // a replayed file keeps the times of its packets
+        listener.capture_loop(
+            Box::new(open_file("tests/fixtures/cap.pcap").unwrap()),
+            None,
+        );
+        listener.dump_ring_to(&path).unwrap();
+        let replayed = capture_times(Path::new("tests/fixtures/cap.pcap"));
+        assert_eq!(capture_times(&path), replayed[replayed.len() - 3..]);
+    }
+
+    #[tokio::test]
+    async fn test_replay_looping() {
+        let node = fixture_node().await;
+        let listener = &node.packet_listener;
+
+        // the first 3 messages of the loopback capture
+        for event in [9359, 4642, 4794] {
+            listener.subscribe_raw(event, "test", |_, _, node| {
+                node.store.lock().unwrap().increment("count", 1);
+            });
+        }
+        // half a ping captured on an interface meanwhile
+        let partial = PacketHeader {
+            source_port: 5555,
+            destination_port: 50000,
+            source_ip: vec![10, 0, 0, 1],
+            destination_ip: vec![10, 0, 0, 2],
+            seq_num: 0,
+            tcp_payload_start: 0,
+            tcp: TcpInfo::default(),
+            body: PING[..2].to_vec(),
+        };
+        listener.process_header_on(Some("eth0"), partial, SystemTime::now());
+        listener
+            .replay_looping(
+                Path::new("tests/fixtures/loop.pcap"),
+                Some(2),
+                listener.capture_generation.load(Ordering::SeqCst),
+            )
+            .unwrap();
+        assert_eq!(node.store.lock().unwrap().get_int("count"), Some(6));
+        assert!(listener
+            .reassembly
+            .lock()
+            .unwrap()
+            .keys()
+            .any(|key| key.interface.as_deref() == Some("eth0")));
+    }
+
+    #[tokio::test]
+    async fn test_detected_version() {
+        let node = fixture_node().await;
+        let listener = &node.packet_listener;
+        assert_eq!(listener.detected_version(), None);
+
+        // ProtocolRequired { version: "abcdef" }, not a version
+        listener.inject_bytes(
+            vec![127, 0, 0, 1],
+            0,
+            &[
+                0x9b, 0x09, 0x08, 0x00, 0x06, b'a', b'b', b'c', b'd', b'e', b'f',
+            ],
+        );
+        assert_eq!(listener.detected_version(), None);
+
+        // ProtocolRequired { version: "1.2.34" }, detected without a subscription
+        listener.inject_bytes(
+            vec![127, 0, 0, 1],
+            11,
+            &[
+                0x9b, 0x09, 0x08, 0x00, 0x06, b'1', b'.', b'2', b'.', b'3', b'4',
+            ],
+        );
+        assert_eq!(listener.detected_version(), Some("1.2.34".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_listener_backoff() {
+        let node = fixture_node().await;
+        let listener = &node.packet_listener;
+
+        listener.subscribe(8285, "failing", |_, node| {
+            node.store.lock().unwrap().increment("failing", 1);
+            panic!("listener failure");
+        });
+        listener.subscribe(8285, "healthy", |_, node| {
+            node.store.lock().unwrap().increment("healthy", 1);
+            ListenerAction::Continue
+        });
+        for index in 0..6 {
+            inject_ping(listener, index);
+        }
+
+        let store = node.store.lock().unwrap();
+        assert_eq!(
+            store.get_int("failing"),
+            Some(LISTENER_BACKOFF_THRESHOLD as i64)
+        );
+        assert_eq!(store.get_int("healthy"), Some(6));
+        assert!(listener.is_backed_off(&8285, "failing"));
+        assert!(!listener.is_backed_off(&8285, "healthy"));
+    }
+
+    #[test]
+    fn test_listener_backoff_grows() {
+        let now = Instant::now();
+        let mut backoff = ListenerBackoff::default();
+
+        assert_eq!(backoff.fail(now), None);
+        assert_eq!(backoff.fail(now), None);
+        assert_eq!(backoff.fail(now), Some(LISTENER_INITIAL_BACKOFF));
+        assert!(backoff.is_skipped(now));
+        assert_eq!(backoff.fail(now), Some(LISTENER_INITIAL_BACKOFF * 2));
+        for _ in 0..20 {
+            backoff.fail(now);
+        }
+        assert_eq!(backoff.fail(now), Some(LISTENER_MAX_BACKOFF));
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_with_context() {
+        let node = fixture_node().await;
+        let listener = &node.packet_listener;
+
+        fn count_pings(_: &Packet, context: &ListenerContext) -> ListenerAction {
+            let name = context.event().map(|event| event.name.clone()).unwrap();
+            context.store().increment(name, 1);
+            ListenerAction::Continue
+        }
+        listener.subscribe_with_context(8285, "test", count_pings);
+        inject_ping(listener, 1);
+        assert_eq!(
+            node.store.lock().unwrap().get_int("BasicPingMessage"),
+            Some(1)
+        );
+
+        // the same listener called in isolation, without a node
+        let store = Mutex::new(Store::new());
+        let protocol = node.protocol.read().unwrap();
+        let event = protocol.get_protocol(&8285);
+        let mut packet = Packet {
+            id: 8285,
+            name: "BasicPingMessage".to_string(),
+            data: indexmap::IndexMap::new(),
+            connection: None,
+            instance_id: None,
+            tcp: None,
+            raw: None,
+            timestamp: None,
+        };
+        count_pings(&packet, &ListenerContext::new(&store, event));
+        assert_eq!(store.lock().unwrap().get_int("BasicPingMessage"), Some(1));
+        drop(protocol);
+
+        // the context carries the capture time of the packet
+        fn captured_at_epoch(_: &Packet, context: &ListenerContext) -> ListenerAction {
+            match context.timestamp() == SystemTime::UNIX_EPOCH + Duration::from_secs(1) {
+                true => ListenerAction::Unsubscribe,
+                false => ListenerAction::Continue,
+            }
+        }
+        packet.timestamp = Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1));
+        assert_eq!(
+            Callback::Context(captured_at_epoch).call(&packet, &node),
+            ListenerAction::Unsubscribe
+        );
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_from_listener() {
+        let node = fixture_node().await;
+        let listener = &node.packet_listener;
+
+        // a one shot listener replacing itself with another one
+        listener.subscribe(8285, "once", |_, node| {
+            node.store.lock().unwrap().increment("once", 1);
+            node.packet_listener.unsubscribe(&8285, "once");
+            node.packet_listener.subscribe(8285, "after", |_, node| {
+                node.store.lock().unwrap().increment("after", 1);
+                ListenerAction::Continue
+            });
+            ListenerAction::Continue
+        });
+        inject_ping(listener, 1);
+        inject_ping(listener, 2);
+
+        let store = node.store.lock().unwrap();
+        assert_eq!(store.get_int("once"), Some(1));
+        assert_eq!(store.get_int("after"), Some(1));
+        drop(store);
+
+        // unsubscribing leaves the subscription it made under the same id
+        listener.subscribe(5906, "pong", |_, node| {
+            node.store.lock().unwrap().increment("first", 1);
+            node.packet_listener.subscribe(5906, "pong", |_, node| {
+                node.store.lock().unwrap().increment("second", 1);
+                ListenerAction::Continue
+            });
+            ListenerAction::Unsubscribe
+        });
+        listener.inject_bytes(vec![127, 0, 0, 1], 12, &[0x5c, 0x49, 0x01, 0x01]);
+        listener.inject_bytes(vec![127, 0, 0, 1], 16, &[0x5c, 0x49, 0x01, 0x01]);
+
+        let store = node.store.lock().unwrap();
+        assert_eq!(store.get_int("first"), Some(1));
+        assert_eq!(store.get_int("second"), Some(1));
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_raw() {
+        let node = fixture_node().await;
+        let listener = &node.packet_listener;
+
+        // ProtocolRequired { version: "abcdef" }, framed but never decoded
+        listener.subscribe_raw(9922, "raw", |event, data, node| {
+            let mut store = node.store.lock().unwrap();
+            store.set_json("event", serde_json::json!(event));
+            store.set_json("data", serde_json::json!(data));
+        });
+        listener.inject_bytes(
+            vec![127, 0, 0, 1],
+            0,
+            &[
+                0x9b, 0x09, 0x08, 0x00, 0x06, b'a', b'b', b'c', b'd', b'e', b'f',
+            ],
+        );
+
+        let store = node.store.lock().unwrap();
+        assert_eq!(store.get_json("event"), Some(&serde_json::json!(9922)));
+        assert_eq!(
+            store.get_json("data"),
+            Some(&serde_json::json!([
+                0x00, 0x06, b'a', b'b', b'c', b'd', b'e', b'f'
+            ]))
+        );
+        assert_eq!(listener.parse_latency(&9922).count, 0);
+    }
+
+    #[tokio::test]
+    async fn test_evict_streams() {
+        let node = fixture_node().await;
+        let listener = &node.packet_listener;
+        listener.inject_bytes(vec![10, 0, 0, 1], 0, &PING);
+        listener.inject_bytes(vec![10, 0, 0, 2], 0, &PING);
+        listener.inject_bytes(vec![10, 0, 0, 3], 0, &PING);
+        assert_eq!(listener.reassembly.lock().unwrap().len(), 3);
+
+        // the first connection is closed, the second one reset
+        for (ip, flags) in [(1, TcpInfo::FIN | TcpInfo::ACK), (2, TcpInfo::RST)] {
+            listener.process_header(PacketHeader {
+                source_port: 0,
+                destination_port: 0,
+                source_ip: vec![10, 0, 0, ip],
+                destination_ip: Vec::new(),
+                seq_num: 2,
+                tcp_payload_start: 0,
+                tcp: TcpInfo {
+                    seq: 4,
+                    flags,
+                    ..Default::default()
+                },
+                body: Vec::new(),
+            });
+        }
+        assert_eq!(listener.reassembly.lock().unwrap().len(), 1);
+
+        // the third one stops sending
+        listener.evict_idle_streams(SystemTime::now() + CONNECTION_IDLE_TIMEOUT / 2);
+        assert_eq!(listener.reassembly.lock().unwrap().len(), 1);
+        listener.evict_idle_streams(SystemTime::now() + CONNECTION_IDLE_TIMEOUT * 2);
+        assert!(listener.reassembly.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_active_connections() {
+        let node = fixture_node().await;
+        let listener = &node.packet_listener;
+
+        // ProtocolRequired split in two segments, the second connection only sent the first one
+        let message = [0x9b, 0x09, 0x03, 0x00, 0x01, b'1'];
+        listener.inject_bytes(vec![10, 0, 0, 1], 0, &message[..3]);
+        listener.inject_bytes(vec![10, 0, 0, 1], 3, &message[3..]);
+        listener.inject_bytes(vec![10, 0, 0, 2], 0, &message[..3]);
+
+        let mut connections = listener.active_connections();
+        connections.sort_by_key(|info| info.connection.src);
+        let counts: Vec<_> = connections
+            .iter()
+            .map(|info| {
+                (
+                    info.connection.src.to_string(),
+                    info.packet_count,
+                    info.buffered_bytes,
+                )
+            })
+            .collect();
+        assert_eq!(
+            counts,
+            vec![
+                ("10.0.0.1:0".to_string(), 2, 0),
+                ("10.0.0.2:0".to_string(), 1, 3)
+            ]
+        );
+
+        // removed once idle, not only left out
+        listener.evict_idle_streams(SystemTime::now() + CONNECTION_IDLE_TIMEOUT * 2);
+        assert!(listener.active_connections().is_empty());
+        assert!(listener.reassembly.lock().unwrap().is_empty());
+    }
+
+    /// Live capture on a quiet interface, every read times out
+    struct QuietCapture;
+
+    impl CaptureSource for QuietCapture {
+        fn next_packet(&mut self) -> Result<&[u8], pcap::Error> {
+            std::thread::sleep(Duration::from_millis(10));
+            Err(pcap::Error::TimeoutExpired)
+        }
+
+        fn stats(&mut self) -> Result<pcap::Stat, pcap::Error> {
+            Err(pcap::Error::TimeoutExpired)
+        }
+
+        fn datalink(&self) -> pcap::Linktype {
+            pcap::Linktype::ETHERNET
+        }
+    }
+
+    #[tokio::test]
+    async fn test_record_capture_time() {
+        let node = fixture_node().await;
+        let listener = &node.packet_listener;
+        let dir = tempdir::TempDir::new("recording").unwrap();
+        listener
+            .record(dir.path(), RotationPolicy::default())
+            .unwrap();
+        listener.capture_loop(
+            Box::new(open_file("tests/fixtures/cap.pcap").unwrap()),
+            None,
+        );
+        let files = listener.stop_recording();
+        assert_eq!(files.len(), 1);
+
+        // stamped with the time of the replayed packets, not of the replay
+        assert_eq!(
+            capture_times(&files[0]),
+            capture_times(Path::new("tests/fixtures/cap.pcap"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_record_with_slow_disk() {
+        /// Doesn't write anything before the test opens the gate
+        struct SlowSink(std::sync::mpsc::Receiver<()>);
+
+        impl PacketSink for SlowSink {
+            fn write_packet(
+                &mut self,
+                _: pcap::Linktype,
+                _: SystemTime,
+                _: &[u8],
+            ) -> Result<(), CaptureFileError> {
+                let _ = self.0.recv();
+                Ok(())
+            }
+        }
+
+        let node = fixture_node().await;
+        let listener = &node.packet_listener;
+        count_packets(listener, 8285);
+        let (gate, receiver) = std::sync::mpsc::channel();
+        let sink: Box<dyn PacketSink> = Box::new(SlowSink(receiver));
+        *listener.recorder.lock().unwrap() = Some(BackgroundRecorder::spawn(sink, 2));
+
+        let frames = (1..=10)
+            .map(|index| Ok(tcp_frame(index * 4, &PING)))
+            .collect();
+        listener.capture_loop(Box::new(MockCapture::new(frames)), None);
+
+        // the loop went on while the writer was stuck
+        assert_eq!(node.store.lock().unwrap().get_int("count"), Some(10));
+        assert!(listener.recording_dropped() > 0);
+        drop(gate);
+        assert!(listener.stop_recording().is_empty());
+    }
+
+    #[test]
+    fn test_stop_without_traffic() {
+        let listener = PacketListener::new();
+        let (done, stopped) = std::sync::mpsc::channel();
+
+        let capture = listener.clone();
+        std::thread::spawn(move || {
+            capture.capture_loop(Box::new(QuietCapture), None);
+            done.send(()).unwrap();
+        });
+        std::thread::sleep(Duration::from_millis(50));
+        listener.stop();
+
+        assert!(stopped.recv_timeout(Duration::from_secs(1)).is_ok());
+    }
+
+    #[test]
+    fn test_is_running() {
+        let listener = PacketListener::new();
+        assert!(!listener.is_running());
+
+        let (done, stopped) = std::sync::mpsc::channel();
+        let capture = listener.clone();
+        std::thread::spawn(move || {
+            capture.capture_loop(Box::new(QuietCapture), None);
+            done.send(()).unwrap();
+        });
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(listener.is_running());
+
+        listener.stop();
+        stopped.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert!(!listener.is_running());
+
+        // a replayed file ends on its own
+        listener.capture_loop(Box::new(MockCapture::new(Vec::new())), None);
+        assert!(!listener.is_running());
+
+        struct PanickingCapture;
+
+        impl CaptureSource for PanickingCapture {
+            fn next_packet(&mut self) -> Result<&[u8], pcap::Error> {
+                panic!("device gone");
+            }
+
+            fn stats(&mut self) -> Result<pcap::Stat, pcap::Error> {
+                Err(pcap::Error::TimeoutExpired)
+            }
+
+            fn datalink(&self) -> pcap::Linktype {
+                pcap::Linktype::ETHERNET
+            }
+        }
+
+        let capture = listener.clone();
+        let panicked = std::thread::spawn(move || {
+            capture.capture_loop(Box::new(PanickingCapture), None);
+        })
+        .join();
+        assert!(panicked.is_err());
+        assert!(!listener.is_running());
+    }
+
+    #[tokio::test]
+    async fn test_stop_then_restart() {
+        let node = fixture_node().await;
+        let listener = &node.packet_listener;
+
+        // stopped before its task gets to run
+        listener.run_with_source(QuietCapture).unwrap();
+        listener.stop();
+        listener.run_with_source(QuietCapture).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(listener.running_captures.load(Ordering::SeqCst), 1);
+
+        listener.stop();
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while listener.is_running() && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(!listener.is_running());
+    }
+
+    #[tokio::test]
+    async fn test_protocol_mismatch() {
+        let node = fixture_node().await;
+        // the protocol of another version, none of the captured ids are known
+        let mut protocol = crate::sniffer::protocol::protocol::ProtocolManager::new();
+        protocol.init("tests/fixtures/protocol_diff/new").unwrap();
+        *node.protocol.write().unwrap() = protocol;
+        let listener = &node.packet_listener;
+
+        let mismatches = Arc::new(Mutex::new(Vec::new()));
+        let collected = mismatches.clone();
+        listener.on_protocol_mismatch(move |mismatch| collected.lock().unwrap().push(*mismatch));
+        let _stream = listener.packet_stream_all();
+        listener.capture_loop(
+            Box::new(open_file("tests/fixtures/cap.pcap").unwrap()),
+            None,
+        );
+
+        let mismatches = mismatches.lock().unwrap();
+        assert_eq!(mismatches.len(), 1);
+        assert!(mismatches[0].failures > mismatches[0].successes);
+    }
+
+    #[test]
+    fn test_protocol_health_per_interface() {
+        let listener = PacketListener::new();
+        let mismatches = Arc::new(AtomicUsize::new(0));
+        let counted = mismatches.clone();
+        listener.on_protocol_mismatch(move |_| {
+            counted.fetch_add(1, Ordering::SeqCst);
+        });
+        for _ in 1..MISMATCH_MIN_SAMPLES {
+            listener.record_parse_outcome(Some("eth0"), false);
+        }
+        // a loop starting on another interface keeps the outcomes of eth0
+        let generation = listener.capture_generation.load(Ordering::SeqCst);
+        listener.capture_loop_until(
+            Some("eth1".to_string()),
+            Box::new(MockCapture::new(vec![])),
+            None,
+            None,
+            generation,
+        );
+        listener.record_parse_outcome(Some("eth0"), false);
+        assert_eq!(mismatches.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_capture_dir() {
+        let node = fixture_node().await;
+        let listener = &node.packet_listener;
+        listener.subscribe(9922, "test", |packet, node| {
+            assert_eq!(packet.data["version"], "abcdef");
+            node.store.lock().unwrap().increment("count", 1);
+            ListenerAction::Continue
+        });
+        // ProtocolRequired split by the rotation of the capture
+        let dir = tempdir::TempDir::new("capture_dir").unwrap();
+        let mut recorder = CaptureRecorder::new(dir.path(), RotationPolicy::default()).unwrap();
+        let message = [
+            0x9b, 0x09, 0x08, 0x00, 0x06, b'a', b'b', b'c', b'd', b'e', b'f',
+        ];
+        for (seq, part) in [(0, &message[..5]), (5, &message[5..])] {
+            recorder
+                .write(pcap::Linktype::ETHERNET, &tcp_frame(seq, part))
+                .unwrap();
+            recorder.close().unwrap();
+        }
+        std::fs::write(dir.path().join("notes.txt"), "not a capture").unwrap();
+
+        let files = capture_files(dir.path()).unwrap();
+        assert_eq!(files, recorder.files());
+        listener.replay_files(&files, listener.capture_generation.load(Ordering::SeqCst));
+
+        assert_eq!(node.store.lock().unwrap().get_int("count"), Some(1));
+    }
+
+    #[tokio::test]
+    async fn test_stop_and_report() {
+        let node = fixture_node().await;
+        let listener = PacketListener::builder().node(node).build().unwrap();
+        listener.subscribe(1304, "test", |_, _| ListenerAction::Continue);
+        listener.capture_loop(
+            Box::new(open_file("tests/fixtures/cap.pcap").unwrap()),
+            None,
+        );
+
+        let summary = listener.stop_and_report();
+        assert!(summary.frames > 0);
+        assert_eq!(summary.frames, listener.frames_read());
+        assert_eq!(summary.events, listener.metrics_report());
+        assert_eq!(
+            summary.parse_failures,
+            listener
+                .metrics()
+                .values()
+                .map(|metrics| metrics.parse_failures)
+                .sum::<u64>()
+        );
+        assert_eq!(summary.resyncs, listener.resyncs());
+        assert_eq!(summary.buffer_clears, listener.buffer_clears());
+        assert_eq!(summary.truncated, listener.truncated_segments());
+        assert_eq!(summary.total_dropped(), 0);
+        assert!(summary.uptime > Duration::ZERO);
+        assert!(summary.frames_per_second() > 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_pcap_stream_delivers_like_file() {
+        let node = fixture_node().await;
+        let from_file = PacketListener::builder()
+            .node(node.clone())
+            .build()
+            .unwrap();
+        let from_stream = PacketListener::builder().node(node).build().unwrap();
+        for listener in [&from_file, &from_stream] {
+            listener.subscribe(1304, "test", |_, _| ListenerAction::Continue);
+        }
+
+        from_file.capture_loop(
+            Box::new(open_file("tests/fixtures/cap.pcap").unwrap()),
+            None,
+        );
+        let bytes = std::fs::read("tests/fixtures/cap.pcap").unwrap();
+        let stream = PcapStream::new(std::io::Cursor::new(bytes)).unwrap();
+        from_stream.capture_loop(Box::new(stream), None);
+
+        let seen = from_file.event_metrics(&1304).seen;
+        assert!(seen > 0);
+        assert_eq!(from_stream.event_metrics(&1304).seen, seen);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_captures() {
+        let node = fixture_node().await;
+        let single = PacketListener::builder()
+            .node(node.clone())
+            .build()
+            .unwrap();
+        single.subscribe(1304, "test", |_, _| ListenerAction::Continue);
+        single.capture_loop(
+            Box::new(open_file("tests/fixtures/cap.pcap").unwrap()),
+            None,
+        );
+        let single_count = single.event_metrics(&1304).seen;
+
+        let listener = PacketListener::builder().node(node).build().unwrap();
+        listener.subscribe(1304, "test", |_, _| ListenerAction::Continue);
+        let captures: Vec<_> = ["en0", "utun0"]
+            .into_iter()
+            .map(|interface| {
+                let listener = listener.clone();
+                std::thread::spawn(move || {
+                    let cap = open_file("tests/fixtures/cap.pcap").unwrap();
+                    let generation = listener.capture_generation.load(Ordering::SeqCst);
+                    listener.capture_loop_until(
+                        Some(interface.to_string()),
+                        Box::new(cap),
+                        None,
+                        None,
+                        generation,
+                    );
+                })
+            })
+            .collect();
+        for capture in captures {
+            capture.join().unwrap();
+        }
+
+        assert!(single_count > 0);
+        assert_eq!(listener.event_metrics(&1304).seen, 2 * single_count);
+    }
+
+    #[tokio::test]
+    async fn test_connection_key() {
+        use tokio_stream::StreamExt;
+
+        let node = fixture_node().await;
+        let listener = PacketListener::builder().node(node).build().unwrap();
+        let stream = listener.packet_stream(8285);
+
+        // the same event from the login and the game servers
+        for source_ip in [vec![172, 65, 243, 165], vec![172, 65, 243, 166]] {
+            listener.process_header(PacketHeader {
+                source_port: 5555,
+                destination_port: 50000,
+                source_ip,
+                destination_ip: vec![192, 168, 1, 43],
+                seq_num: 1,
+                tcp_payload_start: 0,
+                tcp: TcpInfo::default(),
+                body: PING.to_vec(),
+            });
+        }
+        let packets: Vec<Packet> = stream.take(2).collect().await;
+
+        let keys: Vec<_> = packets
+            .iter()
+            .map(|packet| packet.connection.unwrap())
+            .collect();
+        assert_ne!(keys[0], keys[1]);
+        assert_eq!(keys[0].src, "172.65.243.165:5555".parse().unwrap());
+        assert_eq!(keys[0].dst, "192.168.1.43:50000".parse().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_verify_checksums() {
+        let node = fixture_node().await;
+        // a server packet of the fixture, its checksum is valid
+        let mut cap = open_file("tests/fixtures/cap.pcap").unwrap();
+        let frame = (0..5)
+            .map(|_| cap.next_packet().unwrap().data.to_vec())
+            .last()
+            .unwrap();
+        let mut corrupted = frame.clone();
+        *corrupted.last_mut().unwrap() ^= 0xff;
+        assert!(PacketHeader::has_valid_checksum(
+            &frame,
+            LinkLayer::Ethernet
+        ));
+        assert!(!PacketHeader::has_valid_checksum(
+            &corrupted,
+            LinkLayer::Ethernet
+        ));
+
+        let listener = PacketListener::builder()
+            .node(node.clone())
+            .verify_checksums(true)
+            .build()
+            .unwrap();
+        listener.capture_loop(
+            Box::new(MockCapture::new(vec![Ok(corrupted.clone())])),
+            None,
+        );
+        assert!(listener.active_connections().is_empty());
+        listener.capture_loop(Box::new(MockCapture::new(vec![Ok(frame)])), None);
+        assert_eq!(listener.active_connections().len(), 1);
+
+        // without verification the corrupt bytes are reassembled
+        let listener = PacketListener::builder().node(node).build().unwrap();
+        listener.capture_loop(Box::new(MockCapture::new(vec![Ok(corrupted)])), None);
+        assert_eq!(listener.active_connections().len(), 1);
     }
 }
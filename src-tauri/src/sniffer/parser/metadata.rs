@@ -1,3 +1,8 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+};
+
 use anyhow::Result;
 use thiserror::Error;
 
@@ -16,13 +21,129 @@ pub enum ParseResult {
     Incomplete,
     #[error("Missing header")]
     MissingHeader(usize),
+    /// The snaplen cut the frame before the end of its IP packet
+    #[error("Packet is truncated, {captured} of {declared} bytes were captured")]
+    Truncated { declared: usize, captured: usize },
 }
 
 #[derive(Debug, Clone)]
 pub struct PacketMetadata {
-    pub data: Vec<u8>,
+    /// Content of the message, parsed and kept by the packet without another copy
+    pub data: Arc<[u8]>,
     pub id: u16,
-    pub size: u16,
+    /// Length of the content, up to 3 bytes long, so bodies can be larger than 65535 bytes
+    pub size: u32,
+    /// Bytes of the header and length before the content
+    pub header_len: usize,
+    /// Bytes of the buffer taken by the message, `header_len` plus the content
+    pub consumed: usize,
+    /// Counter the client sends with each of its messages, `None` for the server messages
+    pub instance_id: Option<u32>,
+}
+
+/// Side of the connection that sent a message, the headers of the client carry an instance id
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MessageSource {
+    #[default]
+    Server,
+    Client,
+}
+
+impl MessageSource {
+    /// Segments sent to one of the game `ports` come from the client
+    pub fn of(header: &PacketHeader, ports: &[u16]) -> Self {
+        match ports.contains(&header.destination_port) {
+            true => MessageSource::Client,
+            false => MessageSource::Server,
+        }
+    }
+}
+
+/// Link-layer encapsulation in front of the IP header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkLayer {
+    Ethernet,
+    /// BSD loopback (`lo0` on macOS), a 4 bytes protocol family
+    Loopback,
+    /// Linux cooked capture, used by the `any` device
+    LinuxSll,
+    LinuxSll2,
+    /// No link-layer header, the frame starts with the IP header
+    RawIp,
+}
+
+impl LinkLayer {
+    /// `None` for the datalinks we can't parse
+    #[cfg(feature = "capture")]
+    pub fn from_linktype(linktype: pcap::Linktype) -> Option<Self> {
+        match linktype {
+            pcap::Linktype::ETHERNET => Some(LinkLayer::Ethernet),
+            pcap::Linktype::NULL | pcap::Linktype::LOOP => Some(LinkLayer::Loopback),
+            pcap::Linktype::LINUX_SLL => Some(LinkLayer::LinuxSll),
+            pcap::Linktype::LINUX_SLL2 => Some(LinkLayer::LinuxSll2),
+            pcap::Linktype::RAW | pcap::Linktype::IPV4 => Some(LinkLayer::RawIp),
+            _ => None,
+        }
+    }
+
+    pub fn header_length(&self) -> usize {
+        match self {
+            LinkLayer::Ethernet => 14,
+            LinkLayer::Loopback => 4,
+            LinkLayer::LinuxSll => 16,
+            LinkLayer::LinuxSll2 => 20,
+            LinkLayer::RawIp => 0,
+        }
+    }
+}
+
+/// Both ends of the TCP connection a packet was sent on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ConnectionKey {
+    pub src: SocketAddr,
+    pub dst: SocketAddr,
+}
+
+impl ConnectionKey {
+    /// Same connection seen from the packets sent the other way
+    pub fn reversed(&self) -> Self {
+        ConnectionKey {
+            src: self.dst,
+            dst: self.src,
+        }
+    }
+}
+
+/// Fields of the TCP header that aren't needed to find the payload
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TcpInfo {
+    pub seq: u32,
+    pub ack: u32,
+    pub flags: u8,
+    pub window: u16,
+}
+
+impl TcpInfo {
+    pub const FIN: u8 = 0x01;
+    pub const SYN: u8 = 0x02;
+    pub const RST: u8 = 0x04;
+    pub const PSH: u8 = 0x08;
+    pub const ACK: u8 = 0x10;
+
+    /// Opens a connection, the flag is also set on the SYN-ACK answer
+    pub fn is_syn(&self) -> bool {
+        self.flags & TcpInfo::SYN != 0
+    }
+
+    /// The sender has nothing more to send
+    pub fn is_fin(&self) -> bool {
+        self.flags & TcpInfo::FIN != 0
+    }
+
+    /// The connection is aborted, nothing more is sent either way
+    pub fn is_rst(&self) -> bool {
+        self.flags & TcpInfo::RST != 0
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -30,81 +151,404 @@ pub struct PacketHeader {
     pub source_port: u16,
     pub destination_port: u16,
     pub source_ip: Vec<u8>,
+    pub destination_ip: Vec<u8>,
     pub seq_num: u16,
     pub tcp_payload_start: usize,
+    pub tcp: TcpInfo,
     pub body: Vec<u8>,
 }
 
 impl PacketHeader {
     pub fn from_vec(data: &Vec<u8>) -> Result<Self, ParseResult> {
+        PacketHeader::from_link_layer(data, LinkLayer::Ethernet)
+    }
+
+    pub fn from_link_layer(data: &[u8], link_layer: LinkLayer) -> Result<Self, ParseResult> {
         // Structure of a packet:
-        // Ethernet header (14 bytes)
+        // Link-layer header (14 bytes for Ethernet)
         // IP header (20 bytes)
         // TCP header (20 bytes)
         // Data
 
-        if data.len() < 54 {
+        let eth_header_length = link_layer.header_length();
+        if data.len() < eth_header_length + 40 {
             return Err(ParseResult::MissingHeader(data.len()));
         }
 
-        let eth_header_length = 14;
         let ip_header_length = ((data[eth_header_length] & 0x0F) as usize) * 4;
+        if ip_header_length < 20 || data.len() < eth_header_length + ip_header_length + 20 {
+            // the header lengths claim more bytes than the frame holds
+            return Err(ParseResult::Invalid);
+        }
         let seq_num =
             u16::from_be_bytes([data[eth_header_length + 4], data[eth_header_length + 5]]);
         let ip_source = &data[eth_header_length + 12..eth_header_length + 16];
+        let ip_destination = &data[eth_header_length + 16..eth_header_length + 20];
         let tcp_start = eth_header_length + ip_header_length;
         let tcp_header_length = ((data[tcp_start + 12] >> 4) as usize) * 4;
         let tcp_payload_start = tcp_start + tcp_header_length;
+        if tcp_header_length < 20 || data.len() < tcp_payload_start {
+            // Packet is too short to contain a TCP payload
+            return Err(ParseResult::Invalid);
+        }
+        // the frame can be padded past the IP packet, 0 is left by segmentation offload
+        let ip_total_length = match data[eth_header_length] >> 4 {
+            4 => u16::from_be_bytes([data[eth_header_length + 2], data[eth_header_length + 3]]),
+            _ => 0,
+        } as usize;
+        let payload_end = match ip_total_length {
+            0 => data.len(),
+            length if eth_header_length + length < tcp_payload_start => {
+                return Err(ParseResult::Invalid)
+            }
+            length if eth_header_length + length > data.len() => {
+                return Err(ParseResult::Truncated {
+                    declared: eth_header_length + length,
+                    captured: data.len(),
+                })
+            }
+            length => eth_header_length + length,
+        };
 
         let source_port = u16::from_be_bytes([data[tcp_start], data[tcp_start + 1]]);
         let destination_port = u16::from_be_bytes([data[tcp_start + 2], data[tcp_start + 3]]);
+        let tcp = TcpInfo {
+            seq: u32::from_be_bytes(data[tcp_start + 4..tcp_start + 8].try_into().unwrap()),
+            ack: u32::from_be_bytes(data[tcp_start + 8..tcp_start + 12].try_into().unwrap()),
+            flags: data[tcp_start + 13],
+            window: u16::from_be_bytes([data[tcp_start + 14], data[tcp_start + 15]]),
+        };
 
-        if data.len() < tcp_payload_start {
-            // Packet is too short to contain a TCP payload
-            return Err(ParseResult::Invalid);
-        }
         Ok(PacketHeader {
             source_port,
             destination_port,
             source_ip: ip_source.to_vec(),
+            destination_ip: ip_destination.to_vec(),
             seq_num,
             tcp_payload_start,
-            body: data[tcp_payload_start..].to_vec(),
+            tcp,
+            body: data[tcp_payload_start..payload_end].to_vec(),
         })
     }
-}
 
-impl PacketMetadata {
-    pub fn from_buffer(body: Vec<u8>) -> Result<Self, ParseResult> {
-        if body.len() < 3 {
-            return Err(ParseResult::Invalid);
+    pub fn connection_key(&self) -> ConnectionKey {
+        ConnectionKey {
+            src: SocketAddr::new(ip_addr(&self.source_ip), self.source_port),
+            dst: SocketAddr::new(ip_addr(&self.destination_ip), self.destination_port),
+        }
+    }
+
+    /// Checks the TCP checksum of an IPv4 frame, with its pseudo header.
+    /// A zero checksum is accepted, NICs offloading checksums leave it unset on capture.
+    pub fn has_valid_checksum(data: &[u8], link_layer: LinkLayer) -> bool {
+        let Some(ip) = data.get(link_layer.header_length()..) else {
+            return false;
+        };
+        if ip.len() < 20 {
+            return false;
         }
+        let ip_header_length = ((ip[0] & 0x0F) as usize) * 4;
+        // frames can be padded past the end of the IP packet
+        let total_length = (u16::from_be_bytes([ip[2], ip[3]]) as usize).min(ip.len());
+        let Some(tcp) = ip.get(ip_header_length..total_length) else {
+            return false;
+        };
+        if tcp.len() < 20 {
+            return false;
+        }
+        if tcp[16] == 0 && tcp[17] == 0 {
+            return true;
+        }
+
+        let mut sum: u32 = 0;
+        let mut add = |bytes: &[u8]| {
+            for word in bytes.chunks(2) {
+                sum += u16::from_be_bytes([word[0], *word.get(1).unwrap_or(&0)]) as u32;
+            }
+        };
+        add(&ip[12..20]);
+        add(&[0, 6]);
+        add(&(tcp.len() as u16).to_be_bytes());
+        add(tcp);
+        while sum > 0xffff {
+            sum = (sum & 0xffff) + (sum >> 16);
+        }
+        sum == 0xffff
+    }
 
-        //  [header sur 2 octets][taille du contenu sur 1, 2 ou 3 octets][contenu]
-        // (id_du_message) << 2 + type de taille
+    /// Moves the body out of the header, leaving an empty body behind.
+    pub fn take_body(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.body)
+    }
+}
+
+/// Unspecified when the address is neither IPv4 nor IPv6, injected packets may have no address
+fn ip_addr(bytes: &[u8]) -> IpAddr {
+    if let Ok(bytes) = <[u8; 4]>::try_from(bytes) {
+        return IpAddr::V4(Ipv4Addr::from(bytes));
+    }
+    if let Ok(bytes) = <[u8; 16]>::try_from(bytes) {
+        return IpAddr::V6(Ipv6Addr::from(bytes));
+    }
+    IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+}
+
+/// Header of a message, it can be read before the content is received
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessageHeader {
+    pub id: u16,
+    /// Number of length bytes, from 0 to 3
+    pub size_type: u8,
+    pub content_size: usize,
+    /// Bytes of the header and length before the content
+    pub header_len: usize,
+    pub instance_id: Option<u32>,
+}
+
+impl MessageHeader {
+    /// Reads the header at the start of `body`, see `PacketMetadata::from_buffer` for its layout
+    pub fn read(body: &[u8], source: MessageSource) -> Result<Self, ParseResult> {
+        // the header itself can be cut by the end of a segment
+        if body.len() < 2 {
+            return Err(ParseResult::Incomplete);
+        }
 
         let header = u16::from_be_bytes([body[0], body[1]]);
         let id = header >> 2;
-        let size_type = header & 0b11;
+        let size_type = (header & 0b11) as u8;
+        let length_start = match source {
+            MessageSource::Server => 2,
+            MessageSource::Client => 6,
+        };
+        if body.len() < length_start + size_type as usize {
+            return Err(ParseResult::Incomplete);
+        }
+        let instance_id = (source == MessageSource::Client)
+            .then(|| u32::from_be_bytes([body[2], body[3], body[4], body[5]]));
+        let length = &body[length_start..];
         let content_size = match size_type {
-            0 => 0,                                                           // 0 bytes
-            1 => u32::from_be_bytes([0, 0, 0, body[2]]) as usize,             // 1 bytes
-            2 => u32::from_be_bytes([0, 0, body[2], body[3]]) as usize,       // 2 bytes
-            3 => u32::from_be_bytes([0, body[2], body[3], body[4]]) as usize, // 3 bytes
+            0 => 0,                                                                 // 0 bytes
+            1 => u32::from_be_bytes([0, 0, 0, length[0]]) as usize,                 // 1 bytes
+            2 => u32::from_be_bytes([0, 0, length[0], length[1]]) as usize,         // 2 bytes
+            3 => u32::from_be_bytes([0, length[0], length[1], length[2]]) as usize, // 3 bytes
             _ => return Err(ParseResult::Invalid),
         };
 
-        let content_start = 2 + size_type as usize;
+        Ok(MessageHeader {
+            id,
+            size_type,
+            content_size,
+            header_len: length_start + size_type as usize,
+            instance_id,
+        })
+    }
+
+    /// Whether the game could have written it: it never sends id 0
+    /// and writes the length on the fewest bytes that hold it.
+    pub fn is_plausible(&self) -> bool {
+        let fewest = match self.content_size {
+            0 => 0,
+            1..=0xff => 1,
+            0x100..=0xffff => 2,
+            _ => 3,
+        };
+        self.id != 0 && self.size_type == fewest
+    }
+}
+
+impl PacketMetadata {
+    pub fn body_len(&self) -> usize {
+        self.size as usize
+    }
+
+    /// Frames the first message of `body`, sent by the server:
+    /// `[header: u16][length: 0 to 3 bytes][content]`, all big endian.
+    /// The header is `id << 2 | size_type`, the 2 low bits are the number of length bytes,
+    /// so the id is the 14 high bits and a `size_type` of 0 means an empty content.
+    pub fn from_buffer(body: Vec<u8>) -> Result<Self, ParseResult> {
+        PacketMetadata::from_buffer_sent_by(&body, MessageSource::Server)
+    }
+
+    /// Same as `from_buffer`, the messages of the client have their instance id after the header:
+    /// `[header: u16][instance id: u32][length: 0 to 3 bytes][content]`.
+    pub fn from_buffer_sent_by(body: &[u8], source: MessageSource) -> Result<Self, ParseResult> {
+        let MessageHeader {
+            id,
+            content_size,
+            header_len: content_start,
+            instance_id,
+            ..
+        } = MessageHeader::read(body, source)?;
         if body.len() < (content_start + content_size) {
             return Err(ParseResult::Incomplete);
         }
 
-        let body = body[content_start..content_start + content_size].to_vec();
+        let body = Arc::from(&body[content_start..content_start + content_size]);
 
         Ok(PacketMetadata {
             data: body,
             id,
-            size: content_size as u16,
+            size: content_size as u32,
+            header_len: content_start,
+            consumed: content_start + content_size,
+            instance_id,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_size_types() {
+        // (bytes, id, size, plausible)
+        let cases: [(Vec<u8>, u16, u32, bool); 5] = [
+            // BasicPingMessage without content, size_type 0
+            (vec![0x81, 0x74], 8285, 0, true),
+            // size_type 1
+            (vec![0x81, 0x75, 0x01, 0x01], 8285, 1, true),
+            // ProtocolRequired, size_type 2, the game would write this length on 1 byte
+            (vec![0x9b, 0x0a, 0x00, 0x02, 0x00, 0x00], 9922, 2, false),
+            // ProtocolRequired, size_type 3
+            (
+                vec![0x9b, 0x0b, 0x00, 0x00, 0x02, 0x00, 0x00],
+                9922,
+                2,
+                false,
+            ),
+            // id 0 is the base class of the messages, it is never sent
+            (vec![0x00, 0x00], 0, 0, false),
+        ];
+        for (bytes, id, size, plausible) in cases {
+            let metadata = PacketMetadata::from_buffer(bytes.clone()).unwrap();
+            assert_eq!((metadata.id, metadata.size), (id, size), "{:02x?}", bytes);
+            assert_eq!(metadata.data.len(), size as usize);
+            let header = MessageHeader::read(&bytes, MessageSource::Server).unwrap();
+            assert_eq!(header.is_plausible(), plausible, "{:02x?}", bytes);
+        }
+    }
+
+    #[test]
+    fn test_client_header() {
+        // BasicPingMessage of the client, instance id 42, then the next message
+        let bytes = vec![0x81, 0x75, 0x00, 0x00, 0x00, 0x2a, 0x01, 0x01, 0x81];
+        let metadata = PacketMetadata::from_buffer_sent_by(&bytes, MessageSource::Client).unwrap();
+        assert_eq!((metadata.id, metadata.instance_id), (8285, Some(42)));
+        assert_eq!((metadata.header_len, metadata.consumed), (7, 8));
+        assert_eq!(&*metadata.data, [0x01]);
+
+        // the header alone is not enough to frame it
+        assert!(matches!(
+            PacketMetadata::from_buffer_sent_by(&bytes[..5], MessageSource::Client),
+            Err(ParseResult::Incomplete)
+        ));
+        assert_eq!(
+            PacketMetadata::from_buffer(bytes).unwrap().instance_id,
+            None
+        );
+    }
+
+    #[test]
+    fn test_three_bytes_length() {
+        let size = 70_000;
+        let mut body = vec![0x6c, 0x0f, 0x01, 0x11, 0x70];
+        body.extend(vec![0; size]);
+
+        let metadata = PacketMetadata::from_buffer(body.clone()).unwrap();
+        assert_eq!(metadata.id, 6915);
+        assert_eq!(metadata.size, size as u32);
+        assert_eq!(metadata.data.len(), size);
+
+        // the header and the length bytes themselves can be split across segments
+        for cut in 1..5 {
+            assert!(matches!(
+                PacketMetadata::from_buffer(body[..cut].to_vec()),
+                Err(ParseResult::Incomplete)
+            ));
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "capture")]
+    fn test_consumed() {
+        // each segment of the loopback capture carries exactly one message
+        let mut cap = pcap::Capture::from_file("tests/fixtures/loopback.pcap").unwrap();
+        while let Ok(packet) = cap.next_packet() {
+            let header = PacketHeader::from_link_layer(packet.data, LinkLayer::Loopback).unwrap();
+            let metadata = PacketMetadata::from_buffer(header.body.clone()).unwrap();
+            assert_eq!(metadata.consumed, header.body.len());
+            assert_eq!(metadata.header_len + metadata.body_len(), metadata.consumed);
+        }
+
+        // bytes of the next message are not consumed
+        let metadata =
+            PacketMetadata::from_buffer(vec![0x81, 0x75, 0x01, 0x01, 0x81, 0x75]).unwrap();
+        assert_eq!((metadata.header_len, metadata.consumed), (3, 4));
+    }
+
+    #[test]
+    #[cfg(feature = "capture")]
+    fn test_cooked_and_raw_captures() {
+        // the loopback capture with its link-layer header replaced
+        for (path, expected) in [
+            ("tests/fixtures/sll.pcap", LinkLayer::LinuxSll),
+            ("tests/fixtures/raw_ip.pcap", LinkLayer::RawIp),
+        ] {
+            let mut cap = pcap::Capture::from_file(path).unwrap();
+            let link_layer = LinkLayer::from_linktype(cap.get_datalink()).unwrap();
+            assert_eq!(link_layer, expected);
+
+            let mut ids = Vec::new();
+            while let Ok(packet) = cap.next_packet() {
+                let header = PacketHeader::from_link_layer(packet.data, link_layer).unwrap();
+                assert_eq!(header.source_port, 5555);
+                ids.push(PacketMetadata::from_buffer(header.body).unwrap().id);
+            }
+            assert_eq!(ids, vec![9359, 4642, 4794, 7848, 2680], "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_malformed_header_lengths() {
+        // IPv4 and TCP headers of 20 bytes without options
+        let mut frame = vec![0; 40];
+        frame[0] = 0x45;
+        frame[32] = 0x50;
+
+        let header = PacketHeader::from_link_layer(&frame, LinkLayer::RawIp).unwrap();
+        assert_eq!(header.tcp_payload_start, 40);
+        assert!(header.body.is_empty());
+
+        // a 60 bytes IP header runs past the end of the frame
+        let mut overrun = frame.clone();
+        overrun[0] = 0x4f;
+        assert!(matches!(
+            PacketHeader::from_link_layer(&overrun, LinkLayer::RawIp),
+            Err(ParseResult::Invalid)
+        ));
+        // so does a 60 bytes TCP header
+        let mut overrun = frame;
+        overrun[32] = 0xf0;
+        assert!(matches!(
+            PacketHeader::from_link_layer(&overrun, LinkLayer::RawIp),
+            Err(ParseResult::Invalid)
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "capture")]
+    fn test_loopback_capture() {
+        let mut cap = pcap::Capture::from_file("tests/fixtures/loopback.pcap").unwrap();
+        let link_layer = LinkLayer::from_linktype(cap.get_datalink()).unwrap();
+        assert_eq!(link_layer, LinkLayer::Loopback);
+
+        let mut ids = Vec::new();
+        while let Ok(packet) = cap.next_packet() {
+            let header = PacketHeader::from_link_layer(packet.data, link_layer).unwrap();
+            assert_eq!(header.source_port, 5555);
+            ids.push(PacketMetadata::from_buffer(header.body).unwrap().id);
+        }
+        assert_eq!(ids, vec![9359, 4642, 4794, 7848, 2680]);
+    }
+}
@@ -1,69 +1,347 @@
+use std::{borrow::Cow, io::Read, ops::Deref, sync::Arc, time::SystemTime};
+
+use flate2::read::ZlibDecoder;
 use indexmap::IndexMap;
-use serde_json::{Map, Number, Value};
+use serde::de::DeserializeOwned;
+use serde_json::{Number, Value};
 use thiserror::Error;
 use tracing::debug;
 
 use crate::sniffer::protocol::protocol::{
-    EventId, EventName, ProtocolManager, ProtocolSchema, ProtocolVarType,
+    Attribute, EventId, EventName, FieldName, ProtocolError, ProtocolManager, ProtocolSchema,
+    ProtocolVarType, ProtocolVarTypeVector,
 };
 
 use super::{
-    metadata::PacketMetadata,
+    metadata::{ConnectionKey, MessageSource, PacketMetadata, ParseResult, TcpInfo},
     wrapper::{DataWrapper, ParseError},
 };
 
-type PacketData = Map<String, Value>;
+/// Decoded fields in wire order, the ones of the parents first
+type PacketData = IndexMap<FieldName, Value>;
+
+/// Inflated container bodies larger than this are rejected, so a small message can't exhaust memory
+const MAX_INFLATED_SIZE: usize = 4 * 1024 * 1024;
+
+/// Containers nested deeper than this are rejected, each level can inflate up to `MAX_INFLATED_SIZE`
+const MAX_CONTAINER_DEPTH: usize = 4;
+
+/// Whether a flag field enables the optional fields depending on it
+fn is_set(value: &Value) -> bool {
+    match value {
+        Value::Bool(value) => *value,
+        Value::Number(value) => value.as_i64().map_or(false, |value| value != 0),
+        _ => false,
+    }
+}
+
+/// Decodes a single framed message, without going through the capture and the listeners
+pub fn decode_message(protocol: &ProtocolManager, bytes: &[u8]) -> Result<Packet, PacketError> {
+    let metadata =
+        PacketMetadata::from_buffer(bytes.to_vec()).map_err(PacketError::InvalidMessage)?;
+    PacketParser::from_metadata(&metadata).parse(protocol)
+}
 
 #[derive(Debug, Clone)]
 pub struct Packet {
     pub id: u16,
     pub name: EventName,
     pub data: PacketData,
+    /// Set for captured packets, `None` when parsed from a bare buffer
+    pub connection: Option<ConnectionKey>,
+    /// Instance id of the header, only the messages sent by the client have one
+    pub instance_id: Option<u32>,
+    /// TCP header of the segment that completed the message, `None` when parsed from a bare buffer
+    pub tcp: Option<TcpInfo>,
+    /// Body of the message, shared by the clones of the packet, see `raw`
+    pub raw: Option<Arc<[u8]>>,
+    /// Capture time of the segment that completed the message, `None` when parsed from a bare buffer
+    pub timestamp: Option<SystemTime>,
 }
 
-impl Packet {}
+/// Typed reads of the decoded fields, `None` when the field is missing or has another type
+impl Packet {
+    /// Class name from the protocol the packet was parsed with, `Unknown(id)` for a packet built without one
+    pub fn event_name(&self) -> Cow<'_, str> {
+        match self.name.is_empty() {
+            true => Cow::Owned(format!("Unknown({})", self.id)),
+            false => Cow::Borrowed(&self.name),
+        }
+    }
+
+    /// Bytes of the message body the fields were decoded from, without its header.
+    /// The body of a container is the inflated message. `None` for a packet built by hand.
+    pub fn raw(&self) -> Option<&[u8]> {
+        self.raw.as_deref()
+    }
+
+    pub fn get(&self, field: &str) -> Option<&Value> {
+        self.data.get(field)
+    }
+
+    /// Same as `get` with a field resolved once by `FieldAccessor`, usually without hashing its name
+    pub fn get_with(&self, accessor: &FieldAccessor) -> Option<&Value> {
+        if self.id != accessor.event {
+            return None;
+        }
+        match self.data.get_index(accessor.index) {
+            Some((name, value)) if *name == accessor.field => Some(value),
+            // an absent optional field before it shifted the position
+            _ => self.get(&accessor.field),
+        }
+    }
+
+    pub fn get_int(&self, field: &str) -> Option<i64> {
+        self.get(field)?.as_i64()
+    }
+
+    pub fn get_string(&self, field: &str) -> Option<&str> {
+        self.get(field)?.as_str()
+    }
+
+    pub fn get_bool(&self, field: &str) -> Option<bool> {
+        self.get(field)?.as_bool()
+    }
+
+    /// Vectors of bytes, signed values are read as their two's complement
+    pub fn get_bytes(&self, field: &str) -> Option<Vec<u8>> {
+        self.get_array(field)?
+            .iter()
+            .map(|value| match value.as_i64()? {
+                byte @ -128..=255 => Some(byte as u8),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn get_array(&self, field: &str) -> Option<&Vec<Value>> {
+        self.get(field)?.as_array()
+    }
+
+    /// Fields of `self` that differ from the `expected` packet, in the order of `expected` then the extra ones
+    pub fn diff(&self, expected: &Packet) -> Vec<FieldDiff> {
+        let mut diffs: Vec<FieldDiff> = expected
+            .data
+            .iter()
+            .filter_map(|(field, expected)| match self.data.get(field) {
+                None => Some(FieldDiff::Missing {
+                    field: field.clone(),
+                    expected: expected.clone(),
+                }),
+                Some(actual) if actual != expected => Some(FieldDiff::Changed {
+                    field: field.clone(),
+                    expected: expected.clone(),
+                    actual: actual.clone(),
+                }),
+                Some(_) => None,
+            })
+            .collect();
+        diffs.extend(
+            self.data
+                .iter()
+                .filter(|(field, _)| !expected.data.contains_key(*field))
+                .map(|(field, actual)| FieldDiff::Extra {
+                    field: field.clone(),
+                    actual: actual.clone(),
+                }),
+        );
+        diffs
+    }
+
+    /// Decodes the fields into `T`, matched by name. `Option` fields may be missing.
+    pub fn deserialize_into<T: DeserializeOwned>(&self) -> Result<T, serde_json::Error> {
+        serde_json::from_value(Value::Object(self.data.clone().into_iter().collect()))
+    }
+}
+
+/// Packets are equal when they are of the same event with the same fields, wherever they were captured
+impl PartialEq for Packet {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id && self.data == other.data
+    }
+}
+
+/// Difference of a decoded field with a snapshot, see `Packet::diff`
+#[derive(Debug, Clone, PartialEq)]
+pub enum FieldDiff {
+    Missing {
+        field: FieldName,
+        expected: Value,
+    },
+    Extra {
+        field: FieldName,
+        actual: Value,
+    },
+    Changed {
+        field: FieldName,
+        expected: Value,
+        actual: Value,
+    },
+}
+
+/// Position of a field in the packets of an event, built once by the listeners reading it often
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldAccessor {
+    event: EventId,
+    field: FieldName,
+    index: usize,
+}
+
+impl FieldAccessor {
+    /// `None` when `event` doesn't have `field`, its parents included
+    pub fn new(protocol_manager: &ProtocolManager, event: EventId, field: &str) -> Option<Self> {
+        let index = protocol_manager
+            .field_names(&event)?
+            .iter()
+            .position(|name| *name == field)?;
+        Some(FieldAccessor {
+            event,
+            field: field.to_string(),
+            index,
+        })
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct PacketParser {
     pub id: u16,
     pub data: DataWrapper,
+    pub instance_id: Option<u32>,
+    /// Fails on the messages whose fields don't take their whole body, see `strict`
+    strict: bool,
 }
 
 impl PacketParser {
-    pub fn new(id: u16, data: DataWrapper) -> Self {
-        PacketParser { id, data }
+    pub fn new<B: Deref<Target = [u8]> + Into<Arc<[u8]>>>(id: u16, data: DataWrapper<B>) -> Self {
+        PacketParser {
+            id,
+            data: data.into_shared(),
+            instance_id: None,
+            strict: false,
+        }
+    }
+
+    /// Bytes left after the last field mean the protocol definition is wrong or incomplete.
+    /// They are only logged by default, in strict mode the message fails with `LengthMismatch`.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
     }
 
     pub fn from_metadata(meta: &PacketMetadata) -> Self {
-        PacketParser::new(meta.id, DataWrapper::new(meta.data.clone()))
+        PacketParser {
+            instance_id: meta.instance_id,
+            ..PacketParser::new(meta.id, DataWrapper::new(meta.data.clone()))
+        }
     }
 
+    /// Parses the message, the first one it carries for a data container, see `parse_messages`
     pub fn parse(&mut self, protocol_manager: &ProtocolManager) -> Result<Packet, PacketError> {
-        match protocol_manager.get_protocol(&self.id) {
-            Some(event) => {
-                let data = self.parse_packet_data(protocol_manager, event)?;
+        self.parse_messages(protocol_manager)?
+            .into_iter()
+            .next()
+            .ok_or(PacketError::InvalidContainer(ParseResult::Incomplete))
+    }
 
-                // check if there is any data left
-                if !self.data.get_remaining().is_empty() {
-                    debug!("Data left after parsing: {:?}", self.data.get_remaining());
-                }
+    /// Parses the message, or every message a data container carries, nested containers included
+    pub fn parse_messages(
+        &mut self,
+        protocol_manager: &ProtocolManager,
+    ) -> Result<Vec<Packet>, PacketError> {
+        self.parse_at_depth(protocol_manager, 0)
+    }
 
-                Ok(Packet {
-                    id: self.id,
-                    name: event.name.clone(),
-                    data,
-                })
+    fn parse_at_depth(
+        &mut self,
+        protocol_manager: &ProtocolManager,
+        depth: usize,
+    ) -> Result<Vec<Packet>, PacketError> {
+        match protocol_manager.get_protocol(&self.id) {
+            Some(event) if event.is_data_container() => {
+                if depth >= MAX_CONTAINER_DEPTH {
+                    return Err(PacketError::ContainerTooDeep(MAX_CONTAINER_DEPTH));
+                }
+                let mut packets = Vec::new();
+                for metadata in self.inflate_container()? {
+                    packets.extend(
+                        PacketParser::from_metadata(&metadata)
+                            .strict(self.strict)
+                            .parse_at_depth(protocol_manager, depth + 1)?,
+                    );
+                }
+                Ok(packets)
             }
+            Some(event) => self
+                .parse_event(protocol_manager, event)
+                .map(|packet| vec![packet]),
             None => Err(PacketError::UnknownPacketType(self.id)),
         }
     }
 
+    fn parse_event(
+        &mut self,
+        protocol_manager: &ProtocolManager,
+        event: &ProtocolSchema,
+    ) -> Result<Packet, PacketError> {
+        let data = self.parse_packet_data(protocol_manager, event)?;
+
+        // check if there is any data left
+        let left = self.data.remaining();
+        if left > 0 {
+            if self.strict {
+                return Err(PacketError::LengthMismatch {
+                    event: self.id,
+                    declared: self.data.pos + left,
+                    consumed: self.data.pos,
+                });
+            }
+            debug!("Data left after parsing: {:?}", self.data.get_remaining());
+        }
+
+        Ok(Packet {
+            id: self.id,
+            name: event.name.clone(),
+            data,
+            connection: None,
+            instance_id: self.instance_id,
+            tcp: None,
+            raw: Some(self.data.data.clone()),
+            timestamp: None,
+        })
+    }
+
+    /// Frames the messages of a data container, its compressed body holds one after the other
+    fn inflate_container(&mut self) -> Result<Vec<PacketMetadata>, PacketError> {
+        let length = self.data.checked_read_var_int()? as usize;
+        if length > self.data.remaining() {
+            return Err(PacketError::InvalidContainer(ParseResult::Incomplete));
+        }
+        let compressed = &self.data.get_remaining()[..length];
+        let mut inflated = Vec::new();
+        ZlibDecoder::new(compressed)
+            .take(MAX_INFLATED_SIZE as u64 + 1)
+            .read_to_end(&mut inflated)?;
+        self.data.set_pos(self.data.pos + length);
+        if inflated.len() > MAX_INFLATED_SIZE {
+            return Err(PacketError::InflatedTooLarge(MAX_INFLATED_SIZE));
+        }
+        let mut messages = Vec::new();
+        let mut inflated = inflated.as_slice();
+        while !inflated.is_empty() {
+            let metadata = PacketMetadata::from_buffer_sent_by(inflated, MessageSource::Server)
+                .map_err(PacketError::InvalidContainer)?;
+            inflated = &inflated[metadata.consumed..];
+            messages.push(metadata);
+        }
+        Ok(messages)
+    }
+
     fn parse_packet_data(
         &mut self,
         protocol_manager: &ProtocolManager,
         event: &ProtocolSchema,
     ) -> Result<PacketData, PacketError> {
-        let mut data = Map::new();
+        let mut data = PacketData::new();
 
         if let Some(parent) = &event.parent {
             let parent_type = protocol_manager.get_protocol_by_class(&parent);
@@ -85,13 +363,39 @@ impl PacketParser {
     fn parse_packet_attributes(
         &mut self,
         protocol_manager: &ProtocolManager,
-        attributes: &IndexMap<String, ProtocolVarType>,
+        attributes: &IndexMap<String, Attribute>,
     ) -> Result<PacketData, PacketError> {
-        let mut data = Map::new();
+        let mut data = PacketData::new();
 
-        for (name, var_type) in attributes {
+        for (name, Attribute { var_type, .. }) in attributes {
             // info!("Parsing attribute: {} {:?}", name, var_type);
-            let value = self.parse_attribute(protocol_manager, var_type)?;
+            let optional = var_type.parse_optional()?;
+            let var_type = match &optional {
+                Some(optional) => {
+                    let present = match &optional.flag {
+                        Some(flag) => data.get(flag).map_or(false, is_set),
+                        None => is_set(
+                            &self.parse_attribute(protocol_manager, &ProtocolVarType::Boolean)?,
+                        ),
+                    };
+                    // absent values are not on the wire, the field is left out
+                    if !present {
+                        continue;
+                    }
+                    &optional.types
+                }
+                None => var_type,
+            };
+            let value =
+                self.parse_attribute(protocol_manager, var_type)
+                    .map_err(|err| match err {
+                        PacketError::UnknownType(type_name) => PacketError::UnknownFieldType {
+                            event: self.id,
+                            field: name.clone(),
+                            type_name,
+                        },
+                        err => err,
+                    })?;
             // debug!("Parsed attribute: {} {:?} => {:?}", name, var_type, value);
             data.insert(name.clone(), value);
         }
@@ -107,11 +411,15 @@ impl PacketParser {
         std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
             let res = match var_type {
                 ProtocolVarType::Byte => {
+                    let value = self.data.read_signed_byte();
+                    Value::Number(Number::from(value))
+                }
+                ProtocolVarType::UnsignedByte => {
                     let value = self.data.read_byte();
                     Value::Number(Number::from(value))
                 }
                 ProtocolVarType::String => {
-                    let value = self.data.read_utf();
+                    let value = self.data.read_utf()?;
                     Value::String(value)
                 }
                 ProtocolVarType::VarInt => {
@@ -126,11 +434,27 @@ impl PacketParser {
                     let value = self.data.read_var_long()?;
                     Value::Number(Number::from(value))
                 }
+                ProtocolVarType::ZigZagVarInt => {
+                    let value = self.data.read_zigzag_var_int();
+                    Value::Number(Number::from(value))
+                }
+                ProtocolVarType::ZigZagVarLong => {
+                    let value = self.data.read_zigzag_var_long()?;
+                    Value::Number(Number::from(value))
+                }
                 ProtocolVarType::Int => {
                     let value = self.data.read_int();
                     Value::Number(Number::from(value))
                 }
+                ProtocolVarType::UnsignedInt => {
+                    let value = self.data.read_unsigned_int();
+                    Value::Number(Number::from(value))
+                }
                 ProtocolVarType::Short => {
+                    let value = self.data.read_short();
+                    Value::Number(Number::from(value))
+                }
+                ProtocolVarType::UnsignedShort => {
                     let value = self.data.read_unsigned_short();
                     Value::Number(Number::from(value))
                 }
@@ -139,9 +463,13 @@ impl PacketParser {
                     let value = self.data.read_byte() == 1;
                     Value::Bool(value)
                 }
+                ProtocolVarType::Float => {
+                    let value = self.data.read_float();
+                    Value::Number(Number::from_f64(value as f64).unwrap())
+                }
                 ProtocolVarType::Double => {
                     let value = self.data.read_double();
-                    Value::Number(Number::from_f64(value as f64).unwrap())
+                    Value::Number(Number::from_f64(value).unwrap())
                 }
                 ProtocolVarType::Other(name) => {
                     return self.parse_complexe_type(protocol_manager, name, var_type);
@@ -159,12 +487,19 @@ impl PacketParser {
         var_type: &ProtocolVarType,
     ) -> Result<Value, PacketError> {
         if let Some(vector) = var_type.parse_vector() {
+            if let Some(length) = vector.fixed_length {
+                let mut values = Vec::with_capacity(length);
+                for _ in 0..length {
+                    values.push(self.parse_vector_value(protocol_manager, &vector)?);
+                }
+                return Ok(Value::Array(values));
+            }
             match vector.length {
                 ProtocolVarType::Short => {
                     let length = self.data.read_unsigned_short(); // array length is signed
                     let mut values = Vec::with_capacity(length as usize);
                     for _ in 0..length {
-                        let value = self.parse_attribute(protocol_manager, &vector.types)?;
+                        let value = self.parse_vector_value(protocol_manager, &vector)?;
                         values.push(value);
                     }
                     return Ok(Value::Array(values));
@@ -172,18 +507,45 @@ impl PacketParser {
                 _ => {}
             }
             return Err(PacketError::FailedToParseAttribute(var_type.clone()));
-        } else if let Some(type_id) = var_type.parse_type_id() {
-            return self.parse_attribute(protocol_manager, &type_id);
+        } else if var_type.parse_type_id().is_some() {
+            return self.parse_polymorphic(protocol_manager);
+        } else if let Some(reader) = protocol_manager.get_reader(name) {
+            return Ok(reader(&mut self.data)?);
         } else {
             let schema = protocol_manager.get_protocol_by_class(name);
+            // the size of an unknown type can't be guessed, reading on would desync the message
             if schema.is_none() {
-                return Err(PacketError::UnknownParentType(name.clone()));
+                return Err(PacketError::UnknownType(name.clone()));
             }
             let schema = schema.unwrap();
             let value = self.parse_packet_data(protocol_manager, schema)?;
-            Ok(Value::Object(value))
+            Ok(Value::Object(value.into_iter().collect()))
+        }
+    }
+
+    fn parse_vector_value(
+        &mut self,
+        protocol_manager: &ProtocolManager,
+        vector: &ProtocolVarTypeVector,
+    ) -> Result<Value, PacketError> {
+        match vector.type_id {
+            true => self.parse_polymorphic(protocol_manager),
+            false => self.parse_attribute(protocol_manager, &vector.types),
         }
     }
+
+    /// Reads a `TypeId<T>` value, it is decoded as the concrete type named by the id preceding it
+    fn parse_polymorphic(
+        &mut self,
+        protocol_manager: &ProtocolManager,
+    ) -> Result<Value, PacketError> {
+        let type_id = self.data.read_unsigned_short();
+        let schema = protocol_manager
+            .get_protocol(&type_id)
+            .ok_or(PacketError::UnknownTypeId(type_id))?;
+        let value = self.parse_packet_data(protocol_manager, schema)?;
+        Ok(Value::Object(value.into_iter().collect()))
+    }
 }
 
 #[derive(Debug, Error)]
@@ -192,16 +554,45 @@ pub enum PacketError {
     UnknownParentType(EventName),
     #[error("Unknown packet type")]
     UnknownPacketType(EventId),
+    #[error("Unknown type {0:?}")]
+    UnknownType(String),
+    #[error("Unknown type id {0}")]
+    UnknownTypeId(u16),
+    #[error("Unknown type {type_name:?} for field {field:?} of event {event}")]
+    UnknownFieldType {
+        event: EventId,
+        field: FieldName,
+        type_name: String,
+    },
     #[error("Failed to parse attribute")]
     FailedToParseAttribute(ProtocolVarType),
     #[error("Error while parsing attribute")]
     ParseError(#[from] ParseError),
+    #[error("Failed to inflate container")]
+    Inflate(#[from] std::io::Error),
+    #[error("Inflated container is larger than {0} bytes")]
+    InflatedTooLarge(usize),
+    #[error("Containers are nested more than {0} deep")]
+    ContainerTooDeep(usize),
+    #[error("Invalid container message: {0}")]
+    InvalidContainer(ParseResult),
+    #[error("Invalid message: {0}")]
+    InvalidMessage(ParseResult),
+    #[error(transparent)]
+    InvalidProtocol(#[from] ProtocolError),
+    #[error("Event {event} declares {declared} bytes but its fields took {consumed}, {} are left", .declared - .consumed)]
+    LengthMismatch {
+        event: EventId,
+        declared: usize,
+        consumed: usize,
+    },
 }
 
 #[cfg(test)]
 mod tests {
     use std::path::Path;
 
+    #[cfg(feature = "capture")]
     use crate::node::Node;
 
     use super::*;
@@ -230,6 +621,7 @@ mod tests {
         let path = Path::new(&path);
         let mut procol_manager = ProtocolManager::new();
         procol_manager.init(path).unwrap();
+        #[cfg(feature = "capture")]
         let _ = Node::init_logger(path);
 
         for hex_stream in hex_streams.iter() {
@@ -246,4 +638,527 @@ mod tests {
             info!("Packet: {:?}", packet);
         }
     }
+
+    fn chat_server_message(protocol_manager: &ProtocolManager) -> Packet {
+        let mut bytes = vec![0x05, 0x00, 0x02, b'h', b'i', 0x00, 0x00, 0x00, 0x64];
+        bytes.extend([0x00, 0x01, b'f']);
+        bytes.extend(42.0f64.to_be_bytes());
+        bytes.extend([
+            0x00, 0x03, b'B', b'o', b'b', 0x00, 0x00, 0x00, 0x00, 0x00, 0x07,
+        ]);
+        PacketParser::new(1304, DataWrapper::new(bytes))
+            .parse(protocol_manager)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_typed_accessors() {
+        let mut protocol_manager = ProtocolManager::new();
+        protocol_manager.init("tests/fixtures/").unwrap();
+
+        let packet = chat_server_message(&protocol_manager);
+        assert_eq!(packet.get_int("channel"), Some(5));
+        assert_eq!(packet.get_string("content"), Some("hi"));
+        assert_eq!(packet.get_int("senderAccountId"), Some(7));
+        assert_eq!(packet.get_string("senderName"), Some("Bob"));
+        assert_eq!(packet.get_bool("content"), None);
+        assert_eq!(packet.get_int("unknown"), None);
+
+        // BreachCharactersMessage
+        let bytes = vec![0x00, 0x02, 0x01, 0x02];
+        let packet = PacketParser::new(6915, DataWrapper::new(bytes))
+            .parse(&protocol_manager)
+            .unwrap();
+        assert_eq!(packet.get_array("characters").map(Vec::len), Some(2));
+        assert_eq!(packet.get_bytes("characters"), Some(vec![1, 2]));
+    }
+
+    #[test]
+    fn test_field_order() {
+        // declared out of alphabetical order, each type has a different size
+        let protocol_manager = ProtocolManager::from_json(
+            r#"[{"id": "1", "class_name": "OrderedMessage", "superclass": null, "attributes": {"zeta": "Short", "alpha": "Boolean", "mid": "Int"}}]"#,
+        )
+        .unwrap();
+        let event = protocol_manager.get_protocol(&1).unwrap();
+        assert_eq!(
+            event.attributes.keys().collect::<Vec<_>>(),
+            vec!["zeta", "alpha", "mid"]
+        );
+
+        let packet = PacketParser::new(1, DataWrapper::new(vec![0x01, 0x02, 0x01, 0, 0, 0, 0x2a]))
+            .parse(&protocol_manager)
+            .unwrap();
+        assert_eq!(
+            packet.data.keys().collect::<Vec<_>>(),
+            vec!["zeta", "alpha", "mid"]
+        );
+        assert_eq!(packet.get_int("zeta"), Some(0x0102));
+        assert_eq!(packet.get_bool("alpha"), Some(true));
+        assert_eq!(packet.get_int("mid"), Some(42));
+    }
+
+    #[test]
+    fn test_optional_fields() {
+        let protocol_manager = ProtocolManager::from_json(
+            r#"[{"id": "1", "class_name": "OptionalMessage", "superclass": null, "attributes": {"hasName": "Boolean", "name": "Optional<hasName, String>", "level": "Optional<Short>", "end": "Byte"}}]"#,
+        )
+        .unwrap();
+        let parse = |bytes: Vec<u8>| {
+            PacketParser::new(1, DataWrapper::new(bytes))
+                .parse(&protocol_manager)
+                .unwrap()
+        };
+
+        let packet = parse(vec![0x01, 0x00, 0x02, b'h', b'i', 0x01, 0x00, 0x05, 0x07]);
+        assert_eq!(packet.get_string("name"), Some("hi"));
+        assert_eq!(packet.get_int("level"), Some(5));
+        assert_eq!(packet.get_int("end"), Some(7));
+
+        // neither value is on the wire, the next field is still read at the right offset
+        let packet = parse(vec![0x00, 0x00, 0x07]);
+        assert_eq!(packet.get("name"), None);
+        assert_eq!(packet.get("level"), None);
+        assert_eq!(packet.get_int("end"), Some(7));
+    }
+
+    #[test]
+    fn test_fixed_length_vector() {
+        let protocol_manager = ProtocolManager::from_json(
+            r#"[{"id": "1", "class_name": "StatsMessage", "superclass": null, "attributes": {"stats": "Vector<3, Short>", "names": "Vector<Short, String>"}}]"#,
+        )
+        .unwrap();
+
+        let bytes = vec![
+            0x00, 0x01, 0xff, 0xfe, 0x00, 0x03, // 3 shorts without a length
+            0x00, 0x01, 0x00, 0x01, b'a', // a vector of 1 string
+        ];
+        let packet = PacketParser::new(1, DataWrapper::new(bytes))
+            .parse(&protocol_manager)
+            .unwrap();
+        assert_eq!(packet.data["stats"], serde_json::json!([1, -2, 3]));
+        assert_eq!(packet.data["names"], serde_json::json!(["a"]));
+    }
+
+    #[test]
+    fn test_strict_length() {
+        // the protocol misses the `y` field sent after `x`
+        let protocol_manager = ProtocolManager::from_json(
+            r#"[{"id": "1", "class_name": "PositionMessage", "superclass": null, "attributes": {"x": "Short"}}]"#,
+        )
+        .unwrap();
+        let parser = || PacketParser::new(1, DataWrapper::new(vec![0x00, 0x01, 0x00, 0x02]));
+
+        assert!(parser().parse(&protocol_manager).is_ok());
+        let error = parser().strict(true).parse(&protocol_manager).unwrap_err();
+        assert!(matches!(
+            error,
+            PacketError::LengthMismatch {
+                event: 1,
+                declared: 4,
+                consumed: 2
+            }
+        ));
+        assert_eq!(
+            error.to_string(),
+            "Event 1 declares 4 bytes but its fields took 2, 2 are left"
+        );
+    }
+
+    #[test]
+    fn test_override_attribute() {
+        let mut protocol_manager = ProtocolManager::from_json(
+            r#"[{"id": "1", "class_name": "LevelMessage", "superclass": null, "attributes": {"level": "Short"}}]"#,
+        )
+        .unwrap();
+        let parse = |protocol_manager: &ProtocolManager| {
+            PacketParser::new(1, DataWrapper::new(vec![0xac, 0x02]))
+                .parse(protocol_manager)
+                .unwrap()
+        };
+        assert_eq!(parse(&protocol_manager).get_int("level"), Some(-21502));
+
+        protocol_manager
+            .override_attribute(1, "level", ProtocolVarType::VarShort)
+            .unwrap();
+        assert_eq!(parse(&protocol_manager).get_int("level"), Some(300));
+        assert!(matches!(
+            protocol_manager.override_attribute(1, "missing", ProtocolVarType::Byte),
+            Err(ProtocolError::UnknownField { .. })
+        ));
+        assert!(matches!(
+            protocol_manager.override_attribute(2, "level", ProtocolVarType::Byte),
+            Err(ProtocolError::UnknownEvent(2))
+        ));
+    }
+
+    #[test]
+    fn test_polymorphic_fields() {
+        let protocol_manager = ProtocolManager::from_json(
+            r#"[
+                {"id": "1", "class_name": "ShapesMessage", "superclass": null, "attributes": {"main": "TypeId<Shape>", "others": "TypeIdVector<Short,Shape>"}},
+                {"id": "10", "class_name": "Shape", "superclass": null, "attributes": {"x": "Byte"}},
+                {"id": "11", "class_name": "Circle", "superclass": "Shape", "attributes": {"radius": "Short"}},
+                {"id": "12", "class_name": "Square", "superclass": "Shape", "attributes": {"side": "Byte"}}
+            ]"#,
+        )
+        .unwrap();
+        let parse =
+            |bytes: Vec<u8>| PacketParser::new(1, DataWrapper::new(bytes)).parse(&protocol_manager);
+
+        // a circle, then a vector holding a square
+        let packet = parse(vec![
+            0x00, 0x0b, 0x01, 0x00, 0x05, 0x00, 0x01, 0x00, 0x0c, 0x02, 0x03,
+        ])
+        .unwrap();
+        assert_eq!(
+            packet.data["main"],
+            serde_json::json!({"x": 1, "radius": 5})
+        );
+        assert_eq!(
+            packet.data["others"],
+            serde_json::json!([{"x": 2, "side": 3}])
+        );
+
+        // a square and an empty vector
+        let packet = parse(vec![0x00, 0x0c, 0x04, 0x06, 0x00, 0x00]).unwrap();
+        assert_eq!(packet.data["main"], serde_json::json!({"x": 4, "side": 6}));
+        assert_eq!(packet.data["others"], serde_json::json!([]));
+
+        let error = parse(vec![0x00, 0x63, 0x01]).unwrap_err();
+        assert_eq!(error.to_string(), "Unknown type id 99");
+    }
+
+    #[test]
+    fn test_unknown_type() {
+        let mut protocol_manager = ProtocolManager::new();
+        protocol_manager
+            .init("tests/fixtures/unknown_type/")
+            .unwrap();
+
+        let error = PacketParser::new(1, DataWrapper::new(vec![0x01, 0, 0, 0, 0, 0, 0, 0, 42]))
+            .parse(&protocol_manager)
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            r#"Unknown type "Int64" for field "timestamp" of event 1"#
+        );
+        let error = PacketParser::new(2, DataWrapper::new(vec![0x00, 0x01]))
+            .parse(&protocol_manager)
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            PacketError::UnknownFieldType { event: 2, .. }
+        ));
+
+        protocol_manager.register_reader("Int64", |data| Ok(Value::from(data.read_double())));
+        let packet = PacketParser::new(1, DataWrapper::new(vec![0x01, 0, 0, 0, 0, 0, 0, 0, 42]))
+            .parse(&protocol_manager)
+            .unwrap();
+        assert_eq!(packet.get_bool("quiet"), Some(true));
+        assert!(packet.get("timestamp").is_some());
+    }
+
+    #[test]
+    fn test_field_accessor() {
+        let protocol_manager = ProtocolManager::from_json(
+            r#"[
+                {"id": "1", "class_name": "PositionMessage", "superclass": null, "attributes": {"x": "Short"}},
+                {"id": "2", "class_name": "LevelMessage", "superclass": "PositionMessage", "attributes": {"level": "Optional<Short>", "exp": "Byte"}}
+            ]"#,
+        )
+        .unwrap();
+        let exp = FieldAccessor::new(&protocol_manager, 2, "exp").unwrap();
+        assert_eq!(exp.index, 2);
+        assert_eq!(FieldAccessor::new(&protocol_manager, 2, "missing"), None);
+        assert_eq!(FieldAccessor::new(&protocol_manager, 3, "exp"), None);
+
+        let with_level = PacketParser::new(2, DataWrapper::new(vec![0, 1, 1, 0, 5, 7]))
+            .parse(&protocol_manager)
+            .unwrap();
+        // no level, the position of exp shifts
+        let without_level = PacketParser::new(2, DataWrapper::new(vec![0, 1, 0, 9]))
+            .parse(&protocol_manager)
+            .unwrap();
+        assert_eq!(with_level.get_with(&exp), Some(&Value::from(7)));
+        assert_eq!(without_level.get_with(&exp), Some(&Value::from(9)));
+
+        let x = FieldAccessor::new(&protocol_manager, 1, "x").unwrap();
+        assert_eq!(with_level.get_with(&x), None);
+    }
+
+    #[test]
+    fn test_event_name() {
+        let mut protocol_manager = ProtocolManager::new();
+        protocol_manager.init("tests/fixtures/").unwrap();
+
+        let packet = chat_server_message(&protocol_manager);
+        assert_eq!(packet.event_name(), "ChatServerMessage");
+        let packet = Packet {
+            id: 1234,
+            name: String::new(),
+            data: IndexMap::new(),
+            connection: None,
+            instance_id: None,
+            tcp: None,
+            raw: None,
+            timestamp: None,
+        };
+        assert_eq!(packet.event_name(), "Unknown(1234)");
+
+        assert_eq!(protocol_manager.event_name(&8285), "BasicPingMessage");
+        assert_eq!(protocol_manager.event_name(&16000), "Unknown(16000)");
+    }
+
+    #[test]
+    fn test_deserialize_into() {
+        #[derive(serde::Deserialize, Debug, PartialEq)]
+        #[serde(rename_all = "camelCase")]
+        struct ChatServerMessage {
+            channel: i8,
+            content: String,
+            sender_id: f64,
+            sender_account_id: u32,
+            origin_server_id: Option<i16>,
+        }
+        #[derive(serde::Deserialize, Debug)]
+        #[allow(dead_code)]
+        struct WithRequiredField {
+            content: String,
+            objects: Vec<Value>,
+        }
+
+        let mut protocol_manager = ProtocolManager::new();
+        protocol_manager.init("tests/fixtures/").unwrap();
+        let packet = chat_server_message(&protocol_manager);
+
+        assert_eq!(
+            packet.deserialize_into::<ChatServerMessage>().unwrap(),
+            ChatServerMessage {
+                channel: 5,
+                content: "hi".to_string(),
+                sender_id: 42.0,
+                sender_account_id: 7,
+                origin_server_id: None,
+            }
+        );
+        assert!(packet.deserialize_into::<WithRequiredField>().is_err());
+    }
+
+    fn container(message: &[u8]) -> Vec<u8> {
+        let mut encoder =
+            flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        std::io::Write::write_all(&mut encoder, message).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let mut body = Vec::new();
+        let mut length = compressed.len();
+        // VarInt length
+        while length >= 0x80 {
+            body.push((length as u8 & 0x7f) | 0x80);
+            length >>= 7;
+        }
+        body.push(length as u8);
+        body.extend(compressed);
+        body
+    }
+
+    #[test]
+    fn test_data_container() {
+        let mut protocol_manager = ProtocolManager::new();
+        protocol_manager.init("tests/fixtures/").unwrap();
+
+        // BasicPingMessage { quiet: true }
+        let ping = [0x81, 0x75, 0x01, 0x01];
+        let expected =
+            PacketParser::from_metadata(&PacketMetadata::from_buffer(ping.to_vec()).unwrap())
+                .parse(&protocol_manager)
+                .unwrap();
+        // NetworkDataContainerMessage
+        let packet = PacketParser::new(2, DataWrapper::new(container(&ping)))
+            .parse(&protocol_manager)
+            .unwrap();
+        assert_eq!(packet.id, expected.id);
+        assert_eq!(packet.data, expected.data);
+
+        let bomb = container(&vec![0; MAX_INFLATED_SIZE + 1]);
+        assert!(matches!(
+            PacketParser::new(2, DataWrapper::new(bomb)).parse(&protocol_manager),
+            Err(PacketError::InflatedTooLarge(_))
+        ));
+
+        // every message of the container, the ones of a nested container too
+        let mut nested = vec![0x00, 0x09];
+        nested.extend(container(&ping));
+        nested.splice(2..2, [nested.len() as u8 - 2]);
+        let mut messages = [0x5c, 0x49, 0x01, 0x01].to_vec();
+        messages.extend(&nested);
+        messages.extend(ping);
+        let ids: Vec<u16> = PacketParser::new(2, DataWrapper::new(container(&messages)))
+            .parse_messages(&protocol_manager)
+            .unwrap()
+            .iter()
+            .map(|packet| packet.id)
+            .collect();
+        assert_eq!(ids, vec![5906, 8285, 8285]);
+
+        // a container of containers of ...
+        let mut deep = ping.to_vec();
+        for _ in 0..MAX_CONTAINER_DEPTH {
+            let body = container(&deep);
+            deep = vec![0x00, 0x09, body.len() as u8];
+            deep.extend(body);
+        }
+        assert!(matches!(
+            PacketParser::new(2, DataWrapper::new(container(&deep))).parse(&protocol_manager),
+            Err(PacketError::ContainerTooDeep(MAX_CONTAINER_DEPTH))
+        ));
+
+        // the length of the compressed body is missing
+        assert!(matches!(
+            PacketParser::new(2, DataWrapper::new(vec![0x80])).parse(&protocol_manager),
+            Err(PacketError::ParseError(ParseError::NotEnoughData))
+        ));
+    }
+
+    fn parse_primitive(bytes: &[u8], var_type: ProtocolVarType) -> Value {
+        let mut parser = PacketParser::new(0, DataWrapper::new(bytes.to_vec()));
+        let value = parser
+            .parse_attribute(&ProtocolManager::new(), &var_type)
+            .unwrap();
+        assert!(parser.data.get_remaining().is_empty());
+        value
+    }
+
+    #[test]
+    fn test_signed_and_unsigned() {
+        let cases = [
+            (vec![0xff], ProtocolVarType::Byte, "-1"),
+            (vec![0xff], ProtocolVarType::UnsignedByte, "255"),
+            (vec![0xff, 0xfe], ProtocolVarType::Short, "-2"),
+            (vec![0xff, 0xfe], ProtocolVarType::UnsignedShort, "65534"),
+            (vec![0xff, 0xff, 0xff, 0xfd], ProtocolVarType::Int, "-3"),
+            (
+                vec![0xff, 0xff, 0xff, 0xfd],
+                ProtocolVarType::UnsignedInt,
+                "4294967293",
+            ),
+        ];
+        for (bytes, var_type, expected) in cases {
+            let value = parse_primitive(&bytes, var_type.clone());
+            assert_eq!(value.to_string(), expected, "{:?}", var_type);
+        }
+    }
+
+    #[test]
+    fn test_snapshot_diff() {
+        let mut protocol_manager = ProtocolManager::new();
+        protocol_manager.init("tests/fixtures/").unwrap();
+        let snapshot = |data: Value| Packet {
+            id: 8285,
+            name: "BasicPingMessage".to_string(),
+            data: serde_json::from_value(data).unwrap(),
+            connection: None,
+            instance_id: None,
+            tcp: None,
+            raw: None,
+            timestamp: None,
+        };
+
+        let packet = decode_message(&protocol_manager, &[0x81, 0x75, 0x01, 0x01]).unwrap();
+        let expected = snapshot(serde_json::json!({"quiet": true}));
+        assert_eq!(packet, expected);
+        assert!(packet.diff(&expected).is_empty());
+
+        // json! objects are sorted by field name
+        let expected = snapshot(serde_json::json!({"quiet": false, "latency": 3}));
+        assert_ne!(packet, expected);
+        assert_eq!(
+            packet.diff(&expected),
+            vec![
+                FieldDiff::Missing {
+                    field: "latency".to_string(),
+                    expected: Value::from(3),
+                },
+                FieldDiff::Changed {
+                    field: "quiet".to_string(),
+                    expected: Value::Bool(false),
+                    actual: Value::Bool(true),
+                },
+            ]
+        );
+        assert_eq!(
+            snapshot(serde_json::json!({})).diff(&packet),
+            vec![FieldDiff::Missing {
+                field: "quiet".to_string(),
+                expected: Value::Bool(true),
+            }]
+        );
+        assert_eq!(
+            packet.diff(&snapshot(serde_json::json!({}))),
+            vec![FieldDiff::Extra {
+                field: "quiet".to_string(),
+                actual: Value::Bool(true),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_decode_message() {
+        let mut protocol_manager = ProtocolManager::new();
+        protocol_manager.init("tests/fixtures/").unwrap();
+
+        // ProtocolRequired { version: "abcdef" }
+        let bytes = [
+            0x9b, 0x09, 0x08, 0x00, 0x06, b'a', b'b', b'c', b'd', b'e', b'f',
+        ];
+        let packet = decode_message(&protocol_manager, &bytes).unwrap();
+        assert_eq!(packet.name, "ProtocolRequired");
+        assert_eq!(packet.get_string("version"), Some("abcdef"));
+        // the body only, the header and its length are left out
+        assert_eq!(packet.raw(), Some(&bytes[3..]));
+        let cloned = packet.clone();
+        assert!(Arc::ptr_eq(
+            cloned.raw.as_ref().unwrap(),
+            packet.raw.as_ref().unwrap()
+        ));
+        // the framed body isn't copied by the parser either
+        let metadata = PacketMetadata::from_buffer(bytes.to_vec()).unwrap();
+        let packet = PacketParser::from_metadata(&metadata)
+            .parse(&protocol_manager)
+            .unwrap();
+        assert!(Arc::ptr_eq(packet.raw.as_ref().unwrap(), &metadata.data));
+
+        assert!(matches!(
+            decode_message(&protocol_manager, &bytes[..6]),
+            Err(PacketError::InvalidMessage(ParseResult::Incomplete))
+        ));
+        // id 1 isn't in the protocol
+        assert!(matches!(
+            decode_message(&protocol_manager, &[0x00, 0x04]),
+            Err(PacketError::UnknownPacketType(1))
+        ));
+    }
+
+    #[test]
+    fn test_float_and_double_are_big_endian() {
+        let cases = [
+            (vec![0xbf, 0xc0, 0x00, 0x00], ProtocolVarType::Float, -1.5),
+            (vec![0x40, 0x50, 0x00, 0x00], ProtocolVarType::Float, 3.25),
+            (
+                vec![0x3f, 0xb9, 0x99, 0x99, 0x99, 0x99, 0x99, 0x9a],
+                ProtocolVarType::Double,
+                0.1,
+            ),
+            (
+                vec![0xc0, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+                ProtocolVarType::Double,
+                -2.5,
+            ),
+        ];
+        for (bytes, var_type, expected) in cases {
+            let value = parse_primitive(&bytes, var_type.clone());
+            assert_eq!(value.as_f64(), Some(expected), "{:?}", var_type);
+        }
+    }
 }
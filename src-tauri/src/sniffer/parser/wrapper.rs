@@ -1,18 +1,33 @@
+use std::{ops::Deref, sync::Arc};
+
 use thiserror::Error;
 use tracing::debug;
 
+/// Reads the bytes of a message, shared with the packet decoded from them by default.
+/// The reassembly buffer is a `DataWrapper<Vec<u8>>`, the bytes of the next segments are added to it.
 #[derive(Debug, Clone)]
-pub struct DataWrapper {
-    pub data: Vec<u8>,
+pub struct DataWrapper<B = Arc<[u8]>> {
+    pub data: B,
     pub pos: usize,
 }
 
 /// Adapted from com.ankamagames.jerakine.network.CustomDataWrapper
-impl DataWrapper {
-    pub fn new(data: Vec<u8>) -> Self {
+impl<B: Deref<Target = [u8]>> DataWrapper<B> {
+    pub fn new(data: B) -> Self {
         Self { data, pos: 0 }
     }
 
+    /// Same position over shared bytes, the ones of a `Vec` are copied once
+    pub fn into_shared(self) -> DataWrapper
+    where
+        B: Into<Arc<[u8]>>,
+    {
+        DataWrapper {
+            data: self.data.into(),
+            pos: self.pos,
+        }
+    }
+
     pub fn get_remaining(&self) -> &[u8] {
         &self.data[self.pos..]
     }
@@ -21,12 +36,13 @@ impl DataWrapper {
         self.data.len() - self.pos
     }
 
-    pub fn set_pos(&mut self, pos: usize) {
-        self.pos = pos;
+    /// Copy of the bytes not read yet, e.g. to attach a desynced buffer to a bug report
+    pub fn snapshot(&self) -> Vec<u8> {
+        self.get_remaining().to_vec()
     }
 
-    pub fn extend_from_slice(&mut self, data: &[u8]) {
-        self.data.extend_from_slice(data);
+    pub fn set_pos(&mut self, pos: usize) {
+        self.pos = pos;
     }
 
     pub fn debug_remaining(&self) {
@@ -40,28 +56,7 @@ impl DataWrapper {
         debug!("Remaining: {:?}", self.get_remaining());
     }
 
-    pub fn reorder(&mut self, buffer: Vec<u8>) {
-        let buffer_len = buffer.len();
-        let cut_off = self.data.len().saturating_sub(buffer_len) as usize;
-
-        let mut new_data = Vec::with_capacity(self.data.len() + buffer_len);
-        for i in 0..cut_off {
-            new_data.push(self.data[i]);
-        }
-        for b in buffer {
-            new_data.push(b);
-        }
-        for i in cut_off..self.data.len() {
-            new_data.push(self.data[i]);
-        }
-        self.data = new_data;
-    }
-
-    pub fn clear(&mut self) {
-        self.pos = 0;
-        self.data.clear();
-    }
-
+    /// Unsigned, see `read_signed_byte` for the protocol `Byte`
     pub fn read_byte(&mut self) -> u8 {
         let value = self.data[self.pos];
         let value = u8::from_be_bytes([value]); // TODO: check if needed
@@ -69,7 +64,22 @@ impl DataWrapper {
         value
     }
 
-    pub fn read_int(&mut self) -> u32 {
+    pub fn read_signed_byte(&mut self) -> i8 {
+        self.read_byte() as i8
+    }
+
+    pub fn read_int(&mut self) -> i32 {
+        let value = i32::from_be_bytes([
+            self.data[self.pos],
+            self.data[self.pos + 1],
+            self.data[self.pos + 2],
+            self.data[self.pos + 3],
+        ]);
+        self.pos += 4;
+        value
+    }
+
+    pub fn read_unsigned_int(&mut self) -> u32 {
         let value = u32::from_be_bytes([
             self.data[self.pos],
             self.data[self.pos + 1],
@@ -92,6 +102,23 @@ impl DataWrapper {
         panic!("Too much data");
     }
 
+    /// Same as `read_var_int` for bytes that can't be trusted, an error instead of a panic
+    pub fn checked_read_var_int(&mut self) -> Result<u32, ParseError> {
+        let mut value = 0;
+        for i in (0..32).step_by(7) {
+            let byte = *self
+                .get_remaining()
+                .first()
+                .ok_or(ParseError::NotEnoughData)?;
+            self.pos += 1;
+            value |= ((byte & 0x7f) as u32) << i;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(ParseError::TooMuchData)
+    }
+
     pub fn read_short(&mut self) -> i16 {
         let value = i16::from_be_bytes([self.data[self.pos], self.data[self.pos + 1]]);
         self.pos += 2;
@@ -138,11 +165,26 @@ impl DataWrapper {
         return Err(ParseError::TooMuchData);
     }
 
-    pub fn read_utf(&mut self) -> String {
+    /// `read_var_int` of a zig-zag encoded value: 0, -1, 1, -2, ... are sent as 0, 1, 2, 3, ...
+    pub fn read_zigzag_var_int(&mut self) -> i32 {
+        let value = self.read_var_int();
+        (value >> 1) as i32 ^ -((value & 1) as i32)
+    }
+
+    pub fn read_zigzag_var_long(&mut self) -> Result<i64, ParseError> {
+        let value = self.read_var_long()?;
+        Ok((value >> 1) as i64 ^ -((value & 1) as i64))
+    }
+
+    /// Reads an unsigned short byte length followed by that many bytes of UTF-8
+    pub fn read_utf(&mut self) -> Result<String, ParseError> {
         let len = self.read_unsigned_short() as usize;
-        let value = String::from_utf8(self.data[self.pos..self.pos + len].to_vec()).unwrap();
+        if len > self.remaining() {
+            return Err(ParseError::NotEnoughData);
+        }
+        let bytes = self.data[self.pos..self.pos + len].to_vec();
         self.pos += len;
-        value
+        Ok(String::from_utf8(bytes)?)
     }
 
     pub fn read_double(&mut self) -> f64 {
@@ -161,8 +203,194 @@ impl DataWrapper {
     }
 }
 
+impl DataWrapper<Vec<u8>> {
+    pub fn extend_from_slice(&mut self, data: &[u8]) {
+        self.data.extend_from_slice(data);
+    }
+
+    /// Inserts `buffer` right before the last `buffer.len()` bytes of the data.
+    /// The bytes are moved in place, no new allocation is made when the current capacity is enough.
+    pub fn reorder(&mut self, buffer: Vec<u8>) {
+        let cut_off = self.data.len().saturating_sub(buffer.len());
+        self.data.splice(cut_off..cut_off, buffer);
+    }
+
+    /// Inserts `buffer` `at` bytes after the current position
+    pub fn insert(&mut self, at: usize, buffer: Vec<u8>) {
+        let at = (self.pos + at).min(self.data.len());
+        self.data.splice(at..at, buffer);
+    }
+
+    /// Drops the next `n` bytes and keeps the rest, e.g. once a message of the buffer is framed.
+    /// The dropped bytes are only moved out once they make up half of the buffer.
+    pub fn consume(&mut self, n: usize) {
+        self.pos += n.min(self.remaining());
+        if self.pos == self.data.len() {
+            self.clear();
+        } else if self.pos >= self.data.len() / 2 {
+            self.data.drain(..self.pos);
+            self.pos = 0;
+        }
+    }
+
+    /// Empties the buffer but keeps its capacity, so the allocation is reused by the next packets.
+    pub fn clear(&mut self) {
+        self.pos = 0;
+        self.data.clear();
+    }
+
+    /// Empties the buffer and releases its memory.
+    /// Unlike `clear`, this should only be used when the buffer won't be reused soon (e.g. connection closed).
+    pub fn reset(&mut self) {
+        self.pos = 0;
+        self.data = Vec::new();
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum ParseError {
     #[error("Too much data")]
     TooMuchData,
+    #[error("Not enough data")]
+    NotEnoughData,
+    #[error("Invalid UTF-8 string")]
+    InvalidUtf8(#[from] std::string::FromUtf8Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot() {
+        let mut wrapper = DataWrapper::new(Vec::new());
+        wrapper.extend_from_slice(&[0x9b, 0x09, 0x08]);
+        wrapper.extend_from_slice(b"abc");
+        assert_eq!(wrapper.snapshot(), vec![0x9b, 0x09, 0x08, b'a', b'b', b'c']);
+
+        wrapper.read_unsigned_short();
+        assert_eq!(wrapper.snapshot(), vec![0x08, b'a', b'b', b'c']);
+    }
+
+    #[test]
+    fn test_consume() {
+        let first = [0x81, 0x75, 0x01, 0x01];
+        let second = [
+            0x9b, 0x09, 0x08, 0x00, 0x06, b'a', b'b', b'c', b'd', b'e', b'f',
+        ];
+        let mut wrapper = DataWrapper::new(Vec::new());
+        wrapper.extend_from_slice(&first);
+        wrapper.extend_from_slice(&second);
+
+        wrapper.consume(first.len());
+        assert_eq!(wrapper.get_remaining(), second);
+        // not compacted yet, the first message is less than half of the buffer
+        assert_eq!(wrapper.pos, first.len());
+
+        wrapper.consume(6);
+        assert_eq!(wrapper.get_remaining(), &second[6..]);
+        assert_eq!(wrapper.pos, 0);
+
+        wrapper.consume(100);
+        assert_eq!(wrapper.remaining(), 0);
+        assert!(wrapper.data.is_empty());
+    }
+
+    #[test]
+    fn test_insert() {
+        let mut wrapper = DataWrapper::new(vec![0, 1, 2, 5]);
+        wrapper.set_pos(1);
+        wrapper.insert(2, vec![3, 4]);
+        assert_eq!(wrapper.get_remaining(), &[1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn test_reorder() {
+        let mut wrapper = DataWrapper::new(Vec::with_capacity(16));
+        wrapper.extend_from_slice(&[1, 2, 3, 4]);
+        let ptr = wrapper.data.as_ptr();
+
+        wrapper.reorder(vec![5, 6]);
+        assert_eq!(wrapper.data, vec![1, 2, 5, 6, 3, 4]);
+        // no reallocation, the body was moved into the existing buffer
+        assert_eq!(wrapper.data.as_ptr(), ptr);
+
+        wrapper.reorder(vec![7; 8]);
+        assert_eq!(wrapper.data, vec![7, 7, 7, 7, 7, 7, 7, 7, 1, 2, 5, 6, 3, 4]);
+    }
+
+    #[test]
+    fn test_clear_keeps_capacity() {
+        let mut wrapper = DataWrapper::new(Vec::new());
+        wrapper.extend_from_slice(&[0; 1024]);
+        let capacity = wrapper.data.capacity();
+
+        for _ in 0..10 {
+            wrapper.clear();
+            assert_eq!(wrapper.remaining(), 0);
+            assert_eq!(wrapper.data.capacity(), capacity);
+            wrapper.extend_from_slice(&[1; 512]);
+            wrapper.set_pos(10);
+        }
+
+        wrapper.reset();
+        assert_eq!(wrapper.remaining(), 0);
+        assert_eq!(wrapper.data.capacity(), 0);
+    }
+
+    #[test]
+    fn test_zigzag() {
+        fn encode(value: i64) -> Vec<u8> {
+            let mut value = ((value << 1) ^ (value >> 63)) as u64;
+            let mut bytes = Vec::new();
+            loop {
+                let byte = (value & 0x7f) as u8;
+                value >>= 7;
+                if value == 0 {
+                    bytes.push(byte);
+                    return bytes;
+                }
+                bytes.push(byte | 0x80);
+            }
+        }
+
+        assert_eq!(encode(-1), vec![0x01]);
+        for value in [0, -1, 1, -3, -300, i32::MIN as i64, i32::MAX as i64] {
+            let mut wrapper = DataWrapper::new(encode(value));
+            assert_eq!(wrapper.read_zigzag_var_int() as i64, value);
+        }
+        for value in [-1, -3, -1_000_000_000_000, i64::MIN, i64::MAX] {
+            let mut wrapper = DataWrapper::new(encode(value));
+            assert_eq!(wrapper.read_zigzag_var_long().unwrap(), value);
+        }
+
+        // the plain var types keep reading the same bytes as unsigned
+        assert_eq!(DataWrapper::new(vec![0x05]).read_var_int(), 5);
+    }
+
+    #[test]
+    fn test_read_utf() {
+        let mut data = vec![0x00, 0x00];
+        data.extend([0x00, 0x05]);
+        data.extend(b"Iop 1");
+        // "Écaflip", the accent takes two bytes
+        data.extend([0x00, 0x08]);
+        data.extend("Écaflip".as_bytes());
+        data.push(0x2a);
+        let mut wrapper = DataWrapper::new(data);
+
+        assert_eq!(wrapper.read_utf().unwrap(), "");
+        assert_eq!(wrapper.read_utf().unwrap(), "Iop 1");
+        assert_eq!(wrapper.read_utf().unwrap(), "Écaflip");
+        assert_eq!(wrapper.read_byte(), 0x2a);
+
+        let mut wrapper = DataWrapper::new(vec![0x00, 0x02, 0xc3, 0x28]);
+        assert!(matches!(
+            wrapper.read_utf(),
+            Err(ParseError::InvalidUtf8(_))
+        ));
+
+        let mut wrapper = DataWrapper::new(vec![0x00, 0x05, b'I']);
+        assert!(matches!(wrapper.read_utf(), Err(ParseError::NotEnoughData)));
+    }
 }
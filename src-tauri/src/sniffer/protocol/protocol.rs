@@ -1,22 +1,31 @@
+use flate2::read::GzDecoder;
 use indexmap::IndexMap;
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     fmt::{Debug, Display},
-    path::Path,
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
 };
 use thiserror::Error;
 
 use serde::*;
 use serde_aux::field_attributes::deserialize_option_number_from_string;
-use tracing::info;
+use tracing::{info, warn};
 
-use crate::constants::{EVENTS_FILE, EXTRACTOR_DIR};
+use crate::{
+    constants::{EVENTS_FILE, EXTRACTOR_DIR},
+    sniffer::parser::wrapper::{DataWrapper, ParseError},
+};
 
 pub type FieldName = String;
 pub type EventName = String;
 
 pub type EventId = u16;
 
+/// Reads a type the protocol file names but the parser doesn't know about
+pub type CustomReader = fn(&mut DataWrapper) -> Result<serde_json::Value, ParseError>;
+
 #[derive(Debug)]
 pub enum KnownEvent {
     ChatServerMessage,
@@ -29,17 +38,24 @@ impl Display for KnownEvent {
     }
 }
 
-#[derive(Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum ProtocolVarType {
     String,
     VarInt,
     VarLong,
     VarShort,
+    /// Signed var types mapping small negatives to small values, `VarInt` and `VarLong` are not zig-zag encoded
+    ZigZagVarInt,
+    ZigZagVarLong,
     Short,
     Int,
     Byte,
+    UnsignedShort,
+    UnsignedInt,
+    UnsignedByte,
     None,
     Boolean,
+    Float,
     Double,
 
     #[serde(untagged)]
@@ -53,16 +69,36 @@ impl ProtocolVarType {
             | ProtocolVarType::VarInt
             | ProtocolVarType::VarLong
             | ProtocolVarType::VarShort
+            | ProtocolVarType::ZigZagVarInt
+            | ProtocolVarType::ZigZagVarLong
             | ProtocolVarType::Short
             | ProtocolVarType::Int
             | ProtocolVarType::Boolean
             | ProtocolVarType::None
+            | ProtocolVarType::Float
             | ProtocolVarType::Double
-            | ProtocolVarType::Byte => true,
+            | ProtocolVarType::Byte
+            | ProtocolVarType::UnsignedShort
+            | ProtocolVarType::UnsignedInt
+            | ProtocolVarType::UnsignedByte => true,
             _ => false,
         }
     }
 
+    /// Bytes taken on the wire, `None` for var types, strings and complex types
+    pub fn fixed_size(&self) -> Option<usize> {
+        match self {
+            ProtocolVarType::None => Some(0),
+            ProtocolVarType::Byte | ProtocolVarType::UnsignedByte | ProtocolVarType::Boolean => {
+                Some(1)
+            }
+            ProtocolVarType::Short | ProtocolVarType::UnsignedShort => Some(2),
+            ProtocolVarType::Int | ProtocolVarType::UnsignedInt | ProtocolVarType::Float => Some(4),
+            ProtocolVarType::Double => Some(8),
+            _ => None,
+        }
+    }
+
     // The goal is to decompose Vector<ProcolVarType, ProtocolVarType> into a single ProtocolVarType
     // same for TypeId<ProtocolVarType> and HashMap<ProtocolVarType, ProtocolVarType>
     //
@@ -86,11 +122,22 @@ impl ProtocolVarType {
                     let a = parts.next().unwrap().trim();
                     let b = parts.next().unwrap().trim();
 
-                    let a = serde_plain::from_str::<ProtocolVarType>(a).unwrap();
                     let b = serde_plain::from_str::<ProtocolVarType>(b).unwrap();
+                    // `Vector<16, Short>` always holds 16 values, no length is sent
+                    if let Ok(fixed_length) = a.parse::<usize>() {
+                        return Some(ProtocolVarTypeVector {
+                            length: ProtocolVarType::None,
+                            fixed_length: Some(fixed_length),
+                            type_id: is_type_id_vector,
+                            types: b,
+                        });
+                    }
+                    let a = serde_plain::from_str::<ProtocolVarType>(a).unwrap();
 
                     return Some(ProtocolVarTypeVector {
                         length: a,
+                        fixed_length: None,
+                        type_id: is_type_id_vector,
                         types: b,
                     });
                 }
@@ -100,6 +147,25 @@ impl ProtocolVarType {
         }
     }
 
+    /// `Optional<flag, T>` is only read when the `flag` field read before it is set,
+    /// `Optional<T>` is preceded by its own boolean
+    pub fn parse_optional(&self) -> Result<Option<ProtocolVarTypeOptional>, ProtocolError> {
+        let ProtocolVarType::Other(name) = self else {
+            return Ok(None);
+        };
+        if !name.starts_with("Optional<") {
+            return Ok(None);
+        }
+        let invalid = || ProtocolError::InvalidType(name.clone());
+        let (flag, types) = match type_arguments(name).ok_or_else(invalid)?.as_slice() {
+            [types] => (None, *types),
+            [flag, types] => (Some(flag.to_string()), *types),
+            _ => return Err(invalid()),
+        };
+        let types = serde_plain::from_str::<ProtocolVarType>(types).map_err(|_| invalid())?;
+        Ok(Some(ProtocolVarTypeOptional { flag, types }))
+    }
+
     pub fn parse_type_id(&self) -> Option<ProtocolVarType> {
         match self {
             ProtocolVarType::Other(name) => {
@@ -115,10 +181,131 @@ impl ProtocolVarType {
     }
 }
 
+/// Arguments of a generic type like `Vector<Short, Int>`, split on the commas outside of nested brackets.
+/// `None` when the brackets don't match.
+fn type_arguments(name: &str) -> Option<Vec<&str>> {
+    let start = name.find('<')?;
+    let inner = name.strip_suffix('>')?.get(start + 1..)?;
+    let mut arguments = Vec::new();
+    let mut depth = 0usize;
+    let mut argument_start = 0;
+    for (index, character) in inner.char_indices() {
+        match character {
+            '<' => depth += 1,
+            '>' => depth = depth.checked_sub(1)?,
+            ',' if depth == 0 => {
+                arguments.push(inner[argument_start..index].trim());
+                argument_start = index + 1;
+            }
+            _ => {}
+        }
+    }
+    arguments.push(inner[argument_start..].trim());
+    (depth == 0 && arguments.iter().all(|argument| !argument.is_empty())).then_some(arguments)
+}
+
 pub struct ProtocolVarTypeVector {
+    /// Type of the length read before the values, `None` for fixed length vectors
     pub length: ProtocolVarType,
+    pub fixed_length: Option<usize>,
+    /// `TypeIdVector`, each value is preceded by the id of its concrete type
+    pub type_id: bool,
+    pub types: ProtocolVarType,
+}
+
+pub struct ProtocolVarTypeOptional {
+    /// Field deciding if the value is present, `None` when a boolean precedes the value
+    pub flag: Option<FieldName>,
     pub types: ProtocolVarType,
 }
+/// Every event of the protocol with its inherited fields, for the frontend
+#[derive(Serialize, Debug, Clone, PartialEq, specta::Type)]
+#[serde(rename_all = "camelCase")]
+pub struct ProtocolCatalog {
+    /// Sorted by id
+    pub events: Vec<CatalogEvent>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, specta::Type)]
+#[serde(rename_all = "camelCase")]
+pub struct CatalogEvent {
+    pub id: EventId,
+    pub name: EventName,
+    pub parent: Option<EventName>,
+    /// Fields in wire order, the ones of the parents first
+    pub attributes: Vec<CatalogAttribute>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, specta::Type)]
+#[serde(rename_all = "camelCase")]
+pub struct CatalogAttribute {
+    pub name: FieldName,
+    /// Type as written in the protocol file, like `VarInt` or `Vector<Short,Int>`
+    #[serde(rename = "type")]
+    pub var_type: String,
+    pub raw_type: Option<String>,
+    pub comment: Option<String>,
+}
+
+/// Field of an event. The protocol file gives either its type, like `"VarInt"`,
+/// or an object with the type and what the extractor knows about it.
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(from = "AttributeDefinition")]
+pub struct Attribute {
+    pub var_type: ProtocolVarType,
+    /// Type of the field in the game sources, before being mapped to a `ProtocolVarType`
+    pub raw_type: Option<String>,
+    pub comment: Option<String>,
+}
+
+impl From<ProtocolVarType> for Attribute {
+    fn from(var_type: ProtocolVarType) -> Self {
+        Attribute {
+            var_type,
+            raw_type: None,
+            comment: None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum AttributeDefinition {
+    Type(ProtocolVarType),
+    Detailed {
+        #[serde(rename = "type")]
+        var_type: ProtocolVarType,
+        #[serde(default)]
+        raw_type: Option<String>,
+        #[serde(default)]
+        comment: Option<String>,
+    },
+}
+
+impl From<AttributeDefinition> for Attribute {
+    fn from(definition: AttributeDefinition) -> Self {
+        match definition {
+            AttributeDefinition::Type(var_type) => var_type.into(),
+            AttributeDefinition::Detailed {
+                var_type,
+                raw_type,
+                comment,
+            } => Attribute {
+                var_type,
+                raw_type,
+                comment,
+            },
+        }
+    }
+}
+
+/// Size of a message body, see `ProtocolManager::wire_size`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WireSize {
+    Fixed(usize),
+    Variable,
+}
+
 #[derive(Deserialize, Debug)]
 pub struct ProtocolSchema {
     #[serde(deserialize_with = "deserialize_option_number_from_string")]
@@ -127,44 +314,53 @@ pub struct ProtocolSchema {
     pub name: EventName,
     #[serde(rename = "superclass")]
     pub parent: Option<EventName>,
-    pub attributes: IndexMap<FieldName, ProtocolVarType>,
+    pub attributes: IndexMap<FieldName, Attribute>,
+    #[serde(default)]
+    pub interfaces: Vec<String>,
+}
+
+impl ProtocolSchema {
+    /// Containers carry other messages in a zlib compressed body
+    pub fn is_data_container(&self) -> bool {
+        self.interfaces
+            .iter()
+            .any(|interface| interface == "INetworkDataContainerMessage")
+    }
 }
 
 #[derive(Debug)]
 pub struct ProtocolManager {
     protocol_by_id: HashMap<EventId, ProtocolSchema>,
     protocol_id_by_name: HashMap<EventName, EventId>,
+    /// Classes added by an overlay without an id, they are only found by name, see `merge_from`
+    classes_without_id: HashMap<EventName, ProtocolSchema>,
+    custom_readers: HashMap<String, CustomReader>,
 }
 
-fn load_protocol(
-    protocol_file_path: impl AsRef<Path>,
-) -> Result<HashMap<EventId, ProtocolSchema>, std::io::Error> {
-    let protocol_file_path = protocol_file_path.as_ref();
-    let protocol_file_path = protocol_file_path.join(EXTRACTOR_DIR).join(EVENTS_FILE);
+fn is_events_file(path: &Path) -> bool {
+    path.file_name().map_or(false, |name| name == EVENTS_FILE)
+}
 
+/// Indexes the events by id, the one without an id (NetworkMessage) is stored as 0
+fn index_protocol(
+    protocol: Vec<ProtocolSchema>,
+) -> Result<HashMap<EventId, ProtocolSchema>, ProtocolError> {
     let mut event_by_id = HashMap::new();
 
-    assert!(
-        protocol_file_path.exists(),
-        "Protocol file not found at {}",
-        protocol_file_path.display()
-    );
-
-    let content = std::fs::read_to_string(&protocol_file_path)?;
-    let protocol: Vec<ProtocolSchema> = serde_json::from_str(&content)?;
-
-    let mut without_id_count = 0;
+    let mut without_id: Option<EventName> = None;
     for event in protocol {
         if let Some(id) = event.id {
             event_by_id.insert(id, event);
         } else {
             // There should be only one event without an id (NetworkMessage)
-            assert_eq!(without_id_count, 0);
-            without_id_count += 1;
+            if let Some(first) = without_id {
+                return Err(ProtocolError::SeveralWithoutId(first, event.name));
+            }
+            without_id = Some(event.name.clone());
             event_by_id.insert(0, event);
         }
     }
-    return Ok(event_by_id);
+    Ok(event_by_id)
 }
 
 impl ProtocolManager {
@@ -172,11 +368,69 @@ impl ProtocolManager {
         return ProtocolManager {
             protocol_by_id: HashMap::new(),
             protocol_id_by_name: HashMap::new(),
+            classes_without_id: HashMap::new(),
+            custom_readers: HashMap::new(),
         };
     }
 
+    /// Parses the content of an events file, for protocols that are embedded or already in memory
+    pub fn from_json(content: &str) -> Result<Self, ProtocolError> {
+        let mut manager = ProtocolManager::new();
+        manager.load(serde_json::from_str(content)?)?;
+        Ok(manager)
+    }
+
+    pub fn from_reader(reader: impl Read) -> Result<Self, ProtocolError> {
+        let mut manager = ProtocolManager::new();
+        manager.load(serde_json::from_reader(reader)?)?;
+        Ok(manager)
+    }
+
+    /// Loads the events file of a release asset, a `.zip` or `.tar.gz`, without unpacking it to disk
+    pub fn from_archive(archive_path: impl AsRef<Path>) -> Result<Self, ProtocolError> {
+        let archive_path = archive_path.as_ref();
+        let file = File::open(archive_path)?;
+        let name = archive_path.to_string_lossy();
+        if name.ends_with(".zip") {
+            let mut archive = zip::ZipArchive::new(file)?;
+            for i in 0..archive.len() {
+                let entry = archive.by_index(i)?;
+                if is_events_file(Path::new(entry.name())) {
+                    return ProtocolManager::from_reader(entry);
+                }
+            }
+        } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            let mut archive = tar::Archive::new(GzDecoder::new(file));
+            for entry in archive.entries()? {
+                let entry = entry?;
+                if is_events_file(&entry.path()?) {
+                    return ProtocolManager::from_reader(entry);
+                }
+            }
+        } else {
+            return Err(ProtocolError::UnsupportedArchive(
+                archive_path.to_path_buf(),
+            ));
+        }
+        Err(ProtocolError::NotFound(archive_path.join(EVENTS_FILE)))
+    }
+
     pub fn init(&mut self, protocol_file_path: impl AsRef<Path>) -> Result<&Self, ProtocolError> {
-        let protocol_by_id = load_protocol(protocol_file_path)?;
+        let protocol_file_path = protocol_file_path
+            .as_ref()
+            .join(EXTRACTOR_DIR)
+            .join(EVENTS_FILE);
+        if !protocol_file_path.exists() {
+            return Err(ProtocolError::NotFound(protocol_file_path));
+        }
+
+        let content = std::fs::read_to_string(&protocol_file_path)?;
+        self.load(serde_json::from_str(&content)?)?;
+        return Ok(self);
+    }
+
+    fn load(&mut self, protocol: Vec<ProtocolSchema>) -> Result<(), ProtocolError> {
+        let protocol_by_id = index_protocol(protocol)?;
         let protocol_id_by_name: HashMap<EventName, EventId> =
             protocol_by_id
                 .iter()
@@ -189,28 +443,678 @@ impl ProtocolManager {
 
         self.protocol_by_id = protocol_by_id;
         self.protocol_id_by_name = protocol_id_by_name;
+        for (id, parent) in self.missing_parents() {
+            warn!(
+                "{} extends {} which is not in the protocol, its messages won't parse",
+                self.event_name(&id),
+                parent
+            );
+        }
+        Ok(())
+    }
 
-        return Ok(self);
+    /// Layers the events file at `path` on top of the loaded protocol, for events missing from the official one.
+    /// Its events are added, or replace the loaded event of the same id or class for parsing.
+    /// A class without an id replaces the loaded class of its name, or is added to be found by name.
+    pub fn merge_from(&mut self, path: impl AsRef<Path>) -> Result<(), ProtocolError> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Err(ProtocolError::NotFound(path.to_path_buf()));
+        }
+        let overlay: Vec<ProtocolSchema> = serde_json::from_reader(File::open(path)?)?;
+        let (numbered, without_id): (Vec<_>, Vec<_>) =
+            overlay.into_iter().partition(|event| event.id.is_some());
+        let (mut added, mut replaced, mut by_name) = (Vec::new(), Vec::new(), Vec::new());
+        for event in without_id {
+            match self.protocol_id_by_name.get(&event.name).copied() {
+                Some(id) => {
+                    self.protocol_by_id.insert(id, event);
+                    replaced.push(id);
+                }
+                None => {
+                    by_name.push(event.name.clone());
+                    self.classes_without_id.insert(event.name.clone(), event);
+                }
+            }
+        }
+        for (id, event) in index_protocol(numbered)? {
+            // a class moved to another id leaves no stale definition behind
+            if let Some(old_id) = self.protocol_id_by_name.get(&event.name).copied() {
+                if old_id != id {
+                    self.protocol_by_id.remove(&old_id);
+                }
+            }
+            let name = event.name.clone();
+            self.protocol_id_by_name.insert(name.clone(), id);
+            match self.protocol_by_id.insert(id, event) {
+                Some(old) => {
+                    // the id now belongs to another class
+                    if old.name != name && self.protocol_id_by_name.get(&old.name) == Some(&id) {
+                        self.protocol_id_by_name.remove(&old.name);
+                    }
+                    replaced.push(id);
+                }
+                None => added.push(id),
+            }
+        }
+        added.sort();
+        replaced.sort();
+        info!(
+            "Merged {:?}, added events {:?} and classes {:?}, replaced events {:?}",
+            path, added, by_name, replaced
+        );
+        Ok(())
+    }
+
+    /// Events whose superclass is not defined, with the class they name, sorted by id.
+    /// The parser can't decode them since the inherited fields are unknown.
+    pub fn missing_parents(&self) -> Vec<(EventId, EventName)> {
+        let mut missing: Vec<_> = self
+            .protocol_by_id
+            .iter()
+            .filter_map(|(id, event)| {
+                let parent = event.parent.as_ref()?;
+                (!self.protocol_id_by_name.contains_key(parent)).then(|| (*id, parent.clone()))
+            })
+            .collect();
+        missing.sort();
+        missing
     }
 
     pub fn get_protocol(&self, id: &EventId) -> Option<&ProtocolSchema> {
         self.protocol_by_id.get(id)
     }
 
+    /// Class name of `id` for logs, `Unknown(id)` when the protocol doesn't define it
+    pub fn event_name(&self, id: &EventId) -> String {
+        match self.get_protocol(id) {
+            Some(event) => event.name.clone(),
+            None => format!("Unknown({})", id),
+        }
+    }
+
     pub fn get_protocol_by_class(&self, class: &EventName) -> Option<&ProtocolSchema> {
         if let Some(id) = self.protocol_id_by_name.get(class) {
             return self.get_protocol(id);
         }
-        None
+        self.classes_without_id.get(class)
     }
 
     pub fn get_protocol_id_by_class(&self, class: &EventName) -> Option<&EventId> {
         self.protocol_id_by_name.get(class)
     }
+
+    /// Ids of the messages, the base `NetworkMessage` has none and is skipped
+    pub fn ids(&self) -> impl Iterator<Item = &EventId> {
+        self.protocol_by_id
+            .iter()
+            .filter(|(_, event)| event.id.is_some())
+            .map(|(id, _)| id)
+    }
+
+    pub fn to_catalog(&self) -> ProtocolCatalog {
+        let mut events: Vec<CatalogEvent> = self
+            .protocol_by_id
+            .values()
+            .filter_map(|event| {
+                Some(CatalogEvent {
+                    id: event.id?,
+                    name: event.name.clone(),
+                    parent: event.parent.clone(),
+                    attributes: self.resolved_attributes(event),
+                })
+            })
+            .collect();
+        events.sort_by_key(|event| event.id);
+        ProtocolCatalog { events }
+    }
+
+    /// Fields of `id` in the order they are decoded, `None` for an unknown event
+    pub fn field_names(&self, id: &EventId) -> Option<Vec<FieldName>> {
+        let event = self.get_protocol(id)?;
+        Some(
+            self.resolved_attributes(event)
+                .into_iter()
+                .map(|attribute| attribute.name)
+                .collect(),
+        )
+    }
+
+    /// Attributes of `event` and of its parents, resolved like the parser does
+    fn resolved_attributes(&self, event: &ProtocolSchema) -> Vec<CatalogAttribute> {
+        let mut attributes = match event
+            .parent
+            .as_ref()
+            .and_then(|parent| self.get_protocol_by_class(parent))
+        {
+            Some(parent) if parent.id.is_some() => self.resolved_attributes(parent),
+            _ => Vec::new(),
+        };
+        attributes.extend(
+            event
+                .attributes
+                .iter()
+                .map(|(name, attribute)| CatalogAttribute {
+                    name: name.clone(),
+                    var_type: serde_plain::to_string(&attribute.var_type).unwrap_or_default(),
+                    raw_type: attribute.raw_type.clone(),
+                    comment: attribute.comment.clone(),
+                }),
+        );
+        attributes
+    }
+
+    /// Body size of `id` when all its fields, parents included, have a fixed width.
+    /// Unknown events and events with a parent missing from the protocol are `Variable`.
+    pub fn wire_size(&self, id: &EventId) -> WireSize {
+        match self.get_protocol(id) {
+            Some(event) => self.event_wire_size(event),
+            None => WireSize::Variable,
+        }
+    }
+
+    fn event_wire_size(&self, event: &ProtocolSchema) -> WireSize {
+        let mut size = 0;
+        // same parent resolution as the parser
+        if let Some(parent) = &event.parent {
+            match self.get_protocol_by_class(parent) {
+                Some(parent) if parent.id.is_some() => match self.event_wire_size(parent) {
+                    WireSize::Fixed(parent_size) => size += parent_size,
+                    WireSize::Variable => return WireSize::Variable,
+                },
+                Some(_) => {}
+                None => return WireSize::Variable,
+            }
+        }
+        for Attribute { var_type, .. } in event.attributes.values() {
+            let field_size = match var_type.parse_vector() {
+                Some(vector) => vector
+                    .fixed_length
+                    .zip(vector.types.fixed_size())
+                    .map(|(length, size)| length * size),
+                None => var_type.fixed_size(),
+            };
+            match field_size {
+                Some(field_size) => size += field_size,
+                None => return WireSize::Variable,
+            }
+        }
+        WireSize::Fixed(size)
+    }
+
+    /// Replaces the type of the `field` of `id`, to patch a wrong definition of the protocol file.
+    /// It applies to the messages decoded afterwards, and to the events inheriting the field.
+    pub fn override_attribute(
+        &mut self,
+        id: EventId,
+        field: &str,
+        var_type: ProtocolVarType,
+    ) -> Result<(), ProtocolError> {
+        let event = self
+            .protocol_by_id
+            .get_mut(&id)
+            .ok_or(ProtocolError::UnknownEvent(id))?;
+        let attribute =
+            event
+                .attributes
+                .get_mut(field)
+                .ok_or_else(|| ProtocolError::UnknownField {
+                    event: id,
+                    field: field.to_string(),
+                })?;
+        info!(
+            "Overriding {}.{} from {:?} to {:?}",
+            event.name, field, attribute.var_type, var_type
+        );
+        attribute.var_type = var_type;
+        Ok(())
+    }
+
+    /// Fields of type `type_name` are read with `reader` instead of failing as an unknown type
+    pub fn register_reader(&mut self, type_name: impl Into<String>, reader: CustomReader) {
+        self.custom_readers.insert(type_name.into(), reader);
+    }
+
+    pub fn get_reader(&self, type_name: &str) -> Option<CustomReader> {
+        self.custom_readers.get(type_name).copied()
+    }
+
+    /// Lists what changed from `self` to `other`
+    pub fn diff(&self, other: &ProtocolManager) -> ProtocolDiff {
+        let mut diff = ProtocolDiff::default();
+
+        for (id, event) in &self.protocol_by_id {
+            match other.protocol_by_id.get(id) {
+                Some(other_event) => {
+                    let event_diff = EventDiff::new(event, other_event);
+                    if !event_diff.is_empty() {
+                        diff.changed.insert(*id, event_diff);
+                    }
+                }
+                None => diff.removed.push(*id),
+            }
+        }
+        diff.added = other
+            .protocol_by_id
+            .keys()
+            .filter(|id| !self.protocol_by_id.contains_key(id))
+            .copied()
+            .collect();
+
+        diff.added.sort();
+        diff.removed.sort();
+        diff
+    }
+}
+
+#[derive(Serialize, Debug, Default, Clone, PartialEq)]
+pub struct ProtocolDiff {
+    pub added: Vec<EventId>,
+    pub removed: Vec<EventId>,
+    pub changed: BTreeMap<EventId, EventDiff>,
+}
+
+impl ProtocolDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+#[derive(Serialize, Debug, Default, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct EventDiff {
+    pub added: Vec<FieldName>,
+    pub removed: Vec<FieldName>,
+    pub type_changed: Vec<FieldTypeChange>,
+}
+
+impl EventDiff {
+    fn new(old: &ProtocolSchema, new: &ProtocolSchema) -> Self {
+        let mut diff = EventDiff::default();
+        for (name, old_type) in &old.attributes {
+            let old_type = &old_type.var_type;
+            match new
+                .attributes
+                .get(name)
+                .map(|attribute| &attribute.var_type)
+            {
+                Some(new_type) if new_type != old_type => diff.type_changed.push(FieldTypeChange {
+                    name: name.clone(),
+                    from: old_type.clone(),
+                    to: new_type.clone(),
+                }),
+                Some(_) => {}
+                None => diff.removed.push(name.clone()),
+            }
+        }
+        diff.added = new
+            .attributes
+            .keys()
+            .filter(|name| !old.attributes.contains_key(*name))
+            .cloned()
+            .collect();
+
+        // sorted like the ids of `ProtocolDiff`, the output doesn't depend on the declaration order
+        diff.added.sort();
+        diff.removed.sort();
+        diff.type_changed.sort_by(|a, b| a.name.cmp(&b.name));
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.type_changed.is_empty()
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct FieldTypeChange {
+    pub name: FieldName,
+    pub from: ProtocolVarType,
+    pub to: ProtocolVarType,
 }
 
 #[derive(Error, Debug)]
 pub enum ProtocolError {
     #[error(transparent)]
     IoError(#[from] std::io::Error),
+    #[error("Protocol file not found at {}", .0.display())]
+    NotFound(PathBuf),
+    #[error("Invalid protocol file: {0}")]
+    InvalidJson(#[from] serde_json::Error),
+    #[error("Unsupported archive {}, expected a .zip or .tar.gz", .0.display())]
+    UnsupportedArchive(PathBuf),
+    #[error(transparent)]
+    ZipError(#[from] zip::result::ZipError),
+    #[error("Unknown event {0}")]
+    UnknownEvent(EventId),
+    #[error("{0} and {1} have no id, only the base class of the messages can")]
+    SeveralWithoutId(EventName, EventName),
+    #[error("Unknown field {field:?} of event {event}")]
+    UnknownField { event: EventId, field: FieldName },
+    #[error("Invalid type {0:?}")]
+    InvalidType(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_class_names() {
+        let mut protocol = ProtocolManager::new();
+        protocol.init("tests/fixtures/").unwrap();
+
+        let chat = protocol
+            .get_protocol_id_by_class(&KnownEvent::ChatServerMessage.to_string())
+            .copied();
+        let chat_with_object = protocol
+            .get_protocol_id_by_class(&KnownEvent::ChatServerWithObjectMessage.to_string())
+            .copied();
+        assert_eq!(chat, Some(1304));
+        assert_eq!(chat_with_object, Some(2066));
+        assert_eq!(
+            protocol
+                .get_protocol_by_class(&"BasicPingMessage".to_string())
+                .and_then(|event| event.id),
+            Some(8285)
+        );
+        assert_eq!(
+            protocol.get_protocol_id_by_class(&"Unknown".to_string()),
+            None
+        );
+    }
+
+    #[test]
+    fn test_catalog() {
+        let mut protocol = ProtocolManager::new();
+        protocol.init("tests/fixtures/").unwrap();
+
+        let catalog = protocol.to_catalog();
+        assert!(catalog
+            .events
+            .windows(2)
+            .all(|events| events[0].id < events[1].id));
+        let event = catalog
+            .events
+            .iter()
+            .find(|event| event.name == "PlayerStatusExtended")
+            .unwrap();
+        assert_eq!(event.parent.as_deref(), Some("PlayerStatus"));
+        assert_eq!(
+            event.attributes,
+            vec![
+                CatalogAttribute {
+                    name: "statusId".to_string(),
+                    var_type: "Byte".to_string(),
+                    raw_type: None,
+                    comment: None,
+                },
+                CatalogAttribute {
+                    name: "message".to_string(),
+                    var_type: "String".to_string(),
+                    raw_type: None,
+                    comment: None,
+                },
+            ]
+        );
+        let json = serde_json::to_value(event).unwrap();
+        assert_eq!(json["attributes"][1]["type"], "String");
+    }
+
+    #[test]
+    fn test_attribute_metadata() {
+        let protocol = ProtocolManager::from_json(
+            r#"[{"id": "1", "class_name": "PositionMessage", "superclass": null, "attributes": {
+                "x": "Short",
+                "cellId": {"type": "VarShort", "raw_type": "uint", "comment": "Cell of the map, 0 to 559"}
+            }}]"#,
+        )
+        .unwrap();
+
+        let event = protocol.get_protocol(&1).unwrap();
+        assert_eq!(event.attributes["x"], ProtocolVarType::Short.into());
+        assert_eq!(
+            event.attributes["cellId"],
+            Attribute {
+                var_type: ProtocolVarType::VarShort,
+                raw_type: Some("uint".to_string()),
+                comment: Some("Cell of the map, 0 to 559".to_string()),
+            }
+        );
+        let catalog = protocol.to_catalog();
+        assert_eq!(
+            catalog.events[0].attributes[1].comment.as_deref(),
+            Some("Cell of the map, 0 to 559")
+        );
+        assert_eq!(protocol.wire_size(&1), WireSize::Variable);
+    }
+
+    #[test]
+    fn test_merge_from() {
+        let mut protocol = ProtocolManager::from_json(
+            r#"[
+                {"id": "1", "class_name": "PositionMessage", "superclass": null, "attributes": {"x": "Short"}},
+                {"id": "2", "class_name": "LevelMessage", "superclass": null, "attributes": {"level": "Short"}}
+            ]"#,
+        )
+        .unwrap();
+        let dir = tempdir::TempDir::new("protocol_overlay").unwrap();
+        let path = dir.path().join("overlay.json");
+        std::fs::write(
+            &path,
+            r#"[
+                {"id": "1", "class_name": "PositionMessage", "superclass": null, "attributes": {"x": "Short", "y": "Short"}},
+                {"id": "3", "class_name": "BetaMessage", "superclass": null, "attributes": {"flag": "Boolean"}}
+            ]"#,
+        )
+        .unwrap();
+        protocol.merge_from(&path).unwrap();
+
+        assert_eq!(
+            protocol.field_names(&1),
+            Some(vec!["x".to_string(), "y".to_string()])
+        );
+        assert_eq!(protocol.wire_size(&1), WireSize::Fixed(4));
+        assert_eq!(
+            protocol.get_protocol_id_by_class(&"BetaMessage".to_string()),
+            Some(&3)
+        );
+        assert_eq!(protocol.event_name(&2), "LevelMessage");
+        assert!(matches!(
+            protocol.merge_from(dir.path().join("missing.json")),
+            Err(ProtocolError::NotFound(_))
+        ));
+
+        // the base class of the messages keeps id 0, other classes without an id are found by name
+        let mut protocol = ProtocolManager::new();
+        protocol.init("tests/fixtures/").unwrap();
+        std::fs::write(
+            &path,
+            r#"[
+                {"id": "", "class_name": "BetaInformations", "superclass": null, "attributes": {"flag": "Boolean"}}
+            ]"#,
+        )
+        .unwrap();
+        protocol.merge_from(&path).unwrap();
+        assert_eq!(protocol.event_name(&0), "NetworkMessage");
+        assert!(protocol
+            .get_protocol_by_class(&"BetaInformations".to_string())
+            .is_some());
+
+        std::fs::write(
+            &path,
+            r#"[
+                {"id": "", "class_name": "AlphaInformations", "superclass": null, "attributes": {}},
+                {"id": "", "class_name": "BetaInformations", "superclass": null, "attributes": {}}
+            ]"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            ProtocolManager::from_json(&std::fs::read_to_string(&path).unwrap()),
+            Err(ProtocolError::SeveralWithoutId(..))
+        ));
+    }
+
+    #[test]
+    fn test_missing_parents() {
+        let protocol = ProtocolManager::from_json(
+            r#"[
+                {"id": "1", "class_name": "PositionMessage", "superclass": null, "attributes": {"x": "Short"}},
+                {"id": "2", "class_name": "TimedPositionMessage", "superclass": "PositionMessage", "attributes": {}},
+                {"id": "3", "class_name": "GuildMessage", "superclass": "SocialMessage", "attributes": {}}
+            ]"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            protocol.missing_parents(),
+            vec![(3, "SocialMessage".to_string())]
+        );
+
+        let mut protocol = ProtocolManager::new();
+        protocol.init("tests/fixtures/").unwrap();
+        assert!(protocol.missing_parents().is_empty());
+    }
+
+    #[test]
+    fn test_parse_optional() {
+        let optional = |name: &str| ProtocolVarType::Other(name.to_string()).parse_optional();
+
+        let nested = optional("Optional<Vector<Short,Int>>").unwrap().unwrap();
+        assert_eq!(nested.flag, None);
+        let vector = nested.types.parse_vector().unwrap();
+        assert_eq!(vector.length, ProtocolVarType::Short);
+        assert_eq!(vector.types, ProtocolVarType::Int);
+
+        let flagged = optional("Optional<hasIds, Vector<Short, Int>>")
+            .unwrap()
+            .unwrap();
+        assert_eq!(flagged.flag.as_deref(), Some("hasIds"));
+        assert!(flagged.types.parse_vector().is_some());
+
+        assert!(optional("Vector<Short,Int>").unwrap().is_none());
+        for malformed in ["Optional<Short", "Optional<>", "Optional<a, b, Short>"] {
+            assert!(matches!(
+                optional(malformed),
+                Err(ProtocolError::InvalidType(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_wire_size() {
+        let protocol = ProtocolManager::from_json(
+            r#"[
+                {"id": "1", "class_name": "PositionMessage", "superclass": null, "attributes": {"x": "Short", "y": "Short", "visible": "Boolean"}},
+                {"id": "2", "class_name": "TimedPositionMessage", "superclass": "PositionMessage", "attributes": {"time": "Double", "cells": "Vector<4, UnsignedByte>"}},
+                {"id": "3", "class_name": "NamedMessage", "superclass": null, "attributes": {"id": "Int", "name": "String"}}
+            ]"#,
+        )
+        .unwrap();
+
+        assert_eq!(protocol.wire_size(&1), WireSize::Fixed(5));
+        assert_eq!(protocol.wire_size(&2), WireSize::Fixed(17));
+        assert_eq!(protocol.wire_size(&3), WireSize::Variable);
+        assert_eq!(protocol.wire_size(&4), WireSize::Variable);
+    }
+
+    #[test]
+    fn test_from_json() {
+        let protocol = ProtocolManager::from_json(
+            r#"[
+                {"id": "", "class_name": "NetworkMessage", "superclass": null, "attributes": {}},
+                {"id": "8285", "class_name": "BasicPingMessage", "superclass": "NetworkMessage", "attributes": {"quiet": "Boolean"}}
+            ]"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            protocol.get_protocol_id_by_class(&"BasicPingMessage".to_string()),
+            Some(&8285)
+        );
+        assert_eq!(protocol.ids().collect::<Vec<_>>(), vec![&8285]);
+        assert!(matches!(
+            ProtocolManager::from_json("{}"),
+            Err(ProtocolError::InvalidJson(_))
+        ));
+        assert!(matches!(
+            ProtocolManager::new().init("tests/missing/"),
+            Err(ProtocolError::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_from_archive() {
+        use std::io::Write;
+
+        let events = r#"[{"id": "8285", "class_name": "BasicPingMessage", "superclass": null, "attributes": {"quiet": "Boolean"}}]"#;
+        let dir = tempdir::TempDir::new("protocol_archive").unwrap();
+
+        let zip_path = dir.path().join("data.zip");
+        let mut zip = zip::ZipWriter::new(File::create(&zip_path).unwrap());
+        let options = zip::write::FileOptions::default();
+        zip.start_file("data/A/README.md", options).unwrap();
+        zip.start_file("data/A/events.json", options).unwrap();
+        zip.write_all(events.as_bytes()).unwrap();
+        zip.finish().unwrap();
+
+        let tar_path = dir.path().join("data.tar.gz");
+        let encoder = flate2::write::GzEncoder::new(
+            File::create(&tar_path).unwrap(),
+            flate2::Compression::default(),
+        );
+        let mut tar = tar::Builder::new(encoder);
+        let mut header = tar::Header::new_gnu();
+        header.set_size(events.len() as u64);
+        header.set_cksum();
+        tar.append_data(&mut header, "data/A/events.json", events.as_bytes())
+            .unwrap();
+        tar.into_inner().unwrap().finish().unwrap();
+
+        for path in [zip_path, tar_path] {
+            let protocol = ProtocolManager::from_archive(&path).unwrap();
+            assert_eq!(
+                protocol
+                    .get_protocol(&8285)
+                    .map(|event| event.name.as_str()),
+                Some("BasicPingMessage")
+            );
+        }
+        assert!(matches!(
+            ProtocolManager::from_archive(dir.path().join("data.zip.bak")),
+            Err(ProtocolError::IoError(_))
+        ));
+    }
+
+    #[test]
+    fn test_diff() {
+        let mut old = ProtocolManager::new();
+        old.init("tests/fixtures/protocol_diff/old").unwrap();
+        let mut new = ProtocolManager::new();
+        new.init("tests/fixtures/protocol_diff/new").unwrap();
+
+        let diff = old.diff(&new);
+        assert_eq!(diff.added, vec![4]);
+        assert_eq!(diff.removed, vec![3]);
+        assert_eq!(diff.changed.len(), 2);
+        assert_eq!(
+            diff.changed[&1],
+            EventDiff {
+                added: vec!["build".to_string(), "version".to_string()],
+                removed: vec![],
+                type_changed: vec![FieldTypeChange {
+                    name: "key".to_string(),
+                    from: ProtocolVarType::Other("Vector<VarInt,Byte>".to_string()),
+                    to: ProtocolVarType::Other("Vector<VarInt,Int>".to_string()),
+                }],
+            }
+        );
+        assert_eq!(diff.changed[&2].removed, vec!["quiet".to_string()]);
+
+        assert_eq!(
+            serde_json::to_value(&diff.changed[&2]).unwrap(),
+            serde_json::json!({ "added": [], "removed": ["quiet"], "typeChanged": [] })
+        );
+        assert!(new.diff(&new).is_empty());
+    }
 }
@@ -0,0 +1,797 @@
+use std::{
+    fmt::Debug,
+    ops::Range,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use tracing::{debug, warn};
+
+use super::{
+    parser::{
+        metadata::{
+            ConnectionKey, MessageHeader, MessageSource, PacketHeader, PacketMetadata, ParseResult,
+        },
+        wrapper::DataWrapper,
+    },
+    protocol::protocol::EventId,
+};
+
+/// A message missing segments for this long is dropped, its bytes would never be framed
+const STALE_BUFFER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Bytes scanned for the next message after junk, the buffer is dropped past them
+const MAX_RESYNC_SCAN: usize = 64;
+
+/// Segments buffered behind a hole before the missing bytes are given up
+const MAX_REORDER_SEGMENTS: usize = 64;
+
+/// Whether the protocol has a message with this id, see `ReassemblyState::with_resync`
+pub type KnownEvent = Arc<dyn Fn(EventId) -> bool + Send + Sync>;
+
+#[derive(Clone)]
+struct Resync(KnownEvent);
+
+impl Debug for Resync {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Resync")
+    }
+}
+
+/// Whether `a` comes before `b` in the stream, the TCP sequence number wraps around
+fn is_before(a: u32, b: u32) -> bool {
+    (b.wrapping_sub(a) as i32) > 0
+}
+
+/// Bytes that could not be framed into a message, they are dropped from the buffer
+#[derive(Debug)]
+pub struct InvalidBuffer {
+    pub error: ParseResult,
+    pub data: Vec<u8>,
+}
+
+/// Bytes received so far for the message being reassembled
+#[derive(Debug)]
+pub struct ReassemblyState {
+    buffer: DataWrapper<Vec<u8>>,
+    /// TCP sequence number and length of the segments in `buffer`, in stream order
+    segments: Vec<(u32, usize)>,
+    /// TCP sequence number following the bytes framed or dropped, earlier bytes are retransmissions
+    delivered: Option<u32>,
+    /// The buffer is dropped when it grows past this size
+    max_buffer_bytes: Option<usize>,
+    packet_count: u64,
+    last_seen: Option<SystemTime>,
+    /// TCP sequence number expected for the next payload byte
+    next_tcp_seq: Option<u32>,
+    /// Sequence numbers skipped by the last segment, see `take_gap`
+    gap: Option<Range<u32>>,
+    /// Connection of the last segment
+    connection: Option<ConnectionKey>,
+    source: MessageSource,
+    resync: Option<Resync>,
+    /// The buffer starts right after a framed message, junk is only looked for there
+    after_message: bool,
+    /// Junk skipped after a message since the last `take_resyncs`
+    resyncs: u64,
+    /// Buffers dropped with their bytes since the last `take_clears`, see `force_clear`
+    clears: u64,
+}
+
+impl ReassemblyState {
+    pub fn new() -> Self {
+        ReassemblyState::with_max_buffer_bytes(None)
+    }
+
+    pub fn with_max_buffer_bytes(max_buffer_bytes: Option<usize>) -> Self {
+        ReassemblyState {
+            buffer: DataWrapper::new(Vec::new()),
+            segments: Vec::new(),
+            delivered: None,
+            max_buffer_bytes,
+            packet_count: 0,
+            last_seen: None,
+            next_tcp_seq: None,
+            gap: None,
+            connection: None,
+            source: MessageSource::Server,
+            resync: None,
+            after_message: false,
+            resyncs: 0,
+            clears: 0,
+        }
+    }
+
+    /// The stream carries the messages of `source`, they are framed with its header layout
+    pub fn with_source(mut self, source: MessageSource) -> Self {
+        self.source = source;
+        self
+    }
+
+    /// Junk following a message is skipped up to the next message of a known id,
+    /// instead of being framed with a bogus length that would desync the stream.
+    /// Bytes are junk when their header is implausible, see `MessageHeader::is_plausible`,
+    /// or when its length runs over the next segment while that one holds whole messages.
+    pub fn with_resync(mut self, known: KnownEvent) -> Self {
+        self.resync = Some(Resync(known));
+        self
+    }
+
+    /// Number of times junk was skipped since the last call
+    pub fn take_resyncs(&mut self) -> u64 {
+        std::mem::take(&mut self.resyncs)
+    }
+
+    /// Number of times buffered bytes were dropped since the last call: stale, oversized or unframable
+    pub fn take_clears(&mut self) -> u64 {
+        std::mem::take(&mut self.clears)
+    }
+
+    /// Bytes waiting for the rest of their message
+    pub fn buffered(&self) -> &[u8] {
+        self.buffer.get_remaining()
+    }
+
+    /// Number of segments pushed to the stream
+    pub fn packet_count(&self) -> u64 {
+        self.packet_count
+    }
+
+    /// When the last segment was pushed, `None` before the first one
+    pub fn last_seen(&self) -> Option<SystemTime> {
+        self.last_seen
+    }
+
+    pub fn next_tcp_seq(&self) -> Option<u32> {
+        self.next_tcp_seq
+    }
+
+    /// Range of TCP sequence numbers the last segment jumped over, they were lost or arrive later
+    pub fn take_gap(&mut self) -> Option<Range<u32>> {
+        self.gap.take()
+    }
+
+    fn track_sequence(&mut self, seq: u32, len: usize) {
+        let end = seq.wrapping_add(len as u32);
+        match self.next_tcp_seq {
+            Some(expected) => {
+                if (seq.wrapping_sub(expected) as i32) > 0 {
+                    self.gap = Some(expected..seq);
+                }
+                // retransmissions and late segments don't move the stream back
+                if (end.wrapping_sub(expected) as i32) > 0 {
+                    self.next_tcp_seq = Some(end);
+                }
+            }
+            None => self.next_tcp_seq = Some(end),
+        }
+    }
+
+    pub fn connection(&self) -> Option<ConnectionKey> {
+        self.connection
+    }
+
+    /// Adds a segment to the stream and returns the messages it completed.
+    /// A FIN or a RST ends the stream, the buffered messages are flushed and the buffer released.
+    pub fn push(&mut self, header: PacketHeader) -> Vec<Result<PacketMetadata, InvalidBuffer>> {
+        let closes = header.tcp.is_fin() || header.tcp.is_rst();
+        self.insert(header);
+        if !closes {
+            return self.drain();
+        }
+        let frames = self.flush();
+        self.close();
+        frames
+    }
+
+    /// Drops the bytes of a closed connection and releases the memory of the buffer
+    pub fn close(&mut self) {
+        self.drop_buffer();
+        self.buffer.reset();
+    }
+
+    /// Adds a segment to the stream without framing the messages it completed, see `drain`
+    pub fn insert(&mut self, mut header: PacketHeader) {
+        self.packet_count += 1;
+        self.connection = Some(header.connection_key());
+        let now = SystemTime::now();
+        let idle = self
+            .last_seen
+            .and_then(|last_seen| now.duration_since(last_seen).ok())
+            .unwrap_or_default();
+        if self.buffer.remaining() > 0 && idle > STALE_BUFFER_TIMEOUT {
+            warn!(
+                "No segment for {:?}, dropping {} buffered bytes",
+                idle,
+                self.buffer.remaining()
+            );
+            self.force_clear();
+        } else if idle > STALE_BUFFER_TIMEOUT {
+            // the tuple may be reused by a connection whose SYN wasn't captured
+            self.drop_buffer();
+        }
+        self.last_seen = Some(now);
+
+        // a new connection on the same tuple, bytes left by the previous one are stale
+        if header.tcp.is_syn() {
+            self.drop_buffer();
+            self.next_tcp_seq = Some(header.tcp.seq.wrapping_add(1));
+            self.delivered = self.next_tcp_seq;
+        }
+
+        // the SYN takes a sequence number before the payload, the FIN one after it
+        let seq = header.tcp.seq.wrapping_add(header.tcp.is_syn() as u32);
+        self.track_sequence(seq, header.body.len() + header.tcp.is_fin() as usize);
+        // pure ACKs and keepalives, nothing to reassemble and their order doesn't matter
+        if header.body.is_empty() {
+            return;
+        }
+        self.insert_segment(seq, header.take_body());
+        if let Some(max_buffer_bytes) = self.max_buffer_bytes {
+            if self.buffer.get_remaining().len() > max_buffer_bytes {
+                warn!(
+                    "Reassembly buffer exceeded {} bytes, dropping it",
+                    max_buffer_bytes
+                );
+                self.force_clear();
+            }
+        }
+    }
+
+    /// Frames the complete messages at the start of the buffer.
+    /// A segment can end with the start of the next message, it stays buffered.
+    /// Bytes behind a hole wait for the missing segment, up to `MAX_REORDER_SEGMENTS` segments.
+    pub fn drain(&mut self) -> Vec<Result<PacketMetadata, InvalidBuffer>> {
+        let mut frames = Vec::new();
+        while self.buffer.remaining() > 0 {
+            let contiguous = self.contiguous();
+            let waited_too_long = self.segments.len() > MAX_REORDER_SEGMENTS;
+            if contiguous == 0 {
+                // the bytes before the hole were lost when a message starts right after it
+                if !waited_too_long && !self.starts_message_after_hole() {
+                    break;
+                }
+                self.skip_hole();
+                continue;
+            }
+            if self.after_message {
+                match self.next_message_offset(contiguous) {
+                    Some(0) => {}
+                    Some(offset) => {
+                        debug!("Skipping {} bytes of junk after a message", offset);
+                        self.consume(offset);
+                        self.resyncs += 1;
+                        continue;
+                    }
+                    // the next message can start in the bytes of the next segments
+                    None if contiguous <= MAX_RESYNC_SCAN => break,
+                    None => {
+                        let data = self.buffer.snapshot();
+                        self.force_clear();
+                        frames.push(Err(InvalidBuffer {
+                            error: ParseResult::Invalid,
+                            data,
+                        }));
+                        continue;
+                    }
+                }
+            }
+            match PacketMetadata::from_buffer_sent_by(
+                &self.buffer.get_remaining()[..contiguous],
+                self.source,
+            ) {
+                Err(ParseResult::Incomplete)
+                    if waited_too_long && contiguous < self.buffer.remaining() =>
+                {
+                    warn!(
+                        "Segments missing for too long, dropping {} buffered bytes",
+                        contiguous
+                    );
+                    self.clears += 1;
+                    self.consume(contiguous);
+                    self.skip_hole();
+                }
+                Err(ParseResult::Incomplete) => break,
+                Err(error) => {
+                    let data = self.buffer.snapshot();
+                    self.force_clear();
+                    frames.push(Err(InvalidBuffer { error, data }));
+                }
+                Ok(metadata) => {
+                    self.consume(metadata.consumed);
+                    self.after_message = true;
+                    frames.push(Ok(metadata));
+                }
+            }
+        }
+        frames
+    }
+
+    /// Frames what is buffered without waiting for the missing segments, e.g. once the capture ended.
+    /// The start of a message cut by a hole is dropped, one cut by the end of the stream stays buffered.
+    pub fn flush(&mut self) -> Vec<Result<PacketMetadata, InvalidBuffer>> {
+        let mut frames = self.drain();
+        while self.contiguous() < self.buffer.remaining() {
+            let contiguous = self.contiguous();
+            if contiguous > 0 {
+                warn!("Flushing, dropping {} bytes before a hole", contiguous);
+                self.clears += 1;
+                self.consume(contiguous);
+            }
+            self.skip_hole();
+            frames.extend(self.drain());
+        }
+        frames
+    }
+
+    /// Offset of the message following the framed one, 0 when the bytes after it aren't junk.
+    /// `None` while its header is cut or no message shows up within `MAX_RESYNC_SCAN` bytes.
+    /// Without resync every header is taken as is.
+    fn next_message_offset(&self, contiguous: usize) -> Option<usize> {
+        if self.resync.is_none() {
+            return Some(0);
+        }
+        let bytes = &self.buffer.get_remaining()[..contiguous];
+        let header = MessageHeader::read(bytes, self.source).ok()?;
+        if header.is_plausible() {
+            return Some(self.overrun_segment(&header, contiguous).unwrap_or(0));
+        }
+        (1..contiguous.min(MAX_RESYNC_SCAN + 1)).find(|offset| self.is_message(&bytes[*offset..]))
+    }
+
+    /// Offset of the next segment when `header` announces more bytes than its segment holds,
+    /// although the next segment is made of whole messages
+    fn overrun_segment(&self, header: &MessageHeader, contiguous: usize) -> Option<usize> {
+        let boundary = self.segments.first()?.1;
+        let next = self.segments.get(1)?.1;
+        if header.header_len + header.content_size <= boundary || boundary + next > contiguous {
+            return None;
+        }
+        let mut bytes = &self.buffer.get_remaining()[boundary..boundary + next];
+        while !bytes.is_empty() {
+            if !self.is_message(bytes) {
+                return None;
+            }
+            let header = MessageHeader::read(bytes, self.source).ok()?;
+            bytes = &bytes[header.header_len + header.content_size..];
+        }
+        Some(boundary)
+    }
+
+    /// Whether `bytes` start with a whole message, a plausible one of a known id with resync
+    fn is_message(&self, bytes: &[u8]) -> bool {
+        let Ok(header) = MessageHeader::read(bytes, self.source) else {
+            return false;
+        };
+        let complete = header.header_len + header.content_size <= bytes.len();
+        complete
+            && self.resync.as_ref().map_or(true, |Resync(known)| {
+                header.is_plausible() && known(header.id)
+            })
+    }
+
+    /// Places `body` at its sequence number, however late it arrived.
+    /// Bytes already framed or buffered are retransmissions, only the new ones are kept.
+    fn insert_segment(&mut self, mut seq: u32, mut body: Vec<u8>) {
+        if let Some(delivered) = self.delivered {
+            trim_before(&mut seq, &mut body, delivered);
+        }
+        let index = self
+            .segments
+            .partition_point(|(buffered, _)| !is_before(seq, *buffered));
+        if let Some((previous, len)) = index.checked_sub(1).map(|index| self.segments[index]) {
+            trim_before(&mut seq, &mut body, previous.wrapping_add(len as u32));
+        }
+        if body.is_empty() {
+            debug!("Dropping retransmitted segment {}", seq);
+            return;
+        }
+
+        // the part overlapping the next segment is inserted after it, without its duplicated bytes
+        let rest = self.segments.get(index).and_then(|(next, _)| {
+            let room = next.wrapping_sub(seq) as usize;
+            (body.len() > room).then(|| (*next, body.split_off(room)))
+        });
+        let offset = self.segments[..index].iter().map(|(_, len)| len).sum();
+        self.segments.insert(index, (seq, body.len()));
+        self.buffer.insert(offset, body);
+        if let Some((next, rest)) = rest {
+            self.insert_segment(next, rest);
+        }
+    }
+
+    /// Bytes at the start of the buffer without a hole, the only ones that can be framed
+    fn contiguous(&self) -> usize {
+        match (self.delivered, self.segments.first()) {
+            (Some(delivered), Some((seq, _))) if delivered != *seq => 0,
+            _ => self.first_run(),
+        }
+    }
+
+    /// Bytes of the first segment and of the ones following it without a hole
+    fn first_run(&self) -> usize {
+        let mut expected = self.segments.first().map_or(0, |(seq, _)| *seq);
+        let mut len = 0;
+        for (seq, segment_len) in &self.segments {
+            if *seq != expected {
+                break;
+            }
+            len += segment_len;
+            expected = seq.wrapping_add(*segment_len as u32);
+        }
+        len
+    }
+
+    /// Whether a complete message starts right after the hole at the start of the buffer
+    fn starts_message_after_hole(&self) -> bool {
+        self.is_message(&self.buffer.get_remaining()[..self.first_run()])
+    }
+
+    /// Gives up the bytes missing before the first buffered segment, the stream resumes from it
+    fn skip_hole(&mut self) {
+        if let (Some(delivered), Some(&(seq, _))) = (self.delivered, self.segments.first()) {
+            warn!("Giving up {} missing bytes", seq.wrapping_sub(delivered));
+        }
+        self.delivered = self.segments.first().map(|(seq, _)| *seq);
+        self.after_message = false;
+    }
+
+    /// Drops the first `n` bytes of the buffer along with the segments they came from
+    fn consume(&mut self, mut n: usize) {
+        self.buffer.consume(n);
+        while n > 0 && !self.segments.is_empty() {
+            let (seq, len) = &mut self.segments[0];
+            if *len > n {
+                *seq = seq.wrapping_add(n as u32);
+                *len -= n;
+                self.delivered = Some(*seq);
+                return;
+            }
+            n -= *len;
+            self.delivered = Some(seq.wrapping_add(*len as u32));
+            self.segments.remove(0);
+        }
+    }
+
+    /// The stream resumes from the next segment, whatever its sequence number
+    fn drop_buffer(&mut self) {
+        self.buffer.clear();
+        self.segments.clear();
+        self.delivered = None;
+        self.after_message = false;
+    }
+
+    /// Same as `drop_buffer` when the bytes are lost rather than left by a closed connection
+    fn force_clear(&mut self) {
+        self.clears += 1;
+        self.drop_buffer();
+    }
+}
+
+/// Drops the bytes of `body` sent before `until`, they were already received
+fn trim_before(seq: &mut u32, body: &mut Vec<u8>, until: u32) {
+    let duplicate = (until.wrapping_sub(*seq) as i32).max(0) as usize;
+    body.drain(..duplicate.min(body.len()));
+    *seq = seq.wrapping_add(duplicate as u32);
+}
+
+impl Default for ReassemblyState {
+    fn default() -> Self {
+        ReassemblyState::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sniffer::{
+        parser::{metadata::TcpInfo, packet::PacketParser},
+        protocol::protocol::ProtocolManager,
+    };
+
+    // ProtocolRequired { version: "abcdef" }
+    const PROTOCOL_REQUIRED: [u8; 11] = [
+        0x9b, 0x09, 0x08, 0x00, 0x06, b'a', b'b', b'c', b'd', b'e', b'f',
+    ];
+
+    fn protocol() -> ProtocolManager {
+        let mut protocol = ProtocolManager::new();
+        protocol.init("tests/fixtures/").unwrap();
+        protocol
+    }
+
+    /// Segment starting at the TCP sequence number `seq`
+    fn segment(seq: u32, body: &[u8]) -> PacketHeader {
+        PacketHeader {
+            source_port: 5555,
+            destination_port: 50000,
+            source_ip: vec![127, 0, 0, 1],
+            destination_ip: vec![127, 0, 0, 1],
+            seq_num: seq as u16,
+            tcp_payload_start: 0,
+            tcp: TcpInfo {
+                seq,
+                flags: TcpInfo::PSH | TcpInfo::ACK,
+                ..TcpInfo::default()
+            },
+            body: body.to_vec(),
+        }
+    }
+
+    /// Versions carried by the framed `ProtocolRequired`, every frame must be one of them
+    fn versions(frames: Vec<Result<PacketMetadata, InvalidBuffer>>) -> Vec<String> {
+        frames
+            .into_iter()
+            .map(|frame| {
+                let metadata = frame.unwrap();
+                assert_eq!(metadata.id, 9922);
+                // the version is a UTF string, after its 2 bytes length
+                String::from_utf8(metadata.data[2..].to_vec()).unwrap()
+            })
+            .collect()
+    }
+
+    fn resyncing(protocol: &Arc<ProtocolManager>) -> ReassemblyState {
+        let known = protocol.clone();
+        ReassemblyState::new().with_resync(Arc::new(move |id| known.get_protocol(&id).is_some()))
+    }
+
+    #[test]
+    fn test_resync_after_junk() {
+        let protocol = Arc::new(protocol());
+
+        // id 0 can't be sent
+        let mut state = resyncing(&protocol);
+        let mut body = PROTOCOL_REQUIRED.to_vec();
+        body.extend_from_slice(&[0x00, 0x01, 0x00]);
+        body.extend_from_slice(&PROTOCOL_REQUIRED);
+        let frames = state.push(segment(1, &body));
+        assert_eq!(versions(frames), vec!["abcdef", "abcdef"]);
+        assert_eq!(state.take_resyncs(), 1);
+
+        // a plausible length running over a next segment of whole messages
+        let mut state = resyncing(&protocol);
+        let mut body = PROTOCOL_REQUIRED.to_vec();
+        body.extend_from_slice(&[0xff, 0xff, 0xff]);
+        let frames = state.push(segment(1, &body));
+        assert_eq!(versions(frames), vec!["abcdef"]);
+        let frames = state.push(segment(15, &PROTOCOL_REQUIRED));
+        assert_eq!(versions(frames), vec!["abcdef"]);
+        assert_eq!(state.take_resyncs(), 1);
+
+        // junk cut by the end of the segment is skipped once the next one arrives
+        let mut state = resyncing(&protocol);
+        let mut body = PROTOCOL_REQUIRED.to_vec();
+        body.push(0x00);
+        assert_eq!(state.push(segment(1, &body)).len(), 1);
+        let mut body = vec![0x01, 0x00];
+        body.extend_from_slice(&PROTOCOL_REQUIRED);
+        let frames = state.push(segment(13, &body));
+        assert_eq!(versions(frames), vec!["abcdef"]);
+        assert_eq!(state.take_resyncs(), 1);
+
+        assert_eq!(state.take_clears(), 0);
+        assert!(state.buffered().is_empty());
+    }
+
+    #[test]
+    fn test_unknown_message_is_not_junk() {
+        let protocol = Arc::new(protocol());
+        let mut state = resyncing(&protocol);
+
+        // id 16383 isn't in the protocol but its header is one the game writes
+        let mut body = PROTOCOL_REQUIRED.to_vec();
+        body.extend_from_slice(&[0xff, 0xfc]);
+        body.extend_from_slice(&PROTOCOL_REQUIRED);
+        let ids: Vec<u16> = state
+            .push(segment(1, &body))
+            .into_iter()
+            .map(|frame| frame.unwrap().id)
+            .collect();
+
+        assert_eq!(ids, vec![9922, 16383, 9922]);
+        assert_eq!(state.take_resyncs(), 0);
+    }
+
+    #[test]
+    fn test_ordered_segments() {
+        let mut state = ReassemblyState::new();
+
+        let (first, second) = PROTOCOL_REQUIRED.split_at(5);
+        assert!(state.push(segment(1000, first)).is_empty());
+        assert_eq!(state.buffered(), first);
+        let frames = state.push(segment(1005, second));
+
+        assert_eq!(versions(frames), vec!["abcdef"]);
+        assert!(state.buffered().is_empty());
+    }
+
+    #[test]
+    fn test_reordered_segments() {
+        let mut state = ReassemblyState::new();
+
+        // the middle segment arrives last
+        let (head, tail) = PROTOCOL_REQUIRED.split_at(3);
+        let (middle, last) = tail.split_at(4);
+        assert!(state.push(segment(100, head)).is_empty());
+        assert!(state.push(segment(107, last)).is_empty());
+        let frames = state.push(segment(103, middle));
+
+        assert_eq!(versions(frames), vec!["abcdef"]);
+    }
+
+    #[test]
+    fn test_syn_resets_stream() {
+        let mut state = ReassemblyState::new();
+
+        // the previous connection stopped in the middle of a message
+        assert!(state.push(segment(1, &PROTOCOL_REQUIRED[..4])).is_empty());
+        let mut syn = segment(2, &[]);
+        syn.tcp = TcpInfo {
+            seq: 1000,
+            flags: TcpInfo::SYN,
+            ..TcpInfo::default()
+        };
+        assert!(state.push(syn).is_empty());
+        assert!(state.buffered().is_empty());
+        assert_eq!(state.next_tcp_seq(), Some(1001));
+
+        let frames = state.push(segment(1001, &PROTOCOL_REQUIRED));
+        assert_eq!(versions(frames), vec!["abcdef"]);
+    }
+
+    #[test]
+    fn test_empty_segment() {
+        let mut state = ReassemblyState::new();
+
+        // an ACK with a higher sequence number lands between the two halves
+        let (first, second) = PROTOCOL_REQUIRED.split_at(5);
+        assert!(state.push(segment(1, first)).is_empty());
+        assert!(state.push(segment(20, &[])).is_empty());
+        assert_eq!(state.buffered(), first);
+        assert_eq!(state.packet_count(), 2);
+
+        let frames = state.push(segment(6, second));
+        assert_eq!(versions(frames), vec!["abcdef"]);
+    }
+
+    #[test]
+    fn test_sequence_without_payload() {
+        let mut state = ReassemblyState::new();
+
+        assert_eq!(state.push(segment(1, &PROTOCOL_REQUIRED)).len(), 1);
+        assert_eq!(state.next_tcp_seq(), Some(12));
+        // a pure ACK past the expected byte, the segments in between were lost
+        assert!(state.push(segment(20, &[])).is_empty());
+        assert_eq!(state.take_gap(), Some(12..20));
+        assert_eq!(state.next_tcp_seq(), Some(20));
+
+        // the FIN takes a sequence number
+        let mut fin = segment(20, &[]);
+        fin.tcp.flags |= TcpInfo::FIN;
+        assert!(state.push(fin).is_empty());
+        assert_eq!(state.next_tcp_seq(), Some(21));
+        assert_eq!(state.take_gap(), None);
+    }
+
+    #[test]
+    fn test_pipelined_messages() {
+        let mut state = ReassemblyState::new();
+
+        // two messages and the first bytes of a third in one segment
+        let mut body = PROTOCOL_REQUIRED.to_vec();
+        body.extend_from_slice(&PROTOCOL_REQUIRED);
+        body.extend_from_slice(&PROTOCOL_REQUIRED[..1]);
+        let frames = state.push(segment(1, &body));
+        assert_eq!(versions(frames), vec!["abcdef", "abcdef"]);
+        assert_eq!(state.buffered(), &PROTOCOL_REQUIRED[..1]);
+
+        let frames = state.push(segment(24, &PROTOCOL_REQUIRED[1..]));
+        assert_eq!(versions(frames), vec!["abcdef"]);
+        assert!(state.buffered().is_empty());
+    }
+
+    #[test]
+    fn test_message_over_many_segments() {
+        let protocol = Arc::new(protocol());
+
+        // a 20 kB message
+        let content: Vec<u8> = (0..20_000).map(|i| (i % 251) as u8).collect();
+        let mut message = vec![0x6c, 0x0e, 0x4e, 0x20];
+        message.extend(&content);
+        let segments: Vec<&[u8]> = message.chunks(500).collect();
+        assert_eq!(segments.len(), 41);
+        // the sequence numbers wrap around in the middle of the message
+        let seq = |index: usize| (u32::MAX - 10_000).wrapping_add(index as u32 * 500);
+
+        let shuffled = (0..40).map(|i| (i * 7) % 40 + 1);
+        let header_first: Vec<usize> = std::iter::once(0).chain(shuffled.clone()).collect();
+        // the header comes after a few of the segments it announces
+        let header_late: Vec<usize> = shuffled
+            .clone()
+            .take(5)
+            .chain([0])
+            .chain(shuffled.skip(5))
+            .collect();
+        for order in [header_first, header_late] {
+            let mut state = resyncing(&protocol);
+            // a ping before it, the stream is aligned on a message
+            let ping = [0x81, 0x75, 0x01, 0x01];
+            assert_eq!(state.push(segment(seq(0).wrapping_sub(4), &ping)).len(), 1);
+
+            let mut frames = Vec::new();
+            for (pushed, index) in order.iter().enumerate() {
+                frames = state.push(segment(seq(*index), segments[*index]));
+                if pushed < order.len() - 1 {
+                    assert!(frames.is_empty(), "framed after {} segments", pushed + 1);
+                }
+            }
+
+            let metadata = frames.pop().unwrap().unwrap();
+            assert_eq!(metadata.id, 6915);
+            assert_eq!(*metadata.data, content[..]);
+            assert!(state.buffered().is_empty());
+        }
+    }
+
+    #[test]
+    fn test_flush_gives_up_holes() {
+        let protocol = protocol();
+        let mut state = ReassemblyState::new();
+        assert_eq!(state.push(segment(1, &PROTOCOL_REQUIRED)).len(), 1);
+
+        // the start of a message, then a hole, then a whole one and the start of the next
+        state.push(segment(12, &PROTOCOL_REQUIRED[..4]));
+        let mut body = PROTOCOL_REQUIRED.to_vec();
+        body.extend_from_slice(&PROTOCOL_REQUIRED[..4]);
+        assert!(state.push(segment(20, &body)).is_empty());
+
+        let frames = state.flush();
+        assert_eq!(frames.len(), 1);
+        let packet = PacketParser::from_metadata(frames[0].as_ref().unwrap())
+            .parse(&protocol)
+            .unwrap();
+        assert_eq!(packet.get_string("version"), Some("abcdef"));
+        assert_eq!(state.take_clears(), 1);
+        // the end of the stream may still come
+        assert_eq!(state.buffered(), &PROTOCOL_REQUIRED[..4]);
+    }
+
+    #[test]
+    fn test_close_releases_buffer() {
+        let mut state = ReassemblyState::new();
+        state.push(segment(1, &PROTOCOL_REQUIRED[..4]));
+        // the FIN carries the end of the message
+        let mut fin = segment(5, &PROTOCOL_REQUIRED[4..]);
+        fin.tcp.flags |= TcpInfo::FIN;
+        assert_eq!(state.push(fin).len(), 1);
+        assert_eq!(state.buffer.data.capacity(), 0);
+
+        // a message cut by a reset will never end
+        let mut state = ReassemblyState::new();
+        state.push(segment(1, &PROTOCOL_REQUIRED[..4]));
+        let mut rst = segment(5, &[]);
+        rst.tcp.flags = TcpInfo::RST;
+        assert!(state.push(rst).is_empty());
+        assert!(state.buffered().is_empty());
+        assert_eq!(state.buffer.data.capacity(), 0);
+    }
+
+    #[test]
+    fn test_duplicate_segments() {
+        let mut state = ReassemblyState::new();
+
+        // a retransmitted message is dropped, it was already delivered
+        let frames = state.push(segment(1, &PROTOCOL_REQUIRED));
+        assert_eq!(versions(frames), vec!["abcdef"]);
+        assert!(state.push(segment(1, &PROTOCOL_REQUIRED)).is_empty());
+
+        // so are the bytes of a buffered segment sent again with the rest of the message
+        let ping = [0x81, 0x75, 0x01, 0x01];
+        assert!(state.push(segment(12, &ping[..3])).is_empty());
+        let frames = state.push(segment(12, &ping));
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].as_ref().unwrap().id, 8285);
+        assert!(state.buffered().is_empty());
+    }
+}
@@ -0,0 +1,172 @@
+use std::{collections::HashMap, fs, path::Path};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum StoreValue {
+    Int(i64),
+    Float(f64),
+    Text(String),
+    Bytes(Vec<u8>),
+    Json(serde_json::Value),
+}
+
+/// Typed key/value store, often used to share data between packet listeners
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Store {
+    values: HashMap<String, StoreValue>,
+}
+
+impl Store {
+    pub fn new() -> Self {
+        Store::default()
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, StoreError> {
+        let content = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&content)?)
+    }
+
+    /// Writes the store to a temporary file first then renames it, so a crash never leaves a partial file
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), StoreError> {
+        let path = path.as_ref();
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, serde_json::to_string(self)?)?;
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    pub fn get(&self, key: &str) -> Option<&StoreValue> {
+        self.values.get(key)
+    }
+
+    pub fn set(&mut self, key: impl Into<String>, value: StoreValue) {
+        self.values.insert(key.into(), value);
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<StoreValue> {
+        self.values.remove(key)
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.values.contains_key(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    pub fn get_int(&self, key: &str) -> Option<i64> {
+        match self.get(key) {
+            Some(StoreValue::Int(value)) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn set_int(&mut self, key: impl Into<String>, value: i64) {
+        self.set(key, StoreValue::Int(value));
+    }
+
+    /// Adds `by` to the integer stored at `key` (starting from 0) and returns the new value
+    pub fn increment(&mut self, key: impl Into<String>, by: i64) -> i64 {
+        let key = key.into();
+        let value = self.get_int(&key).unwrap_or(0) + by;
+        self.set_int(key, value);
+        value
+    }
+
+    pub fn get_float(&self, key: &str) -> Option<f64> {
+        match self.get(key) {
+            Some(StoreValue::Float(value)) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn set_float(&mut self, key: impl Into<String>, value: f64) {
+        self.set(key, StoreValue::Float(value));
+    }
+
+    pub fn get_text(&self, key: &str) -> Option<&str> {
+        match self.get(key) {
+            Some(StoreValue::Text(value)) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn set_text(&mut self, key: impl Into<String>, value: impl Into<String>) {
+        self.set(key, StoreValue::Text(value.into()));
+    }
+
+    pub fn get_bytes(&self, key: &str) -> Option<&[u8]> {
+        match self.get(key) {
+            Some(StoreValue::Bytes(value)) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn set_bytes(&mut self, key: impl Into<String>, value: Vec<u8>) {
+        self.set(key, StoreValue::Bytes(value));
+    }
+
+    pub fn get_json(&self, key: &str) -> Option<&serde_json::Value> {
+        match self.get(key) {
+            Some(StoreValue::Json(value)) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn set_json(&mut self, key: impl Into<String>, value: serde_json::Value) {
+        self.set(key, StoreValue::Json(value));
+    }
+
+    /// String representation of any value, for code that still expects a String -> String store
+    pub fn get_string(&self, key: &str) -> Option<String> {
+        self.get(key).map(|value| match value {
+            StoreValue::Int(value) => value.to_string(),
+            StoreValue::Float(value) => value.to_string(),
+            StoreValue::Text(value) => value.clone(),
+            StoreValue::Bytes(value) => String::from_utf8_lossy(value).to_string(),
+            StoreValue::Json(value) => value.to_string(),
+        })
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum StoreError {
+    #[error(transparent)]
+    IoError(#[from] std::io::Error),
+    #[error(transparent)]
+    SerdeJson(#[from] serde_json::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_typed_values() {
+        let mut store = Store::new();
+        store.set_int("count", 1);
+        store.set_text("name", "Vahor");
+        store.set_json("data", serde_json::json!({ "id": 1 }));
+
+        assert_eq!(store.increment("count", 2), 3);
+        assert_eq!(store.increment("missing", 1), 1);
+        assert_eq!(store.get_int("count"), Some(3));
+        assert_eq!(store.get_text("name"), Some("Vahor"));
+        assert_eq!(store.get_json("data").unwrap()["id"], 1);
+
+        // wrong type
+        assert_eq!(store.get_int("name"), None);
+        assert_eq!(store.get_float("count"), None);
+
+        assert_eq!(store.get_string("count"), Some("3".to_string()));
+        assert_eq!(store.len(), 4);
+    }
+}
@@ -22,6 +22,12 @@ export const commands = {
   async getLastPacketTimestamp(): Promise<bigint> {
     return await TAURI_INVOKE('get_last_packet_timestamp');
   },
+  async getCaptureStats(): Promise<CaptureStats> {
+    return await TAURI_INVOKE('get_capture_stats');
+  },
+  async getProtocolCatalog(): Promise<ProtocolCatalog> {
+    return await TAURI_INVOKE('get_protocol_catalog');
+  },
   async setActiveChatTab(windowId: string | null): Promise<void> {
     return await TAURI_INVOKE('set_active_chat_tab', { windowId });
   },
@@ -38,6 +44,19 @@ export const events = __makeEvents__<{
 
 /** user-defined types **/
 
+export type CaptureStats = { received: number; dropped: number; ifDropped: number };
+export type CatalogAttribute = {
+  name: string;
+  type: string;
+  rawType: string | null;
+  comment: string | null;
+};
+export type CatalogEvent = {
+  id: number;
+  name: string;
+  parent: string | null;
+  attributes: CatalogAttribute[];
+};
 export type ChatEvent = {
   channel: number;
   sender_name: string;
@@ -61,8 +80,25 @@ export type ChatTabFilterType =
   | { type: 'word'; value: string }
   | { type: 'item'; value: number };
 export type ChatTabOptions = { keepHistory: boolean; notify: boolean };
-export type NetworkConfig = { port: number; interface: string };
-export type NodeConfig = { network: NetworkConfig; gameVersion: Version };
+export type EventFilterConfig = { allow: EventRef[]; deny: EventRef[] };
+export type EventRef = number | string;
+export type NetworkConfig = {
+  ports: number[];
+  interface: string;
+  host: string | null;
+  additionalInterfaces: string[];
+  snaplen: number;
+  bufferSize: number;
+  readTimeoutMs: number;
+  immediateMode: boolean;
+  promiscuous: boolean;
+};
+export type NodeConfig = {
+  network: NetworkConfig;
+  gameVersion: Version;
+  events: EventFilterConfig;
+};
+export type ProtocolCatalog = { events: CatalogEvent[] };
 export type Version = { version: string; checkForUpdates: boolean };
 
 /** tauri-specta globals **/